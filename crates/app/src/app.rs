@@ -1,4 +1,4 @@
-use std::{cell::RefCell, fmt::Display, sync::Arc, time::Duration};
+use std::{cell::RefCell, fmt::Display, path::Path, sync::Arc, time::Duration};
 
 use color_eyre::{eyre::ContextCompat, Result};
 use egui_wgpu::renderer::ScreenDescriptor;
@@ -36,6 +36,7 @@ use self::{
     state::{AppState, StateAction},
 };
 use crate::{
+    pass::{tlas_refit::TlasRefit, Pass},
     AreaLight, Example, Instance, InstancePool, LightPool, MaterialPool, TexturePool,
     {MeshId, MeshPool, MeshRef},
 };
@@ -68,6 +69,7 @@ pub struct App {
 
     draw_cmd_buffer: ResizableBuffer<DrawIndexedIndirect>,
     draw_cmd_bind_group: wgpu::BindGroup,
+    tlas_refit: TlasRefit,
 
     pub blitter: Blitter,
 
@@ -169,6 +171,7 @@ impl App {
             wgpu::BufferUsages::INDIRECT | wgpu::BufferUsages::STORAGE,
         );
         let draw_cmd_bind_group = draw_cmd_buffer.create_storage_write_bind_group(&mut world);
+        let tlas_refit = TlasRefit::new(&world, Path::new("shaders").join("tlas_refit.wgsl"))?;
 
         let profiler = RefCell::new(GpuProfiler::new(
             gpu.adapter(),
@@ -202,6 +205,7 @@ impl App {
 
             draw_cmd_buffer,
             draw_cmd_bind_group,
+            tlas_refit,
 
             profiler,
             blitter: Blitter::new(&world),
@@ -237,54 +241,60 @@ impl App {
 
     pub fn setup_scene(&mut self, example: &mut impl Example) -> Result<()> {
         example.setup_scene(self)?;
+        self.sync_instances()
+    }
+
+    /// Picks up instances moved on the GPU and uploads the ones changed on
+    /// the CPU, then resizes the draw commands and rebuilds or refits the
+    /// TLAS to match them.
+    fn sync_instances(&mut self) -> Result<()> {
+        let changes = {
+            let meshes = self.world.get::<MeshPool>()?;
+            let mut instances = self.world.get_mut::<InstancePool>()?;
+            instances.remove_stale_meshes(|mesh| meshes.contains(mesh));
+            instances.read_back();
+            instances.flush()
+        };
 
-        let mut encoder = self.device().create_command_encoder(&Default::default());
-        self.draw_cmd_buffer.set_len(
-            self.gpu.device(),
-            &mut encoder,
-            self.world.get_mut::<InstancePool>()?.count() as _,
-        );
+        // Single and double sided instances are drawn from separate halves
+        let count = 2 * self.get_instance_pool().count() as usize;
+        if count != self.draw_cmd_buffer.len() {
+            let mut encoder = self.device().create_command_encoder(&Default::default());
+            self.draw_cmd_buffer
+                .set_len(self.gpu.device(), &mut encoder, count);
+            self.gpu.queue().submit(Some(encoder.finish()));
+
+            self.draw_cmd_bind_group = self
+                .draw_cmd_buffer
+                .create_storage_write_bind_group(&mut self.world);
+        }
 
-        self.draw_cmd_bind_group = self
-            .draw_cmd_buffer
-            .create_storage_write_bind_group(&mut self.world);
-
-        let mut mesh_pool = self.get_mesh_pool_mut();
-        mesh_pool.generate_tlas(&self.get_instance_pool().instances_data);
-
-        mesh_pool.trace_bind_group = {
-            let instance_pool = self.get_instance_pool();
-            self.device().create_bind_group(&wgpu::BindGroupDescriptor {
-                label: Some("Trace BG"),
-                layout: &mesh_pool.trace_bind_group_layout,
-                entries: &[
-                    wgpu::BindGroupEntry {
-                        binding: 0,
-                        resource: mesh_pool.tlas_nodes.as_tight_binding(),
-                    },
-                    wgpu::BindGroupEntry {
-                        binding: 1,
-                        resource: instance_pool.instances.as_tight_binding(),
-                    },
-                    wgpu::BindGroupEntry {
-                        binding: 2,
-                        resource: mesh_pool.mesh_info.as_entire_binding(),
-                    },
-                    wgpu::BindGroupEntry {
-                        binding: 3,
-                        resource: mesh_pool.bvh_nodes.as_entire_binding(),
-                    },
-                    wgpu::BindGroupEntry {
-                        binding: 4,
-                        resource: mesh_pool.vertices.as_entire_binding(),
-                    },
-                    wgpu::BindGroupEntry {
-                        binding: 5,
-                        resource: mesh_pool.indices.as_entire_binding(),
-                    },
-                ],
-            })
+        if changes.added_or_removed {
+            self.get_mesh_pool_mut()
+                .generate_tlas(&self.get_instance_pool());
+        } else if changes.moved {
+            self.get_mesh_pool_mut()
+                .refit_tlas(&self.get_instance_pool());
+        } else {
+            return Ok(());
+        }
+
+        // GPU bounds come from the GPU instances, which are ahead of the CPU
+        // copy for anything compute passes move
+        let mut profiler = self.profiler.borrow_mut();
+        let mut encoder = self
+            .device()
+            .create_command_encoder(&wgpu::CommandEncoderDescriptor {
+                label: Some("Tlas Refit Encoder"),
+            });
+        let mut profiler_encoder = ProfilerCommandEncoder {
+            encoder: &mut encoder,
+            device: self.gpu.device(),
+            profiler: &mut profiler,
         };
+        self.tlas_refit
+            .record(&self.world, &mut profiler_encoder, ());
+        self.gpu.queue().submit(Some(encoder.finish()));
 
         Ok(())
     }
@@ -382,6 +392,8 @@ impl App {
         actions: Vec<StateAction>,
         update: impl FnOnce(UpdateContext),
     ) -> Result<()> {
+        self.sync_instances()?;
+
         let mut profiler = self.profiler.borrow_mut();
         let mut encoder = self
             .device()
@@ -468,6 +480,10 @@ impl App {
         self.world.unwrap_mut::<MeshPool>().add(mesh)
     }
 
+    pub fn add_meshes(&mut self, meshes: Vec<MeshRef>) -> Vec<MeshId> {
+        self.world.unwrap_mut::<MeshPool>().add_many(meshes)
+    }
+
     pub fn get_material_pool(&self) -> Read<MaterialPool> {
         self.world.unwrap::<MaterialPool>()
     }
@@ -9,6 +9,8 @@ use gltf::{
 use image::{buffer::ConvertBuffer, ImageBuffer};
 use wgpu::{FilterMode, PrimitiveTopology, TextureFormat};
 
+use crate::SamplerDesc;
+
 pub fn component_type_to_index_format(ty: gltf::accessor::DataType) -> wgpu::IndexFormat {
     match ty {
         DataType::U16 => wgpu::IndexFormat::Uint16,
@@ -79,33 +81,32 @@ pub fn accessor_type_to_format(accessor: &gltf::accessor::Accessor) -> wgpu::Ver
     }
 }
 
-pub fn convert_sampler(device: &wgpu::Device, sampler: gltf::texture::Sampler) -> wgpu::Sampler {
+/// Unset filters fall back to linear filtering with linear mipmaps.
+pub fn convert_sampler(sampler: gltf::texture::Sampler) -> SamplerDesc {
     let mag_filter = match sampler.mag_filter() {
-        Some(MagFilter::Linear) => FilterMode::Nearest,
-        Some(MagFilter::Nearest) => FilterMode::Linear,
-        None => FilterMode::Linear,
+        Some(MagFilter::Nearest) => FilterMode::Nearest,
+        Some(MagFilter::Linear) | None => FilterMode::Linear,
     };
     let min_filter = match sampler.min_filter() {
         Some(
-            MinFilter::Linear | MinFilter::LinearMipmapLinear | MinFilter::LinearMipmapNearest,
-        ) => FilterMode::Linear,
-        _ => FilterMode::Nearest,
+            MinFilter::Nearest | MinFilter::NearestMipmapNearest | MinFilter::NearestMipmapLinear,
+        ) => FilterMode::Nearest,
+        _ => FilterMode::Linear,
     };
     let mipmap_filter = match sampler.min_filter() {
-        Some(MinFilter::LinearMipmapLinear | MinFilter::NearestMipmapLinear) => FilterMode::Linear,
-        _ => FilterMode::Nearest,
+        Some(
+            MinFilter::Nearest | MinFilter::NearestMipmapNearest | MinFilter::LinearMipmapNearest,
+        ) => FilterMode::Nearest,
+        _ => FilterMode::Linear,
     };
 
-    device.create_sampler(&wgpu::SamplerDescriptor {
-        label: sampler.name().or(Some("Unnamed Gltf Sampler")),
+    SamplerDesc {
         address_mode_u: wrappping_to_address_mode(sampler.wrap_s()),
         address_mode_v: wrappping_to_address_mode(sampler.wrap_t()),
-        address_mode_w: wgpu::AddressMode::Repeat,
         mag_filter,
         min_filter,
         mipmap_filter,
-        ..Default::default()
-    })
+    }
 }
 
 pub fn convert_image_format(format: gltf::image::Format) -> TextureFormat {
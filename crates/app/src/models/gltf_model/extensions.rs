@@ -0,0 +1,76 @@
+use serde_json::Value;
+
+/// Material extensions the `gltf` crate doesn't parse, read from the raw JSON
+/// of the document.
+pub struct RawMaterialExtensions {
+    materials: Vec<Value>,
+}
+
+pub struct Clearcoat {
+    pub factor: f32,
+    pub texture: Option<usize>,
+    pub roughness_factor: f32,
+    pub roughness_texture: Option<usize>,
+}
+
+pub struct Sheen {
+    pub color_factor: [f32; 3],
+    pub color_texture: Option<usize>,
+    pub roughness_factor: f32,
+    pub roughness_texture: Option<usize>,
+}
+
+impl RawMaterialExtensions {
+    /// Accepts both `.gltf` and `.glb` contents. Malformed JSON yields no
+    /// extensions, `gltf` reports it while importing anyway.
+    pub fn from_slice(bytes: &[u8]) -> Self {
+        let json = match gltf::Glb::from_slice(bytes) {
+            Ok(glb) => glb.json,
+            Err(_) => bytes.into(),
+        };
+        let materials = serde_json::from_slice::<Value>(&json)
+            .ok()
+            .and_then(|mut root| match root["materials"].take() {
+                Value::Array(materials) => Some(materials),
+                _ => None,
+            })
+            .unwrap_or_default();
+        Self { materials }
+    }
+
+    fn extension(&self, material: usize, name: &str) -> Option<&Value> {
+        self.materials.get(material)?["extensions"].get(name)
+    }
+
+    pub fn clearcoat(&self, material: usize) -> Option<Clearcoat> {
+        let ext = self.extension(material, "KHR_materials_clearcoat")?;
+        Some(Clearcoat {
+            factor: factor(ext, "clearcoatFactor", 0.),
+            texture: texture_index(ext, "clearcoatTexture"),
+            roughness_factor: factor(ext, "clearcoatRoughnessFactor", 0.),
+            roughness_texture: texture_index(ext, "clearcoatRoughnessTexture"),
+        })
+    }
+
+    pub fn sheen(&self, material: usize) -> Option<Sheen> {
+        let ext = self.extension(material, "KHR_materials_sheen")?;
+        let color = ext["sheenColorFactor"].as_array();
+        let color_factor = std::array::from_fn(|i| {
+            color.and_then(|color| color.get(i)?.as_f64()).unwrap_or(0.) as f32
+        });
+        Some(Sheen {
+            color_factor,
+            color_texture: texture_index(ext, "sheenColorTexture"),
+            roughness_factor: factor(ext, "sheenRoughnessFactor", 0.),
+            roughness_texture: texture_index(ext, "sheenRoughnessTexture"),
+        })
+    }
+}
+
+fn factor(ext: &Value, name: &str, default: f32) -> f32 {
+    ext[name].as_f64().map_or(default, |x| x as f32)
+}
+
+fn texture_index(ext: &Value, name: &str) -> Option<usize> {
+    ext[name]["index"].as_u64().map(|index| index as usize)
+}
@@ -7,12 +7,19 @@ use color_eyre::{
 };
 
 mod conversions;
+mod extensions;
 pub use conversions::*;
-use glam::{Mat4, Vec3, Vec4};
+use extensions::RawMaterialExtensions;
+use glam::{Mat4, Vec3};
 
 use crate::{
     app::App,
-    Instance, {Material, MaterialId}, {MeshId, MeshRef}, {TextureId, BLACK_TEXTURE, WHITE_TEXTURE},
+    Instance, {Material, MaterialId}, {MeshId, MeshRef}, {TextureId, TextureRef, WHITE_TEXTURE},
+    {ALPHA_MODE_BLEND, ALPHA_MODE_MASK, ALPHA_MODE_OPAQUE},
+    {
+        MATERIAL_CLEARCOAT, MATERIAL_SHEEN, MATERIAL_STANDARD, MATERIAL_TRANSMISSION,
+        MATERIAL_UNLIT,
+    },
 };
 use components::{FormatConversions, UnwrapRepeat};
 
@@ -27,9 +34,18 @@ impl GltfDocument {
     pub fn import(app: &mut App, path: impl AsRef<Path>) -> Result<Self> {
         let name = path.as_ref().file_name();
         log::info!("Started processing model: {name:?}",);
-        let (document, buffers, images) = gltf::import(&path)
-            .with_context(|| eyre!("Failed to open file: {}", path.as_ref().display()))?;
-        let materials = Self::make_materials(app, &document, &images)?;
+        let open_error = || eyre!("Failed to open file: {}", path.as_ref().display());
+        let bytes = std::fs::read(&path).with_context(open_error)?;
+        let gltf = gltf::Gltf::from_slice(&bytes).with_context(open_error)?;
+        let base = path.as_ref().parent();
+        let buffers =
+            gltf::import_buffers(&gltf.document, base, gltf.blob).with_context(open_error)?;
+        let images =
+            gltf::import_images(&gltf.document, base, &buffers).with_context(open_error)?;
+        let document = gltf.document;
+
+        let extensions = RawMaterialExtensions::from_slice(&bytes);
+        let materials = Self::make_materials(app, &document, &images, &extensions)?;
         let meshes = Self::make_meshes(app, &document, &buffers)?;
 
         app.get_texture_pool_mut().update_bind_group();
@@ -45,6 +61,7 @@ impl GltfDocument {
         app: &App,
         document: &gltf::Document,
         images: &[gltf::image::Data],
+        extensions: &RawMaterialExtensions,
     ) -> Result<Vec<MaterialId>> {
         let mut image_map = AHashMap::new();
         let mut encoder = app.device().create_command_encoder(&Default::default());
@@ -52,43 +69,125 @@ impl GltfDocument {
         for material in document.materials() {
             let name = material.name().unwrap_or("");
             let pbr = material.pbr_metallic_roughness();
-            let mut color: Vec4 = pbr.base_color_factor().into();
-            color.w = material.alpha_cutoff().unwrap_or(0.5);
 
-            let mut process = |img, srgb| {
-                process_texture_cached(app, &mut image_map, images, img, srgb, &mut encoder)
+            let mut process = |texture: gltf::Texture, srgb| -> Result<TextureRef> {
+                let texture_id = process_texture_cached(
+                    app,
+                    &mut image_map,
+                    images,
+                    texture.source(),
+                    srgb,
+                    &mut encoder,
+                )?;
+                let sampler_id = app
+                    .get_texture_pool_mut()
+                    .add_sampler(convert_sampler(texture.sampler()));
+                Ok(TextureRef {
+                    texture_id,
+                    sampler_id,
+                })
             };
 
             let albedo = pbr
                 .base_color_texture()
-                .map(|t| process(t.texture().source(), true))
+                .map(|t| process(t.texture(), true))
                 .transpose()?
-                .unwrap_or(WHITE_TEXTURE);
+                .unwrap_or(WHITE_TEXTURE.into());
 
-            let normal = material
-                .normal_texture()
-                .map(|t| process(t.texture().source(), false))
+            let normal_texture = material.normal_texture();
+            let normal = normal_texture
+                .as_ref()
+                .map(|t| process(t.texture(), false))
                 .transpose()?
-                .unwrap_or(WHITE_TEXTURE);
+                .unwrap_or(WHITE_TEXTURE.into());
 
             let emissive = material
                 .emissive_texture()
-                .map(|t| process(t.texture().source(), true))
+                .map(|t| process(t.texture(), true))
                 .transpose()?
-                .unwrap_or(BLACK_TEXTURE);
+                .unwrap_or(WHITE_TEXTURE.into());
 
             let metallic_roughness = pbr
                 .metallic_roughness_texture()
-                .map(|t| process(t.texture().source(), false))
+                .map(|t| process(t.texture(), false))
                 .transpose()?
-                .unwrap_or(BLACK_TEXTURE);
+                .unwrap_or(WHITE_TEXTURE.into());
+
+            let occlusion_texture = material.occlusion_texture();
+            let occlusion = occlusion_texture
+                .as_ref()
+                .map(|t| process(t.texture(), false))
+                .transpose()?
+                .unwrap_or(WHITE_TEXTURE.into());
+
+            let mut texture = |index: Option<usize>, srgb| {
+                index
+                    .and_then(|index| document.textures().nth(index))
+                    .map(|t| process(t, srgb))
+                    .transpose()
+                    .map(|t| t.unwrap_or(WHITE_TEXTURE.into()))
+            };
+
+            // Only the implicit default material has no index
+            let index = material.index().unwrap_or(usize::MAX);
+            let mut material_type = MATERIAL_STANDARD;
+            let clearcoat = extensions.clearcoat(index);
+            if clearcoat.is_some() {
+                material_type |= MATERIAL_CLEARCOAT;
+            }
+            let sheen = extensions.sheen(index);
+            if sheen.is_some() {
+                material_type |= MATERIAL_SHEEN;
+            }
+            let transmission = material.transmission();
+            if transmission.is_some() {
+                material_type |= MATERIAL_TRANSMISSION;
+            }
+            if material.unlit() {
+                material_type = MATERIAL_UNLIT;
+            }
 
             let material = Material {
-                base_color: color,
+                base_color: pbr.base_color_factor().into(),
+                emissive_factor: material.emissive_factor().into(),
+                metallic_factor: pbr.metallic_factor(),
+                roughness_factor: pbr.roughness_factor(),
+                normal_scale: normal_texture.map_or(1., |t| t.scale()),
+                occlusion_strength: occlusion_texture.map_or(1., |t| t.strength()),
+                alpha_cutoff: material.alpha_cutoff().unwrap_or(0.5),
                 albedo,
                 normal,
                 metallic_roughness,
                 emissive,
+                occlusion,
+                alpha_mode: match material.alpha_mode() {
+                    gltf::material::AlphaMode::Opaque => ALPHA_MODE_OPAQUE,
+                    gltf::material::AlphaMode::Mask => ALPHA_MODE_MASK,
+                    gltf::material::AlphaMode::Blend => ALPHA_MODE_BLEND,
+                },
+                double_sided: material.double_sided() as u32,
+                material_type,
+                clearcoat_factor: clearcoat.as_ref().map_or(0., |c| c.factor),
+                clearcoat: texture(clearcoat.as_ref().and_then(|c| c.texture), false)?,
+                clearcoat_roughness_factor: clearcoat.as_ref().map_or(0., |c| c.roughness_factor),
+                clearcoat_roughness: texture(
+                    clearcoat.as_ref().and_then(|c| c.roughness_texture),
+                    false,
+                )?,
+                sheen_color_factor: sheen.as_ref().map_or(Vec3::ZERO, |s| s.color_factor.into()),
+                sheen_color: texture(sheen.as_ref().and_then(|s| s.color_texture), true)?,
+                sheen_roughness_factor: sheen.as_ref().map_or(0., |s| s.roughness_factor),
+                sheen_roughness: texture(sheen.as_ref().and_then(|s| s.roughness_texture), false)?,
+                transmission_factor: transmission
+                    .as_ref()
+                    .map_or(0., |t| t.transmission_factor()),
+                transmission: transmission
+                    .and_then(|t| t.transmission_texture())
+                    .map(|t| process(t.texture(), false))
+                    .transpose()?
+                    .unwrap_or(WHITE_TEXTURE.into()),
+                ior: material.ior().unwrap_or(1.5),
+                ..Default::default()
             };
             let id = app.get_material_pool_mut().add(material);
             log::info!("Inserted material {name} with id: {:?}", id);
@@ -105,7 +204,7 @@ impl GltfDocument {
         document: &gltf::Document,
         buffers: &[gltf::buffer::Data],
     ) -> Result<AHashMap<(usize, usize), MeshId>> {
-        let mut meshes = AHashMap::new();
+        let mut primitives = vec![];
         for mesh in document.meshes() {
             let gltf_mesh_id = mesh.index();
             for primitive in mesh.primitives() {
@@ -139,17 +238,26 @@ impl GltfDocument {
                     Some(indices) => indices.into_u32().collect(),
                     None => (0..vertices.len() as u32).collect(),
                 };
+                let key = (gltf_mesh_id, primitive.index());
+                primitives.push((key, vertices, normals, tangents, tex_coords, indices));
+            }
+        }
+
+        // BVHs of all primitives are built together before uploading
+        let (keys, mesh_refs): (Vec<_>, Vec<_>) = primitives
+            .iter_mut()
+            .map(|(key, vertices, normals, tangents, tex_coords, indices)| {
                 let mesh = MeshRef {
                     vertices,
                     normals: bytemuck::cast_slice(normals),
-                    tangents: bytemuck::cast_slice(&tangents),
-                    tex_coords: bytemuck::cast_slice(&tex_coords),
-                    indices,
+                    tangents: bytemuck::cast_slice(tangents),
+                    tex_coords: bytemuck::cast_slice(tex_coords),
+                    indices: std::mem::take(indices),
                 };
-                let mesh = app.add_mesh(mesh);
-                meshes.insert((gltf_mesh_id, primitive.index()), mesh);
-            }
-        }
+                (*key, mesh)
+            })
+            .unzip();
+        let meshes = keys.into_iter().zip(app.add_meshes(mesh_refs)).collect();
 
         Ok(meshes)
     }
@@ -4,7 +4,7 @@ use color_eyre::{
     eyre::{eyre, Context},
     Result,
 };
-use glam::{Vec3, Vec4};
+use glam::{Vec2, Vec3, Vec4};
 use std::path::Path;
 
 pub use gltf_model::*;
@@ -29,7 +29,7 @@ impl ObjModel {
             for material in model_materials {
                 let base_color = Vec3::from_array(material.diffuse.unwrap_or([1., 1., 1.]));
                 let material_id = app.get_material_pool_mut().add(Material {
-                    base_color: base_color.extend(0.5),
+                    base_color: base_color.extend(material.dissolve.unwrap_or(1.)),
                     ..Default::default()
                 });
                 materials.push(material_id);
@@ -38,11 +38,12 @@ impl ObjModel {
 
         let mut meshes = vec![];
         for mesh in model_meshes.iter().map(|m| &m.mesh) {
+            let vertex_count = mesh.positions.len() / 3;
             let mesh_id = app.add_mesh(MeshRef {
                 vertices: bytemuck::cast_slice(&mesh.positions),
-                normals: bytemuck::cast_slice(&mesh.normals),
-                tangents: &vec![Vec4::ZERO; mesh.positions.len()],
-                tex_coords: bytemuck::cast_slice(&mesh.texcoords),
+                normals: &per_vertex::<Vec3>(&mesh.normals, vertex_count),
+                tangents: &vec![Vec4::ZERO; vertex_count],
+                tex_coords: &per_vertex::<Vec2>(&mesh.texcoords, vertex_count),
                 indices: mesh.indices.to_vec(),
             });
             let material_id = match mesh.material_id {
@@ -56,3 +57,12 @@ impl ObjModel {
         Ok(meshes)
     }
 }
+
+/// Attribute of every vertex, zeroed when the file has none for them.
+fn per_vertex<T: bytemuck::Pod>(values: &[f32], vertex_count: usize) -> Vec<T> {
+    let values: &[T] = bytemuck::cast_slice(values);
+    match values.len() == vertex_count {
+        true => values.to_vec(),
+        false => vec![T::zeroed(); vertex_count],
+    }
+}
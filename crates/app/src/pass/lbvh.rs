@@ -0,0 +1,294 @@
+use std::path::Path;
+
+use bytemuck::{Pod, Zeroable};
+use color_eyre::Result;
+use pools::MeshPool;
+use wgpu::util::{align_to, DeviceExt};
+
+use crate::{
+    bind_group_layout::{BindGroupLayout, WrappedBindGroupLayout},
+    pipeline::{ComputeHandle, ComputePipelineDescriptor, PipelineArena},
+    MeshId, ProfilerCommandEncoder,
+};
+use components::world::World;
+
+use super::Pass;
+
+const WORKGROUP_SIZE: u32 = 256;
+const RADIX: u32 = 16;
+/// 4 bit digits over 32 bit keys, even count leaves sorted keys in the first half.
+const RADIX_PASSES: u32 = 8;
+
+#[repr(C)]
+#[derive(Clone, Copy, Default, Pod, Zeroable)]
+struct LbvhParams {
+    triangle_count: u32,
+    vertex_offset: u32,
+    base_index: u32,
+    bvh_index: u32,
+    shift: u32,
+    flip: u32,
+    tile_count: u32,
+    _padding: u32,
+}
+
+/// Builds a BLAS on the GPU into nodes reserved by
+/// [`MeshPool::add_with_gpu_bvh`], reordering the mesh indices to match.
+///
+/// Trees are linear BVHs: lower quality than the CPU SAH builder but fast
+/// enough to rebuild meshes generated on the GPU every frame. Their depth is
+/// not limited to [`bvh::MAX_DEPTH`], [`MeshPool::add_with_gpu_bvh`] warns
+/// about meshes too large for the shader stack.
+pub struct Lbvh {
+    centroid_bounds: ComputeHandle,
+    morton: ComputeHandle,
+    radix_count: ComputeHandle,
+    radix_scan: ComputeHandle,
+    radix_scatter: ComputeHandle,
+    hierarchy: ComputeHandle,
+    leaf_bounds: ComputeHandle,
+    internal_bounds: ComputeHandle,
+    reorder: ComputeHandle,
+
+    params_layout: BindGroupLayout,
+    mesh_layout: BindGroupLayout,
+    scratch_layout: BindGroupLayout,
+}
+
+impl Lbvh {
+    pub fn new(world: &World, path: impl AsRef<Path>) -> Result<Self> {
+        let device = world.gpu.device();
+        let params_layout =
+            device.create_bind_group_layout_wrap(&wgpu::BindGroupLayoutDescriptor {
+                label: Some("Lbvh Params BGL"),
+                entries: &[wgpu::BindGroupLayoutEntry {
+                    binding: 0,
+                    visibility: wgpu::ShaderStages::COMPUTE,
+                    ty: wgpu::BindingType::Buffer {
+                        ty: wgpu::BufferBindingType::Uniform,
+                        has_dynamic_offset: true,
+                        min_binding_size: wgpu::BufferSize::new(
+                            std::mem::size_of::<LbvhParams>() as _
+                        ),
+                    },
+                    count: None,
+                }],
+            });
+        let storage_entry = |binding, read_only| wgpu::BindGroupLayoutEntry {
+            binding,
+            visibility: wgpu::ShaderStages::COMPUTE,
+            ty: wgpu::BindingType::Buffer {
+                ty: wgpu::BufferBindingType::Storage { read_only },
+                has_dynamic_offset: false,
+                min_binding_size: None,
+            },
+            count: None,
+        };
+        let mesh_layout = device.create_bind_group_layout_wrap(&wgpu::BindGroupLayoutDescriptor {
+            label: Some("Lbvh Mesh BGL"),
+            entries: &[
+                storage_entry(0, true),
+                storage_entry(1, true),
+                storage_entry(2, false),
+            ],
+        });
+        let scratch_layout =
+            device.create_bind_group_layout_wrap(&wgpu::BindGroupLayoutDescriptor {
+                label: Some("Lbvh Scratch BGL"),
+                entries: &[
+                    storage_entry(0, false),
+                    storage_entry(1, false),
+                    storage_entry(2, false),
+                    storage_entry(3, false),
+                ],
+            });
+
+        let mut arena = world.get_mut::<PipelineArena>()?;
+        let path = path.as_ref();
+        let mut pipeline = |entry_point: &'static str| {
+            let desc = ComputePipelineDescriptor {
+                label: Some(format!("Lbvh {entry_point}").into()),
+                layout: vec![
+                    params_layout.clone(),
+                    mesh_layout.clone(),
+                    scratch_layout.clone(),
+                ],
+                push_constant_ranges: vec![],
+                entry_point: entry_point.into(),
+            };
+            arena.process_compute_pipeline_from_path(path, desc)
+        };
+
+        Ok(Self {
+            centroid_bounds: pipeline("centroid_bounds")?,
+            morton: pipeline("morton")?,
+            radix_count: pipeline("radix_count")?,
+            radix_scan: pipeline("radix_scan")?,
+            radix_scatter: pipeline("radix_scatter")?,
+            hierarchy: pipeline("hierarchy")?,
+            leaf_bounds: pipeline("leaf_bounds")?,
+            internal_bounds: pipeline("internal_bounds")?,
+            reorder: pipeline("reorder")?,
+
+            params_layout,
+            mesh_layout,
+            scratch_layout,
+        })
+    }
+}
+
+fn scratch_buffer(device: &wgpu::Device, label: &str, len: u32) -> wgpu::Buffer {
+    device.create_buffer(&wgpu::BufferDescriptor {
+        label: Some(label),
+        size: (len.max(1) * 4) as u64,
+        usage: wgpu::BufferUsages::STORAGE
+            | wgpu::BufferUsages::COPY_SRC
+            | wgpu::BufferUsages::COPY_DST,
+        mapped_at_creation: false,
+    })
+}
+
+impl Pass for Lbvh {
+    type Resources<'a> = MeshId;
+
+    fn record(&self, world: &World, encoder: &mut ProfilerCommandEncoder, mesh: MeshId) {
+        let device = world.gpu.device();
+        let arena = world.unwrap::<PipelineArena>();
+        let meshes = world.unwrap::<MeshPool>();
+        let mesh_index = mesh.index() as usize;
+        if !meshes.contains(mesh) {
+            log::warn!("Attempted to build BVH of removed mesh {mesh_index}");
+            return;
+        }
+        if !meshes.blases[mesh_index].is_empty() {
+            log::warn!("Mesh {mesh_index} has a CPU built BVH, skipping GPU build");
+            return;
+        }
+        let info = meshes.mesh_info_cpu[mesh_index];
+        let n = info.index_count / 3;
+        if n == 0 {
+            return;
+        }
+        let tile_count = align_to(n, WORKGROUP_SIZE) / WORKGROUP_SIZE;
+
+        // Every radix pass gets its own params, the first ones serve the
+        // remaining stages as well. Offsets must respect the dynamic offset
+        // alignment.
+        let stride = align_to(
+            std::mem::size_of::<LbvhParams>() as u32,
+            device.limits().min_uniform_buffer_offset_alignment,
+        );
+        let mut params = vec![0u8; (stride * RADIX_PASSES) as usize];
+        for pass in 0..RADIX_PASSES {
+            let pass_params = LbvhParams {
+                triangle_count: n,
+                vertex_offset: info.vertex_offset as u32,
+                base_index: info.base_index,
+                bvh_index: info.bvh_index,
+                shift: pass * 4,
+                flip: pass % 2,
+                tile_count,
+                _padding: 0,
+            };
+            let offset = (pass * stride) as usize;
+            params[offset..][..std::mem::size_of::<LbvhParams>()]
+                .copy_from_slice(bytemuck::bytes_of(&pass_params));
+        }
+        let params = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+            label: Some("Lbvh Params"),
+            contents: &params,
+            usage: wgpu::BufferUsages::UNIFORM,
+        });
+
+        let keys = scratch_buffer(device, "Lbvh Keys", 3 * n);
+        let values = scratch_buffer(device, "Lbvh Values", 2 * n);
+        let scratch = scratch_buffer(device, "Lbvh Scratch", (RADIX * tile_count).max(2 * n));
+        let bounds = scratch_buffer(device, "Lbvh Bounds", 6 * n);
+
+        let params_bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
+            label: Some("Lbvh Params Bind Group"),
+            layout: &self.params_layout,
+            entries: &[wgpu::BindGroupEntry {
+                binding: 0,
+                resource: wgpu::BindingResource::Buffer(wgpu::BufferBinding {
+                    buffer: &params,
+                    offset: 0,
+                    size: wgpu::BufferSize::new(std::mem::size_of::<LbvhParams>() as _),
+                }),
+            }],
+        });
+        let mesh_bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
+            label: Some("Lbvh Mesh Bind Group"),
+            layout: &self.mesh_layout,
+            entries: &[
+                wgpu::BindGroupEntry {
+                    binding: 0,
+                    resource: meshes.vertices.as_tight_binding(),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 1,
+                    resource: meshes.indices.as_tight_binding(),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 2,
+                    resource: meshes.bvh_nodes.as_tight_binding(),
+                },
+            ],
+        });
+        let scratch_bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
+            label: Some("Lbvh Scratch Bind Group"),
+            layout: &self.scratch_layout,
+            entries: &[
+                wgpu::BindGroupEntry {
+                    binding: 0,
+                    resource: keys.as_entire_binding(),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 1,
+                    resource: values.as_entire_binding(),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 2,
+                    resource: scratch.as_entire_binding(),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 3,
+                    resource: bounds.as_entire_binding(),
+                },
+            ],
+        });
+
+        encoder.clear_buffer(&bounds, 0, None);
+        {
+            let mut cpass = encoder.begin_compute_pass(&wgpu::ComputePassDescriptor {
+                label: Some("Lbvh Build Pass"),
+            });
+            cpass.set_bind_group(1, &mesh_bind_group, &[]);
+            cpass.set_bind_group(2, &scratch_bind_group, &[]);
+            let mut dispatch = |pipeline, pass: u32, groups: u32| {
+                cpass.set_pipeline(arena.get_pipeline(pipeline));
+                cpass.set_bind_group(0, &params_bind_group, &[pass * stride]);
+                cpass.dispatch_workgroups(groups, 1, 1);
+            };
+
+            dispatch(self.centroid_bounds, 0, tile_count);
+            dispatch(self.morton, 0, tile_count);
+            for pass in 0..RADIX_PASSES {
+                dispatch(self.radix_count, pass, tile_count);
+                dispatch(self.radix_scan, pass, 1);
+                dispatch(self.radix_scatter, pass, tile_count);
+            }
+            dispatch(self.hierarchy, 0, tile_count);
+            dispatch(self.leaf_bounds, 0, tile_count);
+            dispatch(self.internal_bounds, 0, tile_count);
+            dispatch(self.reorder, 0, tile_count);
+        }
+        encoder.copy_buffer_to_buffer(
+            &keys,
+            0,
+            &meshes.indices,
+            info.base_index as u64 * 4,
+            (3 * n) as u64 * 4,
+        );
+    }
+}
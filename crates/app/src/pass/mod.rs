@@ -1,9 +1,11 @@
 use components::world::World;
 
 pub mod compute_update;
+pub mod lbvh;
 pub mod postprocess;
 pub mod shading;
 pub mod taa;
+pub mod tlas_refit;
 pub mod visibility;
 
 pub trait Pass {
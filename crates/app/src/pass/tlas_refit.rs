@@ -0,0 +1,52 @@
+use std::path::Path;
+
+use color_eyre::Result;
+use pools::MeshPool;
+
+use crate::{
+    pipeline::{ComputeHandle, ComputePipelineDescriptor, PipelineArena},
+    ProfilerCommandEncoder,
+};
+use components::world::World;
+
+use super::Pass;
+
+/// Refits TLAS bounds on the GPU after instances were moved by compute passes.
+pub struct TlasRefit {
+    pipeline: ComputeHandle,
+}
+
+impl TlasRefit {
+    pub fn new(world: &World, path: impl AsRef<Path>) -> Result<Self> {
+        let meshes = world.get::<MeshPool>()?;
+        let desc = ComputePipelineDescriptor {
+            label: Some("Tlas Refit Pass".into()),
+            layout: vec![meshes.tlas_refit_bind_group_layout.clone()],
+            push_constant_ranges: vec![],
+            entry_point: "refit".into(),
+        };
+        let pipeline = world
+            .get_mut::<PipelineArena>()?
+            .process_compute_pipeline_from_path(path, desc)?;
+        Ok(Self { pipeline })
+    }
+}
+
+impl Pass for TlasRefit {
+    type Resources<'a> = ();
+
+    fn record(&self, world: &World, encoder: &mut ProfilerCommandEncoder, _: Self::Resources<'_>) {
+        let arena = world.unwrap::<PipelineArena>();
+        let meshes = world.unwrap::<MeshPool>();
+        let Some(bind_group) = &meshes.tlas_refit_bind_group else {
+            return;
+        };
+        let mut cpass = encoder.begin_compute_pass(&wgpu::ComputePassDescriptor {
+            label: Some("Tlas Refit Pass"),
+        });
+
+        cpass.set_pipeline(arena.get_pipeline(self.pipeline));
+        cpass.set_bind_group(0, bind_group, &[]);
+        cpass.dispatch_workgroups(1, 1, 1);
+    }
+}
@@ -69,7 +69,10 @@ impl Pass for Visibility {
 }
 
 struct Geometry {
+    /// Culls back faces, draws instances of single sided materials.
     pipeline: RenderHandle,
+    /// Draws instances of double sided materials without culling.
+    double_sided_pipeline: RenderHandle,
 }
 
 impl Geometry {
@@ -133,16 +136,27 @@ impl Geometry {
             }),
             ..Default::default()
         };
-        let pipeline = world
-            .get_mut::<PipelineArena>()?
-            .process_render_pipeline_from_path(path, render_desc)?;
-        Ok(Self { pipeline })
+        let double_sided_desc = RenderPipelineDescriptor {
+            label: Some("Visibilty Double Sided Pipeline".into()),
+            primitive: wgpu::PrimitiveState::default(),
+            ..render_desc.clone()
+        };
+        let mut arena = world.get_mut::<PipelineArena>()?;
+        let pipeline = arena.process_render_pipeline_from_path(&path, render_desc)?;
+        let double_sided_pipeline =
+            arena.process_render_pipeline_from_path(&path, double_sided_desc)?;
+        Ok(Self {
+            pipeline,
+            double_sided_pipeline,
+        })
     }
 }
 
 struct GeometryResource<'a> {
     pub gbuffer: &'a GBuffer,
 
+    /// Draws of single sided instances followed by double sided ones, see
+    /// `emit_draws.wgsl`.
     pub draw_cmd_buffer: &'a ResizableBuffer<DrawIndexedIndirect>,
 }
 
@@ -174,7 +188,6 @@ impl Pass for Geometry {
             }),
         });
 
-        rpass.set_pipeline(arena.get_pipeline(self.pipeline));
         rpass.set_bind_group(0, &camera.binding, &[]);
         rpass.set_bind_group(1, &textures.bind_group, &[]);
         rpass.set_bind_group(2, &instances.bind_group, &[]);
@@ -185,10 +198,15 @@ impl Pass for Geometry {
         rpass.set_vertex_buffer(2, meshes.tangents.full_slice());
         rpass.set_vertex_buffer(3, meshes.tex_coords.full_slice());
         rpass.set_index_buffer(meshes.indices.full_slice(), IndexFormat::Uint32);
+
+        let count = resources.draw_cmd_buffer.len() / 2;
+        rpass.set_pipeline(arena.get_pipeline(self.pipeline));
+        rpass.multi_draw_indexed_indirect(resources.draw_cmd_buffer, 0, count as _);
+        rpass.set_pipeline(arena.get_pipeline(self.double_sided_pipeline));
         rpass.multi_draw_indexed_indirect(
             resources.draw_cmd_buffer,
-            0,
-            resources.draw_cmd_buffer.len() as _,
+            (count * DrawIndexedIndirect::SIZE) as _,
+            count as _,
         );
     }
 }
@@ -202,6 +220,7 @@ impl EmitDraws {
         let camera = world.get::<CameraUniformBinding>()?;
         let meshes = world.get::<MeshPool>()?;
         let instances = world.get::<InstancePool>()?;
+        let materials = world.get::<MaterialPool>()?;
         let draw_cmd_layout = world.get::<StorageWriteBindGroupLayout<DrawIndexedIndirect>>()?;
         let path = Path::new("shaders").join("emit_draws.wgsl");
         let comp_desc = ComputePipelineDescriptor {
@@ -211,6 +230,7 @@ impl EmitDraws {
                 meshes.mesh_info_layout.clone(),
                 instances.bind_group_layout.clone(),
                 draw_cmd_layout.layout.clone(),
+                materials.bind_group_layout.clone(),
             ],
             push_constant_ranges: vec![],
             entry_point: "emit_draws".into(),
@@ -240,6 +260,7 @@ impl Pass for EmitDraws {
         let meshes = world.unwrap::<MeshPool>();
         let arena = world.unwrap::<PipelineArena>();
         let instances = world.unwrap::<InstancePool>();
+        let materials = world.unwrap::<MaterialPool>();
         let mut cpass = encoder.begin_compute_pass(&wgpu::ComputePassDescriptor {
             label: Some("Emit Draws Pass"),
         });
@@ -249,7 +270,8 @@ impl Pass for EmitDraws {
         cpass.set_bind_group(1, &meshes.mesh_info_bind_group, &[]);
         cpass.set_bind_group(2, &instances.bind_group, &[]);
         cpass.set_bind_group(3, resources.draw_cmd_bind_group, &[]);
-        let num_dispatches = align_to(resources.draw_cmd_buffer.len() as _, 64) / 64;
+        cpass.set_bind_group(4, &materials.bind_group, &[]);
+        let num_dispatches = align_to(resources.draw_cmd_buffer.len() as u32 / 2, 64) / 64;
         cpass.dispatch_workgroups(num_dispatches, 1, 1);
     }
 }
@@ -1,10 +1,11 @@
 use bytemuck::{Pod, Zeroable};
-use glam::{UVec3, UVec4, Vec3, Vec4};
+use glam::{UVec3, UVec4, Vec2, Vec3, Vec4};
+use rayon::prelude::*;
 
 use crate::{
-    intersection::{intersect_aabb, Aabb, MAX_DIST},
+    intersection::{intersect_aabb, Aabb, TriangleHit, MAX_DIST},
     Dist::{self, *},
-    Ray,
+    ProceduralPrimitive, Ray,
 };
 
 #[repr(C)]
@@ -38,176 +39,851 @@ impl BvhNode {
     }
 }
 
+#[derive(Clone, Copy)]
+struct Bin {
+    bounds: Aabb,
+    count: u32,
+}
+
+impl Default for Bin {
+    fn default() -> Self {
+        Self {
+            bounds: Aabb::empty(),
+            count: 0,
+        }
+    }
+}
+
+#[derive(Clone, Copy)]
+struct Split {
+    axis: usize,
+    bin: usize,
+    min: f32,
+    scale: f32,
+    cost: f32,
+}
+
+/// Bin of a spatial split: references are clipped to it, and counted where
+/// they start and end.
+#[derive(Clone, Copy)]
+struct SpatialBin {
+    bounds: Aabb,
+    enter: u32,
+    exit: u32,
+}
+
+impl Default for SpatialBin {
+    fn default() -> Self {
+        Self {
+            bounds: Aabb::empty(),
+            enter: 0,
+            exit: 0,
+        }
+    }
+}
+
+#[derive(Clone, Copy)]
+struct SpatialSplit {
+    axis: usize,
+    plane: f32,
+    cost: f32,
+}
+
+/// Part of a triangle that ended up on one side of spatial splits.
+#[derive(Clone, Copy)]
+struct Reference {
+    triangle: usize,
+    bounds: Aabb,
+}
+
+/// Anything the builder bins, either a whole triangle or a reference to a
+/// clipped one.
+trait Primitive: Copy {
+    fn bounds(&self, builder: &BvhBuilder) -> Aabb;
+    fn centroid(&self, builder: &BvhBuilder) -> Vec3;
+}
+
+impl Primitive for usize {
+    fn bounds(&self, builder: &BvhBuilder) -> Aabb {
+        builder.bounds[*self]
+    }
+
+    fn centroid(&self, builder: &BvhBuilder) -> Vec3 {
+        builder.centroids[*self]
+    }
+}
+
+impl Primitive for Reference {
+    fn bounds(&self, _: &BvhBuilder) -> Aabb {
+        self.bounds
+    }
+
+    fn centroid(&self, _: &BvhBuilder) -> Vec3 {
+        (self.bounds.min + self.bounds.max) * 0.5
+    }
+}
+
+/// Subtrees with fewer triangles than this are built on the current thread.
+const PARALLEL_THRESHOLD: u32 = 4096;
+/// Spatial splits are only tried where children of the best object split
+/// overlap by more than this fraction of the root surface area.
+const SPATIAL_SPLIT_OVERLAP: f32 = 1e-5;
+/// Entries of the traversal stack in `shaders/utils/stack.wgsl`. Deeper
+/// trees lose subtrees when traced on the GPU.
+pub const WGSL_STACK_LEN: usize = 24;
+/// Default depth limit of [`BvhBuilder`]. Traversal needs one stack entry
+/// more than the depth, which fits [`WGSL_STACK_LEN`].
+pub const MAX_DEPTH: usize = WGSL_STACK_LEN - 1;
+
 pub struct BvhBuilder<'a> {
     num_bins: usize,
+    max_depth: usize,
+    spatial_budget: Option<f32>,
     vertices: &'a [Vec3],
     indices: &'a mut [UVec3],
     centroids: Vec<Vec3>,
-    nodes: Vec<BvhNode>,
-    triangle_indices: Vec<usize>,
+    bounds: Vec<Aabb>,
 }
 
 impl<'a> BvhBuilder<'a> {
     pub fn new(vertices: &'a [Vec3], indices: &'a mut [UVec3]) -> Self {
-        let nodes = vec![BvhNode::default(); indices.len() * 2];
-
         Self {
             num_bins: 8,
+            max_depth: MAX_DEPTH,
+            spatial_budget: None,
             vertices,
             indices,
             centroids: vec![],
-            nodes,
-            triangle_indices: vec![],
+            bounds: vec![],
         }
     }
 
     pub fn set_bin_number(mut self, num_bins: usize) -> Self {
-        self.num_bins = num_bins;
+        self.num_bins = num_bins.max(2);
+        self
+    }
+
+    /// Limits the depth of leaves, defaults to [`MAX_DEPTH`].
+    ///
+    /// Subtrees with more triangles than the remaining levels could hold one
+    /// per leaf are split at the median instead of by SAH, and leaves at the
+    /// limit take all triangles left.
+    ///
+    /// CPU traversal handles any depth, but trees deeper than [`MAX_DEPTH`]
+    /// overflow [`WGSL_STACK_LEN`], which `MeshPool` warns about on upload.
+    pub fn set_max_depth(mut self, max_depth: usize) -> Self {
+        self.max_depth = max_depth;
         self
     }
 
+    /// Enables spatial splits, which clip triangles straddling a split plane
+    /// into both children when that lowers the SAH cost. Helps with long
+    /// thin or unevenly sized triangles at the price of duplicated references.
+    ///
+    /// `budget` caps the extra references relative to the triangle count,
+    /// `0.3` lets the index buffer grow by at most 30%. Trees with spatial
+    /// splits can only be built with [`BvhBuilder::build_indexed`].
+    pub fn spatial_splits(mut self, budget: f32) -> Self {
+        self.spatial_budget = Some(budget.max(0.));
+        self
+    }
+
+    /// Builds the tree and reorders the indices in place to match its leaves.
     pub fn build(mut self) -> Bvh {
-        self.centroids = self
+        assert!(
+            self.spatial_budget.is_none(),
+            "Spatial splits duplicate triangles, use `build_indexed`"
+        );
+        let (bvh, order) = self.build_order();
+        let indices_copy: Vec<_> = order.into_iter().map(|i| self.indices[i]).collect();
+        self.indices.copy_from_slice(&indices_copy);
+        bvh
+    }
+
+    /// Builds the tree and returns the index buffer matching its leaves,
+    /// leaving the input untouched. With spatial splits triangles
+    /// referenced by several leaves are repeated in it, so it can be longer
+    /// than the input.
+    pub fn build_indexed(mut self) -> (Bvh, Vec<UVec3>) {
+        let (bvh, order) = self.build_order();
+        let indices = order.into_iter().map(|i| self.indices[i]).collect();
+        (bvh, indices)
+    }
+
+    /// Builds the tree along with the triangle behind every leaf entry.
+    fn build_order(&mut self) -> (Bvh, Vec<usize>) {
+        self.compute_triangle_bounds();
+        self.build_from_bounds()
+    }
+
+    fn compute_triangle_bounds(&mut self) {
+        self.bounds = self
             .indices
-            .iter()
+            .par_iter()
             .map(|idx| {
-                [
-                    self.vertices[idx[0] as usize],
-                    self.vertices[idx[1] as usize],
-                    self.vertices[idx[2] as usize],
-                ]
+                let mut aabb = Aabb::empty();
+                for i in idx.to_array() {
+                    aabb.grow(self.vertices[i as usize]);
+                }
+                aabb
             })
+            .collect();
+        self.centroids = self
+            .indices
+            .par_iter()
+            .map(|idx| idx.to_array().map(|i| self.vertices[i as usize]))
             .map(|trig| (trig[0] + trig[1] + trig[2]) / 3f32)
             .collect();
+    }
 
-        self.triangle_indices = (0..self.indices.len()).collect();
-        self.nodes[0].left_first = 0;
-        self.nodes[0].count = self.triangle_indices.len() as u32;
+    /// Builds over the primitives described by `bounds` and `centroids`.
+    /// Without any the tree is [`Bvh::empty`], as a root with no primitives
+    /// would be taken for an interior node.
+    fn build_from_bounds(&mut self) -> (Bvh, Vec<usize>) {
+        if self.bounds.is_empty() {
+            return (Bvh::empty(), vec![]);
+        }
+        let mut triangle_indices: Vec<_> = (0..self.bounds.len()).collect();
+        let mut root = BvhNode {
+            left_first: 0,
+            count: triangle_indices.len() as u32,
+            ..Default::default()
+        };
+        let aabb = self.calculate_bounds(&triangle_indices, false);
+        set_bound(&mut root, &aabb);
 
-        let aabb = self.calculate_bounds(0, self.nodes[0].count, false);
-        self.set_bound(0, &aabb);
+        // Second node is left empty so that sibling pairs share a cache line
+        let mut nodes = vec![root, BvhNode::default()];
+        let descendants = match self.spatial_budget {
+            None => self.subdivide(&mut nodes[0], &mut triangle_indices, 0),
+            Some(budget) => {
+                let references = triangle_indices
+                    .iter()
+                    .map(|&triangle| Reference {
+                        triangle,
+                        bounds: self.bounds[triangle],
+                    })
+                    .collect();
+                let budget = (triangle_indices.len() as f32 * budget) as usize;
+                let (descendants, order) =
+                    self.subdivide_spatial(&mut nodes[0], references, budget, aabb.area(), 0);
+                triangle_indices = order;
+                descendants
+            }
+        };
+        if !descendants.is_empty() {
+            nodes.extend(descendants);
+            relocate(&mut nodes[..1], 2);
+            relocate(&mut nodes[2..], 2);
+        }
 
-        let mut new_node_index = 2;
+        (Bvh::from_nodes(nodes), triangle_indices)
+    }
 
-        self.subdivide(0, 0, &mut new_node_index);
-        self.nodes.truncate(new_node_index as usize);
+    /// Splits `node` over `triangles` and returns all of its descendants.
+    ///
+    /// Child indices in the returned nodes, as well as in `node` itself, are
+    /// relative to the start of the returned vector. Children are laid out
+    /// depth first with the left subtree before the right one, so the result
+    /// doesn't depend on how the work was scheduled.
+    fn subdivide(&self, node: &mut BvhNode, triangles: &mut [usize], depth: usize) -> Vec<BvhNode> {
+        let (start, count) = (node.left_first, node.count);
+        if count <= 1 || depth >= self.max_depth {
+            return vec![];
+        }
 
-        let indices_copy: Vec<_> = self
-            .triangle_indices
-            .into_iter()
-            .map(|i| self.indices[i])
-            .collect();
-        self.indices.copy_from_slice(&indices_copy);
+        let left_count = if self.needs_median_split(count, depth) {
+            self.partition_median(triangles)
+        } else {
+            let Some(split) = self.find_best_split(triangles) else {
+                return vec![];
+            };
+            let leaf_cost = Aabb::new(node.min, node.max).area() * count as f32;
+            if split.cost >= leaf_cost {
+                return vec![];
+            }
+            self.partition_shuffle(&split, triangles)
+        };
+        let right_count = count - left_count;
+        if left_count == 0 || right_count == 0 {
+            return vec![];
+        }
+        let (left_triangles, right_triangles) = triangles.split_at_mut(left_count as usize);
 
-        Bvh { nodes: self.nodes }
+        let mut left = BvhNode {
+            left_first: start,
+            count: left_count,
+            ..Default::default()
+        };
+        set_bound(&mut left, &self.calculate_bounds(left_triangles, false));
+        let mut right = BvhNode {
+            left_first: start + left_count,
+            count: right_count,
+            ..Default::default()
+        };
+        set_bound(&mut right, &self.calculate_bounds(right_triangles, false));
+
+        let (left_nodes, right_nodes) = if count >= PARALLEL_THRESHOLD {
+            rayon::join(
+                || self.subdivide(&mut left, left_triangles, depth + 1),
+                || self.subdivide(&mut right, right_triangles, depth + 1),
+            )
+        } else {
+            (
+                self.subdivide(&mut left, left_triangles, depth + 1),
+                self.subdivide(&mut right, right_triangles, depth + 1),
+            )
+        };
+
+        link_children(node, left, right, left_nodes, right_nodes)
     }
 
-    fn subdivide(&mut self, current_bvh_index: usize, start: u32, pool_index: &mut u32) {
-        if self.nodes[current_bvh_index].count <= 3 {
-            self.nodes[current_bvh_index].left_first = start;
-            return;
+    /// Like [`BvhBuilder::subdivide`], but picks between object and spatial
+    /// splits. Returns the triangle behind every leaf entry of the subtree,
+    /// leaf starts are relative to it.
+    ///
+    /// What is left of `budget` after a split is shared between the children
+    /// in proportion to their sizes, keeping the result deterministic.
+    fn subdivide_spatial(
+        &self,
+        node: &mut BvhNode,
+        mut references: Vec<Reference>,
+        budget: usize,
+        root_area: f32,
+        depth: usize,
+    ) -> (Vec<BvhNode>, Vec<usize>) {
+        let leaf = |node: &mut BvhNode, references: Vec<Reference>| {
+            node.left_first = 0;
+            node.count = references.len() as u32;
+            let order = references.into_iter().map(|r| r.triangle).collect();
+            (vec![], order)
+        };
+        let count = references.len() as u32;
+        if count <= 1 || depth >= self.max_depth {
+            return leaf(node, references);
+        }
+
+        let (left_refs, right_refs) = if self.needs_median_split(count, depth) {
+            let left_count = self.partition_median(&mut references);
+            let right_refs = references.split_off(left_count as usize);
+            (references, right_refs)
+        } else {
+            match self.find_spatial_or_object_split(node, references, budget, root_area) {
+                Ok(split) => split,
+                Err(references) => return leaf(node, references),
+            }
+        };
+        if left_refs.is_empty() || right_refs.is_empty() {
+            let mut references = left_refs;
+            references.extend(right_refs);
+            return leaf(node, references);
         }
-        let index = *pool_index;
-        *pool_index += 2;
-        self.nodes[current_bvh_index].left_first = index;
 
-        let pivot = self.partition(start, self.nodes[current_bvh_index].count);
-        let left_count = pivot - start;
-        self.nodes[index as usize].count = left_count;
-        let bounds = self.calculate_bounds(start, left_count, false);
-        self.set_bound(index as usize, &bounds);
+        let total = left_refs.len() + right_refs.len();
+        let remaining = budget.saturating_sub(total - count as usize);
+        let left_budget = remaining * left_refs.len() / total;
+        let right_budget = remaining - left_budget;
 
-        let right_count = self.nodes[current_bvh_index].count - left_count;
-        self.nodes[index as usize + 1].count = right_count;
-        let bounds = self.calculate_bounds(pivot, right_count, false);
-        self.set_bound(index as usize + 1, &bounds);
+        let mut left = BvhNode::default();
+        set_bound(&mut left, &self.calculate_bounds(&left_refs, false));
+        let mut right = BvhNode::default();
+        set_bound(&mut right, &self.calculate_bounds(&right_refs, false));
 
-        self.subdivide(index as usize, start, pool_index);
-        self.subdivide(index as usize + 1, pivot, pool_index);
-        self.nodes[current_bvh_index].count = 0;
+        let build = |node: &mut BvhNode, references, budget| {
+            self.subdivide_spatial(node, references, budget, root_area, depth + 1)
+        };
+        let ((left_nodes, left_order), (right_nodes, right_order)) = if count >= PARALLEL_THRESHOLD
+        {
+            rayon::join(
+                || build(&mut left, left_refs, left_budget),
+                || build(&mut right, right_refs, right_budget),
+            )
+        } else {
+            (
+                build(&mut left, left_refs, left_budget),
+                build(&mut right, right_refs, right_budget),
+            )
+        };
+
+        let right_start = 2 + left_nodes.len();
+        let mut nodes = link_children(node, left, right, left_nodes, right_nodes);
+        relocate_leaves(&mut nodes[1..2], left_order.len() as u32);
+        relocate_leaves(&mut nodes[right_start..], left_order.len() as u32);
+
+        let mut order = left_order;
+        order.extend(right_order);
+        (nodes, order)
     }
 
-    fn set_bound(&mut self, bvh_index: usize, aabb: &Aabb) {
-        self.nodes[bvh_index].max = aabb.max;
-        self.nodes[bvh_index].min = aabb.min;
+    /// Distributes `references` by the cheaper of the best object and spatial
+    /// splits, or hands them back if a leaf is cheaper than both.
+    fn find_spatial_or_object_split(
+        &self,
+        node: &BvhNode,
+        mut references: Vec<Reference>,
+        budget: usize,
+        root_area: f32,
+    ) -> Result<(Vec<Reference>, Vec<Reference>), Vec<Reference>> {
+        let count = references.len();
+        let aabb = Aabb::new(node.min, node.max);
+        let leaf_cost = aabb.area() * count as f32;
+        let object = self.find_best_split(&references);
+        let overlapping = object.is_none_or(|split| {
+            let overlap = self.split_overlap(&split, &references);
+            overlap.is_valid() && overlap.area() > root_area * SPATIAL_SPLIT_OVERLAP
+        });
+        let spatial = overlapping
+            .then(|| self.find_spatial_split(&aabb, &references, budget))
+            .flatten();
+
+        let object_cost = object.map_or(f32::MAX, |split| split.cost);
+        match spatial {
+            Some(split) if split.cost < object_cost && split.cost < leaf_cost => {
+                Ok(self.split_references(&split, references))
+            }
+            _ => {
+                let Some(split) = object.filter(|split| split.cost < leaf_cost) else {
+                    return Err(references);
+                };
+                let left_count = self.partition_shuffle(&split, &mut references);
+                let right_refs = references.split_off(left_count as usize);
+                Ok((references, right_refs))
+            }
+        }
     }
 
-    fn partition(&mut self, start: u32, count: u32) -> u32 {
-        let bins = 8;
-        let mut optimal_axis = 0;
-        let mut optimal_pos = 0f32;
-        let mut optimal_pivot = 0;
-        let mut optimal_cost = f32::MAX;
+    /// Bins centroids along every axis and sweeps the bins from both sides
+    /// to find the plane with the lowest SAH cost.
+    fn find_best_split<P: Primitive>(&self, primitives: &[P]) -> Option<Split> {
+        let num_bins = self.num_bins;
+        let centroid_bounds = self.calculate_bounds(primitives, true);
 
-        let aabb = self.calculate_bounds(start, count, true);
+        let mut bins = vec![Bin::default(); num_bins];
+        let mut left_area = vec![0f32; num_bins - 1];
+        let mut left_count = vec![0u32; num_bins - 1];
+        let mut right_area = vec![0f32; num_bins - 1];
+        let mut right_count = vec![0u32; num_bins - 1];
 
+        let mut best: Option<Split> = None;
         for axis in 0..3 {
-            for scale in (1..bins).map(|b| (b as f32) / (bins as f32)) {
-                let pos = aabb.min.lerp(aabb.max, scale)[axis];
-                let pivot = self.partition_shuffle(axis, pos, start, count);
-
-                let bb1_count = pivot - start;
-                let bb2_count = count - bb1_count;
-
-                let bb1 = self.calculate_bounds(start, bb1_count, false);
-                let bb2 = self.calculate_bounds(pivot, bb2_count, false);
-
-                let cost = bb1.area() * bb1_count as f32 + bb2.area() * bb2_count as f32;
-                if cost < optimal_cost {
-                    optimal_axis = axis;
-                    optimal_pos = pos;
-                    optimal_pivot = pivot;
-                    optimal_cost = cost;
+            let min = centroid_bounds.min[axis];
+            let max = centroid_bounds.max[axis];
+            if min == max {
+                continue;
+            }
+            let scale = num_bins as f32 / (max - min);
+
+            bins.fill(Bin::default());
+            for primitive in primitives {
+                let bin = bin_index(primitive.centroid(self)[axis], min, scale, num_bins);
+                bins[bin].count += 1;
+                bins[bin].bounds.union(&primitive.bounds(self));
+            }
+
+            let mut left_box = Aabb::empty();
+            let mut right_box = Aabb::empty();
+            let (mut left_sum, mut right_sum) = (0, 0);
+            for i in 0..num_bins - 1 {
+                left_sum += bins[i].count;
+                left_box.union(&bins[i].bounds);
+                left_count[i] = left_sum;
+                left_area[i] = left_box.area();
+
+                let j = num_bins - 1 - i;
+                right_sum += bins[j].count;
+                right_box.union(&bins[j].bounds);
+                right_count[j - 1] = right_sum;
+                right_area[j - 1] = right_box.area();
+            }
+
+            for bin in 0..num_bins - 1 {
+                if left_count[bin] == 0 || right_count[bin] == 0 {
+                    continue;
+                }
+                let cost = left_area[bin] * left_count[bin] as f32
+                    + right_area[bin] * right_count[bin] as f32;
+                if cost < best.map_or(f32::MAX, |best| best.cost) {
+                    best = Some(Split {
+                        axis,
+                        bin,
+                        min,
+                        scale,
+                        cost,
+                    });
                 }
             }
         }
-        self.partition_shuffle(optimal_axis, optimal_pos, start, count);
-        optimal_pivot
+        best
     }
 
-    fn partition_shuffle(&mut self, axis: usize, pos: f32, start: u32, count: u32) -> u32 {
-        let mut end = (start + count - 1) as usize;
-        let mut i = start as usize;
+    /// Overlap of the children `split` would produce.
+    fn split_overlap<P: Primitive>(&self, split: &Split, primitives: &[P]) -> Aabb {
+        let mut left = Aabb::empty();
+        let mut right = Aabb::empty();
+        for primitive in primitives {
+            let centroid = primitive.centroid(self)[split.axis];
+            match bin_index(centroid, split.min, split.scale, self.num_bins) <= split.bin {
+                true => left.union(&primitive.bounds(self)),
+                false => right.union(&primitive.bounds(self)),
+            }
+        }
+        left.intersection(&right)
+    }
+
+    /// Bins references by their extent instead of their centroid, clipping
+    /// them to every bin they touch. Splits duplicating more references than
+    /// `budget` are skipped.
+    fn find_spatial_split(
+        &self,
+        bounds: &Aabb,
+        references: &[Reference],
+        budget: usize,
+    ) -> Option<SpatialSplit> {
+        let num_bins = self.num_bins;
+        let mut bins = vec![SpatialBin::default(); num_bins];
+        let mut right_area = vec![0f32; num_bins - 1];
+        let mut right_count = vec![0u32; num_bins - 1];
+
+        let mut best: Option<SpatialSplit> = None;
+        for axis in 0..3 {
+            let min = bounds.min[axis];
+            let extent = bounds.max[axis] - min;
+            if extent <= 0. {
+                continue;
+            }
+            let scale = num_bins as f32 / extent;
+            let plane = |bin: usize| min + bin as f32 / scale;
+
+            bins.fill(SpatialBin::default());
+            for reference in references {
+                let first = bin_index(reference.bounds.min[axis], min, scale, num_bins);
+                let last = bin_index(reference.bounds.max[axis], min, scale, num_bins);
+                bins[first].enter += 1;
+                bins[last].exit += 1;
+                if first == last {
+                    bins[first].bounds.union(&reference.bounds);
+                    continue;
+                }
+                for (i, bin) in bins.iter_mut().enumerate().take(last + 1).skip(first) {
+                    let clipped = self.clip_reference(reference, axis, plane(i), plane(i + 1));
+                    if clipped.is_valid() {
+                        bin.bounds.union(&clipped);
+                    }
+                }
+            }
+
+            let mut right_box = Aabb::empty();
+            let mut right_sum = 0;
+            for bin in (1..num_bins).rev() {
+                right_sum += bins[bin].exit;
+                right_box.union(&bins[bin].bounds);
+                right_count[bin - 1] = right_sum;
+                right_area[bin - 1] = right_box.area();
+            }
+
+            let mut left_box = Aabb::empty();
+            let mut left_sum = 0;
+            for bin in 0..num_bins - 1 {
+                left_sum += bins[bin].enter;
+                left_box.union(&bins[bin].bounds);
+                if left_sum == 0 || right_count[bin] == 0 {
+                    continue;
+                }
+                // Every reference enters before or exits after the plane
+                let duplicates = (left_sum + right_count[bin]) as usize - references.len();
+                if duplicates > budget {
+                    continue;
+                }
+                let cost =
+                    left_box.area() * left_sum as f32 + right_area[bin] * right_count[bin] as f32;
+                if cost < best.map_or(f32::MAX, |best| best.cost) {
+                    best = Some(SpatialSplit {
+                        axis,
+                        plane: plane(bin + 1),
+                        cost,
+                    });
+                }
+            }
+        }
+        best
+    }
+
+    /// Distributes references over both sides of `split`, clipping the ones
+    /// straddling the plane into both.
+    fn split_references(
+        &self,
+        split: &SpatialSplit,
+        references: Vec<Reference>,
+    ) -> (Vec<Reference>, Vec<Reference>) {
+        let SpatialSplit { axis, plane, .. } = *split;
+        let mut left = Vec::with_capacity(references.len());
+        let mut right = Vec::with_capacity(references.len());
+        for reference in references {
+            if reference.bounds.max[axis] <= plane {
+                left.push(reference);
+            } else if reference.bounds.min[axis] >= plane {
+                right.push(reference);
+            } else {
+                let sides = [
+                    (&mut left, f32::NEG_INFINITY, plane),
+                    (&mut right, plane, f32::INFINITY),
+                ];
+                for (side, lo, hi) in sides {
+                    let bounds = self.clip_reference(&reference, axis, lo, hi);
+                    if bounds.is_valid() {
+                        side.push(Reference {
+                            bounds,
+                            ..reference
+                        });
+                    }
+                }
+            }
+        }
+        (left, right)
+    }
+
+    /// Bounds of the part of the referenced triangle between `lo` and `hi`
+    /// along `axis`, invalid if there is none.
+    fn clip_reference(&self, reference: &Reference, axis: usize, lo: f32, hi: f32) -> Aabb {
+        let trig = self.indices[reference.triangle]
+            .to_array()
+            .map(|i| self.vertices[i as usize]);
+
+        let mut aabb = Aabb::empty();
+        for i in 0..3 {
+            let (a, b) = (trig[i], trig[(i + 1) % 3]);
+            if (lo..=hi).contains(&a[axis]) {
+                aabb.grow(a);
+            }
+            for plane in [lo, hi] {
+                if (a[axis] < plane) != (b[axis] < plane) {
+                    let t = (plane - a[axis]) / (b[axis] - a[axis]);
+                    let mut point = a.lerp(b, t);
+                    point[axis] = plane;
+                    aabb.grow(point);
+                }
+            }
+        }
+        aabb.intersection(&reference.bounds)
+    }
+
+    /// Moves primitives left of `split` to the front, returning their count.
+    fn partition_shuffle<P: Primitive>(&self, split: &Split, primitives: &mut [P]) -> u32 {
+        let mut i = 0;
+        let mut end = primitives.len();
 
         while i < end {
-            if self.centroids[self.triangle_indices[i]][axis] < pos {
+            let centroid = primitives[i].centroid(self)[split.axis];
+            if bin_index(centroid, split.min, split.scale, self.num_bins) <= split.bin {
                 i += 1;
             } else {
-                self.triangle_indices.swap(i, end);
                 end -= 1;
+                primitives.swap(i, end);
             }
         }
 
         i as u32
     }
 
-    fn calculate_bounds(&self, first: u32, amount: u32, centroids: bool) -> Aabb {
-        let mut max = Vec3::splat(-MAX_DIST);
-        let mut min = Vec3::splat(MAX_DIST);
-        for &idx in &self.triangle_indices[first as usize..][..amount as usize] {
+    /// Whether the remaining levels can't hold a leaf per primitive, in
+    /// which case only balanced splits keep the subtree within the limit.
+    fn needs_median_split(&self, count: u32, depth: usize) -> bool {
+        !fits_levels(count, self.max_depth - depth)
+    }
+
+    /// Splits primitives in halves at the median centroid along the longest
+    /// axis, returning the size of the first half.
+    fn partition_median<P: Primitive>(&self, primitives: &mut [P]) -> u32 {
+        let centroid_bounds = self.calculate_bounds(primitives, true);
+        let extent = centroid_bounds.max - centroid_bounds.min;
+        let axis = match extent.max_element() {
+            max if max == extent.x => 0,
+            max if max == extent.y => 1,
+            _ => 2,
+        };
+        let mid = primitives.len() / 2;
+        primitives.select_nth_unstable_by(mid, |a, b| {
+            a.centroid(self)[axis].total_cmp(&b.centroid(self)[axis])
+        });
+        mid as u32
+    }
+
+    fn calculate_bounds<P: Primitive>(&self, primitives: &[P], centroids: bool) -> Aabb {
+        let mut aabb = Aabb::empty();
+        for primitive in primitives {
             if centroids {
-                let vertex = self.centroids[idx];
-                max = max.max(vertex);
-                min = min.min(vertex);
+                aabb.grow(primitive.centroid(self));
             } else {
-                self.indices[idx].to_array()[..3]
-                    .iter()
-                    .map(|&i| self.vertices[i as usize])
-                    .for_each(|vertex| {
-                        max = max.max(vertex);
-                        min = min.min(vertex);
-                    });
+                aabb.union(&primitive.bounds(self));
             }
         }
-        Aabb { max, min }
+        aabb
     }
 }
 
+fn set_bound(node: &mut BvhNode, aabb: &Aabb) {
+    node.max = aabb.max;
+    node.min = aabb.min;
+}
+
+/// Makes `left` and `right` children of `node`, followed by their subtrees.
+/// Child indices are relative to the returned vector.
+fn link_children(
+    node: &mut BvhNode,
+    mut left: BvhNode,
+    mut right: BvhNode,
+    left_nodes: Vec<BvhNode>,
+    right_nodes: Vec<BvhNode>,
+) -> Vec<BvhNode> {
+    let left_offset = 2;
+    let right_offset = left_offset + left_nodes.len() as u32;
+    relocate(std::slice::from_mut(&mut left), left_offset);
+    relocate(std::slice::from_mut(&mut right), right_offset);
+
+    node.left_first = 0;
+    node.count = 0;
+
+    let mut nodes = Vec::with_capacity(2 + left_nodes.len() + right_nodes.len());
+    nodes.push(left);
+    nodes.push(right);
+    nodes.extend(left_nodes);
+    nodes.extend(right_nodes);
+    relocate(
+        &mut nodes[left_offset as usize..right_offset as usize],
+        left_offset,
+    );
+    relocate(&mut nodes[right_offset as usize..], right_offset);
+    nodes
+}
+
+/// Shifts child indices of interior nodes by `offset`.
+fn relocate(nodes: &mut [BvhNode], offset: u32) {
+    for node in nodes.iter_mut().filter(|node| !node.is_leaf()) {
+        node.left_first += offset;
+    }
+}
+
+/// Shifts the first triangle of leaves by `offset`.
+fn relocate_leaves(nodes: &mut [BvhNode], offset: u32) {
+    for node in nodes.iter_mut().filter(|node| node.is_leaf()) {
+        node.left_first += offset;
+    }
+}
+
+/// Whether `count` primitives fit a subtree of `levels` levels below its
+/// root with one primitive per leaf.
+pub(crate) fn fits_levels(count: u32, levels: usize) -> bool {
+    count <= 1 << levels.min(31)
+}
+
+pub(crate) fn bin_index(centroid: f32, min: f32, scale: f32, num_bins: usize) -> usize {
+    (((centroid - min) * scale) as usize).min(num_bins - 1)
+}
+
+/// Refitted trees whose SAH cost grew by more than this factor should be rebuilt.
+const REBUILD_THRESHOLD: f32 = 1.5;
+
 pub struct Bvh {
     pub nodes: Vec<BvhNode>,
+    build_cost: f32,
+    depth: usize,
 }
 
 impl Bvh {
+    /// Tree without nodes, built for meshes without triangles and used for
+    /// meshes whose BVH lives only on the GPU. CPU queries against it never
+    /// hit.
+    pub fn empty() -> Self {
+        Self {
+            nodes: vec![],
+            build_cost: 0.,
+            depth: 0,
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.nodes.is_empty()
+    }
+
+    /// Wraps nodes of a previously built tree, e.g. loaded from a cache.
+    /// Its current SAH cost becomes the baseline for
+    /// [`Bvh::rebuild_recommended`].
+    pub fn from_nodes(nodes: Vec<BvhNode>) -> Self {
+        let mut bvh = Self {
+            nodes,
+            build_cost: 0.,
+            depth: 0,
+        };
+        bvh.build_cost = bvh.sah_cost();
+        bvh.depth = bvh.stats().max_depth;
+        bvh
+    }
+
+    /// Depth of the deepest leaf, the root is at depth 0. Traversal needs
+    /// one stack entry more than this, see [`BvhBuilder::set_max_depth`].
+    pub fn depth(&self) -> usize {
+        self.depth
+    }
+
+    /// SAH cost of the tree relative to the surface area of its root.
+    pub fn sah_cost(&self) -> f32 {
+        if self.is_empty() {
+            return 0.;
+        }
+        let root_area = Aabb::new(self.nodes[0].min, self.nodes[0].max).area();
+        if root_area <= 0. {
+            return 0.;
+        }
+        let cost: f32 = self
+            .nodes
+            .iter()
+            .enumerate()
+            .filter(|&(i, _)| i != 1)
+            .map(|(_, node)| {
+                let area = Aabb::new(node.min, node.max).area();
+                match node.is_leaf() {
+                    true => area * node.count as f32,
+                    false => area,
+                }
+            })
+            .sum();
+        cost / root_area
+    }
+
+    /// Recomputes node bounds bottom-up after vertex positions changed.
+    ///
+    /// The topology is kept, so `indices` must be the buffer reordered by
+    /// [`BvhBuilder::build`] or returned by [`BvhBuilder::build_indexed`].
+    pub fn refit(&mut self, vertices: &[Vec3], indices: &[UVec3]) {
+        // Children are always stored after their parents
+        for i in (0..self.nodes.len()).rev() {
+            if i == 1 {
+                continue;
+            }
+            let node = self.nodes[i];
+            let mut aabb = Aabb::empty();
+            if node.is_leaf() {
+                for idx in &indices[node.triangle_start()..][..node.triangle_count()] {
+                    for v in idx.to_array() {
+                        aabb.grow(vertices[v as usize]);
+                    }
+                }
+            } else {
+                for child in [node.left_node_index(), node.right_node_index()] {
+                    let child = self.nodes[child];
+                    aabb.union(&Aabb::new(child.min, child.max));
+                }
+            }
+            set_bound(&mut self.nodes[i], &aabb);
+        }
+    }
+
+    /// Whether refitting degraded the tree enough that a full rebuild
+    /// would be cheaper to trace.
+    pub fn rebuild_recommended(&self) -> bool {
+        self.sah_cost() > self.build_cost * REBUILD_THRESHOLD
+    }
+
     pub fn traverse(
         &self,
         vertices: &[Vec4],
@@ -245,38 +921,151 @@ impl Bvh {
     }
 
     pub fn traverse_iter(&self, vertices: &[Vec3], indices: &[UVec3], ray: Ray) -> Dist {
+        self.intersect(vertices, indices, ray)
+            .map(|hit| hit.t)
+            .into()
+    }
+
+    /// Returns `true` as soon as any triangle is hit closer than `t_max`.
+    pub fn occluded(&self, vertices: &[Vec3], indices: &[UVec3], ray: Ray, t_max: f32) -> bool {
+        self.occluded_with(vertices, indices, ray, t_max, |_| true)
+    }
+
+    /// Like [`Bvh::occluded`], but hits only count when `alpha_test` accepts
+    /// them, letting cutout materials pass light through.
+    pub fn occluded_with(
+        &self,
+        vertices: &[Vec3],
+        indices: &[UVec3],
+        ray: Ray,
+        t_max: f32,
+        alpha_test: impl FnMut(TriangleHit) -> bool,
+    ) -> bool {
+        self.occluded_by(
+            ray,
+            t_max,
+            |triangle| triangle_hit(vertices, indices, ray, triangle),
+            alpha_test,
+        )
+    }
+
+    /// Finds the closest triangle hit by `ray`.
+    pub fn intersect(&self, vertices: &[Vec3], indices: &[UVec3], ray: Ray) -> Option<TriangleHit> {
+        self.closest_hit(ray, |triangle| {
+            triangle_hit(vertices, indices, ray, triangle)
+        })
+    }
+
+    /// Builds a tree over analytic primitives and reorders them in place to
+    /// match its leaves.
+    pub fn build_procedural(primitives: &mut [ProceduralPrimitive]) -> Bvh {
+        let mut builder = BvhBuilder::new(&[], &mut []);
+        builder.bounds = primitives.iter().map(|p| p.bounds()).collect();
+        builder.centroids = primitives.iter().map(|p| p.center()).collect();
+        let (bvh, order) = builder.build_from_bounds();
+        let reordered: Vec<_> = order.into_iter().map(|i| primitives[i]).collect();
+        primitives.copy_from_slice(&reordered);
+        bvh
+    }
+
+    /// Finds the closest primitive hit by `ray` in a tree built with
+    /// [`Bvh::build_procedural`]. The hit `triangle` is the primitive index
+    /// and barycentrics are zero.
+    pub fn intersect_procedural(
+        &self,
+        primitives: &[ProceduralPrimitive],
+        ray: Ray,
+    ) -> Option<TriangleHit> {
+        self.closest_hit(ray, |primitive| primitive_hit(primitives, ray, primitive))
+    }
+
+    /// Any-hit counterpart of [`Bvh::intersect_procedural`].
+    pub fn occluded_procedural(
+        &self,
+        primitives: &[ProceduralPrimitive],
+        ray: Ray,
+        t_max: f32,
+    ) -> bool {
+        self.occluded_by(
+            ray,
+            t_max,
+            |primitive| primitive_hit(primitives, ray, primitive),
+            |_| true,
+        )
+    }
+
+    /// Any-hit traversal, `leaf_hit` intersects the primitive at an index
+    /// referenced by a leaf.
+    fn occluded_by(
+        &self,
+        ray: Ray,
+        t_max: f32,
+        mut leaf_hit: impl FnMut(usize) -> Option<TriangleHit>,
+        mut accept: impl FnMut(TriangleHit) -> bool,
+    ) -> bool {
+        if self.is_empty() {
+            return false;
+        }
         let mut stack = Stack::new();
         stack.push(0);
 
-        let mut hit = Dist::Miss;
         while !stack.is_empty() {
             let node = self.nodes[stack.pop()];
             if node.is_leaf() {
                 for i in 0..node.triangle_count() {
-                    let idx = indices[node.triangle_start() + i];
-                    let trig = [
-                        vertices[idx[0] as usize],
-                        vertices[idx[1] as usize],
-                        vertices[idx[2] as usize],
-                    ];
-                    if let Hit(dist) = ray.intersect(trig) {
-                        hit = match hit {
-                            Hit(t) => Hit(t.min(dist)),
-                            Miss => Hit(dist),
+                    let Some(hit) = leaf_hit(node.triangle_start() + i) else {
+                        continue;
+                    };
+                    if hit.t < t_max && accept(hit) {
+                        return true;
+                    }
+                }
+            } else {
+                for child in [node.left_node_index(), node.right_node_index()] {
+                    let child_node = self.nodes[child];
+                    if let Hit(_) = intersect_aabb(ray, child_node.min, child_node.max, t_max) {
+                        stack.push(child);
+                    }
+                }
+            }
+        }
+        false
+    }
+
+    /// Closest hit traversal, `leaf_hit` intersects the primitive at an
+    /// index referenced by a leaf.
+    fn closest_hit(
+        &self,
+        ray: Ray,
+        mut leaf_hit: impl FnMut(usize) -> Option<TriangleHit>,
+    ) -> Option<TriangleHit> {
+        if self.is_empty() {
+            return None;
+        }
+        let mut stack = Stack::new();
+        stack.push(0);
+
+        let mut hit: Option<TriangleHit> = None;
+        while !stack.is_empty() {
+            let node = self.nodes[stack.pop()];
+            if node.is_leaf() {
+                for i in 0..node.triangle_count() {
+                    if let Some(new_hit) = leaf_hit(node.triangle_start() + i) {
+                        if new_hit.t < hit.map_or(MAX_DIST, |hit| hit.t) {
+                            hit = Some(new_hit);
                         }
                     }
                 }
             } else {
+                let t = hit.map_or(MAX_DIST, |hit| hit.t);
                 let mut min_index = node.left_node_index();
                 let mut max_index = node.right_node_index();
 
                 let min_child = self.nodes[min_index];
                 let max_child = self.nodes[max_index];
 
-                let mut min_dist =
-                    intersect_aabb(ray, min_child.min, min_child.max, hit.unwrap_or(MAX_DIST));
-                let mut max_dist =
-                    intersect_aabb(ray, max_child.min, max_child.max, hit.unwrap_or(MAX_DIST));
+                let mut min_dist = intersect_aabb(ray, min_child.min, min_child.max, t);
+                let mut max_dist = intersect_aabb(ray, max_child.min, max_child.max, t);
                 if min_dist > max_dist {
                     (min_index, max_index) = (max_index, min_index);
                     (min_dist, max_dist) = (max_dist, min_dist);
@@ -295,30 +1084,294 @@ impl Bvh {
     }
 }
 
-struct Stack {
-    arr: [usize; 32],
+fn triangle_hit(
+    vertices: &[Vec3],
+    indices: &[UVec3],
+    ray: Ray,
+    triangle: usize,
+) -> Option<TriangleHit> {
+    let trig = indices[triangle].to_array().map(|i| vertices[i as usize]);
+    let (t, barycentrics) = ray.intersect_barycentric(trig)?;
+    Some(TriangleHit {
+        t,
+        triangle: triangle as u32,
+        barycentrics,
+    })
+}
+
+fn primitive_hit(
+    primitives: &[ProceduralPrimitive],
+    ray: Ray,
+    primitive: usize,
+) -> Option<TriangleHit> {
+    let t = primitives[primitive].intersect(ray)?;
+    Some(TriangleHit {
+        t,
+        triangle: primitive as u32,
+        barycentrics: Vec2::ZERO,
+    })
+}
+
+/// Entries kept inline by the traversal stacks of [`Bvh`] and
+/// [`crate::Tlas`], deeper trees spill the rest to the heap.
+pub const STACK_LEN: usize = 32;
+
+pub(crate) struct Stack {
+    arr: [usize; STACK_LEN],
     head: usize,
+    spilled: Vec<usize>,
 }
 
 impl Stack {
-    fn new() -> Self {
+    pub(crate) fn new() -> Self {
         Self {
-            arr: [usize::MAX; 32],
+            arr: [usize::MAX; STACK_LEN],
             head: 0,
+            spilled: vec![],
         }
     }
 
-    fn is_empty(&self) -> bool {
+    pub(crate) fn is_empty(&self) -> bool {
         self.head == 0
     }
 
-    fn push(&mut self, val: usize) {
+    pub(crate) fn push(&mut self, val: usize) {
+        if self.head == STACK_LEN {
+            self.spilled.push(val);
+            return;
+        }
         self.arr[self.head] = val;
         self.head += 1;
     }
 
-    fn pop(&mut self) -> usize {
+    pub(crate) fn pop(&mut self) -> usize {
+        if let Some(val) = self.spilled.pop() {
+            return val;
+        }
         self.head -= 1;
         self.arr[self.head]
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use glam::vec3;
+
+    use super::*;
+
+    /// Wavy `side` by `side` height field crossed by long thin triangles, so
+    /// that spatial splits pay off.
+    fn test_mesh(side: u32) -> (Vec<Vec3>, Vec<UVec3>) {
+        let mut vertices = vec![];
+        let mut indices = vec![];
+        for y in 0..=side {
+            for x in 0..=side {
+                let (fx, fy) = (x as f32 / side as f32, y as f32 / side as f32);
+                let z = 0.1 * (fx * 9.).sin() * (fy * 7.).cos();
+                vertices.push(vec3(fx * 2. - 1., fy * 2. - 1., z));
+            }
+        }
+        for y in 0..side {
+            for x in 0..side {
+                let i = y * (side + 1) + x;
+                indices.push(UVec3::new(i, i + 1, i + side + 1));
+                indices.push(UVec3::new(i + 1, i + side + 2, i + side + 1));
+            }
+        }
+
+        let mut seed = 0x2545f491u32;
+        let mut random = || {
+            seed ^= seed << 13;
+            seed ^= seed >> 17;
+            seed ^= seed << 5;
+            seed as f32 / u32::MAX as f32
+        };
+        for _ in 0..side {
+            let from = vec3(random() * 2. - 1., random() * 2. - 1., 0.2 + random() * 0.4);
+            let to = vec3(random() * 2. - 1., random() * 2. - 1., 0.2 + random() * 0.4);
+            let first = vertices.len() as u32;
+            vertices.extend([from, to, from + vec3(0., 0., 0.02)]);
+            indices.push(UVec3::new(first, first + 1, first + 2));
+        }
+        (vertices, indices)
+    }
+
+    #[test]
+    fn wgsl_stack_len_matches_shader() {
+        let shader = include_str!("../../../shaders/utils/stack.wgsl");
+        let len = shader
+            .lines()
+            .find_map(|line| line.strip_prefix("const STACK_LEN: u32 = "))
+            .and_then(|len| len.strip_suffix("u;"))
+            .expect("`STACK_LEN` not found in stack.wgsl");
+        assert_eq!(len.parse::<usize>().unwrap(), WGSL_STACK_LEN);
+    }
+
+    #[test]
+    fn stack_spills_past_inline_entries() {
+        let mut stack = Stack::new();
+        for i in 0..2 * STACK_LEN {
+            stack.push(i);
+        }
+        for i in (0..2 * STACK_LEN).rev() {
+            assert!(!stack.is_empty());
+            assert_eq!(stack.pop(), i);
+        }
+        assert!(stack.is_empty());
+    }
+
+    #[test]
+    fn empty_mesh_builds_empty_bvh() {
+        let bvh = BvhBuilder::new(&[], &mut []).build();
+        assert!(bvh.is_empty());
+        assert_eq!(bvh.depth(), 0);
+
+        let ray = Ray::new(Vec3::new(0., 0., -1.), Vec3::Z);
+        assert!(bvh.intersect(&[], &[], ray).is_none());
+        assert!(!bvh.occluded(&[], &[], ray, MAX_DIST));
+        assert!(Bvh::build_procedural(&mut []).is_empty());
+    }
+
+    #[test]
+    fn parallel_builds_match_single_threaded() {
+        let (vertices, indices) = test_mesh(64);
+        assert!(indices.len() as u32 > 2 * PARALLEL_THRESHOLD);
+
+        let build = |threads: usize| {
+            let pool = rayon::ThreadPoolBuilder::new()
+                .num_threads(threads)
+                .build()
+                .unwrap();
+            pool.install(|| {
+                let mut reordered = indices.clone();
+                let bvh = BvhBuilder::new(&vertices, &mut reordered).build();
+                let (split_bvh, split_indices) = BvhBuilder::new(&vertices, &mut indices.clone())
+                    .spatial_splits(0.3)
+                    .build_indexed();
+                [
+                    (
+                        bytemuck::cast_slice::<_, u8>(&bvh.nodes).to_vec(),
+                        reordered,
+                    ),
+                    (
+                        bytemuck::cast_slice::<_, u8>(&split_bvh.nodes).to_vec(),
+                        split_indices,
+                    ),
+                ]
+            })
+        };
+
+        let single_threaded = build(1);
+        for threads in [2, 4, 8, 8] {
+            assert!(build(threads) == single_threaded, "{threads} threads");
+        }
+    }
+
+    #[test]
+    fn refit_matches_fresh_build() {
+        let (vertices, mut indices) = test_mesh(16);
+        let mut bvh = BvhBuilder::new(&vertices, &mut indices).build();
+        assert!(!bvh.rebuild_recommended());
+
+        let rays: Vec<_> = (0..32 * 32)
+            .map(|i| {
+                let (x, y) = ((i % 32) as f32 + 0.37, (i / 32) as f32 + 0.61);
+                let (x, y) = (x / 16. - 1., y / 16. - 1.);
+                Ray::new(vec3(x * 1.2, y * 1.2, 2.), vec3(0.1 * y, -0.1 * x, -1.))
+            })
+            .collect();
+        let waves = |amplitude: f32| {
+            vertices
+                .iter()
+                .map(|&v| v + Vec3::Z * amplitude * (v.x * 5.).cos() * (v.y * 3.).sin())
+                .collect::<Vec<_>>()
+        };
+        // Every other vertex jumps far away, stretching most triangles over
+        // the whole mesh
+        let scrambled: Vec<_> = vertices
+            .iter()
+            .enumerate()
+            .map(|(i, &v)| match i % 2 {
+                0 => v,
+                _ => -v * 4.,
+            })
+            .collect();
+
+        for (displaced, degraded) in [(waves(0.05), false), (scrambled, true)] {
+            bvh.refit(&displaced, &indices);
+            assert_eq!(bvh.rebuild_recommended(), degraded);
+
+            let mut fresh_indices = indices.clone();
+            let fresh = BvhBuilder::new(&displaced, &mut fresh_indices).build();
+            let mut hits = 0;
+            for &ray in &rays {
+                let refitted = bvh.intersect(&displaced, &indices, ray);
+                let rebuilt = fresh.intersect(&displaced, &fresh_indices, ray);
+                assert_eq!(
+                    refitted.map(|hit| (indices[hit.triangle as usize], hit.t)),
+                    rebuilt.map(|hit| (fresh_indices[hit.triangle as usize], hit.t)),
+                    "{ray:?}"
+                );
+                hits += refitted.is_some() as usize;
+            }
+            assert!(hits > 0);
+        }
+    }
+
+    #[test]
+    fn spatial_splits_stay_valid_and_within_budget() {
+        let (vertices, mut indices) = test_mesh(24);
+        let triangles = indices.len();
+        let (split, split_indices) = BvhBuilder::new(&vertices, &mut indices.clone())
+            .spatial_splits(0.3)
+            .build_indexed();
+        let bvh = BvhBuilder::new(&vertices, &mut indices).build();
+
+        let mut referenced = vec![0; triangles];
+        let mut stack = vec![0];
+        while let Some(idx) = stack.pop() {
+            let node = split.nodes[idx];
+            if !node.is_leaf() {
+                stack.extend([node.left_node_index(), node.right_node_index()]);
+                continue;
+            }
+            let leaf = Aabb::new(node.min, node.max);
+            assert!(node.triangle_start() + node.triangle_count() <= split_indices.len());
+            for triangle in &split_indices[node.triangle_start()..][..node.triangle_count()] {
+                let original = indices.iter().position(|t| t == triangle).unwrap();
+                referenced[original] += 1;
+                // Clipped references still overlap the leaf
+                let mut bounds = Aabb::empty();
+                for vertex in triangle.to_array() {
+                    bounds.grow(vertices[vertex as usize]);
+                }
+                assert!(bounds.intersection(&leaf).is_valid());
+            }
+        }
+        assert!(referenced.iter().all(|&count| count > 0));
+        let total: usize = referenced.iter().sum();
+        assert_eq!(total, split_indices.len());
+        assert!(total > triangles, "No triangle was split");
+        assert!(total as f32 <= triangles as f32 * 1.3);
+
+        let mut hits = 0;
+        for i in 0..48 * 48 {
+            let (x, y) = ((i % 48) as f32 + 0.37, (i / 48) as f32 + 0.61);
+            let orig = vec3(x / 16. - 1.5, y / 16. - 1.5, 2.);
+            let ray = Ray::new(orig, vec3(0.3 * orig.y, -0.2 * orig.x, -1.));
+            let expected = bvh.intersect(&vertices, &indices, ray);
+            let actual = split.intersect(&vertices, &split_indices, ray);
+            assert_eq!(
+                expected.map(|hit| (indices[hit.triangle as usize], hit.t)),
+                actual.map(|hit| (split_indices[hit.triangle as usize], hit.t)),
+                "{ray:?}"
+            );
+            assert_eq!(
+                bvh.occluded(&vertices, &indices, ray, MAX_DIST),
+                split.occluded(&vertices, &split_indices, ray, MAX_DIST)
+            );
+            hits += expected.is_some() as usize;
+        }
+        assert!(hits > 0 && hits < 48 * 48);
+    }
+}
@@ -0,0 +1,524 @@
+use bytemuck::{Pod, Zeroable};
+use components::{Instance, MeshInfo};
+use glam::{UVec3, Vec3};
+
+use crate::{
+    blas::{Bvh, Stack},
+    intersection::{intersect_aabb, Aabb, Dist::*, Hit, TriangleHit, MAX_DIST},
+    tlas::instance_hit,
+    wide::{
+        exp2, leaves_fit_child_words, quantization_exponent, LEAF_COUNT_SHIFT, MAX_LEAF_COUNT,
+        MAX_LEAF_START,
+    },
+    ProceduralPrimitive, Ray, Tlas, WIDE_EMPTY_CHILD, WIDE_LEAF_FLAG,
+};
+
+/// Binary node whose children bounds are quantized to [`CompressedNode::BITS`]
+/// per plane relative to the bounds of the node itself.
+///
+/// Children are encoded like the ones of [`crate::WideNode`], except that
+/// leaves of a compressed TLAS hold an instance index below
+/// [`WIDE_LEAF_FLAG`].
+pub trait CompressedNode: Pod + Default {
+    const BITS: u32;
+
+    fn origin(&self) -> Vec3;
+    fn exponents(&self) -> u32;
+    fn children(&self) -> [u32; 2];
+    /// Lower x, y, z then upper x, y, z planes, packed into words with both
+    /// children of a plane next to each other.
+    fn bounds(&self) -> &[u32];
+
+    fn set_frame(&mut self, origin: Vec3, exponents: u32);
+    fn children_mut(&mut self) -> &mut [u32; 2];
+    fn bounds_mut(&mut self) -> &mut [u32];
+
+    fn plane(&self, plane: usize, slot: usize) -> u32 {
+        let (word, shift) = plane_position(Self::BITS, plane, slot);
+        (self.bounds()[word] >> shift) & plane_mask(Self::BITS)
+    }
+
+    fn set_plane(&mut self, plane: usize, slot: usize, value: u32) {
+        let (word, shift) = plane_position(Self::BITS, plane, slot);
+        let word = &mut self.bounds_mut()[word];
+        *word &= !(plane_mask(Self::BITS) << shift);
+        *word |= (value & plane_mask(Self::BITS)) << shift;
+    }
+
+    fn child_bounds(&self, slot: usize) -> Aabb {
+        let scale =
+            Vec3::from_array([0, 8, 16].map(|shift| exp2((self.exponents() >> shift) & 0xff)));
+        let plane = |plane: usize| self.plane(plane, slot) as f32;
+        let lo = Vec3::new(plane(0), plane(1), plane(2));
+        let hi = Vec3::new(plane(3), plane(4), plane(5));
+        Aabb::new(self.origin() + lo * scale, self.origin() + hi * scale)
+    }
+}
+
+/// Word and bit offset of a quantized plane.
+fn plane_position(bits: u32, plane: usize, slot: usize) -> (usize, u32) {
+    let index = plane * 2 + slot;
+    let per_word = (32 / bits) as usize;
+    (index / per_word, bits * (index % per_word) as u32)
+}
+
+fn plane_mask(bits: u32) -> u32 {
+    (1 << bits) - 1
+}
+
+macro_rules! compressed_node {
+    ($name:ident, $bits:literal) => {
+        #[repr(C)]
+        #[derive(Copy, Clone, Default, Debug, Pod, Zeroable)]
+        pub struct $name {
+            pub origin: [f32; 3],
+            /// Biased power of two scale of x, y and z, one byte each.
+            pub exponents: u32,
+            pub children: [u32; 2],
+            pub bounds: [u32; 12 * $bits / 32],
+        }
+
+        impl CompressedNode for $name {
+            const BITS: u32 = $bits;
+
+            fn origin(&self) -> Vec3 {
+                Vec3::from_array(self.origin)
+            }
+
+            fn exponents(&self) -> u32 {
+                self.exponents
+            }
+
+            fn children(&self) -> [u32; 2] {
+                self.children
+            }
+
+            fn bounds(&self) -> &[u32] {
+                &self.bounds
+            }
+
+            fn set_frame(&mut self, origin: Vec3, exponents: u32) {
+                self.origin = origin.to_array();
+                self.exponents = exponents;
+            }
+
+            fn children_mut(&mut self) -> &mut [u32; 2] {
+                &mut self.children
+            }
+
+            fn bounds_mut(&mut self) -> &mut [u32] {
+                &mut self.bounds
+            }
+        }
+    };
+}
+
+compressed_node!(CompressedNode8, 8);
+compressed_node!(CompressedNode16, 16);
+
+/// Tree of [`CompressedNode`]s with one node per interior node of the
+/// source tree. Both children of a binary node pair take 64 bytes, while a
+/// compressed node takes 36 bytes with 8 bit and 48 bytes with 16 bit planes.
+pub struct CompressedBvh<T> {
+    pub nodes: Vec<T>,
+}
+
+impl<T: CompressedNode> CompressedBvh<T> {
+    /// Compresses a BLAS. Leaves keep their triangle ranges, so the indices
+    /// reordered by [`crate::BvhBuilder`] stay valid.
+    ///
+    /// Returns `None` for meshes with triangles past the 24 bit first
+    /// triangle of a leaf child, like [`crate::WideBvh::collapse`].
+    pub fn compress(bvh: &Bvh) -> Option<Self> {
+        if !leaves_fit_child_words(bvh) {
+            return None;
+        }
+        let mut this = Self { nodes: vec![] };
+        if !bvh.is_empty() {
+            this.compress_node(bvh, 0);
+        }
+        Some(this)
+    }
+
+    fn compress_node(&mut self, bvh: &Bvh, node_idx: usize) -> u32 {
+        let compressed_idx = self.nodes.len();
+        self.nodes.push(T::default());
+
+        let node = bvh.nodes[node_idx];
+        let slots = match node.is_leaf() {
+            true => vec![node_idx],
+            false => vec![node.left_node_index(), node.right_node_index()],
+        };
+        let children = slots
+            .into_iter()
+            .map(|idx| {
+                let child = bvh.nodes[idx];
+                let aabb = Aabb::new(child.min, child.max);
+                let word = match child.is_leaf() {
+                    true => self.leaf_child(child.left_first, child.count, &aabb),
+                    false => self.compress_node(bvh, idx),
+                };
+                (word, aabb)
+            })
+            .collect::<Vec<_>>();
+        self.nodes[compressed_idx] = encode_node(&children);
+        compressed_idx as u32
+    }
+
+    /// Leaves too large for a single child are split in halves under an
+    /// extra node.
+    fn leaf_child(&mut self, start: u32, count: u32, aabb: &Aabb) -> u32 {
+        debug_assert!(start + count - 1 <= MAX_LEAF_START);
+        if count <= MAX_LEAF_COUNT {
+            return WIDE_LEAF_FLAG | (count << LEAF_COUNT_SHIFT) | start;
+        }
+
+        let compressed_idx = self.nodes.len();
+        self.nodes.push(T::default());
+        let half = count / 2;
+        let children = [
+            (self.leaf_child(start, half, aabb), *aabb),
+            (self.leaf_child(start + half, count - half, aabb), *aabb),
+        ];
+        self.nodes[compressed_idx] = encode_node(&children);
+        compressed_idx as u32
+    }
+
+    /// Compresses a TLAS, leaves reference instances by index.
+    pub fn compress_tlas(tlas: &Tlas) -> Self {
+        let mut this = Self { nodes: vec![] };
+        if !tlas.nodes.is_empty() {
+            this.compress_tlas_node(tlas, 0);
+        }
+        this
+    }
+
+    fn compress_tlas_node(&mut self, tlas: &Tlas, node_idx: usize) -> u32 {
+        let compressed_idx = self.nodes.len();
+        self.nodes.push(T::default());
+
+        let node = tlas.nodes[node_idx];
+        let slots = match node.is_leaf() {
+            true => vec![node_idx],
+            false => vec![node.left_node_index(), node.right_node_index()],
+        };
+        let children = slots
+            .into_iter()
+            .map(|idx| {
+                let child = tlas.nodes[idx];
+                let word = match child.is_leaf() {
+                    true => WIDE_LEAF_FLAG | child.instance_idx,
+                    false => self.compress_tlas_node(tlas, idx),
+                };
+                (word, Aabb::new(child.min, child.max))
+            })
+            .collect::<Vec<_>>();
+        self.nodes[compressed_idx] = encode_node(&children);
+        compressed_idx as u32
+    }
+
+    pub fn size_bytes(&self) -> usize {
+        std::mem::size_of_val(self.nodes.as_slice())
+    }
+
+    /// Finds the closest triangle hit by `ray` in a compressed BLAS.
+    pub fn intersect(&self, vertices: &[Vec3], indices: &[UVec3], ray: Ray) -> Option<TriangleHit> {
+        self.closest_hit(ray, |child, t| {
+            let start = child & MAX_LEAF_START;
+            let count = (child & !WIDE_LEAF_FLAG) >> LEAF_COUNT_SHIFT;
+            let mut hit: Option<TriangleHit> = None;
+            for triangle in start..start + count {
+                let trig = indices[triangle as usize]
+                    .to_array()
+                    .map(|i| vertices[i as usize]);
+                if let Some((t_hit, barycentrics)) = ray.intersect_barycentric(trig) {
+                    if t_hit < hit.map_or(t, |hit| hit.t) {
+                        hit = Some(TriangleHit {
+                            t: t_hit,
+                            triangle,
+                            barycentrics,
+                        });
+                    }
+                }
+            }
+            hit.map(|hit| (hit.t, hit))
+        })
+    }
+
+    /// Finds the closest hit of a world space `ray` in a compressed TLAS,
+    /// like [`Tlas::intersect`] does with the uncompressed one.
+    #[allow(clippy::too_many_arguments)]
+    pub fn intersect_tlas(
+        &self,
+        ray: Ray,
+        instances: &[Instance],
+        meshes: &[MeshInfo],
+        blases: &[Bvh],
+        vertices: &[Vec3],
+        indices: &[u32],
+        primitives: &[ProceduralPrimitive],
+    ) -> Option<Hit> {
+        self.closest_hit(ray, |child, t| {
+            let instance_idx = child & !WIDE_LEAF_FLAG;
+            instance_hit(
+                ray,
+                instance_idx,
+                instances,
+                meshes,
+                blases,
+                vertices,
+                indices,
+                primitives,
+            )
+            .filter(|hit| hit.t < t)
+            .map(|hit| (hit.t, hit))
+        })
+    }
+
+    /// Walks the tree nearest child first. `leaf` gets the word of every hit
+    /// leaf child along with the closest distance so far and returns a
+    /// closer hit, if any.
+    fn closest_hit<H>(
+        &self,
+        ray: Ray,
+        mut leaf: impl FnMut(u32, f32) -> Option<(f32, H)>,
+    ) -> Option<H> {
+        if self.nodes.is_empty() {
+            return None;
+        }
+        let mut stack = Stack::new();
+        stack.push(0);
+
+        let mut hit: Option<(f32, H)> = None;
+        while !stack.is_empty() {
+            let node = self.nodes[stack.pop()];
+            let mut dists = [Miss; 2];
+            for (slot, &child) in node.children().iter().enumerate() {
+                if child == WIDE_EMPTY_CHILD {
+                    continue;
+                }
+                let t = hit.as_ref().map_or(MAX_DIST, |hit| hit.0);
+                let aabb = node.child_bounds(slot);
+                dists[slot] = intersect_aabb(ray, aabb.min, aabb.max, t);
+            }
+            let mut order = [0, 1];
+            if dists[1] < dists[0] {
+                order.swap(0, 1);
+            }
+
+            // Leaves are tested right away, nodes are pushed far one first
+            for slot in order {
+                let child = node.children()[slot];
+                if dists[slot] == Miss || child & WIDE_LEAF_FLAG == 0 {
+                    continue;
+                }
+                let t = hit.as_ref().map_or(MAX_DIST, |hit| hit.0);
+                if let Some(leaf_hit) = leaf(child, t) {
+                    hit = Some(leaf_hit);
+                }
+            }
+            for slot in order.into_iter().rev() {
+                let child = node.children()[slot];
+                if dists[slot] != Miss && child & WIDE_LEAF_FLAG == 0 {
+                    stack.push(child as usize);
+                }
+            }
+        }
+        hit.map(|(_, hit)| hit)
+    }
+}
+
+fn encode_node<T: CompressedNode>(children: &[(u32, Aabb)]) -> T {
+    let mut bounds = Aabb::empty();
+    children.iter().for_each(|(_, aabb)| bounds.union(aabb));
+    let steps = plane_mask(T::BITS) as f32;
+    let extent = bounds.max - bounds.min;
+    let exponents = extent
+        .to_array()
+        .map(|extent| quantization_exponent(extent, steps));
+    let scale = Vec3::from_array(exponents.map(exp2));
+
+    let mut node = T::default();
+    node.set_frame(
+        bounds.min,
+        exponents[0] | exponents[1] << 8 | exponents[2] << 16,
+    );
+    *node.children_mut() = [WIDE_EMPTY_CHILD; 2];
+    for (slot, (child, aabb)) in children.iter().enumerate() {
+        node.children_mut()[slot] = *child;
+        let lo = ((aabb.min - bounds.min) / scale).floor();
+        let hi = ((aabb.max - bounds.min) / scale).ceil();
+        let planes = lo.to_array().into_iter().chain(hi.to_array());
+        for (plane, value) in planes.enumerate() {
+            node.set_plane(plane, slot, value.clamp(0., steps) as u32);
+        }
+    }
+    node
+}
+
+#[cfg(test)]
+mod tests {
+    use components::{MaterialId, MeshId};
+    use glam::{vec3, Mat4, Quat};
+
+    use super::*;
+    use crate::BvhBuilder;
+
+    /// Wavy height field with a cloud of small triangles above it, so rays
+    /// see both large coherent surfaces and scattered geometry.
+    fn test_mesh() -> (Vec<Vec3>, Vec<UVec3>) {
+        const SIDE: u32 = 24;
+        let mut vertices = vec![];
+        let mut indices = vec![];
+        for y in 0..=SIDE {
+            for x in 0..=SIDE {
+                let (fx, fy) = (x as f32 / SIDE as f32, y as f32 / SIDE as f32);
+                let z = 0.1 * (fx * 9.).sin() * (fy * 7.).cos();
+                vertices.push(vec3(fx * 2. - 1., fy * 2. - 1., z));
+            }
+        }
+        for y in 0..SIDE {
+            for x in 0..SIDE {
+                let i = y * (SIDE + 1) + x;
+                indices.push(UVec3::new(i, i + 1, i + SIDE + 1));
+                indices.push(UVec3::new(i + 1, i + SIDE + 2, i + SIDE + 1));
+            }
+        }
+
+        let mut seed = 0x2545f491u32;
+        let mut random = || {
+            seed ^= seed << 13;
+            seed ^= seed >> 17;
+            seed ^= seed << 5;
+            seed as f32 / u32::MAX as f32
+        };
+        for _ in 0..300 {
+            let center = vec3(random() * 2. - 1., random() * 2. - 1., 0.2 + random() * 0.6);
+            let first = vertices.len() as u32;
+            for _ in 0..3 {
+                let offset = vec3(random() - 0.5, random() - 0.5, random() - 0.5) * 0.15;
+                vertices.push(center + offset);
+            }
+            indices.push(UVec3::new(first, first + 1, first + 2));
+        }
+        (vertices, indices)
+    }
+
+    /// Rays from a grid above the mesh, half of them straight down and half
+    /// tilted. The grid overhangs the mesh, so the outer rays miss.
+    fn test_rays() -> Vec<Ray> {
+        const SIDE: usize = 48;
+        let mut rays = vec![];
+        for y in 0..SIDE {
+            for x in 0..SIDE {
+                let (fx, fy) = (x as f32 + 0.37, y as f32 + 0.61);
+                let orig = vec3(fx / SIDE as f32 * 3. - 1.5, fy / SIDE as f32 * 3. - 1.5, 2.);
+                let dir = match (x + y) % 2 {
+                    0 => -Vec3::Z,
+                    _ => vec3(0.3 * orig.y, -0.2 * orig.x, -1.).normalize(),
+                };
+                rays.push(Ray::new(orig, dir));
+            }
+        }
+        rays
+    }
+
+    fn compressed_bvh_matches<T: CompressedNode>() {
+        let (vertices, mut indices) = test_mesh();
+        let bvh = BvhBuilder::new(&vertices, &mut indices).build();
+        let compressed = CompressedBvh::<T>::compress(&bvh).unwrap();
+
+        let mut hits = 0;
+        for ray in test_rays() {
+            let expected = bvh.intersect(&vertices, &indices, ray);
+            let actual = compressed.intersect(&vertices, &indices, ray);
+            assert_eq!(
+                expected.map(|hit| (hit.triangle, hit.t)),
+                actual.map(|hit| (hit.triangle, hit.t)),
+                "{ray:?}"
+            );
+            hits += expected.is_some() as usize;
+        }
+        assert!(hits > 0 && hits < test_rays().len());
+    }
+
+    #[test]
+    fn compressed8_matches_bvh() {
+        compressed_bvh_matches::<CompressedNode8>();
+    }
+
+    #[test]
+    fn compressed16_matches_bvh() {
+        compressed_bvh_matches::<CompressedNode16>();
+    }
+
+    fn compressed_tlas_matches<T: CompressedNode>() {
+        let (vertices, mut indices) = test_mesh();
+        let bvh = BvhBuilder::new(&vertices, &mut indices).build();
+        let root = bvh.nodes[0];
+        let meshes = [MeshInfo {
+            min: root.min,
+            max: root.max,
+            index_count: indices.len() as u32 * 3,
+            ..Default::default()
+        }];
+        let blases = [bvh];
+        let indices: &[u32] = bytemuck::cast_slice(&indices);
+
+        let instances: Vec<_> = (0..6)
+            .map(|i| {
+                let transform = Mat4::from_scale_rotation_translation(
+                    Vec3::splat(0.3 + 0.05 * i as f32),
+                    Quat::from_rotation_z(i as f32) * Quat::from_rotation_x(0.2 * i as f32),
+                    vec3(i as f32 % 3. - 1., (i / 3) as f32 - 0.5, -0.3 * i as f32),
+                );
+                Instance::new(transform, MeshId::default(), MaterialId::default())
+            })
+            .collect();
+        let mut tlas = Tlas::empty();
+        tlas.build(&instances, &meshes);
+        let compressed = CompressedBvh::<T>::compress_tlas(&tlas);
+
+        let mut hits = 0;
+        for ray in test_rays() {
+            let expected =
+                tlas.intersect(ray, &instances, &meshes, &blases, &vertices, indices, &[]);
+            let actual = compressed.intersect_tlas(
+                ray,
+                &instances,
+                &meshes,
+                &blases,
+                &vertices,
+                indices,
+                &[],
+            );
+            assert_eq!(
+                expected.map(|hit| (hit.instance.index(), hit.triangle, hit.t)),
+                actual.map(|hit| (hit.instance.index(), hit.triangle, hit.t)),
+                "{ray:?}"
+            );
+            hits += expected.is_some() as usize;
+        }
+        assert!(hits > 0 && hits < test_rays().len());
+    }
+
+    #[test]
+    fn leaves_past_the_leaf_start_are_not_compressed() {
+        let (vertices, mut indices) = test_mesh();
+        let mut bvh = BvhBuilder::new(&vertices, &mut indices).build();
+        let leaf = bvh.nodes.iter_mut().rfind(|node| node.count > 0).unwrap();
+        leaf.left_first = MAX_LEAF_START + 1;
+        assert!(CompressedBvh::<CompressedNode8>::compress(&bvh).is_none());
+        assert!(CompressedBvh::<CompressedNode16>::compress(&bvh).is_none());
+    }
+
+    #[test]
+    fn compressed8_tlas_matches_tlas() {
+        compressed_tlas_matches::<CompressedNode8>();
+    }
+
+    #[test]
+    fn compressed16_tlas_matches_tlas() {
+        compressed_tlas_matches::<CompressedNode16>();
+    }
+}
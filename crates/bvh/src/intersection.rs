@@ -1,4 +1,5 @@
-use glam::Vec3;
+use components::{InstanceId, MeshId};
+use glam::{Mat4, Vec2, Vec3};
 
 pub const MAX_DIST: f32 = 1e30;
 
@@ -13,6 +14,32 @@ impl Aabb {
         Self { min, max }
     }
 
+    pub fn empty() -> Self {
+        Self {
+            min: Vec3::splat(MAX_DIST),
+            max: Vec3::splat(-MAX_DIST),
+        }
+    }
+
+    pub fn grow(&mut self, point: Vec3) {
+        self.min = self.min.min(point);
+        self.max = self.max.max(point);
+    }
+
+    pub fn union(&mut self, other: &Aabb) {
+        self.min = self.min.min(other.min);
+        self.max = self.max.max(other.max);
+    }
+
+    /// Overlap of both boxes, inverted on some axis when they are disjoint.
+    pub fn intersection(&self, other: &Aabb) -> Aabb {
+        Aabb::new(self.min.max(other.min), self.max.min(other.max))
+    }
+
+    pub fn is_valid(&self) -> bool {
+        self.min.cmple(self.max).all()
+    }
+
     pub fn area(&self) -> f32 {
         let diff = self.max - self.min;
         (diff.x * diff.y + diff.x * diff.z + diff.y * diff.z) * 2.
@@ -44,6 +71,41 @@ impl From<Option<f32>> for Dist {
     }
 }
 
+/// Closest intersection with a triangle of a single mesh.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct TriangleHit {
+    pub t: f32,
+    /// Index of the triangle in the index buffer reordered by the builder.
+    pub triangle: u32,
+    /// Weights of the second and third vertices of the triangle.
+    pub barycentrics: Vec2,
+}
+
+impl TriangleHit {
+    pub fn with_instance(self, mesh: MeshId, instance: InstanceId, transform: Mat4) -> Hit {
+        Hit {
+            t: self.t,
+            triangle: self.triangle,
+            barycentrics: self.barycentrics,
+            mesh,
+            instance,
+            transform,
+        }
+    }
+}
+
+/// Closest intersection with the scene, matching `TraceResult` in `utils/bvh.wgsl`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Hit {
+    pub t: f32,
+    pub triangle: u32,
+    pub barycentrics: Vec2,
+    pub mesh: MeshId,
+    pub instance: InstanceId,
+    /// Object to world transform of the instance.
+    pub transform: Mat4,
+}
+
 pub fn intersect_aabb(ray: Ray, bmin: Vec3, bmax: Vec3, t: f32) -> Dist {
     let tx1 = (bmin - ray.orig) / ray.dir;
     let tx2 = (bmax - ray.orig) / ray.dir;
@@ -65,29 +127,31 @@ impl Ray {
         Self { orig, dir }
     }
 
-    pub fn intersect(&self, [v0, v1, v2]: [Vec3; 3]) -> Dist {
+    pub fn intersect(&self, trig: [Vec3; 3]) -> Dist {
+        self.intersect_barycentric(trig).map(|(t, _)| t).into()
+    }
+
+    /// Returns distance to the triangle along with barycentrics of the hit.
+    pub fn intersect_barycentric(&self, [v0, v1, v2]: [Vec3; 3]) -> Option<(f32, Vec2)> {
         const EPS: f32 = 0.0001;
         let (edge1, edge2) = (v1 - v0, v2 - v0);
         let h = self.dir.cross(edge2);
         let a = edge1.dot(h);
         if -EPS < a && a < EPS {
-            return Miss;
+            return None;
         }
         let f = 1. / a;
         let s = self.orig - v0;
         let u = f * s.dot(h);
         if !(0. ..=1.).contains(&u) {
-            return Miss;
+            return None;
         }
         let q = s.cross(edge1);
         let v = f * self.dir.dot(q);
         if v < 0. || u + v > 1. {
-            return Miss;
+            return None;
         }
         let t = f * edge2.dot(q);
-        match t > EPS {
-            true => Hit(t),
-            false => Miss,
-        }
+        (t > EPS).then_some((t, Vec2::new(u, v)))
     }
 }
@@ -1,7 +1,21 @@
 mod blas;
+mod compressed;
 mod intersection;
+mod packet;
+mod procedural;
+mod query;
+mod stats;
 mod tlas;
+mod wide;
 
-pub use blas::{Bvh, BvhBuilder, BvhNode};
-pub use intersection::{Dist, Ray};
+pub use blas::{Bvh, BvhBuilder, BvhNode, MAX_DEPTH, STACK_LEN, WGSL_STACK_LEN};
+pub use compressed::{CompressedBvh, CompressedNode, CompressedNode16, CompressedNode8};
+pub use intersection::{Aabb, Dist, Hit, Ray, TriangleHit};
+pub use packet::{RayPacket, PACKET_SIZE};
+pub use procedural::{ProceduralPrimitive, PRIMITIVE_AABB, PRIMITIVE_SPHERE};
+pub use query::{closest_point_on_triangle, ClosestPoint, Frustum, InstanceClosestPoint, Sphere};
+pub use stats::BvhStats;
 pub use tlas::{Tlas, TlasNode};
+pub use wide::{
+    Bvh4Node, Bvh8Node, WideBvh, WideNode, WIDE_EMPTY_CHILD, WIDE_LEAF_FLAG, WIDE_STACK_LEN,
+};
@@ -0,0 +1,382 @@
+use std::ops::{Mul, Sub};
+
+use glam::{BVec4A, UVec3, Vec2, Vec3, Vec4};
+
+use crate::{
+    blas::{Bvh, Stack},
+    intersection::{TriangleHit, MAX_DIST},
+    Ray,
+};
+
+/// Rays traced together by [`Bvh::intersect_packet`], one per lane of a
+/// SIMD backed [`Vec4`].
+pub const PACKET_SIZE: usize = 4;
+
+/// Three component vectors of all packet lanes, in structure of arrays layout.
+#[derive(Copy, Clone, Debug)]
+struct Vec3x4 {
+    x: Vec4,
+    y: Vec4,
+    z: Vec4,
+}
+
+impl Vec3x4 {
+    fn splat(v: Vec3) -> Self {
+        Self {
+            x: Vec4::splat(v.x),
+            y: Vec4::splat(v.y),
+            z: Vec4::splat(v.z),
+        }
+    }
+
+    fn from_lanes(lanes: [Vec3; PACKET_SIZE]) -> Self {
+        Self {
+            x: Vec4::from_array(lanes.map(|v| v.x)),
+            y: Vec4::from_array(lanes.map(|v| v.y)),
+            z: Vec4::from_array(lanes.map(|v| v.z)),
+        }
+    }
+
+    fn dot(self, rhs: Self) -> Vec4 {
+        self.x * rhs.x + self.y * rhs.y + self.z * rhs.z
+    }
+
+    fn cross(self, rhs: Self) -> Self {
+        Self {
+            x: self.y * rhs.z - self.z * rhs.y,
+            y: self.z * rhs.x - self.x * rhs.z,
+            z: self.x * rhs.y - self.y * rhs.x,
+        }
+    }
+}
+
+impl Sub for Vec3x4 {
+    type Output = Self;
+
+    fn sub(self, rhs: Self) -> Self {
+        Self {
+            x: self.x - rhs.x,
+            y: self.y - rhs.y,
+            z: self.z - rhs.z,
+        }
+    }
+}
+
+impl Mul for Vec3x4 {
+    type Output = Self;
+
+    fn mul(self, rhs: Self) -> Self {
+        Self {
+            x: self.x * rhs.x,
+            y: self.y * rhs.y,
+            z: self.z * rhs.z,
+        }
+    }
+}
+
+/// [`PACKET_SIZE`] rays traversed together. Coherent rays, like the ones of
+/// neighbouring pixels, visit mostly the same nodes, so every box and
+/// triangle test is done for all of them at once.
+#[derive(Copy, Clone, Debug)]
+pub struct RayPacket {
+    orig: Vec3x4,
+    dir: Vec3x4,
+    inv_dir: Vec3x4,
+}
+
+impl RayPacket {
+    pub fn new(rays: [Ray; PACKET_SIZE]) -> Self {
+        let dir = Vec3x4::from_lanes(rays.map(|ray| ray.dir));
+        Self {
+            orig: Vec3x4::from_lanes(rays.map(|ray| ray.orig)),
+            dir,
+            inv_dir: Vec3x4 {
+                x: dir.x.recip(),
+                y: dir.y.recip(),
+                z: dir.z.recip(),
+            },
+        }
+    }
+
+    /// Entry distance of every lane into the box, lanes missing it or
+    /// entering past `t` are cleared in the mask.
+    fn intersect_aabb(&self, bmin: Vec3, bmax: Vec3, t: Vec4) -> (Vec4, BVec4A) {
+        let t1 = (Vec3x4::splat(bmin) - self.orig) * self.inv_dir;
+        let t2 = (Vec3x4::splat(bmax) - self.orig) * self.inv_dir;
+        let tmin = t1.x.min(t2.x).max(t1.y.min(t2.y)).max(t1.z.min(t2.z));
+        let tmax = t1.x.max(t2.x).min(t1.y.max(t2.y)).min(t1.z.max(t2.z));
+        let mask = tmax.cmpge(tmin) & tmin.cmplt(t) & tmax.cmpgt(Vec4::ZERO);
+        (Vec4::select(mask, tmin, Vec4::splat(MAX_DIST)), mask)
+    }
+
+    /// Lane wise [`Ray::intersect_barycentric`] against a single triangle.
+    /// Returns the mask of lanes hitting it closer than `t` along with
+    /// their distances and barycentrics.
+    fn intersect_triangle(&self, [v0, v1, v2]: [Vec3; 3], t: Vec4) -> (BVec4A, Vec4, Vec4, Vec4) {
+        const EPS: f32 = 0.0001;
+        let (edge1, edge2) = (Vec3x4::splat(v1 - v0), Vec3x4::splat(v2 - v0));
+        let h = self.dir.cross(edge2);
+        let a = edge1.dot(h);
+        let f = a.recip();
+        let s = self.orig - Vec3x4::splat(v0);
+        let u = f * s.dot(h);
+        let q = s.cross(edge1);
+        let v = f * self.dir.dot(q);
+        let dist = f * edge2.dot(q);
+
+        let mask = a.abs().cmpge(Vec4::splat(EPS))
+            & u.cmpge(Vec4::ZERO)
+            & u.cmple(Vec4::ONE)
+            & v.cmpge(Vec4::ZERO)
+            & (u + v).cmple(Vec4::ONE)
+            & dist.cmpgt(Vec4::splat(EPS))
+            & dist.cmplt(t);
+        (mask, dist, u, v)
+    }
+}
+
+impl Bvh {
+    /// Finds the closest triangle hit by every ray of the packet. Results
+    /// match [`Bvh::intersect`] called on each ray.
+    pub fn intersect_packet(
+        &self,
+        vertices: &[Vec3],
+        indices: &[UVec3],
+        packet: &RayPacket,
+    ) -> [Option<TriangleHit>; PACKET_SIZE] {
+        let mut t = Vec4::splat(MAX_DIST);
+        let mut u = Vec4::ZERO;
+        let mut v = Vec4::ZERO;
+        let mut triangles = [u32::MAX; PACKET_SIZE];
+        if !self.is_empty() {
+            let mut stack = Stack::new();
+            stack.push(0);
+
+            while !stack.is_empty() {
+                let node = self.nodes[stack.pop()];
+                if node.is_leaf() {
+                    let start = node.triangle_start();
+                    let leaf = &indices[start..start + node.triangle_count()];
+                    for (triangle, trig) in (start..).zip(leaf) {
+                        let trig = trig.to_array().map(|i| vertices[i as usize]);
+                        let (mask, dist, hit_u, hit_v) = packet.intersect_triangle(trig, t);
+                        if !mask.any() {
+                            continue;
+                        }
+                        t = Vec4::select(mask, dist, t);
+                        u = Vec4::select(mask, hit_u, u);
+                        v = Vec4::select(mask, hit_v, v);
+                        let bitmask = mask.bitmask();
+                        for (lane, hit_triangle) in triangles.iter_mut().enumerate() {
+                            if bitmask & (1 << lane) != 0 {
+                                *hit_triangle = triangle as u32;
+                            }
+                        }
+                    }
+                } else {
+                    let mut near = node.left_node_index();
+                    let mut far = node.right_node_index();
+                    let (near_dist, near_mask) =
+                        packet.intersect_aabb(self.nodes[near].min, self.nodes[near].max, t);
+                    let (far_dist, far_mask) =
+                        packet.intersect_aabb(self.nodes[far].min, self.nodes[far].max, t);
+                    let (mut near_hit, mut far_hit) = (near_mask.any(), far_mask.any());
+                    // The child closest to any lane is visited first
+                    if far_dist.min_element() < near_dist.min_element() {
+                        (near, far) = (far, near);
+                        (near_hit, far_hit) = (far_hit, near_hit);
+                    }
+                    if far_hit {
+                        stack.push(far);
+                    }
+                    if near_hit {
+                        stack.push(near);
+                    }
+                }
+            }
+        }
+
+        let (t, u, v) = (t.to_array(), u.to_array(), v.to_array());
+        std::array::from_fn(|lane| {
+            (triangles[lane] != u32::MAX).then(|| TriangleHit {
+                t: t[lane],
+                triangle: triangles[lane],
+                barycentrics: Vec2::new(u[lane], v[lane]),
+            })
+        })
+    }
+
+    /// Packet counterpart of [`Bvh::occluded`], lanes are set for rays hitting
+    /// any triangle closer than their `t_max`.
+    pub fn occluded_packet(
+        &self,
+        vertices: &[Vec3],
+        indices: &[UVec3],
+        packet: &RayPacket,
+        t_max: [f32; PACKET_SIZE],
+    ) -> [bool; PACKET_SIZE] {
+        let t_max = Vec4::from_array(t_max);
+        let mut occluded = BVec4A::default();
+        if !self.is_empty() {
+            let mut stack = Stack::new();
+            stack.push(0);
+
+            while !stack.is_empty() {
+                let node = self.nodes[stack.pop()];
+                if node.is_leaf() {
+                    let start = node.triangle_start();
+                    for trig in &indices[start..start + node.triangle_count()] {
+                        let trig = trig.to_array().map(|i| vertices[i as usize]);
+                        occluded |= packet.intersect_triangle(trig, t_max).0;
+                    }
+                    if occluded.all() {
+                        break;
+                    }
+                } else {
+                    // Occluded lanes are done, only the others keep children alive
+                    let active = !occluded.bitmask();
+                    for child in [node.left_node_index(), node.right_node_index()] {
+                        let child_node = self.nodes[child];
+                        let (_, mask) =
+                            packet.intersect_aabb(child_node.min, child_node.max, t_max);
+                        if mask.bitmask() & active != 0 {
+                            stack.push(child);
+                        }
+                    }
+                }
+            }
+        }
+        let bitmask = occluded.bitmask();
+        std::array::from_fn(|lane| bitmask & (1 << lane) != 0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use glam::vec3;
+
+    use super::*;
+    use crate::BvhBuilder;
+
+    /// Unit UV sphere with a smaller one inside its upper half, so rays can
+    /// hit either, both or none.
+    fn test_mesh() -> (Vec<Vec3>, Vec<UVec3>) {
+        const RINGS: u32 = 16;
+        const SEGMENTS: u32 = 24;
+        let mut vertices = vec![];
+        let mut indices = vec![];
+        for (center, radius) in [(Vec3::ZERO, 1.), (vec3(0.2, 0.3, 0.1), 0.4)] {
+            let first = vertices.len() as u32;
+            for ring in 0..=RINGS {
+                let theta = ring as f32 / RINGS as f32 * std::f32::consts::PI;
+                for segment in 0..=SEGMENTS {
+                    let phi = segment as f32 / SEGMENTS as f32 * std::f32::consts::TAU;
+                    let dir = vec3(
+                        theta.sin() * phi.cos(),
+                        theta.cos(),
+                        theta.sin() * phi.sin(),
+                    );
+                    vertices.push(center + dir * radius);
+                }
+            }
+            for ring in 0..RINGS {
+                for segment in 0..SEGMENTS {
+                    let i = first + ring * (SEGMENTS + 1) + segment;
+                    indices.push(UVec3::new(i, i + SEGMENTS + 1, i + 1));
+                    indices.push(UVec3::new(i + 1, i + SEGMENTS + 1, i + SEGMENTS + 2));
+                }
+            }
+        }
+        (vertices, indices)
+    }
+
+    fn assert_packet_matches(bvh: &Bvh, vertices: &[Vec3], indices: &[UVec3], rays: [Ray; 4]) {
+        let packet = RayPacket::new(rays);
+        let hits = bvh.intersect_packet(vertices, indices, &packet);
+        let t_max = [0.5, 1.5, 2.5, MAX_DIST];
+        let occluded = bvh.occluded_packet(vertices, indices, &packet, t_max);
+        for lane in 0..PACKET_SIZE {
+            let expected = bvh.intersect(vertices, indices, rays[lane]);
+            assert_eq!(
+                expected.map(|hit| (hit.triangle, hit.t)),
+                hits[lane].map(|hit| (hit.triangle, hit.t)),
+                "{:?}",
+                rays[lane]
+            );
+            let expected = bvh.occluded(vertices, indices, rays[lane], t_max[lane]);
+            assert_eq!(expected, occluded[lane], "{:?}", rays[lane]);
+        }
+    }
+
+    #[test]
+    fn coherent_packets_match_scalar() {
+        let (vertices, mut indices) = test_mesh();
+        let bvh = BvhBuilder::new(&vertices, &mut indices).build();
+
+        // 2x2 pixel blocks of a camera whose view overhangs the sphere, so
+        // blocks on its silhouette mix hits and misses
+        const SIDE: usize = 32;
+        let orig = vec3(0.1, 0.2, -3.);
+        let pixel = |x: usize, y: usize| {
+            let uv = vec3(x as f32 + 0.5, y as f32 + 0.5, 0.) / SIDE as f32 * 2. - 1.;
+            Ray::new(orig, vec3(uv.x * 0.6, uv.y * 0.6, 1.).normalize())
+        };
+        for y in (0..SIDE).step_by(2) {
+            for x in (0..SIDE).step_by(2) {
+                let rays = [
+                    pixel(x, y),
+                    pixel(x + 1, y),
+                    pixel(x, y + 1),
+                    pixel(x + 1, y + 1),
+                ];
+                assert_packet_matches(&bvh, &vertices, &indices, rays);
+            }
+        }
+    }
+
+    #[test]
+    fn incoherent_packets_match_scalar() {
+        let (vertices, mut indices) = test_mesh();
+        let bvh = BvhBuilder::new(&vertices, &mut indices).build();
+
+        let mut seed = 0x9e3779b9u32;
+        let mut random = || {
+            seed ^= seed << 13;
+            seed ^= seed >> 17;
+            seed ^= seed << 5;
+            seed as f32 / u32::MAX as f32 * 2. - 1.
+        };
+        let mut random_vec = || vec3(random(), random(), random());
+        for _ in 0..256 {
+            // Rays from around the sphere towards random points in a box
+            // overhanging it, so some of them miss
+            let rays = [(); PACKET_SIZE].map(|_| {
+                let orig = random_vec().normalize() * 3.;
+                let target = random_vec() * 1.6;
+                Ray::new(orig, (target - orig).normalize())
+            });
+            assert_packet_matches(&bvh, &vertices, &indices, rays);
+        }
+    }
+
+    #[test]
+    fn missing_lane_stays_empty() {
+        let (vertices, mut indices) = test_mesh();
+        let bvh = BvhBuilder::new(&vertices, &mut indices).build();
+
+        let orig = vec3(0., 0., -3.);
+        let rays = [
+            Ray::new(orig, Vec3::Z),
+            Ray::new(orig, vec3(0.05, 0., 1.).normalize()),
+            Ray::new(orig, -Vec3::Z),
+            Ray::new(orig, vec3(0., 0.05, 1.).normalize()),
+        ];
+        let hits = bvh.intersect_packet(&vertices, &indices, &RayPacket::new(rays));
+        assert!(hits[2].is_none());
+        assert!(hits
+            .iter()
+            .enumerate()
+            .all(|(lane, hit)| lane == 2 || hit.is_some()));
+        assert_packet_matches(&bvh, &vertices, &indices, rays);
+    }
+}
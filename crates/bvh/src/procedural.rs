@@ -0,0 +1,302 @@
+use bytemuck::{Pod, Zeroable};
+use glam::Vec3;
+
+use crate::{intersection::Aabb, Ray};
+
+pub const PRIMITIVE_SPHERE: u32 = 0;
+pub const PRIMITIVE_AABB: u32 = 1;
+
+/// Analytic shape stored in BVH leaves in place of triangles, matching
+/// `ProceduralPrimitive` in `utils/bvh_nodes.wgsl`.
+///
+/// Every kind is described by its bounds, spheres touch all six faces.
+#[repr(C)]
+#[derive(Copy, Clone, Default, Debug, Pod, Zeroable)]
+pub struct ProceduralPrimitive {
+    pub min: Vec3,
+    /// [`PRIMITIVE_SPHERE`] or [`PRIMITIVE_AABB`].
+    pub kind: u32,
+    pub max: Vec3,
+    pub padding: u32,
+}
+
+impl ProceduralPrimitive {
+    pub fn sphere(center: Vec3, radius: f32) -> Self {
+        Self {
+            min: center - radius,
+            kind: PRIMITIVE_SPHERE,
+            max: center + radius,
+            padding: 0,
+        }
+    }
+
+    pub fn aabb(min: Vec3, max: Vec3) -> Self {
+        Self {
+            min,
+            kind: PRIMITIVE_AABB,
+            max,
+            padding: 0,
+        }
+    }
+
+    pub fn bounds(&self) -> Aabb {
+        Aabb::new(self.min, self.max)
+    }
+
+    pub fn center(&self) -> Vec3 {
+        (self.min + self.max) * 0.5
+    }
+
+    pub fn radius(&self) -> f32 {
+        (self.max.x - self.min.x) * 0.5
+    }
+
+    /// Distance to the first surface crossing in front of the ray origin,
+    /// the exit point when it starts inside.
+    pub fn intersect(&self, ray: Ray) -> Option<f32> {
+        const EPS: f32 = 0.0001;
+        let (near, far) = match self.kind {
+            PRIMITIVE_SPHERE => {
+                // Directions of object space rays are not normalized
+                let oc = ray.orig - self.center();
+                let a = ray.dir.length_squared();
+                let b = oc.dot(ray.dir);
+                let c = oc.length_squared() - self.radius() * self.radius();
+                let discriminant = b * b - a * c;
+                if discriminant < 0. {
+                    return None;
+                }
+                let root = discriminant.sqrt();
+                ((-b - root) / a, (-b + root) / a)
+            }
+            _ => {
+                let t1 = (self.min - ray.orig) / ray.dir;
+                let t2 = (self.max - ray.orig) / ray.dir;
+                let (near, far) = (t1.min(t2).max_element(), t1.max(t2).min_element());
+                if near > far {
+                    return None;
+                }
+                (near, far)
+            }
+        };
+        [near, far].into_iter().find(|&t| t > EPS)
+    }
+
+    /// Outward facing normal at `point` on the surface.
+    pub fn normal(&self, point: Vec3) -> Vec3 {
+        match self.kind {
+            PRIMITIVE_SPHERE => (point - self.center()).normalize(),
+            _ => {
+                // Axis along which the point is closest to a face
+                let half = (self.max - self.min) * 0.5;
+                let local = (point - self.center()) / half;
+                let dist = local.abs();
+                let axis = match dist.max_element() {
+                    max if max == dist.x => Vec3::X,
+                    max if max == dist.y => Vec3::Y,
+                    _ => Vec3::Z,
+                };
+                axis * local.signum()
+            }
+        }
+    }
+
+    /// Point of the surface closest to `point`, or `point` itself when it
+    /// is inside.
+    pub fn closest_point(&self, point: Vec3) -> Vec3 {
+        match self.kind {
+            PRIMITIVE_SPHERE => {
+                let offset = point - self.center();
+                if offset.length() <= self.radius() {
+                    return point;
+                }
+                self.center() + offset.normalize() * self.radius()
+            }
+            _ => point.clamp(self.min, self.max),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use glam::vec3;
+
+    use super::*;
+    use crate::{intersection::MAX_DIST, Bvh};
+
+    fn assert_close(actual: Option<f32>, expected: Option<f32>) {
+        match (actual, expected) {
+            (Some(actual), Some(expected)) => {
+                assert!((actual - expected).abs() < 1e-5, "{actual} != {expected}")
+            }
+            _ => assert_eq!(actual, expected),
+        }
+    }
+
+    #[test]
+    fn sphere_hits_entry_then_exit() {
+        let sphere = ProceduralPrimitive::sphere(vec3(0., 0., 5.), 1.);
+        assert_close(sphere.intersect(Ray::new(Vec3::ZERO, Vec3::Z)), Some(4.));
+        // From inside the exit is the first crossing
+        assert_close(
+            sphere.intersect(Ray::new(vec3(0., 0., 5.5), Vec3::Z)),
+            Some(0.5),
+        );
+        assert_close(
+            sphere.intersect(Ray::new(vec3(0., 0., 5.5), -Vec3::Z)),
+            Some(1.5),
+        );
+        assert_close(sphere.intersect(Ray::new(Vec3::ZERO, -Vec3::Z)), None);
+        assert_close(sphere.intersect(Ray::new(vec3(1.5, 0., 0.), Vec3::Z)), None);
+        // Grazing and slanted rays
+        assert_close(
+            sphere.intersect(Ray::new(vec3(1., 0., 0.), Vec3::Z)),
+            Some(5.),
+        );
+        let dir = vec3(0., 0.1, 1.).normalize();
+        let t = sphere.intersect(Ray::new(Vec3::ZERO, dir)).unwrap();
+        assert!(((dir * t).distance(sphere.center()) - 1.).abs() < 1e-5);
+    }
+
+    #[test]
+    fn aabb_hits_entry_then_exit() {
+        let aabb = ProceduralPrimitive::aabb(vec3(-1., -2., 3.), vec3(1., 2., 4.));
+        assert_close(aabb.intersect(Ray::new(Vec3::ZERO, Vec3::Z)), Some(3.));
+        assert_close(
+            aabb.intersect(Ray::new(vec3(0., 0., 3.25), Vec3::Z)),
+            Some(0.75),
+        );
+        assert_close(
+            aabb.intersect(Ray::new(vec3(0., 0., 3.25), Vec3::Y)),
+            Some(2.),
+        );
+        assert_close(aabb.intersect(Ray::new(Vec3::ZERO, -Vec3::Z)), None);
+        assert_close(aabb.intersect(Ray::new(vec3(0., 2.5, 0.), Vec3::Z)), None);
+        let dir = vec3(0.2, 0., 1.).normalize();
+        assert_close(aabb.intersect(Ray::new(Vec3::ZERO, dir)), Some(3. / dir.z));
+    }
+
+    #[test]
+    fn unnormalized_directions_scale_the_distance() {
+        // Object space rays of scaled instances keep the world space `t`
+        let primitives = [
+            ProceduralPrimitive::sphere(vec3(0.3, -0.2, 5.), 1.),
+            ProceduralPrimitive::aabb(vec3(-1., -1., 3.), vec3(1., 1., 4.)),
+        ];
+        for primitive in primitives {
+            for orig in [vec3(0.1, 0.2, 0.), primitive.center()] {
+                let dir = vec3(0.05, -0.1, 1.).normalize();
+                let unit = primitive.intersect(Ray::new(orig, dir)).unwrap();
+                for scale in [0.25, 3.] {
+                    let scaled = primitive.intersect(Ray::new(orig, dir * scale));
+                    assert_close(scaled.map(|t| t * scale), Some(unit));
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn normals_point_outwards() {
+        let sphere = ProceduralPrimitive::sphere(vec3(1., 2., 3.), 2.);
+        let normal = sphere.normal(vec3(1., 2., 3.) + vec3(1., -1., 1.).normalize() * 2.);
+        assert!(normal.abs_diff_eq(vec3(1., -1., 1.).normalize(), 1e-6));
+
+        // Faces of a box that is far from a cube
+        let aabb = ProceduralPrimitive::aabb(vec3(-4., -1., 0.), vec3(4., 1., 0.5));
+        let cases = [
+            (vec3(4., 0.9, 0.4), Vec3::X),
+            (vec3(-4., -0.9, 0.1), -Vec3::X),
+            (vec3(3.9, 1., 0.45), Vec3::Y),
+            (vec3(-3.9, -1., 0.05), -Vec3::Y),
+            (vec3(3.9, 0.9, 0.5), Vec3::Z),
+            (vec3(-3.9, -0.9, 0.), -Vec3::Z),
+        ];
+        for (point, expected) in cases {
+            assert_eq!(aabb.normal(point), expected, "{point}");
+        }
+    }
+
+    #[test]
+    fn closest_points_stay_inside_or_on_the_surface() {
+        let sphere = ProceduralPrimitive::sphere(vec3(0., 0., 2.), 1.);
+        assert!(sphere
+            .closest_point(vec3(0., 3., 2.))
+            .abs_diff_eq(vec3(0., 1., 2.), 1e-6));
+        assert_eq!(
+            sphere.closest_point(vec3(0.2, 0.3, 2.1)),
+            vec3(0.2, 0.3, 2.1)
+        );
+
+        let aabb = ProceduralPrimitive::aabb(Vec3::ZERO, vec3(1., 2., 3.));
+        assert_eq!(aabb.closest_point(vec3(-1., 5., 1.5)), vec3(0., 2., 1.5));
+        assert_eq!(aabb.closest_point(vec3(0.5, 0.5, 0.5)), vec3(0.5, 0.5, 0.5));
+    }
+
+    #[test]
+    fn procedural_bvh_matches_brute_force() {
+        let mut seed = 0x2545f491u32;
+        let mut random = || {
+            seed ^= seed << 13;
+            seed ^= seed >> 17;
+            seed ^= seed << 5;
+            seed as f32 / u32::MAX as f32
+        };
+        let mut primitives: Vec<_> = (0..200)
+            .map(|i| {
+                let center = vec3(random(), random(), random()) * 8. - 4.;
+                let size = 0.1 + random() * 0.4;
+                match i % 2 {
+                    0 => ProceduralPrimitive::sphere(center, size),
+                    _ => ProceduralPrimitive::aabb(
+                        center - size,
+                        center + size * vec3(random(), random(), random()),
+                    ),
+                }
+            })
+            .collect();
+        let bvh = Bvh::build_procedural(&mut primitives);
+
+        let brute_force = |ray: Ray| {
+            primitives
+                .iter()
+                .enumerate()
+                .filter_map(|(i, primitive)| Some((i as u32, primitive.intersect(ray)?)))
+                .min_by(|a, b| a.1.total_cmp(&b.1))
+        };
+
+        let mut hits = 0;
+        let mut rays = 0;
+        for i in 0..2000 {
+            // Every tenth ray starts inside a primitive
+            let orig = match i % 10 {
+                0 => primitives[i % primitives.len()].center(),
+                _ => vec3(random(), random(), random()) * 12. - 6.,
+            };
+            let dir = (vec3(random(), random(), random()) * 2. - 1.) * (0.5 + random());
+            let ray = Ray::new(orig, dir);
+            let expected = brute_force(ray);
+            let actual = bvh.intersect_procedural(&primitives, ray);
+            assert_eq!(actual.map(|hit| (hit.triangle, hit.t)), expected, "{ray:?}");
+
+            let t_max = expected.map_or(MAX_DIST, |(_, t)| t);
+            assert!(!bvh.occluded_procedural(&primitives, ray, t_max * 0.99));
+            assert_eq!(
+                bvh.occluded_procedural(&primitives, ray, MAX_DIST),
+                expected.is_some()
+            );
+
+            let closest = bvh
+                .closest_point_procedural(&primitives, orig, MAX_DIST)
+                .unwrap();
+            let expected_dist = primitives
+                .iter()
+                .map(|primitive| primitive.closest_point(orig).distance(orig))
+                .fold(f32::INFINITY, f32::min);
+            assert!((closest.distance - expected_dist).abs() < 1e-5);
+
+            hits += expected.is_some() as usize;
+            rays += 1;
+        }
+        assert!(hits > 0 && hits < rays);
+    }
+}
@@ -0,0 +1,761 @@
+use components::{Instance, InstanceId, MeshId, MeshInfo};
+use glam::{Mat4, UVec3, Vec3, Vec4, Vec4Swizzles};
+
+use crate::{
+    blas::Stack,
+    intersection::{Aabb, MAX_DIST},
+    tlas::{mesh_geometry, mesh_primitives},
+    Bvh, ProceduralPrimitive, Tlas,
+};
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Sphere {
+    pub center: Vec3,
+    pub radius: f32,
+}
+
+impl Sphere {
+    pub fn new(center: Vec3, radius: f32) -> Self {
+        Self { center, radius }
+    }
+}
+
+/// Six planes bounding the volume seen by a camera. Points in front of all
+/// planes, where `plane.xyz · p + plane.w >= 0`, are inside.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Frustum {
+    pub planes: [Vec4; 6],
+}
+
+impl Frustum {
+    /// Extracts the planes of a `clip_from_world` matrix with depth in
+    /// `0..1`, e.g. `projection * view` of `CameraUniform`.
+    ///
+    /// Infinite projections yield a far plane that accepts every point,
+    /// the same way `emit_draws.wgsl` skips the far test for `zfar = inf`.
+    pub fn from_matrix(clip_from_world: Mat4) -> Self {
+        let rows = clip_from_world.transpose();
+        let [x, y, z, w] = [rows.x_axis, rows.y_axis, rows.z_axis, rows.w_axis];
+        let planes = [w + x, w - x, w + y, w - y, z, w - z].map(|plane| {
+            let len = plane.xyz().length();
+            match len > 0. {
+                true => plane / len,
+                false => plane,
+            }
+        });
+        Self { planes }
+    }
+
+    pub fn contains_point(&self, point: Vec3) -> bool {
+        self.planes
+            .iter()
+            .all(|plane| plane.xyz().dot(point) + plane.w >= 0.)
+    }
+
+    /// Same test as the culling in `emit_draws.wgsl`, done in world space.
+    pub fn overlaps_sphere(&self, sphere: &Sphere) -> bool {
+        self.planes
+            .iter()
+            .all(|plane| plane.xyz().dot(sphere.center) + plane.w >= -sphere.radius)
+    }
+
+    /// Conservative test, boxes near the frustum corners may be accepted.
+    pub fn overlaps_aabb(&self, aabb: &Aabb) -> bool {
+        self.planes.iter().all(|plane| {
+            let normal = plane.xyz();
+            let corner = Vec3::select(normal.cmpge(Vec3::ZERO), aabb.max, aabb.min);
+            normal.dot(corner) + plane.w >= 0.
+        })
+    }
+
+    /// Conservative test, rejects triangles fully behind a single plane.
+    fn overlaps_triangle(&self, trig: [Vec3; 3]) -> bool {
+        self.planes
+            .iter()
+            .all(|plane| trig.iter().any(|&v| plane.xyz().dot(v) + plane.w >= 0.))
+    }
+}
+
+/// Point of a mesh closest to a query point.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ClosestPoint {
+    pub point: Vec3,
+    pub distance: f32,
+    /// Index of the triangle in the index buffer reordered by the builder.
+    pub triangle: u32,
+}
+
+impl ClosestPoint {
+    pub fn with_instance(
+        self,
+        mesh: MeshId,
+        instance: InstanceId,
+        transform: Mat4,
+    ) -> InstanceClosestPoint {
+        InstanceClosestPoint {
+            point: transform.transform_point3(self.point),
+            distance: self.distance,
+            triangle: self.triangle,
+            mesh,
+            instance,
+        }
+    }
+}
+
+/// Point of the scene closest to a query point, in world space.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct InstanceClosestPoint {
+    pub point: Vec3,
+    pub distance: f32,
+    pub triangle: u32,
+    pub mesh: MeshId,
+    pub instance: InstanceId,
+}
+
+impl Aabb {
+    pub fn overlaps(&self, other: &Aabb) -> bool {
+        self.intersection(other).is_valid()
+    }
+
+    pub fn distance_squared(&self, point: Vec3) -> f32 {
+        point.distance_squared(point.clamp(self.min, self.max))
+    }
+
+    pub fn overlaps_sphere(&self, sphere: &Sphere) -> bool {
+        self.distance_squared(sphere.center) <= sphere.radius * sphere.radius
+    }
+}
+
+/// Closest point of triangle `[a, b, c]` to `p`, see Ericson,
+/// "Real-Time Collision Detection", 5.1.5.
+pub fn closest_point_on_triangle(p: Vec3, [a, b, c]: [Vec3; 3]) -> Vec3 {
+    let (ab, ac, ap) = (b - a, c - a, p - a);
+    let (d1, d2) = (ab.dot(ap), ac.dot(ap));
+    if d1 <= 0. && d2 <= 0. {
+        return a;
+    }
+
+    let bp = p - b;
+    let (d3, d4) = (ab.dot(bp), ac.dot(bp));
+    if d3 >= 0. && d4 <= d3 {
+        return b;
+    }
+
+    let vc = d1 * d4 - d3 * d2;
+    if vc <= 0. && d1 >= 0. && d3 <= 0. {
+        return a + ab * (d1 / (d1 - d3));
+    }
+
+    let cp = p - c;
+    let (d5, d6) = (ab.dot(cp), ac.dot(cp));
+    if d6 >= 0. && d5 <= d6 {
+        return c;
+    }
+
+    let vb = d5 * d2 - d1 * d6;
+    if vb <= 0. && d2 >= 0. && d6 <= 0. {
+        return a + ac * (d2 / (d2 - d6));
+    }
+
+    let va = d3 * d6 - d5 * d4;
+    if va <= 0. && d4 - d3 >= 0. && d5 - d6 >= 0. {
+        return b + (c - b) * ((d4 - d3) / ((d4 - d3) + (d5 - d6)));
+    }
+
+    let denom = 1. / (va + vb + vc);
+    a + ab * (vb * denom) + ac * (vc * denom)
+}
+
+/// Separating axis test between a triangle and a box, see Akenine-Möller,
+/// "Fast 3D Triangle-Box Overlap Testing".
+fn triangle_overlaps_aabb(trig: [Vec3; 3], aabb: &Aabb) -> bool {
+    let center = (aabb.min + aabb.max) * 0.5;
+    let half = (aabb.max - aabb.min) * 0.5;
+    let [v0, v1, v2] = trig.map(|v| v - center);
+    let edges = [v1 - v0, v2 - v1, v0 - v2];
+
+    let separated = |axis: Vec3| {
+        let (p0, p1, p2) = (axis.dot(v0), axis.dot(v1), axis.dot(v2));
+        let r = half.dot(axis.abs());
+        p0.min(p1).min(p2) > r || p0.max(p1).max(p2) < -r
+    };
+
+    if [Vec3::X, Vec3::Y, Vec3::Z].into_iter().any(separated) {
+        return false;
+    }
+    if separated(edges[0].cross(edges[1])) {
+        return false;
+    }
+    !edges
+        .iter()
+        .flat_map(|edge| [Vec3::X, Vec3::Y, Vec3::Z].map(|axis| axis.cross(*edge)))
+        .any(separated)
+}
+
+impl Bvh {
+    /// Collects triangles of leaves whose bounds pass `node_test` and that
+    /// pass `triangle_test` themselves.
+    fn collect_triangles(
+        &self,
+        vertices: &[Vec3],
+        indices: &[UVec3],
+        node_test: impl Fn(&Aabb) -> bool,
+        triangle_test: impl Fn([Vec3; 3]) -> bool,
+    ) -> Vec<u32> {
+        let mut triangles = vec![];
+        if self.is_empty() {
+            return triangles;
+        }
+        let mut stack = Stack::new();
+        stack.push(0);
+
+        while !stack.is_empty() {
+            let node = self.nodes[stack.pop()];
+            if !node_test(&Aabb::new(node.min, node.max)) {
+                continue;
+            }
+            if node.is_leaf() {
+                for i in 0..node.triangle_count() {
+                    let triangle = node.triangle_start() + i;
+                    let trig = indices[triangle].to_array().map(|i| vertices[i as usize]);
+                    if triangle_test(trig) {
+                        triangles.push(triangle as u32);
+                    }
+                }
+            } else {
+                stack.push(node.left_node_index());
+                stack.push(node.right_node_index());
+            }
+        }
+        triangles
+    }
+
+    /// Triangles intersecting `aabb`, as indices into the reordered index buffer.
+    pub fn overlap_aabb(&self, vertices: &[Vec3], indices: &[UVec3], aabb: &Aabb) -> Vec<u32> {
+        self.collect_triangles(
+            vertices,
+            indices,
+            |bounds| bounds.overlaps(aabb),
+            |trig| triangle_overlaps_aabb(trig, aabb),
+        )
+    }
+
+    /// Triangles intersecting `sphere`, as indices into the reordered index buffer.
+    pub fn overlap_sphere(
+        &self,
+        vertices: &[Vec3],
+        indices: &[UVec3],
+        sphere: &Sphere,
+    ) -> Vec<u32> {
+        let radius_sq = sphere.radius * sphere.radius;
+        self.collect_triangles(
+            vertices,
+            indices,
+            |bounds| bounds.overlaps_sphere(sphere),
+            |trig| {
+                let closest = closest_point_on_triangle(sphere.center, trig);
+                closest.distance_squared(sphere.center) <= radius_sq
+            },
+        )
+    }
+
+    /// Triangles that may be visible in `frustum`. The test is conservative,
+    /// like the GPU culling.
+    pub fn overlap_frustum(
+        &self,
+        vertices: &[Vec3],
+        indices: &[UVec3],
+        frustum: &Frustum,
+    ) -> Vec<u32> {
+        self.collect_triangles(
+            vertices,
+            indices,
+            |bounds| frustum.overlaps_aabb(bounds),
+            |trig| frustum.overlaps_triangle(trig),
+        )
+    }
+
+    /// Finds the point of the mesh closest to `point` within `max_dist`.
+    pub fn closest_point(
+        &self,
+        vertices: &[Vec3],
+        indices: &[UVec3],
+        point: Vec3,
+        max_dist: f32,
+    ) -> Option<ClosestPoint> {
+        self.closest_by(point, max_dist, |triangle| {
+            let trig = indices[triangle].to_array().map(|i| vertices[i as usize]);
+            closest_point_on_triangle(point, trig)
+        })
+    }
+
+    /// Procedural counterpart of [`Bvh::closest_point`], the result
+    /// `triangle` is the primitive index. Points inside a primitive are at
+    /// distance zero.
+    pub fn closest_point_procedural(
+        &self,
+        primitives: &[ProceduralPrimitive],
+        point: Vec3,
+        max_dist: f32,
+    ) -> Option<ClosestPoint> {
+        self.closest_by(point, max_dist, |primitive| {
+            primitives[primitive].closest_point(point)
+        })
+    }
+
+    /// Branch and bound search, `leaf_point` returns the point of the
+    /// primitive at an index referenced by a leaf closest to `point`.
+    fn closest_by(
+        &self,
+        point: Vec3,
+        max_dist: f32,
+        mut leaf_point: impl FnMut(usize) -> Vec3,
+    ) -> Option<ClosestPoint> {
+        if self.is_empty() {
+            return None;
+        }
+        let mut stack = Stack::new();
+        stack.push(0);
+
+        let mut best_sq = max_dist * max_dist;
+        let mut closest: Option<ClosestPoint> = None;
+        while !stack.is_empty() {
+            let node = self.nodes[stack.pop()];
+            if Aabb::new(node.min, node.max).distance_squared(point) > best_sq {
+                continue;
+            }
+            if node.is_leaf() {
+                for i in 0..node.triangle_count() {
+                    let triangle = node.triangle_start() + i;
+                    let candidate = leaf_point(triangle);
+                    let dist_sq = candidate.distance_squared(point);
+                    if dist_sq <= best_sq {
+                        best_sq = dist_sq;
+                        closest = Some(ClosestPoint {
+                            point: candidate,
+                            distance: dist_sq.sqrt(),
+                            triangle: triangle as u32,
+                        });
+                    }
+                }
+            } else {
+                let mut near = node.left_node_index();
+                let mut far = node.right_node_index();
+                let near_dist = self.node_bounds(near).distance_squared(point);
+                let far_dist = self.node_bounds(far).distance_squared(point);
+                if near_dist > far_dist {
+                    (near, far) = (far, near);
+                }
+                // Visit the nearer child first so it tightens the bound
+                stack.push(far);
+                stack.push(near);
+            }
+        }
+        closest
+    }
+
+    fn node_bounds(&self, node: usize) -> Aabb {
+        Aabb::new(self.nodes[node].min, self.nodes[node].max)
+    }
+}
+
+impl Tlas {
+    /// Collects instances whose world space bounds pass `test`.
+    fn collect_instances(
+        &self,
+        instances: &[Instance],
+        test: impl Fn(&Aabb) -> bool,
+    ) -> Vec<InstanceId> {
+        let mut found = vec![];
+        if self.nodes.is_empty() {
+            return found;
+        }
+        let mut stack = Stack::new();
+        stack.push(0);
+
+        while !stack.is_empty() {
+            let node = self.nodes[stack.pop()];
+            if !test(&Aabb::new(node.min, node.max)) {
+                continue;
+            }
+            if node.is_leaf() {
+                found.push(instances[node.instance_idx as usize].id(node.instance_idx));
+            } else {
+                stack.push(node.left_node_index());
+                stack.push(node.right_node_index());
+            }
+        }
+        found
+    }
+
+    /// Instances whose world space bounds intersect `aabb`.
+    pub fn overlap_aabb(&self, aabb: &Aabb, instances: &[Instance]) -> Vec<InstanceId> {
+        self.collect_instances(instances, |bounds| bounds.overlaps(aabb))
+    }
+
+    /// Instances whose world space bounds intersect `sphere`.
+    pub fn overlap_sphere(&self, sphere: &Sphere, instances: &[Instance]) -> Vec<InstanceId> {
+        self.collect_instances(instances, |bounds| bounds.overlaps_sphere(sphere))
+    }
+
+    /// Instances whose world space bounds may be visible in `frustum`.
+    ///
+    /// Bounds are tested as boxes rather than the spheres `emit_draws.wgsl`
+    /// culls with, so this accepts a subset of the instances drawn there.
+    pub fn overlap_frustum(&self, frustum: &Frustum, instances: &[Instance]) -> Vec<InstanceId> {
+        self.collect_instances(instances, |bounds| frustum.overlaps_aabb(bounds))
+    }
+
+    /// Finds the point of any instance closest to the world space `point`
+    /// within `max_dist`.
+    ///
+    /// Meshes are searched in object space, so the result is exact for
+    /// transforms with uniform scale and approximate otherwise.
+    #[allow(clippy::too_many_arguments)]
+    pub fn closest_point(
+        &self,
+        point: Vec3,
+        max_dist: f32,
+        instances: &[Instance],
+        meshes: &[MeshInfo],
+        blases: &[Bvh],
+        vertices: &[Vec3],
+        indices: &[u32],
+        primitives: &[ProceduralPrimitive],
+    ) -> Option<InstanceClosestPoint> {
+        if self.nodes.is_empty() {
+            return None;
+        }
+        let mut stack = Stack::new();
+        stack.push(0);
+
+        let mut best = max_dist.min(MAX_DIST);
+        let mut closest: Option<InstanceClosestPoint> = None;
+        while !stack.is_empty() {
+            let node = self.nodes[stack.pop()];
+            if Aabb::new(node.min, node.max).distance_squared(point) > best * best {
+                continue;
+            }
+            if node.is_leaf() {
+                let instance = &instances[node.instance_idx as usize];
+                let instance_id = instance.id(node.instance_idx);
+                let local_point = instance.inv_transform().transform_point3(point);
+                let max_local = best / instance_min_scale(instance);
+                let blas = &blases[instance.mesh.index() as usize];
+                let local = match mesh_primitives(instance, meshes, primitives) {
+                    Some(primitives) => {
+                        blas.closest_point_procedural(primitives, local_point, max_local)
+                    }
+                    None => {
+                        let (vertices, indices) =
+                            mesh_geometry(instance, meshes, vertices, indices);
+                        blas.closest_point(vertices, indices, local_point, max_local)
+                    }
+                };
+                let Some(local) = local else {
+                    continue;
+                };
+                let mut candidate =
+                    local.with_instance(instance.mesh, instance_id, instance.transform);
+                candidate.distance = candidate.point.distance(point);
+                if candidate.distance <= best {
+                    best = candidate.distance;
+                    closest = Some(candidate);
+                }
+            } else {
+                let mut near = node.left_node_index();
+                let mut far = node.right_node_index();
+                let bounds = |i: usize| Aabb::new(self.nodes[i].min, self.nodes[i].max);
+                if bounds(near).distance_squared(point) > bounds(far).distance_squared(point) {
+                    (near, far) = (far, near);
+                }
+                stack.push(far);
+                stack.push(near);
+            }
+        }
+        closest
+    }
+}
+
+/// Smallest axis scale of the instance transform, so that object space
+/// distances are at most world space distances divided by it.
+fn instance_min_scale(instance: &Instance) -> f32 {
+    let transform = instance.transform;
+    transform
+        .x_axis
+        .xyz()
+        .length()
+        .min(transform.y_axis.xyz().length())
+        .min(transform.z_axis.xyz().length())
+}
+
+#[cfg(test)]
+mod tests {
+    use components::MaterialId;
+    use glam::{vec3, BVec3, Quat};
+
+    use super::*;
+    use crate::BvhBuilder;
+
+    fn random(seed: &mut u32) -> f32 {
+        *seed ^= *seed << 13;
+        *seed ^= *seed >> 17;
+        *seed ^= *seed << 5;
+        *seed as f32 / u32::MAX as f32
+    }
+
+    fn random_point(seed: &mut u32, extent: f32) -> Vec3 {
+        vec3(random(seed), random(seed), random(seed)) * 2. * extent - extent
+    }
+
+    /// Wavy height field with a few tilted triangles above it.
+    fn test_mesh() -> (Vec<Vec3>, Vec<UVec3>) {
+        const SIDE: u32 = 12;
+        let mut vertices = vec![];
+        let mut indices = vec![];
+        for y in 0..=SIDE {
+            for x in 0..=SIDE {
+                let (fx, fy) = (x as f32 / SIDE as f32, y as f32 / SIDE as f32);
+                let z = 0.1 * (fx * 9.).sin() * (fy * 7.).cos();
+                vertices.push(vec3(fx * 2. - 1., fy * 2. - 1., z));
+            }
+        }
+        for y in 0..SIDE {
+            for x in 0..SIDE {
+                let i = y * (SIDE + 1) + x;
+                indices.push(UVec3::new(i, i + 1, i + SIDE + 1));
+                indices.push(UVec3::new(i + 1, i + SIDE + 2, i + SIDE + 1));
+            }
+        }
+        let mut seed = 0x9e3779b9;
+        for _ in 0..40 {
+            let center = random_point(&mut seed, 1.) * vec3(1., 1., 0.3) + vec3(0., 0., 0.5);
+            let first = vertices.len() as u32;
+            for _ in 0..3 {
+                vertices.push(center + random_point(&mut seed, 0.1));
+            }
+            indices.push(UVec3::new(first, first + 1, first + 2));
+        }
+        (vertices, indices)
+    }
+
+    fn brute_force_closest(point: Vec3, trigs: impl Iterator<Item = [Vec3; 3]>) -> f32 {
+        trigs
+            .map(|trig| closest_point_on_triangle(point, trig).distance(point))
+            .fold(f32::INFINITY, f32::min)
+    }
+
+    #[test]
+    fn closest_point_covers_every_voronoi_region() {
+        let trig = [Vec3::ZERO, Vec3::X, Vec3::Y];
+        let cases = [
+            (vec3(-1., -1., 0.5), Vec3::ZERO),
+            (vec3(2., -0.5, -0.5), Vec3::X),
+            (vec3(-0.5, 2., 0.3), Vec3::Y),
+            (vec3(0.3, -1., 0.2), vec3(0.3, 0., 0.)),
+            (vec3(-1., 0.6, -0.2), vec3(0., 0.6, 0.)),
+            (vec3(1., 0.8, 0.4), vec3(0.6, 0.4, 0.)),
+            (vec3(0.2, 0.3, 0.7), vec3(0.2, 0.3, 0.)),
+        ];
+        for (point, expected) in cases {
+            let closest = closest_point_on_triangle(point, trig);
+            assert!(closest.abs_diff_eq(expected, 1e-6), "{point} {closest}");
+        }
+
+        // No point sampled on the triangle is any closer
+        let mut seed = 0x2545f491;
+        for _ in 0..200 {
+            let trig = [(); 3].map(|_| random_point(&mut seed, 1.));
+            let point = random_point(&mut seed, 2.);
+            let dist = closest_point_on_triangle(point, trig).distance(point);
+            for u in 0..=20 {
+                for v in 0..=20 - u {
+                    let (u, v) = (u as f32 / 20., v as f32 / 20.);
+                    let sample = trig[0] + (trig[1] - trig[0]) * u + (trig[2] - trig[0]) * v;
+                    assert!(dist <= sample.distance(point) + 1e-5);
+                }
+            }
+        }
+    }
+
+    /// Clips the triangle against every box plane, it overlaps the box if
+    /// anything is left.
+    fn clipped_triangle_overlaps(trig: [Vec3; 3], aabb: &Aabb) -> bool {
+        let mut polygon = trig.to_vec();
+        for axis in 0..3 {
+            for (bound, sign) in [(aabb.min[axis], 1.), (aabb.max[axis], -1.)] {
+                let inside = |v: Vec3| (v[axis] - bound) * sign >= 0.;
+                let mut clipped = vec![];
+                for (i, &v) in polygon.iter().enumerate() {
+                    let next = polygon[(i + 1) % polygon.len()];
+                    if inside(v) {
+                        clipped.push(v);
+                    }
+                    if inside(v) != inside(next) {
+                        let t = (bound - v[axis]) / (next[axis] - v[axis]);
+                        clipped.push(v + (next - v) * t);
+                    }
+                }
+                polygon = clipped;
+                if polygon.is_empty() {
+                    return false;
+                }
+            }
+        }
+        true
+    }
+
+    #[test]
+    fn triangle_aabb_overlap_matches_clipping() {
+        let aabb = Aabb::new(Vec3::ZERO, Vec3::ONE);
+        let mut seed = 0x1234567;
+        let mut edge_separated = 0;
+        for _ in 0..20000 {
+            let center = random_point(&mut seed, 1.) + 0.5;
+            let trig = [(); 3].map(|_| center + random_point(&mut seed, 0.8));
+            let expected = clipped_triangle_overlaps(trig, &aabb);
+            assert_eq!(triangle_overlaps_aabb(trig, &aabb), expected, "{trig:?}");
+
+            // Misses that neither the box faces nor the triangle plane find
+            let mut bounds = Aabb::empty();
+            trig.iter().for_each(|&v| bounds.grow(v));
+            let normal = (trig[1] - trig[0]).cross(trig[2] - trig[0]);
+            let sides: Vec<f32> = (0..8)
+                .map(|i| {
+                    let corner = BVec3::new(i & 1 != 0, i & 2 != 0, i & 4 != 0);
+                    let corner = Vec3::select(corner, aabb.max, aabb.min);
+                    normal.dot(corner - trig[0])
+                })
+                .collect();
+            let straddles =
+                sides.iter().any(|&side| side >= 0.) && sides.iter().any(|&side| side <= 0.);
+            if !expected && bounds.overlaps(&aabb) && straddles {
+                edge_separated += 1;
+            }
+        }
+        assert!(edge_separated > 0);
+    }
+
+    #[test]
+    fn frustum_planes_match_clip_space() {
+        let view = Mat4::look_at_rh(vec3(1., 2., 3.), vec3(0., 0.5, 0.), Vec3::Y);
+        let finite = Mat4::perspective_rh(1., 1.5, 0.5, 8.) * view;
+        let infinite = Mat4::perspective_infinite_reverse_rh(1., 1.5, 0.5) * view;
+
+        let mut seed = 0x7654321;
+        let mut inside = 0;
+        for _ in 0..5000 {
+            let point = random_point(&mut seed, 10.);
+            for (clip_from_world, far) in [(finite, true), (infinite, false)] {
+                let frustum = Frustum::from_matrix(clip_from_world);
+                for plane in &frustum.planes[..4] {
+                    assert!((plane.xyz().length() - 1.).abs() < 1e-5);
+                }
+                let clip = clip_from_world * point.extend(1.);
+                let (near, far) = match far {
+                    true => (clip.z >= 0., clip.z <= clip.w),
+                    // Reverse depth puts the near plane at `z = w`
+                    false => (clip.z <= clip.w, true),
+                };
+                let expected = clip.x.abs() <= clip.w && clip.y.abs() <= clip.w && near && far;
+                assert_eq!(frustum.contains_point(point), expected, "{point}");
+                inside += expected as usize;
+            }
+        }
+        assert!(inside > 0);
+    }
+
+    #[test]
+    fn bvh_closest_point_matches_brute_force() {
+        let (vertices, mut indices) = test_mesh();
+        let bvh = BvhBuilder::new(&vertices, &mut indices).build();
+        let trigs = || {
+            indices
+                .iter()
+                .map(|trig| trig.to_array().map(|i| vertices[i as usize]))
+        };
+
+        let mut seed = 0xdeadbeef;
+        for _ in 0..500 {
+            let point = random_point(&mut seed, 2.);
+            let expected = brute_force_closest(point, trigs());
+            let closest = bvh
+                .closest_point(&vertices, &indices, point, MAX_DIST)
+                .unwrap();
+            assert!((closest.distance - expected).abs() < 1e-5, "{point}");
+            let trig = trigs().nth(closest.triangle as usize).unwrap();
+            assert_eq!(closest_point_on_triangle(point, trig), closest.point);
+
+            let limited = bvh.closest_point(&vertices, &indices, point, expected * 0.99);
+            assert_eq!(limited, None);
+        }
+    }
+
+    #[test]
+    fn tlas_closest_point_matches_brute_force() {
+        let (vertices, mut indices) = test_mesh();
+        let bvh = BvhBuilder::new(&vertices, &mut indices).build();
+        let meshes = [MeshInfo {
+            min: bvh.nodes[0].min,
+            max: bvh.nodes[0].max,
+            index_count: indices.len() as u32 * 3,
+            ..Default::default()
+        }];
+        let blases = [bvh];
+
+        // Small scales make object space distances larger than world space
+        // ones, large scales smaller
+        let instances: Vec<_> = (0..8)
+            .map(|i| {
+                let transform = Mat4::from_scale_rotation_translation(
+                    Vec3::splat([0.1, 0.5, 1., 3.][i % 4]),
+                    Quat::from_rotation_z(i as f32) * Quat::from_rotation_x(0.4 * i as f32),
+                    vec3(
+                        (i % 3) as f32 * 2. - 2.,
+                        (i / 3) as f32 * 2. - 2.,
+                        i as f32 * 0.3,
+                    ),
+                );
+                Instance::new(transform, MeshId::default(), MaterialId::default())
+            })
+            .collect();
+        let mut tlas = Tlas::empty();
+        tlas.build(&instances, &meshes);
+        let flat_indices: &[u32] = bytemuck::cast_slice(&indices);
+
+        let mut seed = 0xcafef00d;
+        for _ in 0..300 {
+            let point = random_point(&mut seed, 5.);
+            let (expected, instance) = instances
+                .iter()
+                .enumerate()
+                .map(|(i, instance)| {
+                    let trigs = indices.iter().map(|trig| {
+                        trig.to_array()
+                            .map(|i| instance.transform.transform_point3(vertices[i as usize]))
+                    });
+                    (brute_force_closest(point, trigs), i)
+                })
+                .min_by(|a, b| a.0.total_cmp(&b.0))
+                .unwrap();
+
+            let query = |max_dist| {
+                tlas.closest_point(
+                    point,
+                    max_dist,
+                    &instances,
+                    &meshes,
+                    &blases,
+                    &vertices,
+                    flat_indices,
+                    &[],
+                )
+            };
+            let closest = query(MAX_DIST).unwrap();
+            assert!((closest.distance - expected).abs() < 1e-4, "{point}");
+            assert_eq!(closest.instance.index(), instance as u32);
+            assert!((closest.point.distance(point) - closest.distance).abs() < 1e-4);
+            assert_eq!(query(expected * 0.99), None);
+        }
+    }
+}
@@ -0,0 +1,143 @@
+use std::fmt;
+
+use crate::{intersection::Aabb, Bvh, Tlas};
+
+/// Shape and quality of a built tree.
+#[derive(Clone, Debug, Default)]
+pub struct BvhStats {
+    /// SAH cost relative to the surface area of the root.
+    pub sah_cost: f32,
+    /// Nodes reachable from the root.
+    pub node_count: usize,
+    pub leaf_count: usize,
+    /// Depth of the deepest leaf, the root is at depth 0.
+    pub max_depth: usize,
+    /// Depth of leaves averaged over all of them.
+    pub average_depth: f32,
+    /// Number of leaves indexed by how many primitives they hold.
+    pub leaf_sizes: Vec<usize>,
+    /// Size of the node buffer, including padding nodes.
+    pub size_bytes: usize,
+}
+
+impl BvhStats {
+    /// Stack entries needed by a traversal that pushes both children of
+    /// every visited node: one pending sibling per level, plus the last pair.
+    pub fn required_stack_len(&self) -> usize {
+        self.max_depth + 1
+    }
+
+    /// Whether traversal with a fixed stack of `stack_len` entries can
+    /// overflow on this tree.
+    pub fn exceeds_stack(&self, stack_len: usize) -> bool {
+        self.node_count > 0 && self.required_stack_len() > stack_len
+    }
+
+    /// Walks the tree from `root`, where `children` returns the child pair of
+    /// interior nodes and `leaf_size` the primitive count of leaves.
+    fn gather(
+        root: usize,
+        children: impl Fn(usize) -> Option<[usize; 2]>,
+        leaf_size: impl Fn(usize) -> usize,
+        bounds: impl Fn(usize) -> Aabb,
+    ) -> Self {
+        let mut stats = Self::default();
+        let root_area = bounds(root).area();
+        let mut cost = 0.;
+        let mut depth_sum = 0;
+
+        let mut stack = vec![(root, 0)];
+        while let Some((node, depth)) = stack.pop() {
+            stats.node_count += 1;
+            let area = bounds(node).area();
+            match children(node) {
+                Some(pair) => {
+                    cost += area;
+                    stack.extend(pair.map(|child| (child, depth + 1)));
+                }
+                None => {
+                    let size = leaf_size(node);
+                    cost += area * size as f32;
+                    stats.leaf_count += 1;
+                    stats.max_depth = stats.max_depth.max(depth);
+                    depth_sum += depth;
+                    if stats.leaf_sizes.len() <= size {
+                        stats.leaf_sizes.resize(size + 1, 0);
+                    }
+                    stats.leaf_sizes[size] += 1;
+                }
+            }
+        }
+
+        stats.average_depth = depth_sum as f32 / stats.leaf_count as f32;
+        if root_area > 0. {
+            stats.sah_cost = cost / root_area;
+        }
+        stats
+    }
+}
+
+impl fmt::Display for BvhStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "SAH cost:      {:.2}", self.sah_cost)?;
+        writeln!(
+            f,
+            "Nodes:         {} ({} leaves, {:.1} KiB)",
+            self.node_count,
+            self.leaf_count,
+            self.size_bytes as f32 / 1024.
+        )?;
+        writeln!(
+            f,
+            "Depth:         {} max, {:.1} average",
+            self.max_depth, self.average_depth
+        )?;
+        write!(f, "Leaf sizes:   ")?;
+        for (size, &count) in self.leaf_sizes.iter().enumerate() {
+            if count > 0 {
+                write!(f, " {size}: {count}")?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Bvh {
+    pub fn stats(&self) -> BvhStats {
+        if self.is_empty() {
+            return BvhStats::default();
+        }
+        let nodes = &self.nodes;
+        let mut stats = BvhStats::gather(
+            0,
+            |i| {
+                (!nodes[i].is_leaf())
+                    .then(|| [nodes[i].left_node_index(), nodes[i].right_node_index()])
+            },
+            |i| nodes[i].triangle_count(),
+            |i| Aabb::new(nodes[i].min, nodes[i].max),
+        );
+        stats.size_bytes = std::mem::size_of_val(nodes.as_slice());
+        stats
+    }
+}
+
+impl Tlas {
+    pub fn stats(&self) -> BvhStats {
+        if self.nodes.is_empty() {
+            return BvhStats::default();
+        }
+        let nodes = &self.nodes;
+        let mut stats = BvhStats::gather(
+            0,
+            |i| {
+                (!nodes[i].is_leaf())
+                    .then(|| [nodes[i].left_node_index(), nodes[i].right_node_index()])
+            },
+            |_| 1,
+            |i| Aabb::new(nodes[i].min, nodes[i].max),
+        );
+        stats.size_bytes = std::mem::size_of_val(nodes.as_slice());
+        stats
+    }
+}
@@ -1,106 +1,585 @@
 use bytemuck::{Pod, Zeroable};
 use components::{Instance, MeshInfo};
-use glam::{vec3, Vec3};
+use glam::{vec3, UVec3, Vec3};
 
-use crate::intersection::Aabb;
+use crate::{
+    blas::{bin_index, fits_levels, Stack},
+    intersection::{intersect_aabb, Aabb, Hit, MAX_DIST},
+    Bvh, Dist, ProceduralPrimitive, Ray, MAX_DEPTH,
+};
+
+/// Number of centroid bins used per axis when splitting instances.
+const TLAS_BINS: usize = 16;
 
 #[repr(C)]
 #[derive(Copy, Clone, Default, Debug, Pod, Zeroable)]
 pub struct TlasNode {
     pub min: Vec3,
-    pub left_right: u32,
+    /// Index of the left child, the right one is stored right after it.
+    /// Zero for leaves.
+    pub left: u32,
     pub max: Vec3,
     pub instance_idx: u32,
 }
 
 impl TlasNode {
     pub fn is_leaf(&self) -> bool {
-        self.left_right == 0
+        self.left == 0
+    }
+
+    pub fn left_node_index(&self) -> usize {
+        self.left as usize
+    }
+
+    pub fn right_node_index(&self) -> usize {
+        self.left as usize + 1
     }
 }
 
 pub struct Tlas {
     pub nodes: Vec<TlasNode>,
+    depth: usize,
 }
 
 impl Tlas {
     pub fn empty() -> Self {
-        Self { nodes: vec![] }
+        Self {
+            nodes: vec![],
+            depth: 0,
+        }
     }
 
+    /// Depth of the deepest leaf, the root is at depth 0. Like
+    /// [`Bvh::depth`] it stays within [`MAX_DEPTH`] unless there are more
+    /// instances than leaves at that depth.
+    pub fn depth(&self) -> usize {
+        self.depth
+    }
+
+    /// Builds the tree top-down with binned SAH splits over instance bounds.
+    /// Removed instances are left out, the tree is empty when all of them are.
+    ///
+    /// Leaves hold a single instance, so splits that would leave a child
+    /// with more instances than its remaining levels can hold fall back to
+    /// the median, like [`crate::BvhBuilder`] does.
     pub fn build(&mut self, instances: &[Instance], meshes: &[MeshInfo]) {
-        self.nodes = vec![TlasNode::default(); 2 * instances.len() + 1];
+        self.build_with_max_depth(instances, meshes, MAX_DEPTH);
+    }
 
-        for (i, instance) in instances.iter().enumerate() {
-            let mesh = meshes[instance.mesh.0 as usize];
-            let bound = [mesh.min, mesh.max];
-            let [min, max] = (0..8)
-                .map(|i| [i & 1, i & 2, i & 4].map(|i| i == 0).map(usize::from))
-                .fold([mesh.min, mesh.max], |[min, max], [i, j, k]| {
-                    let bound = instance
-                        .transform
-                        .transform_point3(vec3(bound[i].x, bound[j].y, bound[k].z));
-                    [min.min(bound), max.max(bound)]
-                });
-            let node = TlasNode {
-                min,
-                left_right: 0,
-                max,
-                instance_idx: i as u32,
+    fn build_with_max_depth(
+        &mut self,
+        instances: &[Instance],
+        meshes: &[MeshInfo],
+        max_depth: usize,
+    ) {
+        self.nodes.clear();
+        self.depth = 0;
+        let mut instance_indices: Vec<_> = (0..instances.len() as u32)
+            .filter(|&idx| !instances[idx as usize].is_removed())
+            .collect();
+        if instance_indices.is_empty() {
+            return;
+        }
+
+        let bounds: Vec<_> = instances
+            .iter()
+            .map(|instance| instance_bounds(instance, meshes))
+            .collect();
+
+        let count = instance_indices.len();
+        let min_depth = count.next_power_of_two().trailing_zeros() as usize;
+        let max_depth = max_depth.max(min_depth);
+        self.nodes.reserve(2 * count - 1);
+        self.nodes.push(TlasNode::default());
+        self.subdivide(0, &mut instance_indices, &bounds, 0, max_depth);
+    }
+
+    fn subdivide(
+        &mut self,
+        node_idx: usize,
+        instances: &mut [u32],
+        bounds: &[Aabb],
+        depth: usize,
+        max_depth: usize,
+    ) {
+        let mut aabb = Aabb::empty();
+        for &idx in instances.iter() {
+            aabb.union(&bounds[idx as usize]);
+        }
+        let left = self.nodes.len();
+        let node = &mut self.nodes[node_idx];
+        node.min = aabb.min;
+        node.max = aabb.max;
+
+        if let [instance] = instances {
+            node.left = 0;
+            node.instance_idx = *instance;
+            self.depth = self.depth.max(depth);
+            return;
+        }
+
+        node.left = left as u32;
+        node.instance_idx = u32::MAX;
+        self.nodes.extend([TlasNode::default(); 2]);
+
+        let child_levels = max_depth - depth - 1;
+        let mut pivot = split_instances(instances, bounds);
+        let right_count = instances.len() - pivot;
+        if !fits_levels(pivot as u32, child_levels)
+            || !fits_levels(right_count as u32, child_levels)
+        {
+            pivot = partition_median(instances, bounds);
+        }
+        let (left_instances, right_instances) = instances.split_at_mut(pivot);
+        self.subdivide(left, left_instances, bounds, depth + 1, max_depth);
+        self.subdivide(left + 1, right_instances, bounds, depth + 1, max_depth);
+    }
+
+    /// Recomputes node bounds for moved instances while keeping the topology.
+    pub fn refit(&mut self, instances: &[Instance], meshes: &[MeshInfo]) {
+        // Children are always stored after their parents
+        for i in (0..self.nodes.len()).rev() {
+            let node = self.nodes[i];
+            let aabb = if node.is_leaf() {
+                instance_bounds(&instances[node.instance_idx as usize], meshes)
+            } else {
+                let (left, right) = (
+                    self.nodes[node.left_node_index()],
+                    self.nodes[node.right_node_index()],
+                );
+                Aabb::new(left.min.min(right.min), left.max.max(right.max))
             };
+            self.nodes[i].min = aabb.min;
+            self.nodes[i].max = aabb.max;
+        }
+    }
+
+    /// Node indices grouped by depth, deepest level first.
+    ///
+    /// Every level only depends on the ones before it, which lets the GPU
+    /// refit a whole level in parallel.
+    pub fn refit_levels(&self) -> Vec<Vec<u32>> {
+        let mut levels: Vec<Vec<u32>> = vec![];
+        if self.nodes.is_empty() {
+            return levels;
+        }
+        let mut depths = vec![0; self.nodes.len()];
+        for (i, node) in self.nodes.iter().enumerate() {
+            let depth = depths[i];
+            if levels.len() <= depth {
+                levels.resize(depth + 1, vec![]);
+            }
+            levels[depth].push(i as u32);
+            if !node.is_leaf() {
+                depths[node.left_node_index()] = depth + 1;
+                depths[node.right_node_index()] = depth + 1;
+            }
+        }
+        levels.reverse();
+        levels
+    }
+
+    /// Finds the closest hit of a world space `ray` against all instances.
+    ///
+    /// `vertices`, `indices` and `primitives` are the whole pools addressed
+    /// by `meshes`, and `blases` holds the BVH of every mesh in the same order.
+    #[allow(clippy::too_many_arguments)]
+    pub fn intersect(
+        &self,
+        ray: Ray,
+        instances: &[Instance],
+        meshes: &[MeshInfo],
+        blases: &[Bvh],
+        vertices: &[Vec3],
+        indices: &[u32],
+        primitives: &[ProceduralPrimitive],
+    ) -> Option<Hit> {
+        if self.nodes.is_empty() {
+            return None;
+        }
+        let mut stack = Stack::new();
+        stack.push(0);
+
+        let mut hit: Option<Hit> = None;
+        while !stack.is_empty() {
+            let node = self.nodes[stack.pop()];
+            let t = hit.map_or(MAX_DIST, |hit| hit.t);
+            if node.is_leaf() {
+                let instance_hit = instance_hit(
+                    ray,
+                    node.instance_idx,
+                    instances,
+                    meshes,
+                    blases,
+                    vertices,
+                    indices,
+                    primitives,
+                );
+                if let Some(instance_hit) = instance_hit.filter(|instance_hit| instance_hit.t < t) {
+                    hit = Some(instance_hit);
+                }
+            } else {
+                let mut min_index = node.left_node_index();
+                let mut max_index = node.right_node_index();
+
+                let min_child = self.nodes[min_index];
+                let max_child = self.nodes[max_index];
+
+                let mut min_dist = intersect_aabb(ray, min_child.min, min_child.max, t);
+                let mut max_dist = intersect_aabb(ray, max_child.min, max_child.max, t);
+                if min_dist > max_dist {
+                    (min_index, max_index) = (max_index, min_index);
+                    (min_dist, max_dist) = (max_dist, min_dist);
+                }
+
+                match min_dist {
+                    Dist::Hit(_) => stack.push(min_index),
+                    Dist::Miss => continue,
+                }
+                if let Dist::Hit(_) = max_dist {
+                    stack.push(max_index);
+                }
+            }
+        }
+        hit
+    }
+
+    /// Returns `true` as soon as any instance is hit closer than `t_max`.
+    #[allow(clippy::too_many_arguments)]
+    pub fn occluded(
+        &self,
+        ray: Ray,
+        t_max: f32,
+        instances: &[Instance],
+        meshes: &[MeshInfo],
+        blases: &[Bvh],
+        vertices: &[Vec3],
+        indices: &[u32],
+        primitives: &[ProceduralPrimitive],
+    ) -> bool {
+        self.occluded_with(
+            ray,
+            t_max,
+            instances,
+            meshes,
+            blases,
+            vertices,
+            indices,
+            primitives,
+            |_| true,
+        )
+    }
 
-            // First node reserved for root
-            self.nodes[i + 1] = node;
-        }
-
-        let mut instance_count = instances.len();
-        let mut nodes_used = 1 + instance_count;
-        let mut node_indices: Vec<_> = (1..).take(instance_count).collect();
-        let mut a = 0;
-        let mut b = self.find_best_match(&node_indices, instance_count, a);
-        while instance_count > 0 {
-            let c = self.find_best_match(&node_indices, instance_count, b);
-            if a == c {
-                let idx_a = node_indices[a];
-                let idx_b = node_indices[b];
-                let node_a = &self.nodes[idx_a];
-                let node_b = &self.nodes[idx_b];
-                self.nodes[nodes_used] = TlasNode {
-                    min: node_a.min.min(node_b.min),
-                    max: node_a.max.max(node_b.max),
-                    left_right: idx_a as u32 + ((idx_b as u32) << 16),
-                    instance_idx: u32::MAX,
+    /// Like [`Tlas::occluded`], but hits only count when `alpha_test` accepts
+    /// them, e.g. after sampling the albedo alpha of cutout foliage.
+    /// Procedural primitives are always opaque.
+    #[allow(clippy::too_many_arguments)]
+    pub fn occluded_with(
+        &self,
+        ray: Ray,
+        t_max: f32,
+        instances: &[Instance],
+        meshes: &[MeshInfo],
+        blases: &[Bvh],
+        vertices: &[Vec3],
+        indices: &[u32],
+        primitives: &[ProceduralPrimitive],
+        mut alpha_test: impl FnMut(&Hit) -> bool,
+    ) -> bool {
+        if self.nodes.is_empty() {
+            return false;
+        }
+        let mut stack = Stack::new();
+        stack.push(0);
+
+        while !stack.is_empty() {
+            let node = self.nodes[stack.pop()];
+            if node.is_leaf() {
+                let instance = &instances[node.instance_idx as usize];
+                let instance_id = instance.id(node.instance_idx);
+                let local_ray = instance_ray(ray, instance);
+                let blas = &blases[instance.mesh.index() as usize];
+                let occluded = match mesh_primitives(instance, meshes, primitives) {
+                    Some(primitives) => blas.occluded_procedural(primitives, local_ray, t_max),
+                    None => {
+                        let (vertices, indices) =
+                            mesh_geometry(instance, meshes, vertices, indices);
+                        blas.occluded_with(vertices, indices, local_ray, t_max, |hit| {
+                            alpha_test(&hit.with_instance(
+                                instance.mesh,
+                                instance_id,
+                                instance.transform,
+                            ))
+                        })
+                    }
                 };
-                node_indices[a] = nodes_used;
-                nodes_used += 1;
-                node_indices[b] = node_indices[instance_count - 1];
-                instance_count -= 1;
-                b = self.find_best_match(&node_indices, instance_count, a);
+                if occluded {
+                    return true;
+                }
             } else {
-                a = b;
-                b = c;
+                for child in [node.left_node_index(), node.right_node_index()] {
+                    let child_node = self.nodes[child];
+                    if let Dist::Hit(_) = intersect_aabb(ray, child_node.min, child_node.max, t_max)
+                    {
+                        stack.push(child);
+                    }
+                }
             }
         }
-        self.nodes[0] = self.nodes[node_indices[a]];
+        false
+    }
+}
+
+/// Closest hit of a world space `ray` against the instance at
+/// `instance_idx`, shared by [`Tlas::intersect`] and the compressed TLAS.
+#[allow(clippy::too_many_arguments)]
+pub(crate) fn instance_hit(
+    ray: Ray,
+    instance_idx: u32,
+    instances: &[Instance],
+    meshes: &[MeshInfo],
+    blases: &[Bvh],
+    vertices: &[Vec3],
+    indices: &[u32],
+    primitives: &[ProceduralPrimitive],
+) -> Option<Hit> {
+    let instance = &instances[instance_idx as usize];
+    let local_ray = instance_ray(ray, instance);
+    let blas = &blases[instance.mesh.index() as usize];
+    let local_hit = match mesh_primitives(instance, meshes, primitives) {
+        Some(primitives) => blas.intersect_procedural(primitives, local_ray),
+        None => {
+            let (vertices, indices) = mesh_geometry(instance, meshes, vertices, indices);
+            blas.intersect(vertices, indices, local_ray)
+        }
+    }?;
+    Some(local_hit.with_instance(instance.mesh, instance.id(instance_idx), instance.transform))
+}
+
+/// Moves `ray` into the object space of `instance`.
+///
+/// The direction is not renormalized, so hit distances stay in world units.
+fn instance_ray(ray: Ray, instance: &Instance) -> Ray {
+    let inv_transform = instance.inv_transform();
+    Ray::new(
+        inv_transform.transform_point3(ray.orig),
+        inv_transform.transform_vector3(ray.dir),
+    )
+}
+
+/// Slices vertex and index pools down to the mesh referenced by `instance`.
+pub(crate) fn mesh_geometry<'a>(
+    instance: &Instance,
+    meshes: &[MeshInfo],
+    vertices: &'a [Vec3],
+    indices: &'a [u32],
+) -> (&'a [Vec3], &'a [UVec3]) {
+    let mesh = meshes[instance.mesh.index() as usize];
+    let vertices = &vertices[mesh.vertex_offset as usize..];
+    let indices = &indices[mesh.base_index as usize..][..mesh.index_count as usize];
+    (vertices, bytemuck::cast_slice(indices))
+}
+
+/// Slices the primitive pool down to the mesh referenced by `instance`,
+/// `None` for triangle meshes.
+pub(crate) fn mesh_primitives<'a>(
+    instance: &Instance,
+    meshes: &[MeshInfo],
+    primitives: &'a [ProceduralPrimitive],
+) -> Option<&'a [ProceduralPrimitive]> {
+    let mesh = meshes[instance.mesh.index() as usize];
+    (mesh.primitive_count > 0)
+        .then(|| &primitives[mesh.first_primitive as usize..][..mesh.primitive_count as usize])
+}
+
+/// World space bounds of the mesh referenced by `instance`.
+fn instance_bounds(instance: &Instance, meshes: &[MeshInfo]) -> Aabb {
+    if instance.is_removed() {
+        return Aabb::empty();
     }
+    let mesh = meshes[instance.mesh.index() as usize];
+    let bound = [mesh.min, mesh.max];
+    let mut aabb = Aabb::empty();
+    for i in 0..8 {
+        let [x, y, z] = [i & 1, (i >> 1) & 1, (i >> 2) & 1];
+        let corner = vec3(bound[x].x, bound[y].y, bound[z].z);
+        aabb.grow(instance.transform.transform_point3(corner));
+    }
+    aabb
+}
+
+/// Splits `instances` in halves at the median centroid along the longest
+/// axis, returning the size of the first half.
+fn partition_median(instances: &mut [u32], bounds: &[Aabb]) -> usize {
+    let centroid = |idx: u32| {
+        let aabb = &bounds[idx as usize];
+        (aabb.min + aabb.max) * 0.5
+    };
+    let mut centroid_bounds = Aabb::empty();
+    for &idx in instances.iter() {
+        centroid_bounds.grow(centroid(idx));
+    }
+    let extent = centroid_bounds.max - centroid_bounds.min;
+    let axis = match extent.max_element() {
+        max if max == extent.x => 0,
+        max if max == extent.y => 1,
+        _ => 2,
+    };
+    let mid = instances.len() / 2;
+    instances.select_nth_unstable_by(mid, |&a, &b| {
+        centroid(a)[axis].total_cmp(&centroid(b)[axis])
+    });
+    mid
+}
+
+/// Partitions `instances` by the cheapest binned SAH split and returns the
+/// size of the left half. Falls back to an even split when all centroids
+/// coincide, so both halves are never empty.
+fn split_instances(instances: &mut [u32], bounds: &[Aabb]) -> usize {
+    let centroid = |idx: u32| {
+        let aabb = &bounds[idx as usize];
+        (aabb.min + aabb.max) * 0.5
+    };
+    let mut centroid_bounds = Aabb::empty();
+    for &idx in instances.iter() {
+        centroid_bounds.grow(centroid(idx));
+    }
+
+    let mut best: Option<(usize, f32, f32, usize)> = None;
+    let mut best_cost = f32::MAX;
+    for axis in 0..3 {
+        let min = centroid_bounds.min[axis];
+        let max = centroid_bounds.max[axis];
+        if min == max {
+            continue;
+        }
+        let scale = TLAS_BINS as f32 / (max - min);
+
+        let mut bin_bounds = [Aabb::empty(); TLAS_BINS];
+        let mut bin_count = [0u32; TLAS_BINS];
+        for &idx in instances.iter() {
+            let bin = bin_index(centroid(idx)[axis], min, scale, TLAS_BINS);
+            bin_count[bin] += 1;
+            bin_bounds[bin].union(&bounds[idx as usize]);
+        }
 
-    fn find_best_match(&self, indices: &[usize], num_unused: usize, target: usize) -> usize {
-        let mut smallest = 1e30;
-        let mut best_idx = target;
-        for i in 0..num_unused {
-            if target == i {
+        let mut right_area = [0f32; TLAS_BINS];
+        let mut right_count = [0u32; TLAS_BINS];
+        let (mut aabb, mut count) = (Aabb::empty(), 0);
+        for bin in (1..TLAS_BINS).rev() {
+            aabb.union(&bin_bounds[bin]);
+            count += bin_count[bin];
+            right_area[bin] = aabb.area();
+            right_count[bin] = count;
+        }
+
+        let (mut aabb, mut count) = (Aabb::empty(), 0);
+        for bin in 0..TLAS_BINS - 1 {
+            aabb.union(&bin_bounds[bin]);
+            count += bin_count[bin];
+            if count == 0 || right_count[bin + 1] == 0 {
                 continue;
             }
-            let target_node = self.nodes[indices[target]];
-            let best_node = self.nodes[indices[i]];
-            let bmin = target_node.min.min(best_node.min);
-            let bmax = target_node.max.max(best_node.max);
-            let surface_area = Aabb::new(bmin, bmax).area();
-            if surface_area < smallest {
-                smallest = surface_area;
-                best_idx = i;
+            let cost =
+                aabb.area() * count as f32 + right_area[bin + 1] * right_count[bin + 1] as f32;
+            if cost < best_cost {
+                best_cost = cost;
+                best = Some((axis, min, scale, bin));
             }
         }
-        best_idx
+    }
+
+    let Some((axis, min, scale, split_bin)) = best else {
+        return instances.len() / 2;
+    };
+    let (mut i, mut end) = (0, instances.len());
+    while i < end {
+        let bin = bin_index(centroid(instances[i])[axis], min, scale, TLAS_BINS);
+        if bin <= split_bin {
+            i += 1;
+        } else {
+            end -= 1;
+            instances.swap(i, end);
+        }
+    }
+    i
+}
+
+#[cfg(test)]
+mod tests {
+    use components::{MaterialId, MeshId};
+    use glam::Mat4;
+
+    use super::*;
+    use crate::BvhBuilder;
+
+    /// Unit cubes at `x = 2^i`, which binned SAH peels off a few at a time
+    /// into a chain deeper than balanced.
+    fn skewed_scene(count: u32) -> (Vec<Instance>, Vec<MeshInfo>) {
+        let instances = (0..count)
+            .map(|i| {
+                let transform = Mat4::from_translation(vec3(2f32.powi(i as i32), 0., 0.));
+                Instance::new(transform, MeshId::default(), MaterialId::default())
+            })
+            .collect();
+        let mesh = MeshInfo {
+            min: Vec3::splat(-0.5),
+            max: Vec3::splat(0.5),
+            index_count: 3,
+            ..Default::default()
+        };
+        (instances, vec![mesh])
+    }
+
+    #[test]
+    fn build_respects_max_depth() {
+        let (instances, meshes) = skewed_scene(16);
+        let mut unlimited = Tlas::empty();
+        unlimited.build_with_max_depth(&instances, &meshes, usize::MAX / 2);
+        assert!(unlimited.depth() > 4);
+
+        let mut tlas = Tlas::empty();
+        tlas.build_with_max_depth(&instances, &meshes, 4);
+        assert_eq!(tlas.depth(), 4);
+        assert_eq!(tlas.stats().max_depth, tlas.depth());
+
+        let mut leaves: Vec<_> = tlas
+            .nodes
+            .iter()
+            .filter(|node| node.is_leaf())
+            .map(|node| node.instance_idx)
+            .collect();
+        leaves.sort();
+        assert_eq!(leaves, (0..16).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn depth_limited_tlas_finds_every_instance() {
+        let (instances, meshes) = skewed_scene(16);
+        let vertices = [vec3(-0.5, -0.5, 0.), vec3(0.5, -0.5, 0.), vec3(0., 0.5, 0.)];
+        let mut indices = [UVec3::new(0, 1, 2)];
+        let blases = [BvhBuilder::new(&vertices, &mut indices).build()];
+        let indices = [0, 1, 2];
+
+        let mut tlas = Tlas::empty();
+        tlas.build_with_max_depth(&instances, &meshes, 4);
+        for (i, instance) in instances.iter().enumerate() {
+            let orig = instance.transform.transform_point3(vec3(0., 0., -1.));
+            let ray = Ray::new(orig, Vec3::Z);
+            let hit = tlas.intersect(ray, &instances, &meshes, &blases, &vertices, &indices, &[]);
+            let hit = hit.expect("Ray towards an instance missed");
+            assert_eq!(hit.instance.index(), i as u32);
+            assert!((hit.t - 1.).abs() < 1e-4);
+            assert!(tlas.occluded(
+                ray,
+                2.,
+                &instances,
+                &meshes,
+                &blases,
+                &vertices,
+                &indices,
+                &[]
+            ));
+        }
     }
 }
@@ -0,0 +1,414 @@
+use bytemuck::{Pod, Zeroable};
+use glam::{UVec3, Vec3};
+
+use crate::{
+    blas::Bvh,
+    intersection::{intersect_aabb, Aabb, Dist::*, TriangleHit, MAX_DIST},
+    Ray,
+};
+
+/// Child slot that holds nothing.
+pub const WIDE_EMPTY_CHILD: u32 = u32::MAX;
+/// Set on child slots that reference triangles instead of a node.
+pub const WIDE_LEAF_FLAG: u32 = 1 << 31;
+/// Entries of the traversal stack in `shaders/utils/bvh_wide.wgsl`. Trees
+/// whose [`WideBvh::required_stack_len`] exceeds it skip their farthest
+/// subtrees when traced on the GPU.
+pub const WIDE_STACK_LEN: usize = 48;
+/// Widest [`WideNode`], sizes the per node scratch of traversal.
+const MAX_WIDTH: usize = 8;
+pub(crate) const LEAF_COUNT_SHIFT: u32 = 24;
+pub(crate) const MAX_LEAF_COUNT: u32 = (1 << (31 - LEAF_COUNT_SHIFT)) - 1;
+pub(crate) const MAX_LEAF_START: u32 = (1 << LEAF_COUNT_SHIFT) - 1;
+
+/// Node with up to [`WideNode::WIDTH`] children whose bounds are quantized
+/// to 8 bits per plane relative to the node origin.
+///
+/// Every child is a single `u32`: either an index of another node, relative
+/// to the root, or [`WIDE_LEAF_FLAG`] with a 7 bit triangle count above a
+/// 24 bit first triangle.
+pub trait WideNode: Pod + Default {
+    const WIDTH: usize;
+
+    fn origin(&self) -> Vec3;
+    fn exponents(&self) -> u32;
+    fn children(&self) -> &[u32];
+    /// Lower x, y, z then upper x, y, z planes, one byte per child each.
+    fn quantized_bounds(&self) -> &[u8];
+
+    fn set_frame(&mut self, origin: Vec3, exponents: u32);
+    fn children_mut(&mut self) -> &mut [u32];
+    fn quantized_bounds_mut(&mut self) -> &mut [u8];
+
+    fn child_bounds(&self, slot: usize) -> Aabb {
+        let scale =
+            Vec3::from_array([0, 8, 16].map(|shift| exp2((self.exponents() >> shift) & 0xff)));
+        let plane = |axis: usize| self.quantized_bounds()[axis * Self::WIDTH + slot] as f32;
+        let lo = Vec3::new(plane(0), plane(1), plane(2));
+        let hi = Vec3::new(plane(3), plane(4), plane(5));
+        Aabb::new(self.origin() + lo * scale, self.origin() + hi * scale)
+    }
+}
+
+macro_rules! wide_node {
+    ($name:ident, $width:literal) => {
+        #[repr(C)]
+        #[derive(Copy, Clone, Default, Debug, Pod, Zeroable)]
+        pub struct $name {
+            pub origin: [f32; 3],
+            /// Biased power of two scale of x, y and z, one byte each.
+            pub exponents: u32,
+            pub children: [u32; $width],
+            pub bounds: [u32; $width * 6 / 4],
+        }
+
+        impl WideNode for $name {
+            const WIDTH: usize = $width;
+
+            fn origin(&self) -> Vec3 {
+                Vec3::from_array(self.origin)
+            }
+
+            fn exponents(&self) -> u32 {
+                self.exponents
+            }
+
+            fn children(&self) -> &[u32] {
+                &self.children
+            }
+
+            fn quantized_bounds(&self) -> &[u8] {
+                bytemuck::cast_slice(&self.bounds)
+            }
+
+            fn set_frame(&mut self, origin: Vec3, exponents: u32) {
+                self.origin = origin.to_array();
+                self.exponents = exponents;
+            }
+
+            fn children_mut(&mut self) -> &mut [u32] {
+                &mut self.children
+            }
+
+            fn quantized_bounds_mut(&mut self) -> &mut [u8] {
+                bytemuck::cast_slice_mut(&mut self.bounds)
+            }
+        }
+    };
+}
+
+wide_node!(Bvh4Node, 4);
+wide_node!(Bvh8Node, 8);
+
+const _: () = assert!(Bvh4Node::WIDTH <= MAX_WIDTH && Bvh8Node::WIDTH <= MAX_WIDTH);
+
+/// `2^(biased - 127)`, exact for the whole normal exponent range.
+pub(crate) fn exp2(biased: u32) -> f32 {
+    f32::from_bits(biased << 23)
+}
+
+/// Whether every leaf of `bvh` ends at or below [`MAX_LEAF_START`], the
+/// last triangle a 24 bit leaf child can reference.
+pub(crate) fn leaves_fit_child_words(bvh: &Bvh) -> bool {
+    bvh.nodes
+        .iter()
+        .filter(|node| node.is_leaf())
+        .all(|node| node.left_first as u64 + node.count as u64 <= MAX_LEAF_START as u64 + 1)
+}
+
+/// Smallest power of two scale that maps `extent` into `steps` steps,
+/// biased like a float exponent.
+pub(crate) fn quantization_exponent(extent: f32, steps: f32) -> u32 {
+    let mut exponent = ((extent / steps).log2().ceil() as i32).clamp(-126, 127);
+    if extent / exp2((exponent + 127) as u32) > steps {
+        exponent += 1;
+    }
+    (exponent + 127) as u32
+}
+
+pub struct WideBvh<T> {
+    pub nodes: Vec<T>,
+    depth: usize,
+}
+
+impl<T: WideNode> WideBvh<T> {
+    /// Collapses a binary tree, greedily opening the child with the largest
+    /// surface area until a node is full. Leaves keep their triangle ranges,
+    /// so the indices reordered by [`crate::BvhBuilder`] stay valid.
+    ///
+    /// Returns `None` for meshes with triangles past the 24 bit first
+    /// triangle of a leaf child, those have to be traced with the binary tree.
+    pub fn collapse(bvh: &Bvh) -> Option<Self> {
+        if !leaves_fit_child_words(bvh) {
+            return None;
+        }
+        let mut this = Self {
+            nodes: vec![],
+            depth: 0,
+        };
+        if !bvh.is_empty() {
+            this.collapse_node(bvh, 0, 0);
+        }
+        Some(this)
+    }
+
+    /// Depth of the deepest node, the root is at depth 0.
+    pub fn depth(&self) -> usize {
+        self.depth
+    }
+
+    /// Stack entries traversal needs in the worst case: every node on the
+    /// way down leaves all but one of its children on the stack.
+    pub fn required_stack_len(&self) -> usize {
+        self.depth * (T::WIDTH - 1) + 1
+    }
+
+    fn collapse_node(&mut self, bvh: &Bvh, node_idx: usize, depth: usize) -> u32 {
+        let wide_idx = self.nodes.len();
+        self.nodes.push(T::default());
+        self.depth = self.depth.max(depth);
+
+        let node = bvh.nodes[node_idx];
+        let mut slots = match node.is_leaf() {
+            true => vec![node_idx],
+            false => vec![node.left_node_index(), node.right_node_index()],
+        };
+        while slots.len() < T::WIDTH {
+            let area = |&(_, &idx): &(usize, &usize)| {
+                let node = bvh.nodes[idx];
+                Aabb::new(node.min, node.max).area()
+            };
+            let Some((i, _)) = slots
+                .iter()
+                .enumerate()
+                .filter(|(_, &idx)| !bvh.nodes[idx].is_leaf())
+                .max_by(|a, b| area(a).total_cmp(&area(b)))
+            else {
+                break;
+            };
+            let opened = bvh.nodes[slots[i]];
+            slots.splice(i..=i, [opened.left_node_index(), opened.right_node_index()]);
+        }
+
+        let children = slots
+            .into_iter()
+            .map(|idx| {
+                let child = bvh.nodes[idx];
+                let aabb = Aabb::new(child.min, child.max);
+                let word = match child.is_leaf() {
+                    true => self.leaf_child(child.left_first, child.count, &aabb, depth + 1),
+                    false => self.collapse_node(bvh, idx, depth + 1),
+                };
+                (word, aabb)
+            })
+            .collect::<Vec<_>>();
+        self.nodes[wide_idx] = encode_node(&children);
+        wide_idx as u32
+    }
+
+    /// Leaves too large for a single slot are spread over an extra node.
+    fn leaf_child(&mut self, start: u32, count: u32, aabb: &Aabb, depth: usize) -> u32 {
+        debug_assert!(start + count - 1 <= MAX_LEAF_START);
+        if count <= MAX_LEAF_COUNT {
+            return WIDE_LEAF_FLAG | (count << LEAF_COUNT_SHIFT) | start;
+        }
+
+        let wide_idx = self.nodes.len();
+        self.nodes.push(T::default());
+        self.depth = self.depth.max(depth);
+        let chunk = count.div_ceil(T::WIDTH as u32);
+        let children = (start..start + count)
+            .step_by(chunk as usize)
+            .map(|first| {
+                let chunk_count = chunk.min(start + count - first);
+                (self.leaf_child(first, chunk_count, aabb, depth + 1), *aabb)
+            })
+            .collect::<Vec<_>>();
+        self.nodes[wide_idx] = encode_node(&children);
+        wide_idx as u32
+    }
+
+    pub fn size_bytes(&self) -> usize {
+        std::mem::size_of_val(self.nodes.as_slice())
+    }
+
+    /// Finds the closest triangle hit by `ray`.
+    pub fn intersect(&self, vertices: &[Vec3], indices: &[UVec3], ray: Ray) -> Option<TriangleHit> {
+        if self.nodes.is_empty() {
+            return None;
+        }
+        let mut stack = vec![0];
+
+        let mut hit: Option<TriangleHit> = None;
+        while let Some(node_idx) = stack.pop() {
+            let node = &self.nodes[node_idx as usize];
+            let t = hit.map_or(MAX_DIST, |hit| hit.t);
+            let mut hits = [(0., 0); MAX_WIDTH];
+            let mut hit_count = 0;
+            for (slot, &child) in node.children().iter().enumerate() {
+                if child == WIDE_EMPTY_CHILD {
+                    continue;
+                }
+                let aabb = node.child_bounds(slot);
+                if let Hit(dist) = intersect_aabb(ray, aabb.min, aabb.max, t) {
+                    hits[hit_count] = (dist, child);
+                    hit_count += 1;
+                }
+            }
+            let hits = &mut hits[..hit_count];
+            // Nearest child ends up on top of the stack
+            hits.sort_unstable_by(|a, b| b.0.total_cmp(&a.0));
+
+            for &(_, child) in hits.iter() {
+                if child & WIDE_LEAF_FLAG == 0 {
+                    stack.push(child);
+                    continue;
+                }
+                let start = child & MAX_LEAF_START;
+                let count = (child & !WIDE_LEAF_FLAG) >> LEAF_COUNT_SHIFT;
+                for triangle in start..start + count {
+                    let trig = indices[triangle as usize]
+                        .to_array()
+                        .map(|i| vertices[i as usize]);
+                    if let Some((t, barycentrics)) = ray.intersect_barycentric(trig) {
+                        if t < hit.map_or(MAX_DIST, |hit| hit.t) {
+                            hit = Some(TriangleHit {
+                                t,
+                                triangle,
+                                barycentrics,
+                            });
+                        }
+                    }
+                }
+            }
+        }
+        hit
+    }
+}
+
+fn encode_node<T: WideNode>(children: &[(u32, Aabb)]) -> T {
+    let mut bounds = Aabb::empty();
+    children.iter().for_each(|(_, aabb)| bounds.union(aabb));
+    let extent = bounds.max - bounds.min;
+    let exponents = extent
+        .to_array()
+        .map(|extent| quantization_exponent(extent, 255.));
+    let scale = Vec3::from_array(exponents.map(exp2));
+
+    let mut node = T::default();
+    node.set_frame(
+        bounds.min,
+        exponents[0] | exponents[1] << 8 | exponents[2] << 16,
+    );
+    node.children_mut().fill(WIDE_EMPTY_CHILD);
+    for (slot, (child, aabb)) in children.iter().enumerate() {
+        node.children_mut()[slot] = *child;
+        let lo = ((aabb.min - bounds.min) / scale).floor();
+        let hi = ((aabb.max - bounds.min) / scale).ceil();
+        let planes = lo.to_array().into_iter().chain(hi.to_array());
+        for (plane, value) in planes.enumerate() {
+            node.quantized_bounds_mut()[plane * T::WIDTH + slot] = value.clamp(0., 255.) as u8;
+        }
+    }
+    node
+}
+
+#[cfg(test)]
+mod tests {
+    use glam::vec3;
+
+    use super::*;
+    use crate::BvhBuilder;
+
+    /// Unit UV sphere with a smaller one inside its upper half.
+    fn test_mesh() -> (Vec<Vec3>, Vec<UVec3>) {
+        const RINGS: u32 = 16;
+        const SEGMENTS: u32 = 24;
+        let mut vertices = vec![];
+        let mut indices = vec![];
+        for (center, radius) in [(Vec3::ZERO, 1.), (vec3(0.2, 0.3, 0.1), 0.4)] {
+            let first = vertices.len() as u32;
+            for ring in 0..=RINGS {
+                let theta = ring as f32 / RINGS as f32 * std::f32::consts::PI;
+                for segment in 0..=SEGMENTS {
+                    let phi = segment as f32 / SEGMENTS as f32 * std::f32::consts::TAU;
+                    let dir = vec3(
+                        theta.sin() * phi.cos(),
+                        theta.cos(),
+                        theta.sin() * phi.sin(),
+                    );
+                    vertices.push(center + dir * radius);
+                }
+            }
+            for ring in 0..RINGS {
+                for segment in 0..SEGMENTS {
+                    let i = first + ring * (SEGMENTS + 1) + segment;
+                    indices.push(UVec3::new(i, i + SEGMENTS + 1, i + 1));
+                    indices.push(UVec3::new(i + 1, i + SEGMENTS + 1, i + SEGMENTS + 2));
+                }
+            }
+        }
+        (vertices, indices)
+    }
+
+    fn wide_bvh_matches<T: WideNode>() {
+        let (vertices, mut indices) = test_mesh();
+        let bvh = BvhBuilder::new(&vertices, &mut indices).build();
+        let wide = WideBvh::<T>::collapse(&bvh).unwrap();
+
+        // Camera whose view overhangs the outer sphere, plus rays from
+        // inside it that hit the inner sphere or the back of the outer one
+        const SIDE: usize = 32;
+        let mut hits = 0;
+        for orig in [vec3(0.1, 0.2, -3.), vec3(-0.3, -0.2, 0.)] {
+            for y in 0..SIDE {
+                for x in 0..SIDE {
+                    let uv = vec3(x as f32 + 0.5, y as f32 + 0.5, 0.) / SIDE as f32 * 2. - 1.;
+                    let ray = Ray::new(orig, vec3(uv.x * 0.6, uv.y * 0.6, 1.).normalize());
+                    let expected = bvh.intersect(&vertices, &indices, ray);
+                    let actual = wide.intersect(&vertices, &indices, ray);
+                    assert_eq!(
+                        expected.map(|hit| (hit.triangle, hit.t)),
+                        actual.map(|hit| (hit.triangle, hit.t)),
+                        "{ray:?}"
+                    );
+                    hits += expected.is_some() as usize;
+                }
+            }
+        }
+        assert!(hits > SIDE * SIDE && hits < 2 * SIDE * SIDE);
+    }
+
+    #[test]
+    fn bvh4_matches_bvh() {
+        wide_bvh_matches::<Bvh4Node>();
+    }
+
+    #[test]
+    fn bvh8_matches_bvh() {
+        wide_bvh_matches::<Bvh8Node>();
+    }
+
+    #[test]
+    fn leaves_past_the_leaf_start_keep_the_binary_tree() {
+        let (vertices, mut indices) = test_mesh();
+        let mut bvh = BvhBuilder::new(&vertices, &mut indices).build();
+        assert!(WideBvh::<Bvh8Node>::collapse(&bvh).is_some());
+
+        let leaf = bvh.nodes.iter_mut().rfind(|node| node.count > 0).unwrap();
+        leaf.left_first = MAX_LEAF_START + 1;
+        assert!(WideBvh::<Bvh4Node>::collapse(&bvh).is_none());
+        assert!(WideBvh::<Bvh8Node>::collapse(&bvh).is_none());
+    }
+
+    #[test]
+    fn wide_stack_len_matches_shader() {
+        let shader = include_str!("../../../shaders/utils/bvh_wide.wgsl");
+        let len = shader
+            .lines()
+            .find_map(|line| line.strip_prefix("const WIDE_STACK_LEN: u32 = "))
+            .and_then(|len| len.strip_suffix("u;"))
+            .expect("`WIDE_STACK_LEN` not found in bvh_wide.wgsl");
+        assert_eq!(len.parse::<usize>().unwrap(), WIDE_STACK_LEN);
+    }
+}
@@ -1,28 +1,40 @@
+use std::{mem::offset_of, ops::Range};
+
 use bytemuck::{Pod, Zeroable};
 use glam::{Mat4, Vec3};
 
+/// Slot of a mesh in `MeshPool` along with the number of times the slot
+/// was freed before, so ids of removed meshes don't match the ones reusing
+/// their slot.
 #[repr(C)]
 #[derive(Debug, Copy, Clone, Default, PartialEq, Eq, Pod, Zeroable)]
-pub struct MeshId(pub u32);
+pub struct MeshId {
+    index: u32,
+    generation: u32,
+}
 
 impl From<MeshId> for u32 {
     fn from(value: MeshId) -> u32 {
-        value.0
+        value.index
     }
 }
 impl From<MeshId> for usize {
     fn from(value: MeshId) -> usize {
-        value.0 as _
+        value.index as _
     }
 }
 
 impl MeshId {
-    pub const fn new(id: u32) -> Self {
-        Self(id)
+    pub const fn new(index: u32, generation: u32) -> Self {
+        Self { index, generation }
+    }
+
+    pub fn index(&self) -> u32 {
+        self.index
     }
 
-    pub fn id(&self) -> u32 {
-        self.0
+    pub fn generation(&self) -> u32 {
+        self.generation
     }
 }
 
@@ -35,32 +47,61 @@ pub struct MeshInfo {
     pub base_index: u32,
     pub vertex_offset: i32,
     pub bvh_index: u32,
-    pub junk: [u32; 2],
+    /// Range of `ProceduralPrimitive`s traced instead of triangles when
+    /// `primitive_count` is not zero.
+    pub first_primitive: u32,
+    pub primitive_count: u32,
 }
 
+/// Slot of a material in `MaterialPool` along with its generation, like
+/// [`MeshId`].
 #[repr(C)]
 #[derive(Debug, Copy, Clone, PartialEq, Eq, Pod, Zeroable)]
-pub struct MaterialId(pub u32);
+pub struct MaterialId {
+    index: u32,
+    generation: u32,
+}
 
 impl MaterialId {
-    pub const fn new(id: u32) -> Self {
-        Self(id)
+    pub const fn new(index: u32, generation: u32) -> Self {
+        Self { index, generation }
+    }
+
+    pub fn index(&self) -> u32 {
+        self.index
+    }
+
+    pub fn generation(&self) -> u32 {
+        self.generation
     }
 }
 
 impl Default for MaterialId {
     fn default() -> Self {
-        Self(1)
+        Self::new(1, 0)
     }
 }
 
+/// Slot of an instance in `InstancePool` along with its generation, like
+/// [`MeshId`].
 #[repr(C)]
-#[derive(Debug, Default, Clone, Copy, Zeroable, Pod)]
-pub struct InstanceId(pub u32);
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Zeroable, Pod)]
+pub struct InstanceId {
+    index: u32,
+    generation: u32,
+}
 
 impl InstanceId {
-    pub fn id(&self) -> u32 {
-        self.0
+    pub const fn new(index: u32, generation: u32) -> Self {
+        Self { index, generation }
+    }
+
+    pub fn index(&self) -> u32 {
+        self.index
+    }
+
+    pub fn generation(&self) -> u32 {
+        self.generation
     }
 }
 
@@ -71,7 +112,11 @@ pub struct Instance {
     inv_transform: glam::Mat4,
     pub mesh: MeshId,
     pub material: MaterialId,
-    junk: [u32; 2],
+    pub flags: u32,
+    /// Generation of the `InstancePool` slot holding the instance, set when
+    /// it's added. Traversals return it as part of the [`InstanceId`].
+    pub generation: u32,
+    padding: [u32; 2],
 }
 
 impl Default for Instance {
@@ -81,23 +126,55 @@ impl Default for Instance {
             inv_transform: Mat4::IDENTITY,
             mesh: MeshId::default(),
             material: MaterialId::default(),
-            junk: [0; 2],
+            flags: 0,
+            generation: 0,
+            padding: [0; 2],
         }
     }
 }
 
 impl Instance {
+    /// Set on slots freed by `InstancePool::remove`, such instances are
+    /// neither drawn nor traced.
+    pub const REMOVED: u32 = 1;
+
+    /// Bytes of `transform` and `inv_transform`, the part of the instance
+    /// compute passes are allowed to animate.
+    pub const TRANSFORM_BYTES: Range<usize> =
+        offset_of!(Instance, transform)..offset_of!(Instance, mesh);
+
     pub fn new(transform: glam::Mat4, mesh: MeshId, material: MaterialId) -> Self {
         Self {
             transform,
             inv_transform: transform.inverse(),
             mesh,
             material,
-            junk: [0; 2],
+            flags: 0,
+            generation: 0,
+            padding: [0; 2],
         }
     }
 
     pub fn transform(&mut self, transform: glam::Mat4) {
         self.transform = transform * self.transform;
+        self.inv_transform = self.transform.inverse();
+    }
+
+    pub fn set_transform(&mut self, transform: glam::Mat4) {
+        self.transform = transform;
+        self.inv_transform = transform.inverse();
+    }
+
+    pub fn inv_transform(&self) -> glam::Mat4 {
+        self.inv_transform
+    }
+
+    /// Id of the instance stored in slot `index`.
+    pub fn id(&self, index: u32) -> InstanceId {
+        InstanceId::new(index, self.generation)
+    }
+
+    pub fn is_removed(&self) -> bool {
+        self.flags & Self::REMOVED != 0
     }
 }
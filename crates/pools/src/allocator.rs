@@ -0,0 +1,231 @@
+use std::ops::Range;
+
+/// First fit allocator of element ranges within a growable buffer.
+///
+/// Freed ranges are merged with their neighbours, ranges freed at the end
+/// shrink the used length instead.
+#[derive(Debug, Default)]
+pub struct RangeAllocator {
+    free: Vec<Range<u32>>,
+    end: u32,
+}
+
+impl RangeAllocator {
+    /// Elements up to the end of the last allocated range.
+    pub fn end(&self) -> u32 {
+        self.end
+    }
+
+    /// Elements available in holes below [`RangeAllocator::end`].
+    pub fn free_len(&self) -> u32 {
+        self.free.iter().map(|range| range.len() as u32).sum()
+    }
+
+    pub fn allocate(&mut self, len: u32) -> Range<u32> {
+        if len == 0 {
+            return self.end..self.end;
+        }
+        let fit = self.free.iter().position(|range| range.len() as u32 >= len);
+        let Some(idx) = fit else {
+            let start = self.end;
+            self.end += len;
+            return start..self.end;
+        };
+        let hole = &mut self.free[idx];
+        let range = hole.start..hole.start + len;
+        hole.start += len;
+        if hole.start == hole.end {
+            self.free.remove(idx);
+        }
+        range
+    }
+
+    pub fn free(&mut self, range: Range<u32>) {
+        if range.is_empty() {
+            return;
+        }
+        let idx = self.free.partition_point(|hole| hole.start < range.start);
+        self.free.insert(idx, range);
+        // Merge with the following and then the preceding hole
+        if idx + 1 < self.free.len() && self.free[idx].end == self.free[idx + 1].start {
+            self.free[idx].end = self.free.remove(idx + 1).end;
+        }
+        if idx > 0 && self.free[idx - 1].end == self.free[idx].start {
+            self.free[idx - 1].end = self.free.remove(idx).end;
+        }
+        if self.free.last().is_some_and(|hole| hole.end == self.end) {
+            self.end = self.free.pop().unwrap().start;
+        }
+    }
+
+    /// Forgets all holes, with everything below `end` in use.
+    pub fn reset(&mut self, end: u32) {
+        self.free.clear();
+        self.end = end;
+    }
+}
+
+/// Hands out slot indices for ids, reusing freed ones.
+///
+/// Every slot counts how many times it was freed, so holders of an id can
+/// tell a reused slot apart from the one they were given.
+#[derive(Debug, Default)]
+pub struct SlotAllocator {
+    generations: Vec<u32>,
+    alive: Vec<bool>,
+    free: Vec<u32>,
+}
+
+impl SlotAllocator {
+    /// Slots ever handed out, including freed ones.
+    pub fn len(&self) -> usize {
+        self.alive.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.alive.is_empty()
+    }
+
+    pub fn allocate(&mut self) -> u32 {
+        if let Some(slot) = self.free.pop() {
+            self.alive[slot as usize] = true;
+            return slot;
+        }
+        self.generations.push(0);
+        self.alive.push(true);
+        self.alive.len() as u32 - 1
+    }
+
+    /// Returns `false` when the slot was already free.
+    pub fn free(&mut self, slot: u32) -> bool {
+        if !self.is_alive(slot) {
+            return false;
+        }
+        self.alive[slot as usize] = false;
+        self.generations[slot as usize] += 1;
+        self.free.push(slot);
+        true
+    }
+
+    pub fn is_alive(&self, slot: u32) -> bool {
+        self.alive.get(slot as usize).copied().unwrap_or(false)
+    }
+
+    /// Whether `slot` is alive and wasn't freed since it was handed out
+    /// with `generation`.
+    pub fn contains(&self, slot: u32, generation: u32) -> bool {
+        self.is_alive(slot) && self.generation(slot) == generation
+    }
+
+    pub fn generation(&self, slot: u32) -> u32 {
+        self.generations.get(slot as usize).copied().unwrap_or(0)
+    }
+
+    pub fn alive(&self) -> impl Iterator<Item = u32> + '_ {
+        (0..self.alive.len() as u32).filter(|&slot| self.is_alive(slot))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ranges_are_allocated_first_fit() {
+        let mut ranges = RangeAllocator::default();
+        assert_eq!(ranges.allocate(4), 0..4);
+        assert_eq!(ranges.allocate(2), 4..6);
+        assert_eq!(ranges.allocate(8), 6..14);
+        assert_eq!(ranges.allocate(0), 14..14);
+
+        ranges.free(0..4);
+        ranges.free(6..14);
+        assert_eq!(ranges.end(), 6);
+        assert_eq!(ranges.free_len(), 4);
+
+        // Too large for the hole, appended
+        assert_eq!(ranges.allocate(5), 6..11);
+        assert_eq!(ranges.allocate(3), 0..3);
+        assert_eq!(ranges.allocate(1), 3..4);
+        assert_eq!(ranges.free_len(), 0);
+    }
+
+    #[test]
+    fn freed_ranges_merge_with_neighbours() {
+        let mut ranges = RangeAllocator::default();
+        let allocated: Vec<_> = (0..4).map(|_| ranges.allocate(2)).collect();
+
+        ranges.free(allocated[0].clone());
+        ranges.free(allocated[2].clone());
+        assert_eq!(ranges.free, [0..2, 4..6]);
+        ranges.free(allocated[1].clone());
+        assert_eq!(ranges.free.first(), Some(&(0..6)));
+        assert_eq!(ranges.free.len(), 1);
+        assert_eq!(ranges.allocate(6), 0..6);
+        assert_eq!(ranges.end(), 8);
+    }
+
+    #[test]
+    fn freeing_the_last_range_shrinks_end() {
+        let mut ranges = RangeAllocator::default();
+        let first = ranges.allocate(2);
+        let second = ranges.allocate(2);
+        let last = ranges.allocate(2);
+
+        ranges.free(second);
+        ranges.free(last);
+        // The hole before the last range is swallowed as well
+        assert_eq!(ranges.end(), 2);
+        assert_eq!(ranges.free_len(), 0);
+
+        ranges.free(first);
+        assert_eq!(ranges.end(), 0);
+        assert_eq!(ranges.allocate(3), 0..3);
+    }
+
+    #[test]
+    fn reset_forgets_holes() {
+        let mut ranges = RangeAllocator::default();
+        ranges.allocate(4);
+        ranges.allocate(4);
+        ranges.free(0..4);
+        ranges.reset(4);
+        assert_eq!(ranges.free_len(), 0);
+        assert_eq!(ranges.allocate(2), 4..6);
+    }
+
+    #[test]
+    fn slots_are_reused_with_new_generation() {
+        let mut slots = SlotAllocator::default();
+        assert_eq!(
+            [slots.allocate(), slots.allocate(), slots.allocate()],
+            [0, 1, 2]
+        );
+        assert!(slots.contains(1, 0));
+
+        assert!(slots.free(1));
+        assert!(!slots.is_alive(1));
+        assert!(!slots.contains(1, 0));
+        assert_eq!(slots.alive().collect::<Vec<_>>(), [0, 2]);
+
+        assert_eq!(slots.allocate(), 1);
+        assert_eq!(slots.generation(1), 1);
+        assert!(slots.contains(1, 1));
+        assert!(!slots.contains(1, 0));
+        assert_eq!(slots.allocate(), 3);
+        assert_eq!(slots.len(), 4);
+    }
+
+    #[test]
+    fn double_free_keeps_generation() {
+        let mut slots = SlotAllocator::default();
+        let slot = slots.allocate();
+        assert!(slots.free(slot));
+        assert!(!slots.free(slot));
+        assert_eq!(slots.generation(slot), 1);
+        // Freed only once, so it's handed out once
+        assert_eq!(slots.allocate(), slot);
+        assert_eq!(slots.allocate(), 1);
+        assert!(!slots.free(7));
+    }
+}
@@ -1,14 +1,49 @@
-use std::sync::Arc;
+use std::{
+    mem::offset_of,
+    ops::Range,
+    sync::{Arc, OnceLock},
+};
 
 use components::{
     bind_group_layout::{self, WrappedBindGroupLayout},
-    Gpu, Instance, InstanceId, NonZeroSized, ResizableBuffer, ResizableBufferExt,
+    Gpu, Instance, InstanceId, MaterialId, MeshId, NonZeroSized, ResizableBuffer,
+    ResizableBufferExt,
 };
 
+use crate::SlotAllocator;
+
+/// What changed since the last [`InstancePool::flush`].
+#[derive(Debug, Default, Clone, Copy)]
+pub struct InstanceChanges {
+    /// Instances were added or removed, the TLAS has to be rebuilt.
+    pub added_or_removed: bool,
+    /// Transforms of existing instances changed, refitting the TLAS is enough.
+    pub moved: bool,
+}
+
+/// Buffer the GPU instances are copied into on their way back to the CPU.
+struct Readback {
+    /// Sized like the instance buffer, recreated only when that one grows.
+    buffer: wgpu::Buffer,
+    /// Bytes of the pending copy, while mapping is set once it finished to
+    /// whether it succeeded. `None` while no copy is pending.
+    pending: Option<(u64, Arc<OnceLock<bool>>)>,
+    /// Slots whose transform was uploaded after the copy was made, the CPU
+    /// one is newer there.
+    overwritten: Vec<u32>,
+}
+
 pub struct InstancePool {
     pub instances_data: Vec<Instance>,
     pub instances: ResizableBuffer<Instance>,
 
+    slots: SlotAllocator,
+    /// Slots written on the CPU but not yet uploaded, with the bytes of
+    /// the instance that changed.
+    dirty: Vec<(u32, Range<usize>)>,
+    changes: InstanceChanges,
+    readback: Option<Readback>,
+
     pub bind_group: wgpu::BindGroup,
     pub bind_group_layout: bind_group_layout::BindGroupLayout,
     gpu: Arc<Gpu>,
@@ -41,6 +76,10 @@ impl InstancePool {
         Self {
             instances_data,
             instances,
+            slots: SlotAllocator::default(),
+            dirty: vec![],
+            changes: InstanceChanges::default(),
+            readback: None,
             bind_group,
             bind_group_layout,
             gpu,
@@ -64,26 +103,390 @@ impl InstancePool {
         bind_group
     }
 
+    /// Adds instances into freed slots first and appends the rest.
+    /// Appended instances are uploaded right away, reused slots on the next
+    /// [`InstancePool::flush`].
     pub fn add(&mut self, instances: &[Instance]) -> Vec<InstanceId> {
-        let initial_len = self.instances.len();
-        self.instances_data.extend_from_slice(instances);
-        self.instances.push(&self.gpu, instances);
-        let bind_group =
-            Self::create_bind_group(self.gpu.device(), &self.bind_group_layout, &self.instances);
-        self.bind_group = bind_group;
+        let initial_len = self.instances_data.len();
+        let ids = instances
+            .iter()
+            .map(|&(mut instance)| {
+                let slot = self.slots.allocate();
+                instance.generation = self.slots.generation(slot);
+                match self.instances_data.get_mut(slot as usize) {
+                    Some(data) => {
+                        *data = instance;
+                        self.dirty.push((slot, 0..Instance::SIZE));
+                    }
+                    None => self.instances_data.push(instance),
+                }
+                instance.id(slot)
+            })
+            .collect();
+        self.changes.added_or_removed |= !instances.is_empty();
 
-        (initial_len..)
-            .take(instances.len())
-            .map(|x| InstanceId(x as u32))
-            .collect()
+        if self.instances_data.len() > initial_len {
+            self.instances
+                .push(&self.gpu, &self.instances_data[initial_len..]);
+            let bind_group = Self::create_bind_group(
+                self.gpu.device(),
+                &self.bind_group_layout,
+                &self.instances,
+            );
+            self.bind_group = bind_group;
+        }
+
+        ids
+    }
+
+    /// Whether `id` is still in the pool. Ids of removed instances are
+    /// stale even after a later instance reused their slot.
+    pub fn contains(&self, id: InstanceId) -> bool {
+        self.slots.contains(id.index(), id.generation())
+    }
+
+    pub fn get(&self, id: InstanceId) -> Option<&Instance> {
+        self.contains(id)
+            .then(|| &self.instances_data[id.index() as usize])
+    }
+
+    /// Marks `bytes` of the instance dirty. Only they are uploaded, so
+    /// setting one field keeps whatever compute passes wrote to the others.
+    fn get_mut(&mut self, id: InstanceId, bytes: Range<usize>) -> Option<&mut Instance> {
+        if !self.contains(id) {
+            log::warn!(
+                "Attempted to modify stale instance {} of generation {}",
+                id.index(),
+                id.generation()
+            );
+            return None;
+        }
+        self.dirty.push((id.index(), bytes));
+        Some(&mut self.instances_data[id.index() as usize])
+    }
+
+    pub fn set_transform(&mut self, id: InstanceId, transform: glam::Mat4) {
+        if let Some(instance) = self.get_mut(id, Instance::TRANSFORM_BYTES) {
+            instance.set_transform(transform);
+            self.changes.moved = true;
+        }
     }
 
+    pub fn set_material(&mut self, id: InstanceId, material: MaterialId) {
+        let offset = offset_of!(Instance, material);
+        if let Some(instance) = self.get_mut(id, offset..offset + MaterialId::SIZE) {
+            instance.material = material;
+        }
+    }
+
+    /// Frees the slot of `id`. It stays in the buffer flagged as
+    /// [`Instance::REMOVED`] until reused by [`InstancePool::add`].
+    pub fn remove(&mut self, id: InstanceId) {
+        if !self.contains(id) {
+            log::warn!(
+                "Attempted to remove stale instance {} of generation {}",
+                id.index(),
+                id.generation()
+            );
+            return;
+        }
+        let slot = id.index();
+        self.slots.free(slot);
+        self.instances_data[slot as usize].flags |= Instance::REMOVED;
+        let offset = offset_of!(Instance, flags);
+        self.dirty.push((slot, offset..offset + u32::SIZE));
+        self.changes.added_or_removed = true;
+    }
+
+    /// Removes instances whose mesh fails `contains`, e.g.
+    /// [`crate::MeshPool::contains`] after the mesh was removed. Their mesh
+    /// id would otherwise resolve to whichever mesh reuses its slot.
+    pub fn remove_stale_meshes(&mut self, contains: impl Fn(MeshId) -> bool) {
+        let stale: Vec<_> = self
+            .slots
+            .alive()
+            .filter(|&slot| !contains(self.instances_data[slot as usize].mesh))
+            .collect();
+        for slot in stale {
+            let instance = self.instances_data[slot as usize];
+            log::warn!(
+                "Removing instance {slot} of stale mesh {} of generation {}",
+                instance.mesh.index(),
+                instance.mesh.generation()
+            );
+            self.remove(instance.id(slot));
+        }
+    }
+
+    /// Uploads the bytes changed since the last flush. Whole instances of
+    /// adjacent slots are coalesced into a single write.
+    pub fn flush(&mut self) -> InstanceChanges {
+        let writes = merge_writes(std::mem::take(&mut self.dirty));
+        let whole = |(_, bytes): &(u32, Range<usize>)| *bytes == (0..Instance::SIZE);
+        for run in writes.chunk_by(|a, b| whole(a) && whole(b) && a.0 + 1 == b.0) {
+            let (slot, bytes) = &run[0];
+            let start = *slot as usize;
+            if whole(&run[0]) {
+                self.instances.write_slice(
+                    &self.gpu,
+                    start,
+                    &self.instances_data[start..start + run.len()],
+                );
+            } else {
+                let offset = start * Instance::SIZE + bytes.start;
+                let data = bytemuck::bytes_of(&self.instances_data[start]);
+                self.instances
+                    .write_bytes(&self.gpu, offset as _, &data[bytes.clone()]);
+            }
+        }
+
+        if let Some(readback) = &mut self.readback {
+            readback.overwritten.extend(writes_transform(&writes));
+        }
+        std::mem::take(&mut self.changes)
+    }
+
+    /// Picks up transforms written by compute passes without waiting on the
+    /// GPU. Applies the copy requested by an earlier call once it's mapped,
+    /// skipping slots whose transform was set on the CPU since, then
+    /// requests the next one into the same buffer. Changed transforms count
+    /// as moved instances on the next [`InstancePool::flush`].
+    pub fn read_back(&mut self) {
+        let Some(mut readback) = self.readback.take() else {
+            self.request_read_back(None);
+            return;
+        };
+        if let Some((size, mapped)) = readback.pending.take() {
+            self.gpu.device().poll(wgpu::Maintain::Poll);
+            match mapped.get() {
+                // Copying again would touch the buffer being mapped
+                None => {
+                    readback.pending = Some((size, mapped));
+                    self.readback = Some(readback);
+                    return;
+                }
+                Some(false) => {}
+                Some(true) => {
+                    self.apply_read_back(&mut readback, size);
+                    readback.buffer.unmap();
+                }
+            }
+        }
+        self.request_read_back(Some(readback));
+    }
+
+    /// Copies the instances into the buffer of `readback`, or a new one
+    /// when there is none or it's too small, and starts mapping it.
+    fn request_read_back(&mut self, readback: Option<Readback>) {
+        if self.instances.is_empty() {
+            self.readback = readback;
+            return;
+        }
+        let capacity = self.instances.size();
+        let mut readback = match readback {
+            Some(readback) if readback.buffer.size() >= capacity => readback,
+            _ => Readback {
+                buffer: self.gpu.device().create_buffer(&wgpu::BufferDescriptor {
+                    label: Some("Instances Readback Buffer"),
+                    size: capacity,
+                    usage: wgpu::BufferUsages::MAP_READ | wgpu::BufferUsages::COPY_DST,
+                    mapped_at_creation: false,
+                }),
+                pending: None,
+                overwritten: vec![],
+            },
+        };
+
+        let size = self.instances.size_bytes();
+        let mut encoder = self
+            .gpu
+            .device()
+            .create_command_encoder(&Default::default());
+        encoder.copy_buffer_to_buffer(&self.instances, 0, &readback.buffer, 0, size);
+        self.gpu.queue().submit(Some(encoder.finish()));
+
+        let mapped = Arc::new(OnceLock::new());
+        let on_mapped = mapped.clone();
+        readback
+            .buffer
+            .slice(..size)
+            .map_async(wgpu::MapMode::Read, move |res| {
+                if let Err(err) = &res {
+                    log::error!("Failed to map instances readback buffer: {err}");
+                }
+                let _ = on_mapped.set(res.is_ok());
+            });
+        readback.pending = Some((size, mapped));
+        readback.overwritten.clear();
+        self.readback = Some(readback);
+    }
+
+    /// Applies the first `size` bytes of the mapped `readback`.
+    fn apply_read_back(&mut self, readback: &mut Readback, size: u64) {
+        readback.overwritten.extend(writes_transform(&self.dirty));
+        readback.overwritten.sort_unstable();
+
+        let transform = Instance::TRANSFORM_BYTES;
+        let mapped = readback.buffer.slice(..size).get_mapped_range();
+        let gpu_data: &[Instance] = bytemuck::cast_slice(&mapped);
+        for slot in self.slots.alive() {
+            let (Some(gpu), Some(cpu)) = (
+                gpu_data.get(slot as usize),
+                self.instances_data.get_mut(slot as usize),
+            ) else {
+                continue;
+            };
+            if readback.overwritten.binary_search(&slot).is_ok() {
+                continue;
+            }
+            let (gpu, cpu) = (bytemuck::bytes_of(gpu), bytemuck::bytes_of_mut(cpu));
+            if gpu[transform.clone()] != cpu[transform.clone()] {
+                cpu[transform.clone()].copy_from_slice(&gpu[transform.clone()]);
+                self.changes.moved = true;
+            }
+        }
+    }
+
+    /// Number of slots, including removed instances still in the buffer.
     pub fn count(&self) -> u32 {
         self.instances.len() as _
     }
 
+    pub fn live_count(&self) -> u32 {
+        self.slots.alive().count() as _
+    }
+
     pub fn clear(&mut self) {
         self.instances_data.clear();
         self.instances.clear();
+        self.slots = SlotAllocator::default();
+        self.dirty.clear();
+        self.readback = None;
+        self.changes.added_or_removed = true;
+    }
+}
+
+/// Sorts `dirty` by slot and merges the byte ranges of every slot.
+fn merge_writes(mut dirty: Vec<(u32, Range<usize>)>) -> Vec<(u32, Range<usize>)> {
+    dirty.sort_unstable_by_key(|(slot, _)| *slot);
+    let mut writes: Vec<(u32, Range<usize>)> = vec![];
+    for (slot, bytes) in dirty {
+        match writes.last_mut() {
+            Some((last, merged)) if *last == slot => {
+                *merged = merged.start.min(bytes.start)..merged.end.max(bytes.end);
+            }
+            _ => writes.push((slot, bytes)),
+        }
+    }
+    writes
+}
+
+/// Slots of `writes` that touch [`Instance::TRANSFORM_BYTES`].
+fn writes_transform(writes: &[(u32, Range<usize>)]) -> impl Iterator<Item = u32> + '_ {
+    let transform = Instance::TRANSFORM_BYTES;
+    writes
+        .iter()
+        .filter(move |(_, bytes)| bytes.start < transform.end && transform.start < bytes.end)
+        .map(|(slot, _)| *slot)
+}
+
+#[cfg(test)]
+mod tests {
+    use glam::{Mat4, Vec3};
+
+    use super::*;
+
+    #[test]
+    fn merge_writes_keeps_one_range_per_slot() {
+        let material = offset_of!(Instance, material);
+        let material = material..material + MaterialId::SIZE;
+        let writes = merge_writes(vec![
+            (3, material.clone()),
+            (1, 0..Instance::SIZE),
+            (3, material.clone()),
+            (0, material.clone()),
+            (0, Instance::TRANSFORM_BYTES),
+        ]);
+        assert_eq!(
+            writes,
+            [
+                (0, Instance::TRANSFORM_BYTES.start..material.end),
+                (1, 0..Instance::SIZE),
+                (3, material),
+            ]
+        );
+    }
+
+    #[test]
+    fn material_writes_leave_transforms_to_the_gpu() {
+        let material = offset_of!(Instance, material);
+        let flags = offset_of!(Instance, flags);
+        let writes = [
+            (0, material..material + MaterialId::SIZE),
+            (1, Instance::TRANSFORM_BYTES),
+            (2, flags..flags + u32::SIZE),
+            (3, 0..Instance::SIZE),
+        ];
+        assert_eq!(writes_transform(&writes).collect::<Vec<_>>(), [1, 3]);
+    }
+
+    /// Waits for the pending copy, then applies it and requests the next.
+    fn finish_read_back(pool: &mut InstancePool) {
+        pool.gpu.device().poll(wgpu::Maintain::Wait);
+        pool.read_back();
+    }
+
+    #[test]
+    fn read_back_reuses_its_buffer() {
+        let Some(gpu) = crate::test_gpu() else {
+            return;
+        };
+        let mut pool = InstancePool::new(gpu.clone());
+        let ids =
+            pool.add(&[Instance::new(Mat4::IDENTITY, MeshId::default(), MaterialId::default()); 3]);
+        pool.flush();
+        let readback_size = |pool: &InstancePool| pool.readback.as_ref().unwrap().buffer.size();
+
+        // Stand in for a compute pass moving the first two instances
+        let moved = Instance::new(
+            Mat4::from_translation(Vec3::X),
+            MeshId::default(),
+            MaterialId::default(),
+        );
+        let transform = &bytemuck::bytes_of(&moved)[Instance::TRANSFORM_BYTES];
+        for slot in [0, 1] {
+            let offset = slot * Instance::SIZE + Instance::TRANSFORM_BYTES.start;
+            pool.instances.write_bytes(&gpu, offset as u64, transform);
+        }
+        pool.read_back();
+        // Sized for every slot the instance buffer has room for
+        let capacity = pool.instances.size();
+        assert!(capacity > pool.instances.size_bytes());
+        assert_eq!(readback_size(&pool), capacity);
+        // The second one is set on the CPU while the copy is in flight
+        pool.set_transform(ids[1], Mat4::from_translation(Vec3::Y));
+        pool.flush();
+        // Calls while the map may still be pending leave the copy alone
+        pool.read_back();
+
+        finish_read_back(&mut pool);
+        assert_eq!(pool.get(ids[0]).unwrap().transform, moved.transform);
+        assert_eq!(
+            pool.get(ids[1]).unwrap().transform,
+            Mat4::from_translation(Vec3::Y)
+        );
+        assert_eq!(pool.get(ids[2]).unwrap().transform, Mat4::IDENTITY);
+        assert!(pool.flush().moved);
+
+        finish_read_back(&mut pool);
+        assert!(!pool.flush().moved);
+        assert_eq!(readback_size(&pool), capacity);
+
+        // Growing the instance buffer grows the readback one
+        pool.add(&[Instance::default(); 64]);
+        pool.flush();
+        finish_read_back(&mut pool);
+        assert!(pool.instances.size() > capacity);
+        assert_eq!(readback_size(&pool), pool.instances.size());
     }
 }
@@ -1,11 +1,42 @@
+mod allocator;
 mod instance;
 mod light;
 mod material;
 mod mesh;
 mod texture;
 
+pub use allocator::*;
 pub use instance::*;
 pub use light::*;
 pub use material::*;
 pub use mesh::*;
 pub use texture::*;
+
+/// Device for tests of the pools, `None` on machines without an adapter.
+#[cfg(test)]
+fn test_gpu() -> Option<std::sync::Arc<components::Gpu>> {
+    use pollster::FutureExt;
+
+    let instance = wgpu::Instance::default();
+    let Some(adapter) = instance
+        .request_adapter(&wgpu::RequestAdapterOptions::default())
+        .block_on()
+    else {
+        log::warn!("No adapter found, skipping GPU test");
+        return None;
+    };
+    let (device, queue) = adapter
+        .request_device(
+            &wgpu::DeviceDescriptor {
+                label: Some("Test Device"),
+                features: adapter.features() - wgpu::Features::MAPPABLE_PRIMARY_BUFFERS,
+                limits: adapter.limits(),
+            },
+            None,
+        )
+        .block_on()
+        .ok()?;
+    Some(std::sync::Arc::new(components::Gpu::new(
+        adapter, device, queue,
+    )))
+}
@@ -1,39 +1,131 @@
 use std::sync::Arc;
 
 use bytemuck::{Pod, Zeroable};
-use glam::Vec4;
+use glam::{Vec3, Vec4};
 
 use components::{
     bind_group_layout::{self, WrappedBindGroupLayout},
     Gpu, MaterialId, NonZeroSized, ResizableBuffer, ResizableBufferExt,
 };
 
-use super::texture::{TextureId, BLACK_TEXTURE, WHITE_TEXTURE};
+use super::texture::{TextureRef, WHITE_TEXTURE};
+use crate::SlotAllocator;
 
+/// Every fragment is opaque, alpha is ignored.
+pub const ALPHA_MODE_OPAQUE: u32 = 0;
+/// Fragments with alpha below `alpha_cutoff` are discarded.
+pub const ALPHA_MODE_MASK: u32 = 1;
+/// Alpha blended in glTF. The deferred renderer has no transparent pass,
+/// so it is cut out at `alpha_cutoff` like [`ALPHA_MODE_MASK`].
+pub const ALPHA_MODE_BLEND: u32 = 2;
+
+/// Metallic-roughness BRDF only.
+pub const MATERIAL_STANDARD: u32 = 0;
+/// `KHR_materials_unlit`, outputs base color plus emission without lighting.
+pub const MATERIAL_UNLIT: u32 = 1;
+/// `KHR_materials_clearcoat`, a dielectric specular layer over the base.
+pub const MATERIAL_CLEARCOAT: u32 = 1 << 1;
+/// `KHR_materials_sheen`, a cloth like retroreflective lobe.
+pub const MATERIAL_SHEEN: u32 = 1 << 2;
+/// `KHR_materials_transmission` on a thin walled surface. The deferred
+/// renderer can't see behind it, so only light sources shine through.
+pub const MATERIAL_TRANSMISSION: u32 = 1 << 3;
+
+/// glTF 2.0 metallic-roughness material, plus the layers enabled by
+/// `material_type`. Factors multiply the values sampled from their textures,
+/// missing textures are white.
 #[repr(C)]
 #[derive(Copy, Clone, Pod, Zeroable)]
 pub struct Material {
     pub base_color: Vec4,
-    pub albedo: TextureId,
-    pub normal: TextureId,
-    pub metallic_roughness: TextureId,
-    pub emissive: TextureId,
+    pub emissive_factor: Vec3,
+    pub metallic_factor: f32,
+    pub sheen_color_factor: Vec3,
+    pub sheen_roughness_factor: f32,
+    pub roughness_factor: f32,
+    /// Scales X and Y of the tangent space normal.
+    pub normal_scale: f32,
+    /// Blends between no occlusion at 0 and full texture occlusion at 1.
+    pub occlusion_strength: f32,
+    pub alpha_cutoff: f32,
+    pub clearcoat_factor: f32,
+    pub clearcoat_roughness_factor: f32,
+    pub transmission_factor: f32,
+    pub ior: f32,
+    pub alpha_mode: u32,
+    /// Back faces are culled unless set.
+    pub double_sided: u32,
+    /// [`MATERIAL_UNLIT`] or a combination of the other `MATERIAL_*` layers.
+    pub material_type: u32,
+    pub padding: u32,
+    pub albedo: TextureRef,
+    pub normal: TextureRef,
+    /// Roughness in green and metallic in blue.
+    pub metallic_roughness: TextureRef,
+    pub emissive: TextureRef,
+    /// Occlusion in red.
+    pub occlusion: TextureRef,
+    /// Clearcoat in red.
+    pub clearcoat: TextureRef,
+    /// Clearcoat roughness in green.
+    pub clearcoat_roughness: TextureRef,
+    pub sheen_color: TextureRef,
+    /// Sheen roughness in alpha.
+    pub sheen_roughness: TextureRef,
+    /// Transmission in red.
+    pub transmission: TextureRef,
 }
 
 impl Default for Material {
     fn default() -> Self {
+        let white = TextureRef::from(WHITE_TEXTURE);
         Self {
             base_color: Vec4::splat(1.),
-            albedo: WHITE_TEXTURE,
-            emissive: BLACK_TEXTURE,
-            metallic_roughness: BLACK_TEXTURE,
-            normal: WHITE_TEXTURE,
+            emissive_factor: Vec3::ZERO,
+            metallic_factor: 0.,
+            sheen_color_factor: Vec3::ZERO,
+            sheen_roughness_factor: 0.,
+            roughness_factor: 1.,
+            normal_scale: 1.,
+            occlusion_strength: 1.,
+            alpha_cutoff: 0.5,
+            clearcoat_factor: 0.,
+            clearcoat_roughness_factor: 0.,
+            transmission_factor: 0.,
+            ior: 1.5,
+            alpha_mode: ALPHA_MODE_OPAQUE,
+            double_sided: 0,
+            material_type: MATERIAL_STANDARD,
+            padding: 0,
+            albedo: white,
+            normal: white,
+            metallic_roughness: white,
+            emissive: white,
+            occlusion: white,
+            clearcoat: white,
+            clearcoat_roughness: white,
+            sheen_color: white,
+            sheen_roughness: white,
+            transmission: white,
+        }
+    }
+}
+
+impl Material {
+    /// Emits its base color as is, used by area light quads.
+    pub fn unlit(color: Vec4) -> Self {
+        Self {
+            base_color: color,
+            material_type: MATERIAL_UNLIT,
+            ..Default::default()
         }
     }
 }
 
 pub struct MaterialPool {
     pub(crate) buffer: ResizableBuffer<Material>,
+    materials: Vec<Material>,
+    slots: SlotAllocator,
 
     pub bind_group_layout: bind_group_layout::BindGroupLayout,
     pub bind_group: wgpu::BindGroup,
@@ -42,14 +134,19 @@ pub struct MaterialPool {
 }
 
 impl MaterialPool {
-    pub const LIGHT_MATERIAL: MaterialId = MaterialId::new(2);
+    pub const LIGHT_MATERIAL: MaterialId = MaterialId::new(2, 0);
+    /// Materials created with the pool, these can't be removed.
+    const BUILTIN_MATERIALS: u32 = 3;
+
     pub fn new(gpu: Arc<Gpu>) -> Self {
+        let mut materials = vec![Material::default(); Self::BUILTIN_MATERIALS as usize];
+        materials[Self::LIGHT_MATERIAL.index() as usize] = Material::unlit(Vec4::ONE);
+        let mut slots = SlotAllocator::default();
+        for _ in 0..Self::BUILTIN_MATERIALS {
+            slots.allocate();
+        }
         let buffer = gpu.device().create_resizable_buffer_init(
-            &[
-                Material::default(),
-                Material::default(),
-                Material::default(),
-            ],
+            &materials,
             wgpu::BufferUsages::STORAGE
                 | wgpu::BufferUsages::COPY_DST
                 | wgpu::BufferUsages::COPY_SRC,
@@ -76,6 +173,8 @@ impl MaterialPool {
 
         Self {
             buffer,
+            materials,
+            slots,
             bind_group_layout,
             bind_group,
 
@@ -83,6 +182,7 @@ impl MaterialPool {
         }
     }
 
+    /// Number of slots, including removed materials.
     pub fn num_materials(&self) -> usize {
         self.buffer.len()
     }
@@ -104,15 +204,121 @@ impl MaterialPool {
         bind_group
     }
 
+    /// Adds a material, reusing the slot of a removed one if there is any.
     pub fn add(&mut self, material: Material) -> MaterialId {
-        let was_resized = self.buffer.push(&self.gpu, &[material]);
+        let index = self.slots.allocate();
+        let id = MaterialId::new(index, self.slots.generation(index));
+        if let Some(slot) = self.materials.get_mut(index as usize) {
+            *slot = material;
+            self.buffer.write(&self.gpu, index as usize, material);
+        } else {
+            self.materials.push(material);
+            let was_resized = self.buffer.push(&self.gpu, &[material]);
 
-        if was_resized {
-            self.bind_group =
-                Self::create_bind_group(self.gpu.device(), &self.bind_group_layout, &self.buffer);
+            if was_resized {
+                self.bind_group = Self::create_bind_group(
+                    self.gpu.device(),
+                    &self.bind_group_layout,
+                    &self.buffer,
+                );
+            }
         }
 
-        log::info!("Added material with id: {}", self.buffer.len() as u32 - 1);
-        MaterialId(self.buffer.len() as u32 - 1)
+        log::info!("Added material with id: {index}");
+        id
+    }
+
+    /// Whether `id` is still in the pool. Ids of removed materials are
+    /// stale even after a later material reused their slot.
+    pub fn contains(&self, id: MaterialId) -> bool {
+        self.slots.contains(id.index(), id.generation())
+    }
+
+    pub fn get(&self, id: MaterialId) -> Option<&Material> {
+        self.contains(id)
+            .then(|| &self.materials[id.index() as usize])
+    }
+
+    pub fn set(&mut self, id: MaterialId, material: Material) {
+        self.update(id, |slot| *slot = material);
+    }
+
+    /// Edits the material in place and uploads only the bytes that changed,
+    /// e.g. `pool.update(id, |m| m.base_color = color)` writes 16 bytes.
+    pub fn update(&mut self, id: MaterialId, f: impl FnOnce(&mut Material)) {
+        if !self.contains(id) {
+            log::warn!(
+                "Attempted to modify stale material {} of generation {}",
+                id.index(),
+                id.generation()
+            );
+            return;
+        }
+        let material = &mut self.materials[id.index() as usize];
+        let old = *material;
+        f(material);
+
+        let (old, new) = (bytemuck::bytes_of(&old), bytemuck::bytes_of(material));
+        let Some(first) = old.iter().zip(new).position(|(a, b)| a != b) else {
+            return;
+        };
+        let last = old.iter().zip(new).rposition(|(a, b)| a != b).unwrap();
+        // Buffer writes have to be 4 byte aligned
+        let align = wgpu::COPY_BUFFER_ALIGNMENT as usize;
+        let range = first / align * align..(last / align + 1) * align;
+        let offset = id.index() as usize * Material::SIZE + range.start;
+        self.buffer.write_bytes(&self.gpu, offset as _, &new[range]);
+    }
+
+    /// Frees the slot of `id` for reuse by [`MaterialPool::add`]. Instances
+    /// still referencing it see the default material until then.
+    pub fn remove(&mut self, id: MaterialId) {
+        let index = id.index();
+        if index < Self::BUILTIN_MATERIALS {
+            log::warn!("Attempted to remove builtin material {index}");
+            return;
+        }
+        if !self.contains(id) {
+            log::warn!(
+                "Attempted to remove stale material {index} of generation {}",
+                id.generation()
+            );
+            return;
+        }
+        self.slots.free(index);
+        self.materials[index as usize] = Material::default();
+        self.buffer
+            .write(&self.gpu, index as usize, Material::default());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn stale_ids_leave_reused_slots_alone() {
+        let Some(gpu) = crate::test_gpu() else {
+            return;
+        };
+        let mut pool = MaterialPool::new(gpu.clone());
+        let red = Material::unlit(Vec4::new(1., 0., 0., 1.));
+        let green = Material::unlit(Vec4::new(0., 1., 0., 1.));
+
+        let stale = pool.add(red);
+        pool.remove(stale);
+        let fresh = pool.add(green);
+        assert_eq!(stale.index(), fresh.index());
+        assert_ne!(stale, fresh);
+        assert!(!pool.contains(stale));
+        assert!(pool.get(stale).is_none());
+
+        pool.set(stale, red);
+        pool.update(stale, |material| material.base_color = Vec4::ZERO);
+        pool.remove(stale);
+        assert!(pool.contains(fresh));
+        assert_eq!(pool.get(fresh).unwrap().base_color, green.base_color);
+        let uploaded = pool.buffer.read(&gpu)[fresh.index() as usize];
+        assert_eq!(uploaded.base_color, green.base_color);
     }
 }
@@ -0,0 +1,320 @@
+use std::{
+    collections::hash_map::DefaultHasher,
+    fs,
+    hash::Hasher,
+    io,
+    path::{Path, PathBuf},
+};
+
+use bvh::{Bvh, BvhNode};
+use glam::Vec3;
+
+/// Bumped whenever the builder or the file layout changes, so that stale
+/// files get rebuilt and overwritten.
+pub const BVH_CACHE_VERSION: u32 = 1;
+
+const MAGIC: [u8; 4] = *b"VBVH";
+/// Magic, version, node count and index count.
+const HEADER_LEN: usize = 16;
+const EXTENSION: &str = "bvh";
+
+/// On-disk cache of built BLASes along with their reordered index buffers.
+///
+/// Files are keyed by a hash of vertex positions, indices and build
+/// settings, see [`BvhCache::key`].
+pub struct BvhCache {
+    dir: Option<PathBuf>,
+}
+
+impl BvhCache {
+    pub const DEFAULT_DIR: &'static str = "target/bvh_cache";
+
+    pub fn new(dir: impl Into<PathBuf>) -> Self {
+        Self {
+            dir: Some(dir.into()),
+        }
+    }
+
+    /// Cache that never hits and never writes.
+    pub fn disabled() -> Self {
+        Self { dir: None }
+    }
+
+    /// Configures the cache from the `BVH_CACHE` environment variable:
+    /// `off` disables it, `clear` empties [`BvhCache::DEFAULT_DIR`] before
+    /// using it and any other value is used as the directory.
+    pub fn from_env() -> Self {
+        match std::env::var("BVH_CACHE").as_deref() {
+            Ok("off") => Self::disabled(),
+            Ok("clear") => {
+                let cache = Self::new(Self::DEFAULT_DIR);
+                if let Err(err) = cache.clear() {
+                    log::warn!("Failed to clear BVH cache: {err}");
+                }
+                cache
+            }
+            Ok(dir) => Self::new(dir),
+            Err(_) => Self::new(Self::DEFAULT_DIR),
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.dir.is_some()
+    }
+
+    pub fn dir(&self) -> Option<&Path> {
+        self.dir.as_deref()
+    }
+
+    /// Removes every cached tree, leaving other files in the directory alone.
+    pub fn clear(&self) -> io::Result<()> {
+        let Some(dir) = &self.dir else {
+            return Ok(());
+        };
+        let entries = match fs::read_dir(dir) {
+            Ok(entries) => entries,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(()),
+            Err(err) => return Err(err),
+        };
+        for entry in entries {
+            let path = entry?.path();
+            if path.extension().is_some_and(|ext| ext == EXTENSION) {
+                fs::remove_file(path)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Hash of everything the built tree depends on. It is stable across
+    /// runs of the same build, a new toolchain may only cause misses.
+    pub fn key(vertices: &[Vec3], indices: &[u32], spatial_split_budget: Option<f32>) -> u64 {
+        let mut hasher = DefaultHasher::new();
+        hasher.write_usize(vertices.len());
+        hasher.write(bytemuck::cast_slice(vertices));
+        hasher.write_usize(indices.len());
+        hasher.write(bytemuck::cast_slice(indices));
+        hasher.write_u32(spatial_split_budget.map_or(u32::MAX, f32::to_bits));
+        hasher.finish()
+    }
+
+    fn path(dir: &Path, key: u64) -> PathBuf {
+        dir.join(format!("{key:016x}.{EXTENSION}"))
+    }
+
+    /// Returns the tree and index buffer stored under `key`, or `None` when
+    /// it's missing, from another version, truncated or references nodes or
+    /// triangles it doesn't contain.
+    pub fn load(&self, key: u64) -> Option<(Bvh, Vec<u32>)> {
+        let path = Self::path(self.dir.as_ref()?, key);
+        let bytes = fs::read(&path).ok()?;
+        if bytes.len() < HEADER_LEN || bytes[..4] != MAGIC {
+            log::warn!("Ignoring malformed BVH cache file {}", path.display());
+            return None;
+        }
+        let header: [u32; 4] = bytemuck::pod_read_unaligned(&bytes[..HEADER_LEN]);
+        let [_, version, node_count, index_count] = header.map(u32::from_le);
+        if version != BVH_CACHE_VERSION {
+            log::info!("BVH cache file {} is outdated", path.display());
+            return None;
+        }
+
+        let nodes_len = node_count as usize * std::mem::size_of::<BvhNode>();
+        let indices_len = index_count as usize * std::mem::size_of::<u32>();
+        if bytes.len() != HEADER_LEN + nodes_len + indices_len {
+            log::warn!("Ignoring truncated BVH cache file {}", path.display());
+            return None;
+        }
+        let (nodes_bytes, indices_bytes) = bytes[HEADER_LEN..].split_at(nodes_len);
+        let mut nodes = vec![BvhNode::default(); node_count as usize];
+        bytemuck::cast_slice_mut(&mut nodes).copy_from_slice(nodes_bytes);
+        let mut indices = vec![0u32; index_count as usize];
+        bytemuck::cast_slice_mut(&mut indices).copy_from_slice(indices_bytes);
+        if !nodes_fit(&nodes, index_count as usize / 3) {
+            log::warn!("Ignoring corrupted BVH cache file {}", path.display());
+            return None;
+        }
+
+        log::debug!("Loaded BVH from {}", path.display());
+        Some((Bvh::from_nodes(nodes), indices))
+    }
+
+    /// Writes the tree under `key`. Failures only cost a rebuild next time,
+    /// so they are logged instead of returned.
+    pub fn store(&self, key: u64, bvh: &Bvh, indices: &[u32]) {
+        let Some(dir) = &self.dir else {
+            return;
+        };
+        if let Err(err) = Self::write(dir, key, bvh, indices) {
+            log::warn!("Failed to write BVH cache: {err}");
+        }
+    }
+
+    fn write(dir: &Path, key: u64, bvh: &Bvh, indices: &[u32]) -> io::Result<()> {
+        fs::create_dir_all(dir)?;
+        let header = [
+            u32::from_le_bytes(MAGIC),
+            BVH_CACHE_VERSION,
+            bvh.nodes.len() as u32,
+            indices.len() as u32,
+        ];
+        let mut bytes = Vec::with_capacity(
+            HEADER_LEN + std::mem::size_of_val(bvh.nodes.as_slice()) + indices.len() * 4,
+        );
+        bytes.extend_from_slice(bytemuck::cast_slice(&header.map(u32::to_le)));
+        bytes.extend_from_slice(bytemuck::cast_slice(&bvh.nodes));
+        bytes.extend_from_slice(bytemuck::cast_slice(indices));
+
+        // Concurrent launches never observe half written files
+        let path = Self::path(dir, key);
+        let tmp = path.with_extension(format!("{}.tmp", std::process::id()));
+        fs::write(&tmp, bytes)?;
+        fs::rename(tmp, path)
+    }
+}
+
+/// Whether every node reachable from the root references children after
+/// itself and within `nodes`, and leaves only reference triangles below
+/// `triangle_count`. Children after their parent also rule out cycles.
+fn nodes_fit(nodes: &[BvhNode], triangle_count: usize) -> bool {
+    if nodes.is_empty() {
+        return true;
+    }
+    let mut stack = vec![0];
+    while let Some(idx) = stack.pop() {
+        let node = nodes[idx];
+        if node.is_leaf() {
+            if node.triangle_start() + node.triangle_count() > triangle_count {
+                return false;
+            }
+            continue;
+        }
+        let (left, right) = (node.left_node_index(), node.right_node_index());
+        if left <= idx || right >= nodes.len() {
+            return false;
+        }
+        stack.extend([left, right]);
+    }
+    true
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{make_uv_sphere, mesh::build_bvh};
+
+    /// Cache in a fresh directory below the system temp dir.
+    fn temp_cache(name: &str) -> BvhCache {
+        let dir = std::env::temp_dir().join(format!("bvh_cache_{name}_{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        BvhCache::new(dir)
+    }
+
+    fn built_sphere(spatial_split_budget: Option<f32>) -> (u64, Bvh, Vec<u32>) {
+        let sphere = make_uv_sphere(1., 4);
+        let mut mesh = sphere.as_ref();
+        let key = BvhCache::key(mesh.vertices, &mesh.indices, spatial_split_budget);
+        let bvh = build_bvh(&mut mesh, spatial_split_budget);
+        (key, bvh, mesh.indices)
+    }
+
+    fn cache_file(cache: &BvhCache, key: u64) -> PathBuf {
+        BvhCache::path(cache.dir().unwrap(), key)
+    }
+
+    #[test]
+    fn stored_trees_load_back() {
+        let cache = temp_cache("round_trip");
+        for budget in [None, Some(0.3)] {
+            let (key, bvh, indices) = built_sphere(budget);
+            assert!(cache.load(key).is_none());
+
+            cache.store(key, &bvh, &indices);
+            let (loaded, loaded_indices) = cache.load(key).unwrap();
+            assert_eq!(
+                bytemuck::cast_slice::<_, u8>(&loaded.nodes),
+                bytemuck::cast_slice(&bvh.nodes)
+            );
+            assert_eq!(loaded_indices, indices);
+            assert_eq!(loaded.depth(), bvh.depth());
+            assert!(cache.load(key + 1).is_none());
+        }
+
+        fs::remove_dir_all(cache.dir().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn other_versions_and_truncated_files_miss() {
+        let cache = temp_cache("invalid");
+        let (key, bvh, indices) = built_sphere(None);
+        cache.store(key, &bvh, &indices);
+        let path = cache_file(&cache, key);
+        let bytes = fs::read(&path).unwrap();
+
+        let mut outdated = bytes.clone();
+        outdated[4..8].copy_from_slice(&(BVH_CACHE_VERSION + 1).to_le_bytes());
+        fs::write(&path, outdated).unwrap();
+        assert!(cache.load(key).is_none());
+
+        fs::write(&path, &bytes[..bytes.len() - 4]).unwrap();
+        assert!(cache.load(key).is_none());
+        fs::write(&path, &bytes[..HEADER_LEN - 1]).unwrap();
+        assert!(cache.load(key).is_none());
+
+        fs::write(&path, &bytes).unwrap();
+        assert!(cache.load(key).is_some());
+
+        fs::remove_dir_all(cache.dir().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn out_of_range_nodes_miss() {
+        let cache = temp_cache("corrupted");
+        let (key, bvh, indices) = built_sphere(None);
+        cache.store(key, &bvh, &indices);
+        let path = cache_file(&cache, key);
+        let bytes = fs::read(&path).unwrap();
+
+        let triangles = indices.len() as u32 / 3;
+        let leaf = bvh.nodes.iter().rposition(|node| node.is_leaf()).unwrap();
+        let interior = bvh.nodes.iter().position(|node| !node.is_leaf()).unwrap();
+        let left_first = std::mem::offset_of!(BvhNode, left_first);
+        let count = std::mem::offset_of!(BvhNode, count);
+        let corruptions = [
+            (leaf, left_first, triangles),
+            (leaf, count, triangles + 1),
+            (interior, left_first, u32::MAX - 1),
+            // Cycle back to the root
+            (interior, left_first, 0),
+        ];
+        for (node, field, value) in corruptions {
+            let mut corrupted = bytes.clone();
+            let offset = HEADER_LEN + node * std::mem::size_of::<BvhNode>() + field;
+            corrupted[offset..offset + 4].copy_from_slice(&value.to_le_bytes());
+            fs::write(&path, corrupted).unwrap();
+            assert!(cache.load(key).is_none());
+        }
+        fs::write(&path, &bytes).unwrap();
+        assert!(cache.load(key).is_some());
+
+        fs::remove_dir_all(cache.dir().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn clear_only_removes_trees() {
+        let cache = temp_cache("clear");
+        assert!(cache.clear().is_ok());
+
+        let (key, bvh, indices) = built_sphere(None);
+        cache.store(key, &bvh, &indices);
+        let other = cache.dir().unwrap().join("notes.txt");
+        fs::write(&other, "kept").unwrap();
+
+        cache.clear().unwrap();
+        assert!(cache.load(key).is_none());
+        assert!(!cache_file(&cache, key).exists());
+        assert!(other.exists());
+
+        assert!(BvhCache::disabled().clear().is_ok());
+        fs::remove_dir_all(cache.dir().unwrap()).unwrap();
+    }
+}
@@ -1,10 +1,10 @@
 mod boxx;
+mod bvh_cache;
 mod cube;
 mod plane;
 mod sphere;
 
-use core::sync::atomic::{AtomicU32, Ordering};
-use std::sync::Arc;
+use std::{collections::HashSet, ops::Range, sync::Arc};
 
 use glam::{Vec2, Vec3, Vec4};
 
@@ -12,9 +12,16 @@ use components::bind_group_layout::{self, WrappedBindGroupLayout};
 use components::{BindGroupLayout, Gpu, Instance, MeshId, MeshInfo};
 use components::{NonZeroSized, ResizableBuffer, ResizableBufferExt};
 
-use bvh::{BvhBuilder, BvhNode, Tlas, TlasNode};
+use bvh::{
+    Aabb, Bvh, BvhBuilder, BvhNode, Hit, InstanceClosestPoint, ProceduralPrimitive, Ray, Tlas,
+    TlasNode, WGSL_STACK_LEN,
+};
+use rayon::prelude::*;
+
+use crate::{InstancePool, RangeAllocator, SlotAllocator};
 
 pub use boxx::make_box_mesh;
+pub use bvh_cache::{BvhCache, BVH_CACHE_VERSION};
 pub use cube::make_cube_mesh;
 pub use plane::make_plane_mesh;
 pub use sphere::make_uv_sphere;
@@ -54,11 +61,22 @@ pub struct MeshRef<'a> {
     pub indices: Vec<u32>,
 }
 
+/// Buffer ranges owned by a mesh, in elements of each buffer.
+#[derive(Clone, Debug, Default)]
+struct MeshAllocation {
+    vertices: Range<u32>,
+    indices: Range<u32>,
+    bvh_nodes: Range<u32>,
+    primitives: Range<u32>,
+}
+
 pub struct MeshPool {
-    vertex_offset: AtomicU32,
-    base_index: AtomicU32,
-    mesh_index: AtomicU32,
-    bvh_index: AtomicU32,
+    meshes: SlotAllocator,
+    allocations: Vec<MeshAllocation>,
+    vertex_ranges: RangeAllocator,
+    index_ranges: RangeAllocator,
+    bvh_ranges: RangeAllocator,
+    primitive_ranges: RangeAllocator,
 
     pub mesh_info_layout: bind_group_layout::BindGroupLayout,
     pub mesh_info_bind_group: wgpu::BindGroup,
@@ -71,6 +89,17 @@ pub struct MeshPool {
     pub tex_coords: ResizableBuffer<Vec2>,
     pub indices: ResizableBuffer<u32>,
     pub bvh_nodes: ResizableBuffer<BvhNode>,
+    pub primitives: ResizableBuffer<ProceduralPrimitive>,
+
+    pub vertices_cpu: Vec<Vec3>,
+    pub indices_cpu: Vec<u32>,
+    pub primitives_cpu: Vec<ProceduralPrimitive>,
+    pub blases: Vec<Bvh>,
+    /// Extra triangle references BLAS builds may add with spatial splits,
+    /// relative to the triangle count. `None` builds with object splits only.
+    pub spatial_split_budget: Option<f32>,
+    /// Built BLASes are looked up here before building and stored after.
+    pub bvh_cache: BvhCache,
 
     pub tlas: Tlas,
     pub tlas_nodes: ResizableBuffer<TlasNode>,
@@ -78,14 +107,19 @@ pub struct MeshPool {
     pub trace_bind_group_layout: BindGroupLayout,
     pub trace_bind_group: wgpu::BindGroup,
 
+    tlas_refit_order: ResizableBuffer<u32>,
+    tlas_refit_levels: ResizableBuffer<[u32; 2]>,
+    pub tlas_refit_bind_group_layout: BindGroupLayout,
+    pub tlas_refit_bind_group: Option<wgpu::BindGroup>,
+
     gpu: Arc<Gpu>,
 }
 
 impl MeshPool {
-    pub const HORISONTAL_PLANE_MESH: MeshId = MeshId::new(0);
-    pub const VERTICAL_PLANE_MESH: MeshId = MeshId::new(1);
-    pub const SPHERE_1_MESH: MeshId = MeshId::new(2);
-    pub const SPHERE_10_MESH: MeshId = MeshId::new(3);
+    pub const HORISONTAL_PLANE_MESH: MeshId = MeshId::new(0, 0);
+    pub const VERTICAL_PLANE_MESH: MeshId = MeshId::new(1, 0);
+    pub const SPHERE_1_MESH: MeshId = MeshId::new(2, 0);
+    pub const SPHERE_10_MESH: MeshId = MeshId::new(3, 0);
 
     pub fn new(gpu: Arc<Gpu>) -> Self {
         let vertices = gpu
@@ -99,13 +133,16 @@ impl MeshPool {
             .create_resizable_buffer(wgpu::BufferUsages::VERTEX);
         let tex_coords = gpu
             .device()
-            .create_resizable_buffer(wgpu::BufferUsages::VERTEX);
+            .create_resizable_buffer(wgpu::BufferUsages::VERTEX | wgpu::BufferUsages::STORAGE);
         let indices = gpu
             .device()
             .create_resizable_buffer(wgpu::BufferUsages::INDEX | wgpu::BufferUsages::STORAGE);
         let bvh_nodes = gpu
             .device()
             .create_resizable_buffer(wgpu::BufferUsages::STORAGE);
+        let primitives = gpu
+            .device()
+            .create_resizable_buffer(wgpu::BufferUsages::STORAGE);
         let tlas = Tlas::empty();
         let tlas_nodes = gpu
             .device()
@@ -198,50 +235,81 @@ impl MeshPool {
                             },
                             count: None,
                         },
+                        wgpu::BindGroupLayoutEntry {
+                            binding: 6,
+                            visibility: wgpu::ShaderStages::FRAGMENT,
+                            ty: wgpu::BindingType::Buffer {
+                                ty: wgpu::BufferBindingType::Storage { read_only: true },
+                                has_dynamic_offset: false,
+                                min_binding_size: Some(Vec2::NSIZE),
+                            },
+                            count: None,
+                        },
+                        wgpu::BindGroupLayoutEntry {
+                            binding: 7,
+                            visibility: wgpu::ShaderStages::FRAGMENT,
+                            ty: wgpu::BindingType::Buffer {
+                                ty: wgpu::BufferBindingType::Storage { read_only: true },
+                                has_dynamic_offset: false,
+                                min_binding_size: Some(ProceduralPrimitive::NSIZE),
+                            },
+                            count: None,
+                        },
                     ],
                 });
 
-        let trace_bind_group = {
-            let instances = gpu
-                .device()
-                .create_resizable_buffer::<Instance>(wgpu::BufferUsages::STORAGE);
-            gpu.device().create_bind_group(&wgpu::BindGroupDescriptor {
-                label: Some("Trace BG"),
-                layout: &trace_bind_group_layout,
-                entries: &[
-                    wgpu::BindGroupEntry {
-                        binding: 0,
-                        resource: tlas_nodes.as_tight_binding(),
-                    },
-                    wgpu::BindGroupEntry {
-                        binding: 1,
-                        resource: instances.as_tight_binding(),
-                    },
-                    wgpu::BindGroupEntry {
-                        binding: 2,
-                        resource: mesh_info.as_entire_binding(),
-                    },
-                    wgpu::BindGroupEntry {
-                        binding: 3,
-                        resource: bvh_nodes.as_entire_binding(),
-                    },
-                    wgpu::BindGroupEntry {
-                        binding: 4,
-                        resource: vertices.as_entire_binding(),
-                    },
-                    wgpu::BindGroupEntry {
-                        binding: 5,
-                        resource: indices.as_entire_binding(),
-                    },
-                ],
-            })
+        let instances = gpu
+            .device()
+            .create_resizable_buffer::<Instance>(wgpu::BufferUsages::STORAGE);
+        let trace_bind_group = Self::trace_bind_group(
+            gpu.device(),
+            &trace_bind_group_layout,
+            &tlas_nodes,
+            &instances,
+            &mesh_info,
+            &bvh_nodes,
+            &vertices,
+            &indices,
+            &tex_coords,
+            &primitives,
+        );
+
+        let storage_entry = |binding, read_only| wgpu::BindGroupLayoutEntry {
+            binding,
+            visibility: wgpu::ShaderStages::COMPUTE,
+            ty: wgpu::BindingType::Buffer {
+                ty: wgpu::BufferBindingType::Storage { read_only },
+                has_dynamic_offset: false,
+                min_binding_size: None,
+            },
+            count: None,
         };
+        let tlas_refit_bind_group_layout =
+            gpu.device()
+                .create_bind_group_layout_wrap(&wgpu::BindGroupLayoutDescriptor {
+                    label: Some("Tlas Refit BGL"),
+                    entries: &[
+                        storage_entry(0, false),
+                        storage_entry(1, true),
+                        storage_entry(2, true),
+                        storage_entry(3, true),
+                        storage_entry(4, true),
+                    ],
+                });
+        let tlas_refit_order = gpu
+            .device()
+            .create_resizable_buffer(wgpu::BufferUsages::STORAGE);
+        let tlas_refit_levels = gpu
+            .device()
+            .create_resizable_buffer(wgpu::BufferUsages::STORAGE);
 
         let mut this = Self {
-            vertex_offset: AtomicU32::new(0),
-            base_index: AtomicU32::new(0),
-            mesh_index: AtomicU32::new(0),
-            bvh_index: AtomicU32::new(0),
+            meshes: SlotAllocator::default(),
+            allocations: vec![],
+            vertex_ranges: RangeAllocator::default(),
+            index_ranges: RangeAllocator::default(),
+            bvh_ranges: RangeAllocator::default(),
+            primitive_ranges: RangeAllocator::default(),
 
             mesh_info_layout,
             mesh_info_bind_group,
@@ -254,6 +322,14 @@ impl MeshPool {
             tangents,
             tex_coords,
             bvh_nodes,
+            primitives,
+
+            vertices_cpu: vec![],
+            indices_cpu: vec![],
+            primitives_cpu: vec![],
+            blases: vec![],
+            spatial_split_budget: None,
+            bvh_cache: BvhCache::from_env(),
 
             tlas,
             tlas_nodes,
@@ -261,6 +337,11 @@ impl MeshPool {
             trace_bind_group_layout,
             trace_bind_group,
 
+            tlas_refit_order,
+            tlas_refit_levels,
+            tlas_refit_bind_group_layout,
+            tlas_refit_bind_group: None,
+
             gpu,
         };
 
@@ -276,13 +357,235 @@ impl MeshPool {
         this
     }
 
-    pub fn generate_tlas(&mut self, instances: &[Instance]) {
-        if instances.is_empty() {
+    /// Builds the TLAS over `instances` and rebinds everything that
+    /// references instance or TLAS buffers.
+    ///
+    /// The uploaded bounds come from the CPU copy, which trails transforms
+    /// written by compute passes. Run `tlas_refit.wgsl` afterwards to fit
+    /// them to the GPU instances.
+    pub fn generate_tlas(&mut self, instances: &InstancePool) {
+        self.tlas_nodes.clear();
+        self.tlas
+            .build(&instances.instances_data, &self.mesh_info_cpu);
+        if self.tlas.depth() + 1 > WGSL_STACK_LEN {
+            log::warn!(
+                "TLAS is {} levels deep, shaders skip subtrees past {WGSL_STACK_LEN} stack entries",
+                self.tlas.depth()
+            );
+        }
+        // Shaders always start from the root, without live instances it's an
+        // inner node over two leaves with inverted bounds no ray enters. Only
+        // the root is refit, the leaves don't reference any instance
+        let levels = if self.tlas.nodes.is_empty() {
+            let empty = Aabb::empty();
+            let leaf = TlasNode {
+                min: empty.min,
+                max: empty.max,
+                ..Default::default()
+            };
+            let root = TlasNode { left: 1, ..leaf };
+            self.tlas_nodes.push(&self.gpu, &[root, leaf, leaf]);
+            vec![vec![0]]
+        } else {
+            self.tlas_nodes.push(&self.gpu, &self.tlas.nodes);
+            self.tlas.refit_levels()
+        };
+        let mut offset = 0;
+        let ranges: Vec<_> = levels
+            .iter()
+            .map(|level| {
+                let range = [offset, level.len() as u32];
+                offset += level.len() as u32;
+                range
+            })
+            .collect();
+        self.tlas_refit_order.clear();
+        self.tlas_refit_order.push(&self.gpu, &levels.concat());
+        self.tlas_refit_levels.clear();
+        self.tlas_refit_levels.push(&self.gpu, &ranges);
+
+        self.update_bind_groups(instances);
+    }
+
+    /// Refits the CPU TLAS to moved instances, cheaper than
+    /// [`MeshPool::generate_tlas`] while no instance was added or removed.
+    ///
+    /// Only queries use it, the GPU nodes are refit by `tlas_refit.wgsl`
+    /// from the same instances buffer compute passes write to.
+    pub fn refit_tlas(&mut self, instances: &InstancePool) {
+        if self.tlas.nodes.is_empty() {
             return;
         }
-        self.tlas_nodes.clear();
-        self.tlas.build(instances, &self.mesh_info_cpu);
-        self.tlas_nodes.push(&self.gpu, &self.tlas.nodes);
+        self.tlas
+            .refit(&instances.instances_data, &self.mesh_info_cpu);
+    }
+
+    /// Recreates bind groups after instance, mesh or TLAS buffers were reallocated.
+    pub fn update_bind_groups(&mut self, instances: &InstancePool) {
+        self.trace_bind_group = Self::trace_bind_group(
+            self.gpu.device(),
+            &self.trace_bind_group_layout,
+            &self.tlas_nodes,
+            &instances.instances,
+            &self.mesh_info,
+            &self.bvh_nodes,
+            &self.vertices,
+            &self.indices,
+            &self.tex_coords,
+            &self.primitives,
+        );
+
+        if self.tlas_nodes.is_empty() {
+            self.tlas_refit_bind_group = None;
+            return;
+        }
+        self.tlas_refit_bind_group = Some(self.gpu.device().create_bind_group(
+            &wgpu::BindGroupDescriptor {
+                label: Some("Tlas Refit BG"),
+                layout: &self.tlas_refit_bind_group_layout,
+                entries: &[
+                    wgpu::BindGroupEntry {
+                        binding: 0,
+                        resource: self.tlas_nodes.as_tight_binding(),
+                    },
+                    wgpu::BindGroupEntry {
+                        binding: 1,
+                        resource: instances.instances.as_tight_binding(),
+                    },
+                    wgpu::BindGroupEntry {
+                        binding: 2,
+                        resource: self.mesh_info.as_tight_binding(),
+                    },
+                    wgpu::BindGroupEntry {
+                        binding: 3,
+                        resource: self.tlas_refit_order.as_tight_binding(),
+                    },
+                    wgpu::BindGroupEntry {
+                        binding: 4,
+                        resource: self.tlas_refit_levels.as_tight_binding(),
+                    },
+                ],
+            },
+        ));
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    fn trace_bind_group(
+        device: &wgpu::Device,
+        layout: &wgpu::BindGroupLayout,
+        tlas_nodes: &ResizableBuffer<TlasNode>,
+        instances: &ResizableBuffer<Instance>,
+        mesh_info: &ResizableBuffer<MeshInfo>,
+        bvh_nodes: &ResizableBuffer<BvhNode>,
+        vertices: &ResizableBuffer<Vec3>,
+        indices: &ResizableBuffer<u32>,
+        tex_coords: &ResizableBuffer<Vec2>,
+        primitives: &ResizableBuffer<ProceduralPrimitive>,
+    ) -> wgpu::BindGroup {
+        device.create_bind_group(&wgpu::BindGroupDescriptor {
+            label: Some("Trace BG"),
+            layout,
+            entries: &[
+                wgpu::BindGroupEntry {
+                    binding: 0,
+                    resource: tlas_nodes.as_tight_binding(),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 1,
+                    resource: instances.as_tight_binding(),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 2,
+                    resource: mesh_info.as_entire_binding(),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 3,
+                    resource: bvh_nodes.as_entire_binding(),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 4,
+                    resource: vertices.as_entire_binding(),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 5,
+                    resource: indices.as_entire_binding(),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 6,
+                    resource: tex_coords.as_entire_binding(),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 7,
+                    resource: primitives.as_entire_binding(),
+                },
+            ],
+        })
+    }
+
+    /// Traces a world space ray against the last generated TLAS.
+    pub fn intersect(&self, ray: Ray, instances: &[Instance]) -> Option<Hit> {
+        self.tlas.intersect(
+            ray,
+            instances,
+            &self.mesh_info_cpu,
+            &self.blases,
+            &self.vertices_cpu,
+            &self.indices_cpu,
+            &self.primitives_cpu,
+        )
+    }
+
+    pub fn occluded(&self, ray: Ray, t_max: f32, instances: &[Instance]) -> bool {
+        self.tlas.occluded(
+            ray,
+            t_max,
+            instances,
+            &self.mesh_info_cpu,
+            &self.blases,
+            &self.vertices_cpu,
+            &self.indices_cpu,
+            &self.primitives_cpu,
+        )
+    }
+
+    /// Occlusion query whose hits only count when `alpha_test` accepts them.
+    pub fn occluded_with(
+        &self,
+        ray: Ray,
+        t_max: f32,
+        instances: &[Instance],
+        alpha_test: impl FnMut(&Hit) -> bool,
+    ) -> bool {
+        self.tlas.occluded_with(
+            ray,
+            t_max,
+            instances,
+            &self.mesh_info_cpu,
+            &self.blases,
+            &self.vertices_cpu,
+            &self.indices_cpu,
+            &self.primitives_cpu,
+            alpha_test,
+        )
+    }
+
+    /// Finds the closest point on any instance of the last generated TLAS.
+    pub fn closest_point(
+        &self,
+        point: Vec3,
+        max_dist: f32,
+        instances: &[Instance],
+    ) -> Option<InstanceClosestPoint> {
+        self.tlas.closest_point(
+            point,
+            max_dist,
+            instances,
+            &self.mesh_info_cpu,
+            &self.blases,
+            &self.vertices_cpu,
+            &self.indices_cpu,
+            &self.primitives_cpu,
+        )
     }
 
     pub fn mesh_info_bind_group(
@@ -302,51 +605,686 @@ impl MeshPool {
         bind_group
     }
 
+    /// Number of mesh ids handed out so far, including removed ones.
     pub fn count(&self) -> u32 {
-        self.mesh_index.load(Ordering::Relaxed)
+        self.meshes.len() as u32
+    }
+
+    /// Whether `mesh` is still in the pool. Ids of removed meshes are stale
+    /// even after a later mesh reused their slot.
+    pub fn contains(&self, mesh: MeshId) -> bool {
+        self.meshes.contains(mesh.index(), mesh.generation())
     }
 
     pub fn add(&mut self, mut mesh: MeshRef) -> MeshId {
-        let vertex_count = mesh.vertices.len() as u32;
-        let vertex_offset = self
-            .vertex_offset
-            .fetch_add(vertex_count, Ordering::Relaxed);
+        let bvh = build_cached_bvh(&mut mesh, self.spatial_split_budget, &self.bvh_cache);
+        self.upload(mesh, bvh)
+    }
 
-        self.vertices.push(&self.gpu, mesh.vertices);
-        self.normals.push(&self.gpu, mesh.normals);
-        self.tangents.push(&self.gpu, mesh.tangents);
-        self.tex_coords.push(&self.gpu, mesh.tex_coords);
+    /// Builds BVHs of all `meshes` concurrently and uploads them in order.
+    pub fn add_many(&mut self, mut meshes: Vec<MeshRef>) -> Vec<MeshId> {
+        let (budget, cache) = (self.spatial_split_budget, &self.bvh_cache);
+        let bvhs: Vec<_> = meshes
+            .par_iter_mut()
+            .map(|mesh| build_cached_bvh(mesh, budget, cache))
+            .collect();
+        meshes
+            .into_iter()
+            .zip(bvhs)
+            .map(|(mesh, bvh)| self.upload(mesh, bvh))
+            .collect()
+    }
 
-        let bvh =
-            BvhBuilder::new(mesh.vertices, bytemuck::cast_slice_mut(&mut mesh.indices)).build();
-        let bvh_index = self
-            .bvh_index
-            .fetch_add(bvh.nodes.len() as u32, Ordering::Relaxed);
-        self.bvh_nodes.push(&self.gpu, &bvh.nodes);
+    /// Uploads `mesh` without building its BVH on the CPU.
+    ///
+    /// Nodes for a GPU build are reserved instead and miss every ray until
+    /// an LBVH build over the mesh is recorded. CPU queries skip such meshes.
+    ///
+    /// LBVH depth isn't limited to [`bvh::MAX_DEPTH`]. Over evenly spread
+    /// triangles it's about `2 * log2(n)`, meshes where that doesn't fit the
+    /// shader stack get a warning as traversal would skip their subtrees.
+    pub fn add_with_gpu_bvh(&mut self, mesh: MeshRef) -> MeshId {
+        let triangles = mesh.indices.len() / 3;
+        let expected_depth = 2 * triangles.next_power_of_two().trailing_zeros() as usize;
+        let id = self.upload(mesh, Bvh::empty());
+        if expected_depth + 1 > WGSL_STACK_LEN {
+            log::warn!(
+                "LBVH of mesh {} over {triangles} triangles is likely {expected_depth} levels deep, \
+                 shaders skip subtrees past {WGSL_STACK_LEN} stack entries, add it with a CPU BVH instead",
+                id.index()
+            );
+        }
+        id
+    }
 
-        let index_count = mesh.indices.len() as u32;
-        let base_index = self.base_index.fetch_add(index_count, Ordering::Relaxed);
+    /// Adds a mesh made of analytic primitives. It's traced like any other
+    /// mesh, but has no triangles to rasterize.
+    pub fn add_procedural(&mut self, primitives: &[ProceduralPrimitive]) -> MeshId {
+        assert!(!primitives.is_empty(), "Procedural mesh without primitives");
+        let mut primitives = primitives.to_vec();
+        let bvh = Bvh::build_procedural(&mut primitives);
 
-        self.indices.push(&self.gpu, &mesh.indices);
-        let mesh_index = self.mesh_index.fetch_add(1, Ordering::Relaxed);
+        let mesh_index = self.meshes.allocate();
+        let allocation = MeshAllocation {
+            bvh_nodes: self.bvh_ranges.allocate(bvh.nodes.len() as u32),
+            primitives: self.primitive_ranges.allocate(primitives.len() as u32),
+            ..Default::default()
+        };
+        write_range(
+            &self.gpu,
+            &mut self.bvh_nodes,
+            allocation.bvh_nodes.start,
+            &bvh.nodes,
+        );
+        write_range(
+            &self.gpu,
+            &mut self.primitives,
+            allocation.primitives.start,
+            &primitives,
+        );
+        write_cpu(
+            &mut self.primitives_cpu,
+            allocation.primitives.start,
+            &primitives,
+        );
 
-        let (min, max) = calculate_bounds(mesh.vertices);
+        let root = bvh.nodes[0];
+        let mesh_info = MeshInfo {
+            min: root.min,
+            vertex_offset: 0,
+            max: root.max,
+            base_index: 0,
+            index_count: 0,
+            bvh_index: allocation.bvh_nodes.start,
+            first_primitive: allocation.primitives.start,
+            primitive_count: primitives.len() as u32,
+        };
+        self.set_mesh(mesh_index, allocation, bvh, mesh_info);
+
+        log::info!("Added new procedural mesh with id: {mesh_index}");
+        MeshId::new(mesh_index, self.meshes.generation(mesh_index))
+    }
+
+    /// Stores the CPU side of a mesh in its slot, which is either reused or
+    /// the next one.
+    fn set_mesh(&mut self, mesh_index: u32, allocation: MeshAllocation, bvh: Bvh, info: MeshInfo) {
+        if bvh.depth() + 1 > WGSL_STACK_LEN {
+            log::warn!(
+                "BVH of mesh {mesh_index} is {} levels deep, shaders skip subtrees past {WGSL_STACK_LEN} stack entries",
+                bvh.depth()
+            );
+        }
+        let slot = mesh_index as usize;
+        if slot < self.mesh_info_cpu.len() {
+            self.allocations[slot] = allocation;
+            self.blases[slot] = bvh;
+            self.mesh_info_cpu[slot] = info;
+            self.mesh_info.write(&self.gpu, slot, info);
+            return;
+        }
+        self.allocations.push(allocation);
+        self.blases.push(bvh);
+        self.mesh_info_cpu.push(info);
+        self.mesh_info.push(&self.gpu, &[info]);
+        self.mesh_info_bind_group =
+            Self::mesh_info_bind_group(self.gpu.device(), &self.mesh_info_layout, &self.mesh_info);
+    }
+
+    /// Frees the buffer ranges of `mesh` for meshes added later, which may
+    /// also reuse its slot under a new generation.
+    ///
+    /// Instances still referencing it are removed on the next sync of the
+    /// instance pool, see [`InstancePool::remove_stale_meshes`].
+    pub fn remove(&mut self, mesh: MeshId) {
+        let mesh_index = mesh.index();
+        if !self.contains(mesh) {
+            log::warn!(
+                "Attempted to remove stale mesh {mesh_index} of generation {}",
+                mesh.generation()
+            );
+            return;
+        }
+        self.meshes.free(mesh_index);
+        let slot = mesh_index as usize;
+        let allocation = std::mem::take(&mut self.allocations[slot]);
+        self.vertex_ranges.free(allocation.vertices);
+        self.index_ranges.free(allocation.indices);
+        self.bvh_ranges.free(allocation.bvh_nodes);
+        self.primitive_ranges.free(allocation.primitives);
+
+        // Draws and traversals of a stale id find an empty mesh
+        self.blases[slot] = Bvh::empty();
+        self.mesh_info_cpu[slot] = MeshInfo::default();
+        self.mesh_info.write(&self.gpu, slot, MeshInfo::default());
+        log::info!("Removed mesh with id: {mesh_index}");
+    }
+
+    /// Moves all meshes to the front of their buffers, closing the holes
+    /// left by [`MeshPool::remove`], and rewrites offsets in their
+    /// [`MeshInfo`]. Mesh ids are kept.
+    ///
+    /// Geometry buffers get reallocated, bind groups of the pool are
+    /// recreated while passes binding them on their own have to do the same.
+    pub fn defragment(&mut self, instances: &InstancePool) {
+        let holes = self.vertex_ranges.free_len()
+            + self.index_ranges.free_len()
+            + self.bvh_ranges.free_len()
+            + self.primitive_ranges.free_len();
+        if holes == 0 {
+            return;
+        }
+
+        let mut vertex_moves = vec![];
+        let mut index_moves = vec![];
+        let mut bvh_moves = vec![];
+        let mut primitive_moves = vec![];
+        let mut ends = [0u32; 4];
+        for mesh_index in self.meshes.alive() {
+            let slot = mesh_index as usize;
+            let allocation = &mut self.allocations[slot];
+            let compact = |range: &mut Range<u32>, end: &mut u32, moves: &mut Vec<_>| {
+                let len = range.end - range.start;
+                moves.push((range.clone(), *end));
+                *range = *end..*end + len;
+                *end += len;
+            };
+            let [vertex_end, index_end, bvh_end, primitive_end] = &mut ends;
+            compact(&mut allocation.vertices, vertex_end, &mut vertex_moves);
+            compact(&mut allocation.indices, index_end, &mut index_moves);
+            compact(&mut allocation.bvh_nodes, bvh_end, &mut bvh_moves);
+            compact(
+                &mut allocation.primitives,
+                primitive_end,
+                &mut primitive_moves,
+            );
+
+            let info = &mut self.mesh_info_cpu[slot];
+            info.vertex_offset = allocation.vertices.start as i32;
+            info.base_index = allocation.indices.start;
+            info.bvh_index = allocation.bvh_nodes.start;
+            info.first_primitive = allocation.primitives.start;
+        }
+
+        let gpu = &self.gpu;
+        let mut encoder = gpu
+            .device()
+            .create_command_encoder(&wgpu::CommandEncoderDescriptor {
+                label: Some("Mesh Defragment Encoder"),
+            });
+        let [vertex_end, index_end, bvh_end, primitive_end] = ends;
+        self.vertices =
+            compact_buffer(gpu, &mut encoder, &self.vertices, &vertex_moves, vertex_end);
+        self.normals = compact_buffer(gpu, &mut encoder, &self.normals, &vertex_moves, vertex_end);
+        self.tangents =
+            compact_buffer(gpu, &mut encoder, &self.tangents, &vertex_moves, vertex_end);
+        self.tex_coords = compact_buffer(
+            gpu,
+            &mut encoder,
+            &self.tex_coords,
+            &vertex_moves,
+            vertex_end,
+        );
+        self.indices = compact_buffer(gpu, &mut encoder, &self.indices, &index_moves, index_end);
+        self.bvh_nodes = compact_buffer(gpu, &mut encoder, &self.bvh_nodes, &bvh_moves, bvh_end);
+        self.primitives = compact_buffer(
+            gpu,
+            &mut encoder,
+            &self.primitives,
+            &primitive_moves,
+            primitive_end,
+        );
+        gpu.queue().submit(Some(encoder.finish()));
+
+        self.vertices_cpu = compact_cpu(&self.vertices_cpu, &vertex_moves);
+        self.indices_cpu = compact_cpu(&self.indices_cpu, &index_moves);
+        self.primitives_cpu = compact_cpu(&self.primitives_cpu, &primitive_moves);
+
+        self.vertex_ranges.reset(vertex_end);
+        self.index_ranges.reset(index_end);
+        self.bvh_ranges.reset(bvh_end);
+        self.primitive_ranges.reset(primitive_end);
+
+        self.mesh_info.write_slice(gpu, 0, &self.mesh_info_cpu);
+        self.update_bind_groups(instances);
+        log::info!("Defragmented mesh buffers, {holes} free elements reclaimed");
+    }
+
+    fn upload(&mut self, mesh: MeshRef, bvh: Bvh) -> MeshId {
+        assert_attributes_match(&mesh);
+        let mesh_index = self.meshes.allocate();
+        let vertices = self.vertex_ranges.allocate(mesh.vertices.len() as u32);
+        let gpu = &self.gpu;
+        write_range(gpu, &mut self.vertices, vertices.start, mesh.vertices);
+        write_cpu(&mut self.vertices_cpu, vertices.start, mesh.vertices);
+        write_range(gpu, &mut self.normals, vertices.start, mesh.normals);
+        write_range(gpu, &mut self.tangents, vertices.start, mesh.tangents);
+        write_range(gpu, &mut self.tex_coords, vertices.start, mesh.tex_coords);
+
+        // Traversal takes the root without a box test and tests its two
+        // children, inverted bounds keep rays out of nodes without a tree
+        let empty = Aabb::empty();
+        let placeholder = BvhNode {
+            min: empty.min,
+            max: empty.max,
+            ..Default::default()
+        };
+        let gpu_nodes;
+        let (nodes, (min, max)) = if mesh.indices.is_empty() {
+            // Infinite bounds would turn bounds of its instances into NaN
+            gpu_nodes = vec![placeholder; 2];
+            (&gpu_nodes, (Vec3::ZERO, Vec3::ZERO))
+        } else if bvh.is_empty() {
+            // LBVH over n triangles takes 2n nodes, including the unused second one
+            gpu_nodes = vec![placeholder; (mesh.indices.len() / 3).max(1) * 2];
+            (&gpu_nodes, calculate_bounds(mesh.vertices))
+        } else {
+            (&bvh.nodes, calculate_bounds(mesh.vertices))
+        };
+        let bvh_nodes = self.bvh_ranges.allocate(nodes.len() as u32);
+        write_range(gpu, &mut self.bvh_nodes, bvh_nodes.start, nodes);
+
+        let indices = self.index_ranges.allocate(mesh.indices.len() as u32);
+        write_range(gpu, &mut self.indices, indices.start, &mesh.indices);
+        write_cpu(&mut self.indices_cpu, indices.start, &mesh.indices);
 
         let mesh_info = MeshInfo {
             min,
-            vertex_offset: vertex_offset as i32,
+            vertex_offset: vertices.start as i32,
             max,
-            base_index,
-            index_count,
-            bvh_index,
-            junk: [0; 2],
+            base_index: indices.start,
+            index_count: mesh.indices.len() as u32,
+            bvh_index: bvh_nodes.start,
+            first_primitive: 0,
+            primitive_count: 0,
         };
-        self.mesh_info_cpu.push(mesh_info);
-        self.mesh_info.push(&self.gpu, &[mesh_info]);
-        self.mesh_info_bind_group =
-            Self::mesh_info_bind_group(self.gpu.device(), &self.mesh_info_layout, &self.mesh_info);
+        let allocation = MeshAllocation {
+            vertices,
+            indices,
+            bvh_nodes,
+            primitives: 0..0,
+        };
+        self.set_mesh(mesh_index, allocation, bvh, mesh_info);
 
         log::info!("Added new mesh with id: {mesh_index}");
-        MeshId(mesh_index)
+        MeshId::new(mesh_index, self.meshes.generation(mesh_index))
+    }
+
+    /// Replaces vertex positions of `mesh` and refits its BVH in place.
+    ///
+    /// When refitting degraded the tree too much it gets rebuilt with
+    /// [`MeshPool::spatial_split_budget`], as long as the new nodes and
+    /// indices fit into the ranges allocated for the old ones.
+    pub fn update_vertices(&mut self, mesh: MeshId, vertices: &[Vec3]) {
+        assert!(self.contains(mesh), "Mesh {} was removed", mesh.index());
+        let mesh_index = mesh.index() as usize;
+        let info = self.mesh_info_cpu[mesh_index];
+        assert_eq!(info.primitive_count, 0, "Mesh {mesh_index} is procedural");
+        let allocated = &self.allocations[mesh_index].vertices;
+        let vertex_range = allocated.start as usize..allocated.end as usize;
+        let vertex_offset = vertex_range.start;
+        assert_eq!(
+            vertex_range.len(),
+            vertices.len(),
+            "Vertex count of mesh {mesh_index} doesn't match the update"
+        );
+        let index_range = info.base_index as usize..(info.base_index + info.index_count) as usize;
+
+        self.vertices_cpu[vertex_range].copy_from_slice(vertices);
+        self.vertices
+            .write_slice(&self.gpu, vertex_offset, vertices);
+
+        let indices = &mut self.indices_cpu[index_range];
+        let bvh = &mut self.blases[mesh_index];
+        if bvh.is_empty() {
+            log::warn!("Mesh {mesh_index} has a GPU built BVH, it has to be rebuilt on the GPU");
+        }
+        bvh.refit(vertices, bytemuck::cast_slice(indices));
+        let mut index_count = info.index_count;
+        if bvh.rebuild_recommended() {
+            let mut mesh = MeshRef {
+                vertices,
+                normals: &[],
+                tangents: &[],
+                tex_coords: &[],
+                indices: unique_triangles(indices),
+            };
+            // Repeated triangles have to fit into the indices of the old tree
+            let spare = (indices.len() - mesh.indices.len()) as f32 / mesh.indices.len() as f32;
+            let budget = self.spatial_split_budget.map(|budget| budget.min(spare));
+            let rebuilt = build_bvh(&mut mesh, budget);
+            if rebuilt.nodes.len() <= bvh.nodes.len() && mesh.indices.len() <= indices.len() {
+                *bvh = rebuilt;
+                indices[..mesh.indices.len()].copy_from_slice(&mesh.indices);
+                index_count = mesh.indices.len() as u32;
+                self.indices
+                    .write_slice(&self.gpu, info.base_index as usize, &mesh.indices);
+            } else {
+                log::warn!("Rebuilt BVH of mesh {mesh_index} doesn't fit, keeping refitted one");
+            }
+        }
+        if !bvh.is_empty() {
+            self.bvh_nodes
+                .write_slice(&self.gpu, info.bvh_index as usize, &bvh.nodes);
+        }
+
+        let (min, max) = calculate_bounds(vertices);
+        let info = &mut self.mesh_info_cpu[mesh_index];
+        info.min = min;
+        info.max = max;
+        info.index_count = index_count;
+        self.mesh_info.write(&self.gpu, mesh_index, *info);
+    }
+}
+
+/// Builds the BLAS of `mesh`, reordering its indices to match the leaves.
+///
+/// With spatial splits the indices are replaced by a possibly longer buffer
+/// repeating triangles referenced by several leaves.
+fn build_bvh(mesh: &mut MeshRef, spatial_split_budget: Option<f32>) -> Bvh {
+    let builder = BvhBuilder::new(mesh.vertices, bytemuck::cast_slice_mut(&mut mesh.indices));
+    let Some(budget) = spatial_split_budget else {
+        return builder.build();
+    };
+    let (bvh, indices) = builder.spatial_splits(budget).build_indexed();
+    mesh.indices = bytemuck::cast_slice(&indices).to_vec();
+    bvh
+}
+
+/// Attribute buffers share vertex offsets, so a mesh missing some of an
+/// attribute would shift it for every mesh uploaded after it.
+fn assert_attributes_match(mesh: &MeshRef) {
+    let vertex_count = mesh.vertices.len();
+    for (name, len) in [
+        ("normals", mesh.normals.len()),
+        ("tangents", mesh.tangents.len()),
+        ("tex coords", mesh.tex_coords.len()),
+    ] {
+        assert_eq!(
+            len, vertex_count,
+            "Mesh has {len} {name} for {vertex_count} vertices"
+        );
+    }
+}
+
+/// Triangles of `indices` in order of their first appearance, dropping the
+/// repeats spatial splits add.
+fn unique_triangles(indices: &[u32]) -> Vec<u32> {
+    let mut seen = HashSet::new();
+    indices
+        .chunks_exact(3)
+        .filter(|trig| seen.insert([trig[0], trig[1], trig[2]]))
+        .flatten()
+        .copied()
+        .collect()
+}
+
+/// Like [`build_bvh`], but reuses the tree and indices stored in `cache`
+/// for identical geometry and settings.
+fn build_cached_bvh(
+    mesh: &mut MeshRef,
+    spatial_split_budget: Option<f32>,
+    cache: &BvhCache,
+) -> Bvh {
+    if !cache.is_enabled() {
+        return build_bvh(mesh, spatial_split_budget);
+    }
+    let key = BvhCache::key(mesh.vertices, &mesh.indices, spatial_split_budget);
+    if let Some((bvh, indices)) = cache.load(key) {
+        mesh.indices = indices;
+        return bvh;
+    }
+    let bvh = build_bvh(mesh, spatial_split_budget);
+    cache.store(key, &bvh, &mesh.indices);
+    bvh
+}
+
+/// Writes `values` at `offset`, pushing the part reaching past the end of
+/// the buffer. Offsets handed out by a [`RangeAllocator`] never leave gaps.
+fn write_range<T: bytemuck::Pod>(
+    gpu: &Gpu,
+    buffer: &mut ResizableBuffer<T>,
+    offset: u32,
+    values: &[T],
+) {
+    let offset = offset as usize;
+    let (overwritten, pushed) = split_write(buffer.len(), offset, values);
+    if !overwritten.is_empty() {
+        buffer.write_slice(gpu, offset, overwritten);
+    }
+    if !pushed.is_empty() {
+        buffer.push(gpu, pushed);
+    }
+}
+
+/// Splits `values` written at `offset` of a buffer of `len` elements into
+/// the part overwriting elements in use and the part appended past them.
+fn split_write<T>(len: usize, offset: usize, values: &[T]) -> (&[T], &[T]) {
+    assert!(
+        offset <= len,
+        "Write at {offset} leaves a gap after {len} elements"
+    );
+    let inside = len.saturating_sub(offset).min(values.len());
+    values.split_at(inside)
+}
+
+/// CPU counterpart of [`write_range`].
+fn write_cpu<T: Copy + Default>(cpu: &mut Vec<T>, offset: u32, values: &[T]) {
+    let range = offset as usize..offset as usize + values.len();
+    assert!(
+        range.start <= cpu.len(),
+        "Write at {} leaves a gap after {} elements",
+        range.start,
+        cpu.len()
+    );
+    if cpu.len() < range.end {
+        cpu.resize(range.end, T::default());
+    }
+    cpu[range].copy_from_slice(values);
+}
+
+/// Copies `(source range, destination offset)` moves of `buffer` into a new
+/// buffer of `len` elements.
+fn compact_buffer<T: bytemuck::Pod>(
+    gpu: &Gpu,
+    encoder: &mut wgpu::CommandEncoder,
+    buffer: &ResizableBuffer<T>,
+    moves: &[(Range<u32>, u32)],
+    len: u32,
+) -> ResizableBuffer<T> {
+    let mut compacted = gpu.device().create_resizable_buffer(buffer.usages());
+    compacted.set_len(gpu.device(), encoder, len as usize);
+    let size = std::mem::size_of::<T>() as wgpu::BufferAddress;
+    for (source, destination) in moves {
+        if source.is_empty() {
+            continue;
+        }
+        encoder.copy_buffer_to_buffer(
+            buffer,
+            source.start as wgpu::BufferAddress * size,
+            &compacted,
+            *destination as wgpu::BufferAddress * size,
+            source.len() as wgpu::BufferAddress * size,
+        );
+    }
+    compacted
+}
+
+/// CPU counterpart of [`compact_buffer`], moves are in destination order.
+fn compact_cpu<T: Copy>(cpu: &[T], moves: &[(Range<u32>, u32)]) -> Vec<T> {
+    moves
+        .iter()
+        .flat_map(|(source, _)| &cpu[source.start as usize..source.end as usize])
+        .copied()
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use glam::Quat;
+
+    use super::*;
+
+    #[test]
+    fn split_write_overwrites_then_pushes() {
+        let values = [1, 2, 3, 4];
+        // Reused hole in the middle
+        assert_eq!(split_write(10, 2, &values), (&values[..], &[][..]));
+        // Range freed at the end and reallocated with more elements
+        assert_eq!(split_write(6, 4, &values), (&values[..2], &values[2..]));
+        // Fresh range at the end
+        assert_eq!(split_write(6, 6, &values), (&[][..], &values[..]));
+        assert_eq!(split_write::<u32>(6, 6, &[]), (&[][..], &[][..]));
+    }
+
+    #[test]
+    fn write_cpu_matches_split_write() {
+        let mut cpu = vec![0; 6];
+        write_cpu(&mut cpu, 4, &[1, 2, 3, 4]);
+        assert_eq!(cpu, [0, 0, 0, 0, 1, 2, 3, 4]);
+        write_cpu(&mut cpu, 1, &[5, 6]);
+        assert_eq!(cpu, [0, 5, 6, 0, 1, 2, 3, 4]);
+    }
+
+    #[test]
+    #[should_panic(expected = "leaves a gap")]
+    fn split_write_rejects_gaps() {
+        split_write(6, 7, &[1, 2]);
+    }
+
+    #[test]
+    #[should_panic(expected = "leaves a gap")]
+    fn write_cpu_rejects_gaps() {
+        write_cpu(&mut vec![0; 6], 7, &[1, 2]);
+    }
+
+    #[test]
+    #[should_panic(expected = "Mesh has 0 tex coords for")]
+    fn meshes_need_every_attribute() {
+        let plane = make_plane_mesh(1., 1.);
+        assert_attributes_match(&plane.as_ref());
+        assert_attributes_match(&MeshRef {
+            tex_coords: &[],
+            ..plane.as_ref()
+        });
+    }
+
+    #[test]
+    fn clearing_instances_empties_the_tlas() {
+        let Some(gpu) = crate::test_gpu() else {
+            return;
+        };
+        let mut meshes = MeshPool::new(gpu.clone());
+        let mut instances = InstancePool::new(gpu);
+        instances.add(&[Instance::new(
+            glam::Mat4::IDENTITY,
+            MeshPool::SPHERE_1_MESH,
+            Default::default(),
+        )]);
+        instances.flush();
+        meshes.generate_tlas(&instances);
+        let ray = Ray::new(Vec3::new(0., 0., -5.), Vec3::Z);
+        assert!(meshes.intersect(ray, &instances.instances_data).is_some());
+
+        instances.clear();
+        assert!(instances.flush().added_or_removed);
+        meshes.generate_tlas(&instances);
+        assert!(meshes.tlas.nodes.is_empty());
+        assert!(meshes.intersect(ray, &instances.instances_data).is_none());
+        assert!(!meshes.occluded(ray, 10., &instances.instances_data));
+    }
+
+    #[test]
+    fn meshes_without_a_tree_never_hit() {
+        let Some(gpu) = crate::test_gpu() else {
+            return;
+        };
+        let mut meshes = MeshPool::new(gpu.clone());
+        let sphere = make_uv_sphere(1., 4);
+        let gpu_built = meshes.add_with_gpu_bvh(sphere.as_ref());
+        let empty = meshes.add(MeshRef {
+            vertices: &[],
+            normals: &[],
+            tangents: &[],
+            tex_coords: &[],
+            indices: vec![],
+        });
+
+        let nodes = meshes.bvh_nodes.read(&gpu);
+        for mesh in [gpu_built, empty] {
+            let info = meshes.mesh_info_cpu[mesh.index() as usize];
+            assert!(info.min.is_finite() && info.max.is_finite());
+            let range = &meshes.allocations[mesh.index() as usize].bvh_nodes;
+            for node in &nodes[range.start as usize..range.end as usize] {
+                assert!(node.min.cmpgt(node.max).all());
+            }
+        }
+
+        let mut instances = InstancePool::new(gpu);
+        instances.add(&[
+            Instance::new(glam::Mat4::IDENTITY, gpu_built, Default::default()),
+            Instance::new(glam::Mat4::IDENTITY, empty, Default::default()),
+        ]);
+        meshes.generate_tlas(&instances);
+        for node in &meshes.tlas.nodes {
+            assert!(node.min.is_finite() && node.max.is_finite());
+        }
+        let ray = Ray::new(Vec3::new(0., 0., -5.), Vec3::Z);
+        assert!(meshes.intersect(ray, &instances.instances_data).is_none());
+    }
+
+    #[test]
+    fn rebuilt_trees_keep_the_split_budget() {
+        let Some(gpu) = crate::test_gpu() else {
+            return;
+        };
+        let mut meshes = MeshPool::new(gpu);
+        meshes.bvh_cache = BvhCache::disabled();
+        meshes.spatial_split_budget = Some(0.3);
+        let sphere = make_uv_sphere(1., 4);
+        let mesh = meshes.add(sphere.as_ref());
+        let mesh_index = mesh.index() as usize;
+        let allocated = meshes.allocations[mesh_index].indices.clone();
+        let built_indices =
+            meshes.indices_cpu[allocated.start as usize..allocated.end as usize].to_vec();
+        assert!(built_indices.len() > sphere.indices.len());
+
+        // Twisting the sphere degrades the refitted tree
+        let displaced: Vec<_> = sphere
+            .vertices
+            .iter()
+            .map(|&v| Quat::from_rotation_y(v.y * 3.) * v)
+            .collect();
+        meshes.update_vertices(mesh, &displaced);
+
+        let mut expected = MeshRef {
+            vertices: &displaced,
+            indices: unique_triangles(&built_indices),
+            ..sphere.as_ref()
+        };
+        assert_eq!(expected.indices.len(), sphere.indices.len());
+        let spare =
+            (built_indices.len() - expected.indices.len()) as f32 / expected.indices.len() as f32;
+        let expected_bvh = build_bvh(&mut expected, Some(spare.min(0.3)));
+        let blas = &meshes.blases[mesh_index];
+        assert_eq!(
+            bytemuck::cast_slice::<_, u8>(&blas.nodes),
+            bytemuck::cast_slice(&expected_bvh.nodes)
+        );
+
+        let info = meshes.mesh_info_cpu[mesh_index];
+        assert_eq!(info.index_count as usize, expected.indices.len());
+        assert!(info.index_count <= allocated.end - allocated.start);
+        let indices = &meshes.indices_cpu[info.base_index as usize..][..info.index_count as usize];
+        assert_eq!(indices, expected.indices);
+        assert_eq!(
+            meshes.indices.read(&meshes.gpu)[info.base_index as usize..][..indices.len()],
+            *indices
+        );
+    }
+
+    #[test]
+    fn unique_triangles_drop_repeats() {
+        let indices = [0, 1, 2, 2, 3, 0, 0, 1, 2, 1, 0, 2];
+        assert_eq!(unique_triangles(&indices), [0, 1, 2, 2, 3, 0, 1, 0, 2]);
     }
 }
@@ -12,6 +12,9 @@ pub const BLACK_TEXTURE: TextureId = TextureId(1);
 pub const LTC1_TEXTURE: TextureId = TextureId(2);
 pub const LTC2_TEXTURE: TextureId = TextureId(3);
 
+/// Repeating, linearly filtered, the sampler of textures without their own.
+pub const DEFAULT_SAMPLER: SamplerId = SamplerId(0);
+
 mod ltc {
     include!("ltc_matrix.raw");
 }
@@ -26,10 +29,63 @@ impl TextureId {
     }
 }
 
+#[repr(C)]
+#[derive(Debug, Copy, Default, Clone, PartialEq, Eq, bytemuck::Pod, bytemuck::Zeroable)]
+pub struct SamplerId(u32);
+
+impl SamplerId {
+    pub fn id(&self) -> u32 {
+        self.0
+    }
+}
+
+/// A texture along with the sampler it is read with, like a glTF texture.
+#[repr(C)]
+#[derive(Debug, Copy, Default, Clone, bytemuck::Pod, bytemuck::Zeroable)]
+pub struct TextureRef {
+    pub texture_id: TextureId,
+    pub sampler_id: SamplerId,
+}
+
+impl From<TextureId> for TextureRef {
+    fn from(texture_id: TextureId) -> Self {
+        Self {
+            texture_id,
+            sampler_id: DEFAULT_SAMPLER,
+        }
+    }
+}
+
+/// Sampler state of a texture. Equal descriptors share a single sampler.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub struct SamplerDesc {
+    pub address_mode_u: wgpu::AddressMode,
+    pub address_mode_v: wgpu::AddressMode,
+    pub mag_filter: wgpu::FilterMode,
+    pub min_filter: wgpu::FilterMode,
+    pub mipmap_filter: wgpu::FilterMode,
+}
+
+impl Default for SamplerDesc {
+    fn default() -> Self {
+        Self {
+            address_mode_u: wgpu::AddressMode::Repeat,
+            address_mode_v: wgpu::AddressMode::Repeat,
+            mag_filter: wgpu::FilterMode::Linear,
+            min_filter: wgpu::FilterMode::Linear,
+            mipmap_filter: wgpu::FilterMode::Linear,
+        }
+    }
+}
+
 pub struct TexturePool {
     pub views: Vec<wgpu::TextureView>,
 
-    sampler: wgpu::Sampler,
+    samplers: Vec<wgpu::Sampler>,
+    sampler_descs: Vec<SamplerDesc>,
+    /// Anisotropy of linearly filtered samplers, 1 if the adapter can't
+    /// filter anisotropically.
+    max_anisotropy: u16,
     ltc_sampler: wgpu::Sampler,
     pub bind_group_layout: bind_group_layout::BindGroupLayout,
     pub bind_group: wgpu::BindGroup,
@@ -38,6 +94,7 @@ pub struct TexturePool {
 }
 
 const MAX_TEXTURES: u32 = 1 << 10;
+const MAX_SAMPLERS: u32 = 1 << 5;
 
 impl TexturePool {
     pub fn new(gpu: Arc<Gpu>) -> Self {
@@ -73,22 +130,27 @@ impl TexturePool {
                             ty: wgpu::BindingType::Sampler(wgpu::SamplerBindingType::Filtering),
                             count: None,
                         },
+                        wgpu::BindGroupLayoutEntry {
+                            binding: 3,
+                            visibility: wgpu::ShaderStages::VERTEX_FRAGMENT
+                                | wgpu::ShaderStages::COMPUTE,
+                            ty: wgpu::BindingType::Sampler(wgpu::SamplerBindingType::Filtering),
+                            count: core::num::NonZeroU32::new(MAX_SAMPLERS),
+                        },
                     ],
                 });
-        let sampler = gpu.device().create_sampler(&wgpu::SamplerDescriptor {
-            label: Some("Gltf Default Sampler"),
-            address_mode_u: wgpu::AddressMode::Repeat,
-            address_mode_v: wgpu::AddressMode::Repeat,
-            address_mode_w: wgpu::AddressMode::Repeat,
-            mag_filter: wgpu::FilterMode::Linear,
-            min_filter: wgpu::FilterMode::Linear,
-            mipmap_filter: wgpu::FilterMode::Linear,
-            lod_min_clamp: 0.0,
-            lod_max_clamp: std::f32::MAX,
-            compare: None,
-            anisotropy_clamp: 1,
-            border_color: None,
-        });
+        let anisotropic = gpu
+            .adapter()
+            .get_downlevel_capabilities()
+            .flags
+            .contains(wgpu::DownlevelFlags::ANISOTROPIC_FILTERING);
+        let max_anisotropy = if anisotropic { 16 } else { 1 };
+        let sampler_descs = vec![SamplerDesc::default()];
+        let samplers = vec![create_sampler(
+            gpu.device(),
+            &sampler_descs[0],
+            max_anisotropy,
+        )];
         let ltc_sampler = gpu.device().create_sampler(&wgpu::SamplerDescriptor {
             label: Some("Ltc Sampler"),
             address_mode_u: wgpu::AddressMode::ClampToEdge,
@@ -101,12 +163,14 @@ impl TexturePool {
         });
 
         let bind_group =
-            Self::create_bind_group(&gpu, &bind_group_layout, &views, &sampler, &ltc_sampler);
+            Self::create_bind_group(&gpu, &bind_group_layout, &views, &samplers, &ltc_sampler);
 
         Self {
             views,
 
-            sampler,
+            samplers,
+            sampler_descs,
+            max_anisotropy,
             ltc_sampler,
             bind_group_layout,
             bind_group,
@@ -120,14 +184,34 @@ impl TexturePool {
         TextureId(self.views.len() as u32 - 1)
     }
 
+    /// Returns the sampler created for an equal descriptor before, if any.
+    pub fn add_sampler(&mut self, desc: SamplerDesc) -> SamplerId {
+        if let Some(idx) = self.sampler_descs.iter().position(|d| *d == desc) {
+            return SamplerId(idx as u32);
+        }
+        if self.samplers.len() as u32 == MAX_SAMPLERS {
+            log::warn!("Ran out of samplers, falling back to the default one for {desc:?}");
+            return DEFAULT_SAMPLER;
+        }
+        self.samplers.push(create_sampler(
+            self.gpu.device(),
+            &desc,
+            self.max_anisotropy,
+        ));
+        self.sampler_descs.push(desc);
+
+        SamplerId(self.samplers.len() as u32 - 1)
+    }
+
     fn create_bind_group(
         gpu: &Gpu,
         bind_group_layout: &wgpu::BindGroupLayout,
         views: &[wgpu::TextureView],
-        sampler: &wgpu::Sampler,
+        samplers: &[wgpu::Sampler],
         ltc_sampler: &wgpu::Sampler,
     ) -> wgpu::BindGroup {
         let views: Vec<_> = views.iter().collect();
+        let samplers: Vec<_> = samplers.iter().collect();
 
         gpu.device().create_bind_group(&wgpu::BindGroupDescriptor {
             label: Some("TexturePool: bind group"),
@@ -139,12 +223,16 @@ impl TexturePool {
                 },
                 wgpu::BindGroupEntry {
                     binding: 1,
-                    resource: wgpu::BindingResource::Sampler(sampler),
+                    resource: wgpu::BindingResource::Sampler(samplers[0]),
                 },
                 wgpu::BindGroupEntry {
                     binding: 2,
                     resource: wgpu::BindingResource::Sampler(ltc_sampler),
                 },
+                wgpu::BindGroupEntry {
+                    binding: 3,
+                    resource: wgpu::BindingResource::SamplerArray(&samplers),
+                },
             ],
         })
     }
@@ -154,12 +242,31 @@ impl TexturePool {
             &self.gpu,
             &self.bind_group_layout,
             &self.views,
-            &self.sampler,
+            &self.samplers,
             &self.ltc_sampler,
         )
     }
 }
 
+fn create_sampler(device: &wgpu::Device, desc: &SamplerDesc, max_anisotropy: u16) -> wgpu::Sampler {
+    // Anisotropic filtering is only valid with every filter linear
+    let linear = [desc.mag_filter, desc.min_filter, desc.mipmap_filter]
+        .iter()
+        .all(|filter| *filter == wgpu::FilterMode::Linear);
+    device.create_sampler(&wgpu::SamplerDescriptor {
+        label: Some("TexturePool: sampler"),
+        address_mode_u: desc.address_mode_u,
+        address_mode_v: desc.address_mode_v,
+        address_mode_w: wgpu::AddressMode::Repeat,
+        mag_filter: desc.mag_filter,
+        min_filter: desc.min_filter,
+        mipmap_filter: desc.mipmap_filter,
+        lod_max_clamp: f32::MAX,
+        anisotropy_clamp: if linear { max_anisotropy } else { 1 },
+        ..Default::default()
+    })
+}
+
 fn default_textures(gpu: &Gpu) -> Vec<wgpu::TextureView> {
     let white = create_solid_color_texture(gpu.device(), gpu.queue(), glam::Vec3::splat(1.))
         .create_view(&Default::default());
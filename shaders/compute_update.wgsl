@@ -25,4 +25,5 @@ fn update(@builtin(global_invocation_id) global_id: vec3<u32>) {
     }
     let rotz = from_rotation_z(speed * un.dt);
     (*instance).transform = rotz * transform;
+    (*instance).inv_transform = (*instance).inv_transform * transpose(rotz);
 }
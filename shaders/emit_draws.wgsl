@@ -7,8 +7,12 @@ var<uniform> camera: Camera;
 var<storage, read> meshes: array<MeshInfo>;
 @group(2) @binding(0)
 var<storage, read_write> instances: array<Instance>;
+// Draws of single sided instances, followed by the same number of double
+// sided ones. Either slot of an instance draws zero instances
 @group(3) @binding(0)
 var<storage, read_write> cmd_buffer: array<DrawIndexedIndirect>;
+@group(4) @binding(0)
+var<storage, read> materials: array<Material>;
 
 fn is_visible(mesh: MeshInfo, transform: mat4x4<f32>, scale: vec3<f32>) -> bool {
     var center = (mesh.max + mesh.min) / 2.;
@@ -48,7 +52,7 @@ fn emit_draws(@builtin(global_invocation_id) global_id: vec3<u32>) {
     let scale = extract_scale(transform);
 
     var instance_count = 1u;
-    if !is_visible(mesh_info, transform, scale) {
+    if (instance.flags & INSTANCE_REMOVED) != 0u || !is_visible(mesh_info, transform, scale) {
         instance_count = 0u;
     }
 
@@ -60,5 +64,9 @@ fn emit_draws(@builtin(global_invocation_id) global_id: vec3<u32>) {
     cmd.vertex_offset = mesh_info.vertex_offset;
     cmd.base_instance = global_id.x;
 
-    cmd_buffer[global_id.x] = cmd;
+    let double_sided = materials[instance.material_id].double_sided != 0u;
+    cmd.instance_count = select(instance_count, 0u, double_sided);
+    cmd_buffer[index] = cmd;
+    cmd.instance_count = select(0u, instance_count, double_sided);
+    cmd_buffer[len + index] = cmd;
 }
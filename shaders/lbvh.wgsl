@@ -0,0 +1,340 @@
+#import "utils/bvh_nodes.wgsl"
+
+// Linear BVH build: Morton codes of triangle centroids are radix sorted and
+// the hierarchy is emitted from the sorted codes (Karras 2012). Internal node
+// `i` keeps its children at `2 + 2 * i` and `3 + 2 * i`, so the output is the
+// same sibling-pair layout the CPU builder produces.
+
+struct Params {
+	triangle_count: u32,
+	vertex_offset: u32,
+	base_index: u32,
+	bvh_index: u32,
+	// Radix sort pass: digit shift, ping-pong direction and tile count
+	shift: u32,
+	flip: u32,
+	tile_count: u32,
+	_padding: u32,
+}
+
+@group(0) @binding(0) var<uniform> params: Params;
+
+@group(1) @binding(0) var<storage, read> vertices: array<f32>;
+@group(1) @binding(1) var<storage, read> indices: array<u32>;
+@group(1) @binding(2) var<storage, read_write> bvh_nodes: array<BvhNode>;
+
+// Two halves of `triangle_count` for ping-pong sorting. `keys` ends up
+// holding the reordered indices.
+@group(2) @binding(0) var<storage, read_write> keys: array<u32>;
+@group(2) @binding(1) var<storage, read_write> values: array<u32>;
+// Digit histograms while sorting, node slots afterwards: leaves first, then
+// internal nodes
+@group(2) @binding(2) var<storage, read_write> scratch: array<u32>;
+// Order preserving encoded bounds, centroid bounds first, then one per
+// internal node. Minimums are stored inverted so everything is `atomicMax`.
+@group(2) @binding(3) var<storage, read_write> bounds: array<atomic<u32>>;
+
+const WORKGROUP_SIZE: u32 = 256u;
+const RADIX_BITS: u32 = 4u;
+const RADIX: u32 = 16u;
+
+fn fetch_vertex(idx: u32) -> vec3<f32> {
+    let i = params.vertex_offset + indices[params.base_index + idx];
+    return vec3(vertices[3u * i + 0u], vertices[3u * i + 1u], vertices[3u * i + 2u]);
+}
+
+fn triangle_bounds(triangle: u32) -> array<vec3<f32>, 2> {
+    let v0 = fetch_vertex(3u * triangle + 0u);
+    let v1 = fetch_vertex(3u * triangle + 1u);
+    let v2 = fetch_vertex(3u * triangle + 2u);
+    return array(min(v0, min(v1, v2)), max(v0, max(v1, v2)));
+}
+
+fn encode_float(x: f32) -> u32 {
+    let bits = bitcast<u32>(x);
+    return select(bits | 0x80000000u, ~bits, (bits & 0x80000000u) != 0u);
+}
+
+fn decode_float(x: u32) -> f32 {
+    return bitcast<f32>(select(~x, x & 0x7fffffffu, (x & 0x80000000u) != 0u));
+}
+
+fn grow_bounds(offset: u32, bmin: vec3<f32>, bmax: vec3<f32>) {
+    atomicMax(&bounds[offset + 0u], ~encode_float(bmin.x));
+    atomicMax(&bounds[offset + 1u], ~encode_float(bmin.y));
+    atomicMax(&bounds[offset + 2u], ~encode_float(bmin.z));
+    atomicMax(&bounds[offset + 3u], encode_float(bmax.x));
+    atomicMax(&bounds[offset + 4u], encode_float(bmax.y));
+    atomicMax(&bounds[offset + 5u], encode_float(bmax.z));
+}
+
+fn load_bounds(offset: u32) -> array<vec3<f32>, 2> {
+    let bmin = vec3(
+        decode_float(~atomicLoad(&bounds[offset + 0u])),
+        decode_float(~atomicLoad(&bounds[offset + 1u])),
+        decode_float(~atomicLoad(&bounds[offset + 2u])),
+    );
+    let bmax = vec3(
+        decode_float(atomicLoad(&bounds[offset + 3u])),
+        decode_float(atomicLoad(&bounds[offset + 4u])),
+        decode_float(atomicLoad(&bounds[offset + 5u])),
+    );
+    return array(bmin, bmax);
+}
+
+@compute
+@workgroup_size(256, 1, 1)
+fn centroid_bounds(@builtin(global_invocation_id) id: vec3<u32>) {
+    if id.x >= params.triangle_count { return; }
+    let aabb = triangle_bounds(id.x);
+    let centroid = (aabb[0] + aabb[1]) * 0.5;
+    grow_bounds(0u, centroid, centroid);
+}
+
+// Spreads the lower 10 bits of `v` out to every third bit
+fn expand_bits(v: u32) -> u32 {
+    var x = v & 0x3ffu;
+    x = (x | (x << 16u)) & 0x030000ffu;
+    x = (x | (x << 8u)) & 0x0300f00fu;
+    x = (x | (x << 4u)) & 0x030c30c3u;
+    x = (x | (x << 2u)) & 0x09249249u;
+    return x;
+}
+
+@compute
+@workgroup_size(256, 1, 1)
+fn morton(@builtin(global_invocation_id) id: vec3<u32>) {
+    if id.x >= params.triangle_count { return; }
+    let scene = load_bounds(0u);
+    let extent = max(scene[1] - scene[0], vec3(1e-20));
+    let aabb = triangle_bounds(id.x);
+    let centroid = (aabb[0] + aabb[1]) * 0.5;
+    let cell = vec3<u32>(clamp((centroid - scene[0]) / extent * 1024., vec3(0.), vec3(1023.)));
+    keys[id.x] = (expand_bits(cell.x) << 2u) | (expand_bits(cell.y) << 1u) | expand_bits(cell.z);
+    values[id.x] = id.x;
+}
+
+fn source_offset() -> u32 {
+    return select(0u, params.triangle_count, params.flip != 0u);
+}
+
+fn digit_of(key: u32) -> u32 {
+    return (key >> params.shift) & (RADIX - 1u);
+}
+
+var<workgroup> digit_counts: array<atomic<u32>, RADIX>;
+
+// Histograms are stored digit major so that their exclusive scan yields
+// the scatter offset of every tile
+@compute
+@workgroup_size(256, 1, 1)
+fn radix_count(
+    @builtin(global_invocation_id) id: vec3<u32>,
+    @builtin(local_invocation_index) local_idx: u32,
+    @builtin(workgroup_id) group_id: vec3<u32>,
+) {
+    if local_idx < RADIX {
+        atomicStore(&digit_counts[local_idx], 0u);
+    }
+    workgroupBarrier();
+    if id.x < params.triangle_count {
+        atomicAdd(&digit_counts[digit_of(keys[source_offset() + id.x])], 1u);
+    }
+    workgroupBarrier();
+    if local_idx < RADIX {
+        scratch[local_idx * params.tile_count + group_id.x] = atomicLoad(&digit_counts[local_idx]);
+    }
+}
+
+var<workgroup> scan_buffer: array<u32, WORKGROUP_SIZE>;
+var<workgroup> scan_carry: u32;
+
+// Exclusive scan of all histograms in a single workgroup
+@compute
+@workgroup_size(256, 1, 1)
+fn radix_scan(@builtin(local_invocation_index) local_idx: u32) {
+    let count = RADIX * params.tile_count;
+    if local_idx == 0u {
+        scan_carry = 0u;
+    }
+    for (var base = 0u; base < count; base += WORKGROUP_SIZE) {
+        let idx = base + local_idx;
+        var value = 0u;
+        if idx < count {
+            value = scratch[idx];
+        }
+        scan_buffer[local_idx] = value;
+        workgroupBarrier();
+        for (var offset = 1u; offset < WORKGROUP_SIZE; offset <<= 1u) {
+            var sum = scan_buffer[local_idx];
+            if local_idx >= offset {
+                sum += scan_buffer[local_idx - offset];
+            }
+            workgroupBarrier();
+            scan_buffer[local_idx] = sum;
+            workgroupBarrier();
+        }
+        let carry = scan_carry;
+        if idx < count {
+            scratch[idx] = carry + scan_buffer[local_idx] - value;
+        }
+        workgroupBarrier();
+        if local_idx == WORKGROUP_SIZE - 1u {
+            scan_carry = carry + scan_buffer[local_idx];
+        }
+        workgroupBarrier();
+    }
+}
+
+var<workgroup> tile_digits: array<u32, WORKGROUP_SIZE>;
+
+// Stable scatter: rank of a key is the number of equal digits before it in
+// its tile
+@compute
+@workgroup_size(256, 1, 1)
+fn radix_scatter(
+    @builtin(global_invocation_id) id: vec3<u32>,
+    @builtin(local_invocation_index) local_idx: u32,
+    @builtin(workgroup_id) group_id: vec3<u32>,
+) {
+    let src = source_offset();
+    let in_range = id.x < params.triangle_count;
+    var key = 0u;
+    var digit = RADIX;
+    if in_range {
+        key = keys[src + id.x];
+        digit = digit_of(key);
+    }
+    tile_digits[local_idx] = digit;
+    workgroupBarrier();
+    if !in_range { return; }
+
+    var rank = 0u;
+    for (var i = 0u; i < local_idx; i += 1u) {
+        rank += u32(tile_digits[i] == digit);
+    }
+    let dst = params.triangle_count - src + scratch[digit * params.tile_count + group_id.x] + rank;
+    keys[dst] = key;
+    values[dst] = values[src + id.x];
+}
+
+// Length of the common prefix of sorted keys `i` and `j`, equal keys are
+// disambiguated by their indices
+fn delta(i: i32, j: i32) -> i32 {
+    if j < 0 || j >= i32(params.triangle_count) {
+        return -1;
+    }
+    let a = keys[i];
+    let b = keys[j];
+    if a == b {
+        return 32 + i32(countLeadingZeros(u32(i) ^ u32(j)));
+    }
+    return i32(countLeadingZeros(a ^ b));
+}
+
+fn internal_node(left_first: u32) -> BvhNode {
+    return BvhNode(vec3(0.), left_first, vec3(0.), 0u);
+}
+
+@compute
+@workgroup_size(256, 1, 1)
+fn hierarchy(@builtin(global_invocation_id) id: vec3<u32>) {
+    let n = params.triangle_count;
+    if n == 1u {
+        // Lone leaf is the root itself
+        if id.x == 0u {
+            scratch[0] = 0u;
+        }
+        return;
+    }
+    if id.x >= n - 1u { return; }
+
+    let i = i32(id.x);
+    if i == 0 {
+        bvh_nodes[params.bvh_index] = internal_node(2u);
+        bvh_nodes[params.bvh_index + 1u] = internal_node(0u);
+        scratch[n] = 0u;
+    }
+
+    // Direction and extent of the key range covered by node `i`
+    let d = select(-1, 1, delta(i, i + 1) > delta(i, i - 1));
+    let delta_min = delta(i, i - d);
+    var l_max = 2;
+    while delta(i, i + l_max * d) > delta_min {
+        l_max *= 2;
+    }
+    var l = 0;
+    for (var t = l_max / 2; t >= 1; t /= 2) {
+        if delta(i, i + (l + t) * d) > delta_min {
+            l += t;
+        }
+    }
+    let j = i + l * d;
+
+    // Binary search for the highest differing bit inside the range
+    let delta_node = delta(i, j);
+    var s = 0;
+    var t = l;
+    loop {
+        t = (t + 1) / 2;
+        if delta(i, i + (s + t) * d) > delta_node {
+            s += t;
+        }
+        if t <= 1 { break; }
+    }
+    let split = u32(i + s * d + min(d, 0));
+
+    let left_slot = 2u + 2u * id.x;
+    let right_slot = left_slot + 1u;
+    if u32(min(i, j)) == split {
+        scratch[split] = left_slot;
+    } else {
+        scratch[n + split] = left_slot;
+        bvh_nodes[params.bvh_index + left_slot] = internal_node(2u + 2u * split);
+    }
+    if u32(max(i, j)) == split + 1u {
+        scratch[split + 1u] = right_slot;
+    } else {
+        scratch[n + split + 1u] = right_slot;
+        bvh_nodes[params.bvh_index + right_slot] = internal_node(4u + 2u * split);
+    }
+}
+
+// Writes leaves and grows the bounds of all of their ancestors
+@compute
+@workgroup_size(256, 1, 1)
+fn leaf_bounds(@builtin(global_invocation_id) id: vec3<u32>) {
+    let n = params.triangle_count;
+    if id.x >= n { return; }
+    let aabb = triangle_bounds(values[id.x]);
+    var slot = scratch[id.x];
+    bvh_nodes[params.bvh_index + slot] = BvhNode(aabb[0], id.x, aabb[1], 1u);
+
+    while slot != 0u {
+        let parent = (slot - 2u) / 2u;
+        grow_bounds(6u + 6u * parent, aabb[0], aabb[1]);
+        slot = scratch[n + parent];
+    }
+}
+
+@compute
+@workgroup_size(256, 1, 1)
+fn internal_bounds(@builtin(global_invocation_id) id: vec3<u32>) {
+    let n = params.triangle_count;
+    if id.x + 1u >= n { return; }
+    let aabb = load_bounds(6u + 6u * id.x);
+    let node_idx = params.bvh_index + scratch[n + id.x];
+    bvh_nodes[node_idx].min = aabb[0];
+    bvh_nodes[node_idx].max = aabb[1];
+}
+
+// Orders triangles like the leaves, `keys` isn't needed anymore
+@compute
+@workgroup_size(256, 1, 1)
+fn reorder(@builtin(global_invocation_id) id: vec3<u32>) {
+    if id.x >= params.triangle_count { return; }
+    let triangle = values[id.x];
+    for (var k = 0u; k < 3u; k += 1u) {
+        keys[3u * id.x + k] = indices[params.base_index + 3u * triangle + k];
+    }
+}
@@ -2,6 +2,7 @@
 #import "utils/encoding.wgsl"
 #import "utils/ltc.wgsl"
 #import "utils/uv.wgsl"
+#import "utils/material.wgsl"
 
 @group(0) @binding(0) var<uniform> global: Globals;
 @group(0) @binding(1) var<uniform> camera: Camera;
@@ -14,6 +15,7 @@
 @group(2) @binding(0) var texture_array: binding_array<texture_2d<f32>>;
 @group(2) @binding(1) var tex_sampler: sampler;
 @group(2) @binding(2) var tex_ltc_sampler: sampler;
+@group(2) @binding(3) var samplers: binding_array<sampler>;
 
 @group(3) @binding(0) var<storage, read> materials: array<Material>;
 
@@ -57,25 +59,27 @@ fn fs_main(in: VertexOutput) -> @location(0) vec4<f32> {
 
     let material = materials[material_id];
     let uv = unpack2x16float(norm_uv_tex.y);
-    let albedo = textureSample(texture_array[material.albedo], t_sampler, uv);
-    let emissive = textureSample(texture_array[material.emissive], t_sampler, uv).rgb;
-    let metallic_roughness = textureSample(texture_array[material.metallic_roughness], t_sampler, uv);
+    let surface = sample_material(material, uv);
+    let albedo = surface.base_color;
+    let emissive = surface.emissive;
 
     let pos = world_position_from_depth(in.uv, depth, camera.clip_to_world);
     let nor = decode_octahedral_32(norm_uv_tex.x);
     let rd = normalize(camera.position.xyz - pos);
 
+    if surface.material_type == MATERIAL_UNLIT {
+        return vec4(max(albedo.rgb + emissive, vec3(0.)), 1.0);
+    }
+
+    let ltc = ltc_matrix(nor, rd, surface.roughness);
+    let brdf = surface_brdf(surface, ltc.t2.xy);
+
     var color = vec3(0.);
 
-    color = albedo.rgb * 0.01 + emissive;
-    if material_id == LIGHT_MATERIAL {
-        color = albedo.rgb + emissive;
-    }
+    color = albedo.rgb * 0.01 * surface.occlusion + emissive;
 
     let light_count = arrayLength(&point_lights);
     for (var i = 0u; i < light_count; i += 1u) {
-        if material_id == LIGHT_MATERIAL { break; }
-
         let light = point_lights[i];
 
         let light_vec = light.position - pos;
@@ -85,20 +89,20 @@ fn fs_main(in: VertexOutput) -> @location(0) vec4<f32> {
         let atten = attenuation(1., 1., dist, light.radius);
 
         let light_dir = normalize(light_vec);
-        let shade = max(0., dot(nor, light_dir));
-        let diff = light.color * albedo.rgb * shade * atten;
-
-        let refl = reflect(-light_dir, rd);
-        let covr = max(0., dot(-rd, nor));
-        let spec = light.color * metallic_roughness.z * pow(covr, 16.) * atten;
+        color += light.color * atten * surface_evaluate(surface, brdf, nor, rd, light_dir);
+    }
 
-        color += diff + spec;
+    // Area lights see the clear coat but not the sheen and transmission lobes
+    var coat_ltc = ltc;
+    var coat_base = vec3(1.);
+    if has_layer(surface, MATERIAL_CLEARCOAT) {
+        coat_ltc = ltc_matrix(nor, rd, surface.clearcoat_roughness);
+        let fc = f_schlick(vec3(DIELECTRIC_F0), saturate(dot(nor, rd))) * surface.clearcoat;
+        coat_base = 1. - fc;
     }
 
-    let ltc = ltc_matrix(nor, rd, saturate(metallic_roughness.x));
     let area_light_count = arrayLength(&area_lights);
     for (var i = 0u; i < area_light_count; i += 1u) {
-        if material_id == LIGHT_MATERIAL { break; }
         let light_radius = 25.;
 
         let light = area_lights[i];
@@ -107,10 +111,16 @@ fn fs_main(in: VertexOutput) -> @location(0) vec4<f32> {
         let dist = length(light_vec);
 
         let diff = get_area_light_diffuse(nor, rd, pos, light.points, false);
-        let spec = get_area_light_specular(nor, rd, pos, ltc, light.points, false, vec3(1.));
+        var spec = get_area_light_specular(nor, rd, pos, ltc, light.points, false, brdf.f0) * brdf.energy_compensation;
+        var base_diff = brdf.diffuse_color * diff;
+        if has_layer(surface, MATERIAL_CLEARCOAT) {
+            let coat_spec = get_area_light_specular(nor, rd, pos, coat_ltc, light.points, false, vec3(DIELECTRIC_F0));
+            spec = spec * coat_base + coat_spec * surface.clearcoat;
+            base_diff *= coat_base;
+        }
 
         let atten = attenuation(light.intensity, 500., distance(center, pos), light_radius);
-        color += light.color * light.intensity * (spec * atten + albedo.rgb * diff) ;
+        color += light.color * light.intensity * (spec * atten + base_diff) ;
     }
 
     color = max(color, vec3(0.));
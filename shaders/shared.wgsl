@@ -1,4 +1,3 @@
-const LIGHT_MATERIAL = 2u;
 const WHITE_TEXTURE = 0u;
 const BLACK_TEXTURE = 1u;
 
@@ -47,25 +46,73 @@ struct MeshInfo {
 	base_index: u32,
     vertex_offset: i32,
 	bvh_index: u32,
-	junk: vec2<f32>,
+	first_primitive: u32,
+	primitive_count: u32,
 }
 
 struct Instance {
     transform: mat4x4<f32>,
     inv_transform: mat4x4<f32>,
 	mesh_id: u32,
+	mesh_generation: u32,
 	material_id: u32,
-	padding: vec2<f32>,
+	material_generation: u32,
+	flags: u32,
+	generation: u32,
+	padding: array<u32, 2>,
+}
+
+// Mirrors `Instance::REMOVED`
+const INSTANCE_REMOVED: u32 = 1u;
+
+// Mirrors `pools::TextureRef`, `sampler` is a reserved word
+struct TextureRef {
+	texture_id: u32,
+	sampler_id: u32,
 }
 
 struct Material {
     base_color: vec4<f32>,
-	albedo: u32,
-	normal: u32,
-	metallic_roughness: u32,
-	emissive: u32,
+	emissive_factor: vec3<f32>,
+	metallic_factor: f32,
+	sheen_color_factor: vec3<f32>,
+	sheen_roughness_factor: f32,
+	roughness_factor: f32,
+	normal_scale: f32,
+	occlusion_strength: f32,
+	alpha_cutoff: f32,
+	clearcoat_factor: f32,
+	clearcoat_roughness_factor: f32,
+	transmission_factor: f32,
+	ior: f32,
+	alpha_mode: u32,
+	double_sided: u32,
+	material_type: u32,
+	padding: u32,
+	albedo: TextureRef,
+	normal: TextureRef,
+	metallic_roughness: TextureRef,
+	emissive: TextureRef,
+	occlusion: TextureRef,
+	clearcoat: TextureRef,
+	clearcoat_roughness: TextureRef,
+	sheen_color: TextureRef,
+	sheen_roughness: TextureRef,
+	transmission: TextureRef,
 }
 
+// Mirror `ALPHA_MODE_*` of `pools::material`
+const ALPHA_MODE_OPAQUE: u32 = 0u;
+const ALPHA_MODE_MASK: u32 = 1u;
+const ALPHA_MODE_BLEND: u32 = 2u;
+
+// Mirror `MATERIAL_*` of `pools::material`
+const MATERIAL_STANDARD: u32 = 0u;
+const MATERIAL_UNLIT: u32 = 1u;
+const MATERIAL_CLEARCOAT: u32 = 2u;
+const MATERIAL_SHEEN: u32 = 4u;
+const MATERIAL_TRANSMISSION: u32 = 8u;
+
 struct DrawIndexedIndirect {
     vertex_count: u32,
     instance_count: u32,
@@ -0,0 +1,51 @@
+#import "shared.wgsl"
+#import "utils/math.wgsl"
+#import "utils/bvh_nodes.wgsl"
+
+@group(0) @binding(0) var<storage, read_write> tlas_nodes: array<TlasNode>;
+@group(0) @binding(1) var<storage, read> instances: array<Instance>;
+@group(0) @binding(2) var<storage, read> meshes: array<MeshInfo>;
+@group(0) @binding(3) var<storage, read> refit_order: array<u32>;
+// Offset into `refit_order` and node count of every level, deepest first
+@group(0) @binding(4) var<storage, read> levels: array<vec2<u32>>;
+
+const WORKGROUP_SIZE: u32 = 256u;
+
+fn refit_node(node_idx: u32) {
+    var node = tlas_nodes[node_idx];
+    if node.left == 0u { // is leaf
+        let instance = instances[node.instance_idx];
+        let mesh = meshes[instance.mesh_id];
+        node.min = vec3(MAX_DIST);
+        node.max = vec3(-MAX_DIST);
+        // Removed instances keep empty bounds
+        let corners = select(8u, 0u, (instance.flags & INSTANCE_REMOVED) != 0u);
+        for (var i = 0u; i < corners; i += 1u) {
+            let corner = select(mesh.min, mesh.max, vec3(i & 1u, i & 2u, i & 4u) != vec3(0u));
+            let pos = (instance.transform * vec4(corner, 1.)).xyz;
+            node.min = min(node.min, pos);
+            node.max = max(node.max, pos);
+        }
+    } else {
+        let left = tlas_nodes[node.left];
+        let right = tlas_nodes[node.left + 1u];
+        node.min = min(left.min, right.min);
+        node.max = max(left.max, right.max);
+    }
+    tlas_nodes[node_idx] = node;
+}
+
+// Single workgroup walks the levels bottom-up, barriers make every level
+// visible to its parents
+@compute
+@workgroup_size(256, 1, 1)
+fn refit(@builtin(local_invocation_index) local_idx: u32) {
+    let level_count = arrayLength(&levels);
+    for (var level = 0u; level < level_count; level += 1u) {
+        let range = levels[level];
+        for (var i = local_idx; i < range.y; i += WORKGROUP_SIZE) {
+            refit_node(refit_order[range.x + i]);
+        }
+        storageBarrier();
+    }
+}
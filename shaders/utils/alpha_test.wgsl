@@ -0,0 +1,25 @@
+// `alpha_test` hook of `./bvh.wgsl` rejecting hits on fragments
+// `visibility.wgsl` discards for their material alpha. Expects `materials`,
+// `texture_array`, `samplers` and `tex_coords: array<vec2<f32>>` in scope.
+
+#import "./material.wgsl"
+
+fn fetch_tex_coord(idx: u32, mesh: MeshInfo) -> vec2<f32> {
+    return tex_coords[u32(mesh.vertex_offset) + indices[mesh.base_index + idx]];
+}
+
+fn alpha_test(instance_id: u32, triangle: u32, barycentrics: vec2<f32>) -> bool {
+    let instance = instances[instance_id];
+    let material = materials[instance.material_id];
+    if material.alpha_mode == ALPHA_MODE_OPAQUE {
+        return true;
+    }
+    let mesh = meshes[instance.mesh_id];
+    let uv0 = fetch_tex_coord(3u * triangle + 0u, mesh);
+    let uv1 = fetch_tex_coord(3u * triangle + 1u, mesh);
+    let uv2 = fetch_tex_coord(3u * triangle + 2u, mesh);
+    let uv = uv0 * (1. - barycentrics.x - barycentrics.y) + uv1 * barycentrics.x + uv2 * barycentrics.y;
+
+    let albedo = sample_texture_level(material.albedo, uv, 0.);
+    return !is_alpha_cut(material, material.base_color.a * albedo.a);
+}
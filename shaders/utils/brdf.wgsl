@@ -0,0 +1,151 @@
+// Cook-Torrance microfacet BRDF: GGX distribution, height correlated Smith
+// visibility and Schlick Fresnel, with a Lambert lobe for the dielectric
+// part. Single scattering loses energy on rough surfaces, it is added back
+// from the directional albedo `dfg` of the specular lobe, which `Ltc.t2.xy`
+// of `./ltc.wgsl` provides.
+
+#import "./math.wgsl"
+
+// Keeps the GGX peak finite for perfectly smooth surfaces
+const MIN_ROUGHNESS: f32 = 0.045;
+// Reflectance at normal incidence of dielectrics with an IOR of 1.5
+const DIELECTRIC_F0: f32 = 0.04;
+
+struct Brdf {
+    diffuse_color: vec3<f32>,
+    f0: vec3<f32>,
+    // GGX alpha, the squared perceptual roughness
+    alpha: f32,
+    // Scales single scattering specular up to the multiple scattering energy
+    energy_compensation: vec3<f32>,
+}
+
+// `dfg.x` is the specular albedo for a fully reflective surface and
+// `dfg.y` the part of it weighted by the Schlick Fresnel term.
+fn brdf_new(base_color: vec3<f32>, metallic: f32, roughness: f32, ior: f32, dfg: vec2<f32>) -> Brdf {
+    let perceptual_roughness = clamp(roughness, MIN_ROUGHNESS, 1.);
+
+    var out: Brdf;
+    out.diffuse_color = base_color * (1. - metallic);
+    out.f0 = mix(vec3(f0_from_ior(ior)), base_color, metallic);
+    out.alpha = perceptual_roughness * perceptual_roughness;
+    out.energy_compensation = 1. + out.f0 * (1. / max(dfg.x, 1e-4) - 1.);
+    return out;
+}
+
+// Directional albedo of the specular lobe, what an environment or area
+// light of uniform radiance 1 reflects
+fn brdf_specular_albedo(brdf: Brdf, dfg: vec2<f32>) -> vec3<f32> {
+    return (brdf.f0 * dfg.x + (1. - brdf.f0) * dfg.y) * brdf.energy_compensation;
+}
+
+fn f0_from_ior(ior: f32) -> f32 {
+    let r = (ior - 1.) / (ior + 1.);
+    return r * r;
+}
+
+fn d_ggx(noh: f32, alpha: f32) -> f32 {
+    let a2 = alpha * alpha;
+    let f = (noh * a2 - noh) * noh + 1.;
+    return a2 / (PI * f * f);
+}
+
+fn v_smith_ggx_correlated(nov: f32, nol: f32, alpha: f32) -> f32 {
+    let a2 = alpha * alpha;
+    let ggx_l = nov * sqrt((nol - nol * a2) * nol + a2);
+    let ggx_v = nol * sqrt((nov - nov * a2) * nov + a2);
+    return 0.5 / max(ggx_v + ggx_l, 1e-5);
+}
+
+fn f_schlick(f0: vec3<f32>, voh: f32) -> vec3<f32> {
+    let f = pow(1. - voh, 5.);
+    return f0 + (1. - f0) * f;
+}
+
+// Reflected radiance for unit irradiance from `light_dir`, cosine included.
+// `view` and `light_dir` point away from the surface.
+fn brdf_evaluate(brdf: Brdf, nor: vec3<f32>, view: vec3<f32>, light_dir: vec3<f32>) -> vec3<f32> {
+    let nol = saturate(dot(nor, light_dir));
+    if nol <= 0. {
+        return vec3(0.);
+    }
+    let h = normalize(view + light_dir);
+    let nov = max(dot(nor, view), 1e-4);
+    let noh = saturate(dot(nor, h));
+    let voh = saturate(dot(view, h));
+
+    let d = d_ggx(noh, brdf.alpha);
+    let v = v_smith_ggx_correlated(nov, nol, brdf.alpha);
+    let f = f_schlick(brdf.f0, voh);
+    let specular = d * v * f * brdf.energy_compensation;
+    let diffuse = brdf.diffuse_color / PI;
+    return (diffuse + specular) * nol;
+}
+
+fn d_charlie(noh: f32, alpha: f32) -> f32 {
+    let inv_alpha = 1. / alpha;
+    let sin2h = max(1. - noh * noh, 0.0078125);
+    return (2. + inv_alpha) * pow(sin2h, inv_alpha * 0.5) / (2. * PI);
+}
+
+fn v_neubelt(nov: f32, nol: f32) -> f32 {
+    return saturate(1. / (4. * (nol + nov - nol * nov)));
+}
+
+fn v_kelemen(loh: f32) -> f32 {
+    return 0.25 / max(loh * loh, 1e-4);
+}
+
+// Charlie sheen lobe of `KHR_materials_sheen`, cosine included. The base is
+// not dimmed by the sheen albedo, which needs a LUT this renderer lacks.
+fn brdf_sheen(color: vec3<f32>, roughness: f32, nor: vec3<f32>, view: vec3<f32>, light_dir: vec3<f32>) -> vec3<f32> {
+    let nol = saturate(dot(nor, light_dir));
+    if nol <= 0. {
+        return vec3(0.);
+    }
+    let h = normalize(view + light_dir);
+    let nov = max(dot(nor, view), 1e-4);
+    let noh = saturate(dot(nor, h));
+    let perceptual_roughness = clamp(roughness, 0.07, 1.);
+
+    let d = d_charlie(noh, perceptual_roughness * perceptual_roughness);
+    return color * d * v_neubelt(nov, nol) * nol;
+}
+
+// Colorless specular of a clear coat layer with an IOR of 1.5, cosine
+// included, in `x`. `y` is the fraction of light reaching the base layer.
+fn brdf_clearcoat(clearcoat: f32, roughness: f32, nor: vec3<f32>, view: vec3<f32>, light_dir: vec3<f32>) -> vec2<f32> {
+    let nol = saturate(dot(nor, light_dir));
+    if nol <= 0. {
+        return vec2(0., 1.);
+    }
+    let h = normalize(view + light_dir);
+    let noh = saturate(dot(nor, h));
+    let loh = saturate(dot(light_dir, h));
+    let perceptual_roughness = clamp(roughness, MIN_ROUGHNESS, 1.);
+
+    let fc = f_schlick(vec3(DIELECTRIC_F0), loh).x * clearcoat;
+    let d = d_ggx(noh, perceptual_roughness * perceptual_roughness);
+    return vec2(d * v_kelemen(loh) * fc * nol, 1. - fc);
+}
+
+// Thin walled specular transmission, light from behind the surface passes
+// straight through. Mirroring it to the front moves the reflection peak to
+// the straight line, so the GGX lobe of `brdf` is reused.
+fn brdf_transmission(brdf: Brdf, tint: vec3<f32>, nor: vec3<f32>, view: vec3<f32>, light_dir: vec3<f32>) -> vec3<f32> {
+    let behind = dot(nor, light_dir);
+    if behind >= 0. {
+        return vec3(0.);
+    }
+    let mirrored = light_dir - 2. * behind * nor;
+    let nol = -behind;
+    let h = normalize(view + mirrored);
+    let nov = max(dot(nor, view), 1e-4);
+    let noh = saturate(dot(nor, h));
+    let voh = saturate(dot(view, h));
+
+    let d = d_ggx(noh, brdf.alpha);
+    let v = v_smith_ggx_correlated(nov, nol, brdf.alpha);
+    let f = f_schlick(brdf.f0, voh);
+    return tint * (1. - f) * d * v * nol;
+}
@@ -1,30 +1,32 @@
 #import "./stack.wgsl"
 #import "./intersections.wgsl"
-
-struct TlasNode {
-	min: vec3<f32>,
-	left_right: u32,
-	max: vec3<f32>,
-	instance_idx: u32,
-}
-
-struct BvhNode {
-	min: vec3<f32>,
-	left_first: u32,
-	max: vec3<f32>,
-	count: u32,
-}
-
+#import "./bvh_nodes.wgsl"
+
+// Any-hit queries only accept hits for which the includer's
+// `alpha_test(instance_id, triangle, barycentrics) -> bool` returns true.
+// `./alpha_test.wgsl` implements it with albedo cutouts, `./opaque.wgsl`
+// accepts everything.
+//
+// Meshes with a non zero `primitive_count` have `ProceduralPrimitive`s in
+// their leaves instead of triangles, so includers also declare
+// `primitives: array<ProceduralPrimitive>`. Primitives are always opaque.
+
+// Mirrors `bvh::Hit`, object to world transform is `instances[instance_id].transform`.
+// Procedural hits have zero vertices, `triangle` is the primitive index in the mesh.
 struct TraceResult {
 	v0: vec3<f32>,
 	v1: vec3<f32>,
 	v2: vec3<f32>,
 	hit: bool,
 	dist: f32,
+	triangle: u32,
+	barycentrics: vec2<f32>,
+	mesh_id: u32,
+	instance_id: u32,
 }
 
 fn trace_result_new() -> TraceResult {
-    return TraceResult(vec3(0.), vec3(0.), vec3(0.), false, MAX_DIST);
+    return TraceResult(vec3(0.), vec3(0.), vec3(0.), false, MAX_DIST, 0u, vec2(0.), 0u, 0u);
 }
 
 fn fetch_vertex(idx: u32, mesh: MeshInfo) -> vec3<f32> {
@@ -32,7 +34,33 @@ fn fetch_vertex(idx: u32, mesh: MeshInfo) -> vec3<f32> {
     return vec3(vertices[3u * i + 0u], vertices[3u * i + 1u], vertices[3u * i + 2u]);
 }
 
-fn traverse_bvh(ray: Ray, mesh: MeshInfo, res: ptr<function, TraceResult>) {
+fn intersect_primitive(ray: Ray, primitive: ProceduralPrimitive, hit: ptr<function, f32>) -> bool {
+    if primitive.kind == PRIMITIVE_SPHERE {
+        let radius = (primitive.max.x - primitive.min.x) * 0.5;
+        return intersect_sphere(ray, (primitive.min + primitive.max) * 0.5, radius, hit);
+    }
+    return intersect_box(ray, primitive.min, primitive.max, hit);
+}
+
+// Object space normal of the primitive surface at `point`
+fn primitive_normal(primitive: ProceduralPrimitive, point: vec3<f32>) -> vec3<f32> {
+    let center = (primitive.min + primitive.max) * 0.5;
+    if primitive.kind == PRIMITIVE_SPHERE {
+        return normalize(point - center);
+    }
+    let local = (point - center) / ((primitive.max - primitive.min) * 0.5);
+    let dist = abs(local);
+    let max_dist = max_element(dist);
+    if max_dist == dist.x {
+        return vec3(sign(local.x), 0., 0.);
+    } else if max_dist == dist.y {
+        return vec3(0., sign(local.y), 0.);
+    }
+    return vec3(0., 0., sign(local.z));
+}
+
+fn traverse_bvh(ray: Ray, mesh_id: u32, instance_id: u32, res: ptr<function, TraceResult>) {
+    let mesh = meshes[mesh_id];
     var stack = stack_new();
     stack_push(&stack, mesh.bvh_index);
 
@@ -42,11 +70,18 @@ fn traverse_bvh(ray: Ray, mesh: MeshInfo, res: ptr<function, TraceResult>) {
         if node.count > 0u { // is leaf
             for (var i = 0u; i < node.count; i += 1u) {
                 let idx = node.left_first + i;
+                if mesh.primitive_count > 0u {
+                    if intersect_primitive(ray, primitives[mesh.first_primitive + idx], &hit) {
+                        *res = TraceResult(vec3(0.), vec3(0.), vec3(0.), true, hit, idx, vec2(0.), mesh_id, instance_id);
+                    }
+                    continue;
+                }
                 let v0 = fetch_vertex(3u * idx + 0u, mesh);
                 let v1 = fetch_vertex(3u * idx + 1u, mesh);
                 let v2 = fetch_vertex(3u * idx + 2u, mesh);
-                if intersect_trig(ray, v0, v1, v2, &hit) {
-                    *res = TraceResult(v0, v1, v2, true, hit);
+                var bary: vec2<f32>;
+                if intersect_trig_bary(ray, v0, v1, v2, &hit, &bary) {
+                    *res = TraceResult(v0, v1, v2, true, hit, idx, bary, mesh_id, instance_id);
                 }
             }
         } else {
@@ -75,15 +110,20 @@ fn traverse_bvh(ray: Ray, mesh: MeshInfo, res: ptr<function, TraceResult>) {
     }
 }
 
-fn instance_intersect(ray: Ray, instance: Instance, res: ptr<function, TraceResult>) {
+fn instance_intersect(ray: Ray, instance_id: u32, res: ptr<function, TraceResult>) {
     var new_ray = ray;
 
-    let mesh = meshes[instance.mesh_id];
+    let instance = instances[instance_id];
+    // A root leaf is traced without a box test, its instance may have been
+    // removed since the TLAS was built
+    if (instance.flags & INSTANCE_REMOVED) != 0u {
+        return;
+    }
     new_ray.eye = (instance.inv_transform * vec4(ray.eye, 1.)).xyz;
     new_ray.dir = (instance.inv_transform * vec4(ray.dir, 0.)).xyz;
     new_ray.inv_dir = 1. / new_ray.dir;
 
-    traverse_bvh(new_ray, mesh, res);
+    traverse_bvh(new_ray, instance.mesh_id, instance_id, res);
 }
 
 fn traverse_tlas(ray: Ray) -> TraceResult {
@@ -93,11 +133,11 @@ fn traverse_tlas(ray: Ray) -> TraceResult {
     var res = trace_result_new();
     while stack.head > 0u {
         let node = tlas_nodes[stack_pop(&stack)];
-        if node.left_right == 0u { // is leaf
-            instance_intersect(ray, instances[node.instance_idx], &res);
+        if node.left == 0u { // is leaf
+            instance_intersect(ray, node.instance_idx, &res);
 		} else {
-            var min_index = node.left_right & 0xffffu;
-            var max_index = node.left_right >> 16u;
+            var min_index = node.left;
+            var max_index = node.left + 1u;
 
             let min_child = tlas_nodes[min_index];
             let max_child = tlas_nodes[max_index];
@@ -121,3 +161,83 @@ fn traverse_tlas(ray: Ray) -> TraceResult {
     }
     return res;
 }
+
+// Any-hit counterpart of `traverse_bvh`, stops at the first accepted hit
+// closer than `t_max`
+fn occluded_bvh(ray: Ray, mesh_id: u32, instance_id: u32, t_max: f32) -> bool {
+    let mesh = meshes[mesh_id];
+    var stack = stack_new();
+    stack_push(&stack, mesh.bvh_index);
+
+    while stack.head > 0u {
+        let node = bvh_nodes[stack_pop(&stack)];
+        if node.count > 0u { // is leaf
+            for (var i = 0u; i < node.count; i += 1u) {
+                let idx = node.left_first + i;
+                var dist = t_max;
+                if mesh.primitive_count > 0u {
+                    if intersect_primitive(ray, primitives[mesh.first_primitive + idx], &dist) {
+                        return true;
+                    }
+                    continue;
+                }
+                let v0 = fetch_vertex(3u * idx + 0u, mesh);
+                let v1 = fetch_vertex(3u * idx + 1u, mesh);
+                let v2 = fetch_vertex(3u * idx + 2u, mesh);
+                var bary: vec2<f32>;
+                if intersect_trig_bary(ray, v0, v1, v2, &dist, &bary) && alpha_test(instance_id, idx, bary) {
+                    return true;
+                }
+            }
+        } else {
+            // Any hit will do, so children are not ordered
+            for (var i = 0u; i < 2u; i += 1u) {
+                let index = mesh.bvh_index + node.left_first + i;
+                let child = bvh_nodes[index];
+                if intersect_aabb(ray, child.min, child.max, t_max) < t_max {
+                    stack_push(&stack, index);
+                }
+            }
+        }
+    }
+    return false;
+}
+
+fn instance_occluded(ray: Ray, instance_id: u32, t_max: f32) -> bool {
+    var new_ray = ray;
+
+    let instance = instances[instance_id];
+    // A root leaf is traced without a box test, its instance may have been
+    // removed since the TLAS was built
+    if (instance.flags & INSTANCE_REMOVED) != 0u {
+        return false;
+    }
+    new_ray.eye = (instance.inv_transform * vec4(ray.eye, 1.)).xyz;
+    new_ray.dir = (instance.inv_transform * vec4(ray.dir, 0.)).xyz;
+    new_ray.inv_dir = 1. / new_ray.dir;
+
+    return occluded_bvh(new_ray, instance.mesh_id, instance_id, t_max);
+}
+
+// Whether anything blocks `ray` before `t_max`, in units of `ray.dir`
+fn occluded_tlas(ray: Ray, t_max: f32) -> bool {
+    var stack = stack_new();
+    stack_push(&stack, 0u);
+
+    while stack.head > 0u {
+        let node = tlas_nodes[stack_pop(&stack)];
+        if node.left == 0u { // is leaf
+            if instance_occluded(ray, node.instance_idx, t_max) {
+                return true;
+            }
+        } else {
+            for (var i = 0u; i < 2u; i += 1u) {
+                let child = tlas_nodes[node.left + i];
+                if intersect_aabb(ray, child.min, child.max, t_max) < t_max {
+                    stack_push(&stack, node.left + i);
+                }
+            }
+        }
+    }
+    return false;
+}
@@ -0,0 +1,102 @@
+#import "./stack.wgsl"
+#import "./intersections.wgsl"
+
+// Traversal of `bvh::CompressedBvh` trees. Nodes are read as raw words so one
+// code path serves 8 and 16 bit planes, call sites pass the bits as a constant.
+// Expects `compressed_nodes: array<u32>` and `fetch_vertex` in scope.
+
+const COMPRESSED_EMPTY_CHILD: u32 = 0xffffffffu;
+const COMPRESSED_LEAF_FLAG: u32 = 0x80000000u;
+
+struct CompressedHit {
+	hit: bool,
+	dist: f32,
+	triangle: u32,
+	barycentrics: vec2<f32>,
+}
+
+// Origin, exponents, two children and 12 planes of `bits` each
+fn compressed_node_stride(bits: u32) -> u32 {
+    return 6u + 12u * bits / 32u;
+}
+
+fn compressed_plane(node: u32, bits: u32, plane: u32, slot: u32) -> f32 {
+    let index = plane * 2u + slot;
+    let per_word = 32u / bits;
+    let word = compressed_nodes[node + 6u + index / per_word];
+    let mask = (1u << bits) - 1u;
+    return f32((word >> (bits * (index % per_word))) & mask);
+}
+
+// Distance to the bounds of child `slot` of the node at word offset `node`
+fn compressed_child_dist(ray: Ray, node: u32, bits: u32, slot: u32, t_max: f32) -> f32 {
+    let origin = bitcast<vec3<f32>>(vec3(compressed_nodes[node], compressed_nodes[node + 1u], compressed_nodes[node + 2u]));
+    let exponents = (vec3(compressed_nodes[node + 3u]) >> vec3(0u, 8u, 16u)) & vec3(0xffu);
+    let scale = bitcast<vec3<f32>>(exponents << vec3(23u));
+    let lo = vec3(
+        compressed_plane(node, bits, 0u, slot),
+        compressed_plane(node, bits, 1u, slot),
+        compressed_plane(node, bits, 2u, slot),
+    );
+    let hi = vec3(
+        compressed_plane(node, bits, 3u, slot),
+        compressed_plane(node, bits, 4u, slot),
+        compressed_plane(node, bits, 5u, slot),
+    );
+    return intersect_aabb(ray, origin + lo * scale, origin + hi * scale, t_max);
+}
+
+fn compressed_leaf_intersect(ray: Ray, mesh: MeshInfo, child: u32, res: ptr<function, CompressedHit>) {
+    let start = child & 0xffffffu;
+    let count = (child & ~COMPRESSED_LEAF_FLAG) >> 24u;
+    for (var idx = start; idx < start + count; idx += 1u) {
+        let v0 = fetch_vertex(3u * idx + 0u, mesh);
+        let v1 = fetch_vertex(3u * idx + 1u, mesh);
+        let v2 = fetch_vertex(3u * idx + 2u, mesh);
+        var bary: vec2<f32>;
+        if intersect_trig_bary(ray, v0, v1, v2, &(*res).dist, &bary) {
+            (*res).hit = true;
+            (*res).triangle = idx;
+            (*res).barycentrics = bary;
+        }
+    }
+}
+
+// `root` is the word offset of the mesh root, child indices are relative to it
+fn traverse_compressed_bvh(ray: Ray, mesh: MeshInfo, root: u32, bits: u32, t_max: f32) -> CompressedHit {
+    var res = CompressedHit(false, t_max, 0u, vec2(0.));
+    let stride = compressed_node_stride(bits);
+
+    var stack = stack_new();
+    stack_push(&stack, 0u);
+    while stack.head > 0u {
+        let node = root + stack_pop(&stack) * stride;
+        var children = vec2(compressed_nodes[node + 4u], compressed_nodes[node + 5u]);
+        var dists = vec2(MAX_DIST);
+        for (var slot = 0u; slot < 2u; slot += 1u) {
+            if children[slot] != COMPRESSED_EMPTY_CHILD {
+                dists[slot] = compressed_child_dist(ray, node, bits, slot, res.dist);
+            }
+        }
+        if dists.y < dists.x {
+            children = children.yx;
+            dists = dists.yx;
+        }
+
+        // Leaves are tested right away, nearest first
+        for (var i = 0u; i < 2u; i += 1u) {
+            if dists[i] < res.dist && (children[i] & COMPRESSED_LEAF_FLAG) != 0u {
+                compressed_leaf_intersect(ray, mesh, children[i], &res);
+            }
+        }
+
+        // Nearest child ends up on top
+        for (var i = 0u; i < 2u; i += 1u) {
+            let slot = 1u - i;
+            if dists[slot] < res.dist && (children[slot] & COMPRESSED_LEAF_FLAG) == 0u {
+                stack_push(&stack, children[slot]);
+            }
+        }
+    }
+    return res;
+}
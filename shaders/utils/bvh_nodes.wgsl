@@ -0,0 +1,24 @@
+struct TlasNode {
+	min: vec3<f32>,
+	left: u32,
+	max: vec3<f32>,
+	instance_idx: u32,
+}
+
+struct BvhNode {
+	min: vec3<f32>,
+	left_first: u32,
+	max: vec3<f32>,
+	count: u32,
+}
+
+// Mirrors `bvh::ProceduralPrimitive`, spheres touch all faces of the bounds
+const PRIMITIVE_SPHERE: u32 = 0u;
+const PRIMITIVE_AABB: u32 = 1u;
+
+struct ProceduralPrimitive {
+	min: vec3<f32>,
+	kind: u32,
+	max: vec3<f32>,
+	padding: u32,
+}
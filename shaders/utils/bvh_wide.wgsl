@@ -0,0 +1,110 @@
+#import "./intersections.wgsl"
+
+// Traversal of `bvh::WideBvh` trees. Nodes are read as raw words so one code
+// path serves every width, call sites pass the width as a constant.
+// Expects `wide_nodes: array<u32>` and `fetch_vertex` in scope.
+
+const WIDE_EMPTY_CHILD: u32 = 0xffffffffu;
+const WIDE_LEAF_FLAG: u32 = 0x80000000u;
+const WIDE_MAX_WIDTH: u32 = 8u;
+// Mirrors `bvh::WIDE_STACK_LEN`
+const WIDE_STACK_LEN: u32 = 48u;
+
+struct WideHit {
+	hit: bool,
+	dist: f32,
+	triangle: u32,
+	barycentrics: vec2<f32>,
+}
+
+// Origin, exponents, children and one byte per child for each of the 6 planes
+fn wide_node_stride(width: u32) -> u32 {
+    return 4u + width + width * 6u / 4u;
+}
+
+fn wide_plane(node: u32, width: u32, plane: u32, slot: u32) -> f32 {
+    let byte = plane * width + slot;
+    let word = wide_nodes[node + 4u + width + byte / 4u];
+    return f32((word >> (8u * (byte % 4u))) & 0xffu);
+}
+
+fn wide_leaf_intersect(ray: Ray, mesh: MeshInfo, child: u32, res: ptr<function, WideHit>) {
+    let start = child & 0xffffffu;
+    let count = (child & ~WIDE_LEAF_FLAG) >> 24u;
+    for (var idx = start; idx < start + count; idx += 1u) {
+        let v0 = fetch_vertex(3u * idx + 0u, mesh);
+        let v1 = fetch_vertex(3u * idx + 1u, mesh);
+        let v2 = fetch_vertex(3u * idx + 2u, mesh);
+        var bary: vec2<f32>;
+        if intersect_trig_bary(ray, v0, v1, v2, &(*res).dist, &bary) {
+            (*res).hit = true;
+            (*res).triangle = idx;
+            (*res).barycentrics = bary;
+        }
+    }
+}
+
+// `root` is the word offset of the mesh root, child indices are relative to it
+fn traverse_wide_bvh(ray: Ray, mesh: MeshInfo, root: u32, width: u32, t_max: f32) -> WideHit {
+    var res = WideHit(false, t_max, 0u, vec2(0.));
+    let stride = wide_node_stride(width);
+
+    var stack: array<u32, WIDE_STACK_LEN>;
+    var head = 1u;
+    stack[0] = 0u;
+    while head > 0u {
+        head -= 1u;
+        let node = root + stack[head] * stride;
+        let origin = bitcast<vec3<f32>>(vec3(wide_nodes[node], wide_nodes[node + 1u], wide_nodes[node + 2u]));
+        let exponents = (vec3(wide_nodes[node + 3u]) >> vec3(0u, 8u, 16u)) & vec3(0xffu);
+        let scale = bitcast<vec3<f32>>(exponents << vec3(23u));
+
+        // Internal children that were hit, farthest first
+        var dists: array<f32, WIDE_MAX_WIDTH>;
+        var children: array<u32, WIDE_MAX_WIDTH>;
+        var count = 0u;
+        for (var slot = 0u; slot < width; slot += 1u) {
+            let child = wide_nodes[node + 4u + slot];
+            if child == WIDE_EMPTY_CHILD {
+                continue;
+            }
+            let lo = vec3(
+                wide_plane(node, width, 0u, slot),
+                wide_plane(node, width, 1u, slot),
+                wide_plane(node, width, 2u, slot),
+            );
+            let hi = vec3(
+                wide_plane(node, width, 3u, slot),
+                wide_plane(node, width, 4u, slot),
+                wide_plane(node, width, 5u, slot),
+            );
+            let dist = intersect_aabb(ray, origin + lo * scale, origin + hi * scale, res.dist);
+            if dist >= res.dist {
+                continue;
+            }
+
+            if (child & WIDE_LEAF_FLAG) != 0u {
+                wide_leaf_intersect(ray, mesh, child, &res);
+            } else {
+                var i = count;
+                while i > 0u && dists[i - 1u] < dist {
+                    dists[i] = dists[i - 1u];
+                    children[i] = children[i - 1u];
+                    i -= 1u;
+                }
+                dists[i] = dist;
+                children[i] = child;
+                count += 1u;
+            }
+        }
+
+        // Nearest child ends up on top. A full stack drops the farthest
+        // children, `bvh::WideBvh::required_stack_len` tells if it can fill.
+        let first = count - min(count, WIDE_STACK_LEN - head);
+        for (var i = first; i < count; i += 1u) {
+            stack[head] = children[i];
+            head += 1u;
+        }
+    }
+    return res;
+}
@@ -23,6 +23,11 @@ fn intersect_aabb(ray: Ray, bmin: vec3<f32>, bmax: vec3<f32>, t: f32) -> f32 {
 }
 
 fn intersect_trig(ray: Ray, v0: vec3<f32>, v1: vec3<f32>, v2: vec3<f32>, hit: ptr<function,f32>) -> bool {
+    var bary: vec2<f32>;
+    return intersect_trig_bary(ray, v0, v1, v2, hit, &bary);
+}
+
+fn intersect_trig_bary(ray: Ray, v0: vec3<f32>, v1: vec3<f32>, v2: vec3<f32>, hit: ptr<function,f32>, bary: ptr<function, vec2<f32>>) -> bool {
     let edge1 = v1 - v0;
     let edge2 = v2 - v0;
     let uvec = cross(ray.dir, edge2);
@@ -38,8 +43,49 @@ fn intersect_trig(ray: Ray, v0: vec3<f32>, v1: vec3<f32>, v2: vec3<f32>, hit: pt
     let t = inv_det * dot(edge2, vvec);
     if t > 0.0 && t < *hit {
         *hit = t;
+        *bary = vec2(u, v);
         return true;
     } else {
         return false;
     }
 }
+
+// Takes the first of the `near` and `far` surface crossings in front of the
+// ray, so rays starting inside a shape hit it on the way out
+fn closest_crossing(near: f32, far: f32, hit: ptr<function, f32>) -> bool {
+    var t = near;
+    if t <= 0.0001 {
+        t = far;
+    }
+    if t > 0.0001 && t < *hit {
+        *hit = t;
+        return true;
+    }
+    return false;
+}
+
+// `ray.dir` doesn't have to be normalized, as for rays in object space
+fn intersect_sphere(ray: Ray, center: vec3<f32>, radius: f32, hit: ptr<function, f32>) -> bool {
+    let oc = ray.eye - center;
+    let a = dot(ray.dir, ray.dir);
+    let b = dot(oc, ray.dir);
+    let c = dot(oc, oc) - radius * radius;
+    let discriminant = b * b - a * c;
+    if discriminant < 0. {
+        return false;
+    }
+    let root = sqrt(discriminant);
+    return closest_crossing((-b - root) / a, (-b + root) / a, hit);
+}
+
+// Unlike `intersect_aabb` this hits the surface, not the volume of the box
+fn intersect_box(ray: Ray, bmin: vec3<f32>, bmax: vec3<f32>, hit: ptr<function, f32>) -> bool {
+    let t1 = (bmin - ray.eye) * ray.inv_dir;
+    let t2 = (bmax - ray.eye) * ray.inv_dir;
+    let near = max_element(min(t1, t2));
+    let far = min_element(max(t1, t2));
+    if near > far {
+        return false;
+    }
+    return closest_crossing(near, far, hit);
+}
@@ -149,7 +149,7 @@ fn ltc_evaluate_rect(nor: vec3<f32>, view: vec3<f32>, pos: vec3<f32>, mminv: mat
     var uv = vec2(z * 0.5 + 0.5, len);
     uv = uv * LUT_SCALE + LUT_BIAS;
 
-    let scale = textureSample(texture_array[LTC2_TEXTURE], tex_ltc_sampler, uv).w;
+    let scale = textureSampleLevel(texture_array[LTC2_TEXTURE], tex_ltc_sampler, uv, 0.).w;
 
     var sum = len * scale;
     if behind && !two_sided {
@@ -164,8 +164,9 @@ fn ltc_matrix(nor: vec3<f32>, view: vec3<f32>, roughness: f32) -> Ltc {
     var uv = vec2(roughness, sqrt(1.0 - ndotv));
     uv = uv * LUT_SCALE + LUT_BIAS;
 
-    let t1 = textureSample(texture_array[LTC1_TEXTURE], tex_sampler, uv);
-    let t2 = textureSample(texture_array[LTC2_TEXTURE], tex_sampler, uv);
+    // The LUTs have a single mip, explicit level allows non-uniform control flow
+    let t1 = textureSampleLevel(texture_array[LTC1_TEXTURE], tex_sampler, uv, 0.);
+    let t2 = textureSampleLevel(texture_array[LTC2_TEXTURE], tex_sampler, uv, 0.);
 
     var res: Ltc;
     res.t1 = t1;
@@ -0,0 +1,92 @@
+// glTF metallic-roughness inputs of a `Material` at some uv, every factor
+// already applied, and the per `MATERIAL_*` layer shading built on them.
+// Expects `texture_array` and `samplers` in scope.
+
+#import "./brdf.wgsl"
+
+struct SurfaceSample {
+    base_color: vec4<f32>,
+    emissive: vec3<f32>,
+    metallic: f32,
+    roughness: f32,
+    occlusion: f32,
+    material_type: u32,
+    ior: f32,
+    clearcoat: f32,
+    clearcoat_roughness: f32,
+    sheen_color: vec3<f32>,
+    sheen_roughness: f32,
+    transmission: f32,
+}
+
+// Reads the texture with the sampler of its glTF texture.
+fn sample_texture(t: TextureRef, uv: vec2<f32>) -> vec4<f32> {
+    return textureSample(texture_array[t.texture_id], samplers[t.sampler_id], uv);
+}
+
+fn sample_texture_level(t: TextureRef, uv: vec2<f32>, level: f32) -> vec4<f32> {
+    return textureSampleLevel(texture_array[t.texture_id], samplers[t.sampler_id], uv, level);
+}
+
+fn sample_material(material: Material, uv: vec2<f32>) -> SurfaceSample {
+    let albedo = sample_texture(material.albedo, uv);
+    let emissive = sample_texture(material.emissive, uv).rgb;
+    let metallic_roughness = sample_texture(material.metallic_roughness, uv);
+    let occlusion = sample_texture(material.occlusion, uv).r;
+    // Sampled for every material, derivatives are undefined in the
+    // non-uniform branch on the material type
+    let clearcoat = sample_texture(material.clearcoat, uv).r;
+    let clearcoat_roughness = sample_texture(material.clearcoat_roughness, uv).g;
+    let sheen_color = sample_texture(material.sheen_color, uv).rgb;
+    let sheen_roughness = sample_texture(material.sheen_roughness, uv).a;
+    let transmission = sample_texture(material.transmission, uv).r;
+
+    var out: SurfaceSample;
+    out.base_color = material.base_color * albedo;
+    out.emissive = material.emissive_factor * emissive;
+    out.metallic = saturate(material.metallic_factor * metallic_roughness.b);
+    out.roughness = saturate(material.roughness_factor * metallic_roughness.g);
+    out.occlusion = mix(1., occlusion, material.occlusion_strength);
+    out.material_type = material.material_type;
+    out.ior = material.ior;
+    out.clearcoat = material.clearcoat_factor * clearcoat;
+    out.clearcoat_roughness = material.clearcoat_roughness_factor * clearcoat_roughness;
+    out.sheen_color = material.sheen_color_factor * sheen_color;
+    out.sheen_roughness = material.sheen_roughness_factor * sheen_roughness;
+    out.transmission = material.transmission_factor * transmission;
+    return out;
+}
+
+fn is_alpha_cut(material: Material, alpha: f32) -> bool {
+    return material.alpha_mode != ALPHA_MODE_OPAQUE && alpha < material.alpha_cutoff;
+}
+
+fn has_layer(surface: SurfaceSample, layer: u32) -> bool {
+    return (surface.material_type & layer) != 0u;
+}
+
+fn surface_brdf(surface: SurfaceSample, dfg: vec2<f32>) -> Brdf {
+    var brdf = brdf_new(surface.base_color.rgb, surface.metallic, surface.roughness, surface.ior, dfg);
+    // Transmitted light replaces the diffuse reflection
+    brdf.diffuse_color *= 1. - surface.transmission;
+    return brdf;
+}
+
+// `brdf_evaluate` with the clear coat, sheen and transmission layers of the
+// surface on top. `MATERIAL_UNLIT` surfaces aren't lit at all, callers
+// output their base color and emission instead.
+fn surface_evaluate(surface: SurfaceSample, brdf: Brdf, nor: vec3<f32>, view: vec3<f32>, light_dir: vec3<f32>) -> vec3<f32> {
+    var color = brdf_evaluate(brdf, nor, view, light_dir);
+    if has_layer(surface, MATERIAL_TRANSMISSION) {
+        let tint = surface.base_color.rgb * surface.transmission * (1. - surface.metallic);
+        color += brdf_transmission(brdf, tint, nor, view, light_dir);
+    }
+    if has_layer(surface, MATERIAL_SHEEN) {
+        color += brdf_sheen(surface.sheen_color, surface.sheen_roughness, nor, view, light_dir);
+    }
+    if has_layer(surface, MATERIAL_CLEARCOAT) {
+        let coat = brdf_clearcoat(surface.clearcoat, surface.clearcoat_roughness, nor, view, light_dir);
+        color = color * coat.y + coat.x;
+    }
+    return color;
+}
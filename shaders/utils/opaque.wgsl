@@ -0,0 +1,4 @@
+// `alpha_test` hook of `./bvh.wgsl` for scenes without cutout materials
+fn alpha_test(instance_id: u32, triangle: u32, barycentrics: vec2<f32>) -> bool {
+    return true;
+}
@@ -1,3 +1,4 @@
+// Mirrors `bvh::WGSL_STACK_LEN`, `bvh::MAX_DEPTH` keeps CPU built trees within it
 const STACK_LEN: u32 = 24u;
 struct Stack {
     arr: array<u32, STACK_LEN>,
@@ -9,9 +10,12 @@ fn stack_new() -> Stack {
     return Stack(arr, 0u);
 }
 
+// Deeper trees lose subtrees instead of writing past the end
 fn stack_push(stack: ptr<function, Stack>, val: u32) {
-    (*stack).arr[(*stack).head] = val;
-    (*stack).head += 1u;
+    if (*stack).head < STACK_LEN {
+        (*stack).arr[(*stack).head] = val;
+        (*stack).head += 1u;
+    }
 }
 
 fn stack_pop(stack: ptr<function, Stack>) -> u32 {
@@ -1,11 +1,13 @@
 #import "shared.wgsl"
 #import "utils/math.wgsl"
 #import "utils/encoding.wgsl"
+#import "utils/material.wgsl"
 
 @group(0) @binding(0) var<uniform> camera: Camera;
 @group(1) @binding(0) var texture_array: binding_array<texture_2d<f32>>;
 @group(1) @binding(1) var tex_sampler: sampler;
 @group(1) @binding(2) var tex_int_sampler: sampler;
+@group(1) @binding(3) var samplers: binding_array<sampler>;
 
 // FIXME: add more bind groups for only read storage
 @group(2) @binding(0) var<storage, read_write> instances: array<Instance>;
@@ -64,22 +66,28 @@ fn get_tbn(normal: vec3<f32>, tangent: vec3<f32>, bitangent: vec3<f32>) -> mat3x
 }
 
 @fragment
-fn fs_main(in: VertexOutput) -> FragmentOutput {
+fn fs_main(in: VertexOutput, @builtin(front_facing) front_facing: bool) -> FragmentOutput {
     let uv = in.uv;
     let material = materials[in.material_id];
-    let albedo_tex = textureSample(texture_array[material.albedo], tex_sampler, uv);
-    let normal_tex = textureSample(texture_array[material.normal], tex_sampler, uv);
+    let albedo_tex = sample_texture(material.albedo, uv);
+    let normal_tex = sample_texture(material.normal, uv);
 
-    if material.base_color.w < 0.5 || albedo_tex.a < 0.5 {
+    if is_alpha_cut(material, material.base_color.a * albedo_tex.a) {
      	 discard;
     }
 
     var normal = vec3(0.);
-    if material.normal == 0u {
+    if material.normal.texture_id == 0u {
         normal = normalize(in.normal);
     } else {
         let tbn = get_tbn(in.normal, in.tangent, in.bitangent);
-        normal = normalize(tbn * (normal_tex.rgb * 2.0 - 1.0));
+        var tangent_normal = normal_tex.rgb * 2.0 - 1.0;
+        tangent_normal = vec3(tangent_normal.xy * material.normal_scale, tangent_normal.z);
+        normal = normalize(tbn * tangent_normal);
+    }
+    // Only double sided materials are drawn without culling
+    if !front_facing {
+        normal = -normal;
     }
 
     let packed_norm = encode_octahedral_32(normal);
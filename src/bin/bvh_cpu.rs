@@ -1,17 +1,23 @@
-use std::{array, time::Duration};
+use std::{
+    array,
+    time::{Duration, Instant},
+};
 
-use bvh::{Bvh, Dist::*, Ray};
+use bvh::{Bvh, Ray, RayPacket, TriangleHit};
 
 use color_eyre::Result;
 use glam::Vec4Swizzles;
 use half::f16;
 use rand::Rng;
+use rayon::prelude::*;
 use voidin::*;
 
 const WIDTH: usize = 640;
 const HEIGHT: usize = 640;
 type Pixel = [f16; 4];
 const PIXEL_SIZE: usize = std::mem::size_of::<Pixel>();
+/// Rows rendered by one task, packets cover 2x2 pixel quads within them.
+const TILE_ROWS: usize = 8;
 
 struct Demo {
     cpu_pixels: Vec<Pixel>,
@@ -21,6 +27,9 @@ struct Demo {
     indices: Vec<UVec3>,
 
     bvh: Bvh,
+
+    use_packets: bool,
+    render_time: Duration,
 }
 
 impl Example for Demo {
@@ -61,6 +70,9 @@ impl Example for Demo {
             indices,
 
             bvh,
+
+            use_packets: true,
+            render_time: Duration::ZERO,
         })
     }
 
@@ -70,10 +82,9 @@ impl Example for Demo {
 
     fn render(&mut self, mut ctx: RenderContext) {
         let camera = ctx.app_state.camera.get_uniform(None);
-        for (i, p) in self.cpu_pixels.iter_mut().enumerate() {
-            let x = (i % WIDTH) as f32 / WIDTH as f32;
-            let y = (i / HEIGHT) as f32 / HEIGHT as f32;
-            let Vec2 { x, y } = (vec2(x, y) - 0.5) * vec2(2., -2.);
+        let primary_ray = |x: usize, y: usize| {
+            let uv = vec2(x as f32 / WIDTH as f32, y as f32 / HEIGHT as f32);
+            let Vec2 { x, y } = (uv - 0.5) * vec2(2., -2.);
 
             let view_pos = camera.clip_to_world * vec4(x, y, 1., 1.);
             let view_tang = camera.clip_to_world * vec4(x, y, 0., 1.);
@@ -81,19 +92,46 @@ impl Example for Demo {
             let eye = view_pos.xyz() / view_pos.w;
             let dir = view_tang.xyz().normalize();
 
-            let ray = Ray::new(eye, dir);
-
-            // let hit = self.bvh.traverse(&self.vertices, &self.indices, ray, 0, 1e30);
-            let hit = self.bvh.traverse_iter(&self.vertices, &self.indices, ray);
+            Ray::new(eye, dir)
+        };
+        let shade = |hit: Option<TriangleHit>| {
             let val = match hit {
-                Hit(dist) => {
+                Some(hit) => {
                     let limit = 50.;
-                    f16::from_f32((limit - dist) / limit)
+                    f16::from_f32((limit - hit.t) / limit)
                 }
-                Miss => f16::ZERO,
+                None => f16::ZERO,
             };
-            *p = [val, val, val, f16::ONE];
-        }
+            [val, val, val, f16::ONE]
+        };
+
+        let (bvh, vertices, indices) = (&self.bvh, &self.vertices, &self.indices);
+        let use_packets = self.use_packets;
+        let start = Instant::now();
+        self.cpu_pixels
+            .par_chunks_mut(WIDTH * TILE_ROWS)
+            .enumerate()
+            .for_each(|(tile, pixels)| {
+                let y0 = tile * TILE_ROWS;
+                if !use_packets {
+                    for (i, p) in pixels.iter_mut().enumerate() {
+                        let ray = primary_ray(i % WIDTH, y0 + i / WIDTH);
+                        *p = shade(bvh.intersect(vertices, indices, ray));
+                    }
+                    return;
+                }
+                for y in (0..TILE_ROWS).step_by(2) {
+                    for x in (0..WIDTH).step_by(2) {
+                        let quad = [(x, y), (x + 1, y), (x, y + 1), (x + 1, y + 1)];
+                        let packet = RayPacket::new(quad.map(|(x, y)| primary_ray(x, y0 + y)));
+                        let hits = bvh.intersect_packet(vertices, indices, &packet);
+                        for ((x, y), hit) in quad.into_iter().zip(hits) {
+                            pixels[y * WIDTH + x] = shade(hit);
+                        }
+                    }
+                }
+            });
+        self.render_time = start.elapsed();
 
         ctx.gpu
             .queue()
@@ -121,6 +159,8 @@ impl Example for Demo {
                     "Fps: {:.04?}",
                     Duration::from_secs_f64(ctx.app_state.dt)
                 ));
+                ui.label(format!("Render: {:.04?}", self.render_time));
+                ui.checkbox(&mut self.use_packets, "Ray packets");
             });
         });
     }
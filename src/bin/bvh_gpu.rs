@@ -1,18 +1,147 @@
 use std::time::Duration;
 
-use app::MeshInfo;
-use bvh::{BvhNode, Tlas, TlasNode};
+use app::{MeshId, MeshInfo};
+use bvh::{
+    Bvh, Bvh4Node, Bvh8Node, BvhNode, CompressedBvh, CompressedNode16, CompressedNode8, Ray, Tlas,
+    TlasNode, WideBvh, WideNode, WGSL_STACK_LEN, WIDE_STACK_LEN,
+};
 use color_eyre::Result;
 use voidin::*;
 
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+enum BvhLayout {
+    Binary,
+    Bvh4,
+    Bvh8,
+    Compressed8,
+    Compressed16,
+    /// Binary trees of the bunnies built on the GPU by [`pass::lbvh::Lbvh`].
+    Lbvh,
+}
+
+impl BvhLayout {
+    const ALL: [Self; 6] = [
+        Self::Binary,
+        Self::Bvh4,
+        Self::Bvh8,
+        Self::Compressed8,
+        Self::Compressed16,
+        Self::Lbvh,
+    ];
+
+    fn entry_point(self) -> &'static str {
+        match self {
+            Self::Binary => "fs_main",
+            Self::Bvh4 => "fs_bvh4",
+            Self::Bvh8 => "fs_bvh8",
+            Self::Compressed8 => "fs_compressed8",
+            Self::Compressed16 => "fs_compressed16",
+            Self::Lbvh => "fs_main",
+        }
+    }
+}
+
+/// Root of meshes too large for the wide and compressed layouts, matches
+/// `BINARY_ROOT` in `bvh_trace.wgsl`.
+const BINARY_ROOT: u32 = u32::MAX;
+
+/// Rays per side of the grid traced along each axis by [`check_lbvh`].
+const CHECK_GRID: usize = 64;
+
+struct LbvhCheck {
+    rays: usize,
+    mismatches: usize,
+    depth: usize,
+}
+
+enum LbvhState {
+    Pending,
+    Built,
+    Checked(LbvhCheck),
+}
+
+/// Traces the same rays through the CPU built BVH and the LBVH of every
+/// pair of meshes, which share their vertices. Rays form a grid along each
+/// axis over the mesh bounds, hits match when both miss or agree on `t`.
+fn check_lbvh(gpu: &Gpu, meshes: &MeshPool, pairs: &[(MeshId, MeshId)]) -> LbvhCheck {
+    let nodes = meshes.bvh_nodes.read(gpu);
+    let gpu_indices = meshes.indices.read(gpu);
+    let mut check = LbvhCheck {
+        rays: 0,
+        mismatches: 0,
+        depth: 0,
+    };
+    for &(cpu_mesh, gpu_mesh) in pairs {
+        let cpu_info = meshes.mesh_info_cpu[cpu_mesh.index() as usize];
+        let gpu_info = meshes.mesh_info_cpu[gpu_mesh.index() as usize];
+        let triangles = gpu_info.index_count as usize / 3;
+        let lbvh = Bvh::from_nodes(nodes[gpu_info.bvh_index as usize..][..2 * triangles].to_vec());
+        check.depth = check.depth.max(lbvh.depth());
+
+        let mesh_indices = |indices: &[u32], info: MeshInfo| -> Vec<UVec3> {
+            bytemuck::cast_slice(&indices[info.base_index as usize..][..info.index_count as usize])
+                .to_vec()
+        };
+        let cpu_indices = mesh_indices(&meshes.indices_cpu, cpu_info);
+        let gpu_indices = mesh_indices(&gpu_indices, gpu_info);
+        let cpu_vertices = &meshes.vertices_cpu[cpu_info.vertex_offset as usize..];
+        let gpu_vertices = &meshes.vertices_cpu[gpu_info.vertex_offset as usize..];
+
+        let extent = cpu_info.max - cpu_info.min;
+        for axis in 0..3 {
+            let (u, v) = ((axis + 1) % 3, (axis + 2) % 3);
+            for i in 0..CHECK_GRID {
+                for j in 0..CHECK_GRID {
+                    let mut orig = cpu_info.min;
+                    orig[axis] -= extent[axis].max(1.);
+                    orig[u] += extent[u] * (i as f32 + 0.5) / CHECK_GRID as f32;
+                    orig[v] += extent[v] * (j as f32 + 0.5) / CHECK_GRID as f32;
+                    let ray = Ray::new(orig, Vec3::AXES[axis]);
+
+                    let expected = meshes.blases[cpu_mesh.index() as usize].intersect(
+                        cpu_vertices,
+                        &cpu_indices,
+                        ray,
+                    );
+                    let actual = lbvh.intersect(gpu_vertices, &gpu_indices, ray);
+                    let matches = match (expected, actual) {
+                        (None, None) => true,
+                        (Some(expected), Some(actual)) => {
+                            (expected.t - actual.t).abs() <= 1e-4 * expected.t.max(1.)
+                        }
+                        _ => false,
+                    };
+                    check.rays += 1;
+                    check.mismatches += !matches as usize;
+                }
+            }
+        }
+    }
+    check
+}
+
 #[allow(dead_code)]
 struct Demo {
-    pipeline: RenderHandle,
+    pipelines: [RenderHandle; 5],
+    layout: BvhLayout,
+    layout_sizes: [usize; 6],
 
     tlas: Tlas,
     tlas_nodes: ResizableBuffer<TlasNode>,
+    wide_nodes: ResizableBuffer<u32>,
+    wide_roots: ResizableBuffer<[u32; 2]>,
+    compressed_nodes: ResizableBuffer<u32>,
+    compressed_roots: ResizableBuffer<[u32; 2]>,
 
     geometry_bind_group: wgpu::BindGroup,
+
+    lbvh_pass: pass::lbvh::Lbvh,
+    /// Bunny meshes paired with their copies whose BVH is built on the GPU.
+    lbvh_meshes: Vec<(MeshId, MeshId)>,
+    lbvh_state: LbvhState,
+    lbvh_tlas_nodes: ResizableBuffer<TlasNode>,
+    lbvh_instances: ResizableBuffer<Instance>,
+    lbvh_bind_group: wgpu::BindGroup,
 }
 
 impl Example for Demo {
@@ -86,22 +215,75 @@ impl Example for Demo {
                             },
                             count: None,
                         },
+                        wgpu::BindGroupLayoutEntry {
+                            binding: 6,
+                            visibility: wgpu::ShaderStages::FRAGMENT,
+                            ty: wgpu::BindingType::Buffer {
+                                ty: wgpu::BufferBindingType::Storage { read_only: true },
+                                has_dynamic_offset: false,
+                                min_binding_size: Some(u32::NSIZE),
+                            },
+                            count: None,
+                        },
+                        wgpu::BindGroupLayoutEntry {
+                            binding: 7,
+                            visibility: wgpu::ShaderStages::FRAGMENT,
+                            ty: wgpu::BindingType::Buffer {
+                                ty: wgpu::BufferBindingType::Storage { read_only: true },
+                                has_dynamic_offset: false,
+                                min_binding_size: Some(<[u32; 2]>::NSIZE),
+                            },
+                            count: None,
+                        },
+                        wgpu::BindGroupLayoutEntry {
+                            binding: 8,
+                            visibility: wgpu::ShaderStages::FRAGMENT,
+                            ty: wgpu::BindingType::Buffer {
+                                ty: wgpu::BufferBindingType::Storage { read_only: true },
+                                has_dynamic_offset: false,
+                                min_binding_size: Some(u32::NSIZE),
+                            },
+                            count: None,
+                        },
+                        wgpu::BindGroupLayoutEntry {
+                            binding: 9,
+                            visibility: wgpu::ShaderStages::FRAGMENT,
+                            ty: wgpu::BindingType::Buffer {
+                                ty: wgpu::BufferBindingType::Storage { read_only: true },
+                                has_dynamic_offset: false,
+                                min_binding_size: Some(<[u32; 2]>::NSIZE),
+                            },
+                            count: None,
+                        },
                     ],
                 });
-        let pipeline = {
+        let pipelines = {
             let camera_binding = app.world.get::<CameraUniformBinding>()?;
-            app.get_pipeline_arena_mut()
-                .process_render_pipeline_from_path(
+            let mut arena = app.get_pipeline_arena_mut();
+            let mut pipeline = |layout: BvhLayout| {
+                arena.process_render_pipeline_from_path(
                     "src/bin/bvh_trace.wgsl",
                     pipeline::RenderPipelineDescriptor {
                         layout: vec![
                             camera_binding.bind_group_layout.clone(),
                             geometry_bgl.clone(),
                         ],
+                        fragment: Some(pipeline::FragmentState {
+                            entry_point: layout.entry_point().into(),
+                            ..Default::default()
+                        }),
                         depth_stencil: None,
                         ..Default::default()
                     },
-                )?
+                )
+            };
+            [
+                pipeline(BvhLayout::Binary)?,
+                pipeline(BvhLayout::Bvh4)?,
+                pipeline(BvhLayout::Bvh8)?,
+                pipeline(BvhLayout::Compressed8)?,
+                pipeline(BvhLayout::Compressed16)?,
+            ]
         };
 
         let mut instances = vec![];
@@ -134,46 +316,301 @@ impl Example for Demo {
             .device()
             .create_resizable_buffer_init(&tlas.nodes, wgpu::BufferUsages::STORAGE);
 
-        let geometry_bind_group = app.device().create_bind_group(&wgpu::BindGroupDescriptor {
-            label: Some("Geometry Bind Group"),
-            layout: &geometry_bgl,
-            entries: &[
-                wgpu::BindGroupEntry {
-                    binding: 0,
-                    resource: tlas_nodes.as_entire_binding(),
-                },
-                wgpu::BindGroupEntry {
-                    binding: 1,
-                    resource: app.get_instance_pool().instances.as_entire_binding(),
-                },
-                wgpu::BindGroupEntry {
-                    binding: 2,
-                    resource: app.get_mesh_pool().mesh_info.as_entire_binding(),
-                },
-                wgpu::BindGroupEntry {
-                    binding: 3,
-                    resource: app.get_mesh_pool().bvh_nodes.as_entire_binding(),
-                },
-                wgpu::BindGroupEntry {
-                    binding: 4,
-                    resource: app.get_mesh_pool().vertices.as_entire_binding(),
-                },
-                wgpu::BindGroupEntry {
-                    binding: 5,
-                    resource: app.get_mesh_pool().indices.as_entire_binding(),
-                },
-            ],
-        });
+        // All BVH4 trees followed by all BVH8 ones, roots are word offsets
+        let mut wide_nodes: Vec<u32> = vec![];
+        let mut wide_roots = vec![[0u32; 2]; app.get_mesh_pool().blases.len()];
+        let mut layout_sizes = [0; 6];
+        for (mesh, bvh) in app.get_mesh_pool().blases.iter().enumerate() {
+            layout_sizes[0] += bvh.nodes.len() * std::mem::size_of::<BvhNode>();
+            let Some(wide) = WideBvh::<Bvh4Node>::collapse(bvh) else {
+                log::warn!(
+                    "Mesh {mesh} is too large for BVH{}, tracing its binary tree",
+                    Bvh4Node::WIDTH
+                );
+                wide_roots[mesh][0] = BINARY_ROOT;
+                layout_sizes[1] += bvh.nodes.len() * std::mem::size_of::<BvhNode>();
+                continue;
+            };
+            if wide.required_stack_len() > WIDE_STACK_LEN {
+                log::warn!(
+                    "BVH{} of mesh {mesh} needs {} stack entries, shaders skip subtrees past {WIDE_STACK_LEN}",
+                    Bvh4Node::WIDTH,
+                    wide.required_stack_len()
+                );
+            }
+            wide_roots[mesh][0] = wide_nodes.len() as u32;
+            wide_nodes.extend_from_slice(bytemuck::cast_slice(&wide.nodes));
+            layout_sizes[1] += wide.size_bytes();
+        }
+        for (mesh, bvh) in app.get_mesh_pool().blases.iter().enumerate() {
+            let Some(wide) = WideBvh::<Bvh8Node>::collapse(bvh) else {
+                log::warn!(
+                    "Mesh {mesh} is too large for BVH{}, tracing its binary tree",
+                    Bvh8Node::WIDTH
+                );
+                wide_roots[mesh][1] = BINARY_ROOT;
+                layout_sizes[2] += bvh.nodes.len() * std::mem::size_of::<BvhNode>();
+                continue;
+            };
+            if wide.required_stack_len() > WIDE_STACK_LEN {
+                log::warn!(
+                    "BVH{} of mesh {mesh} needs {} stack entries, shaders skip subtrees past {WIDE_STACK_LEN}",
+                    Bvh8Node::WIDTH,
+                    wide.required_stack_len()
+                );
+            }
+            wide_roots[mesh][1] = wide_nodes.len() as u32;
+            wide_nodes.extend_from_slice(bytemuck::cast_slice(&wide.nodes));
+            layout_sizes[2] += wide.size_bytes();
+        }
+        log::info!(
+            "BVH sizes: binary {} KiB, BVH{} {} KiB, BVH{} {} KiB",
+            layout_sizes[0] / 1024,
+            Bvh4Node::WIDTH,
+            layout_sizes[1] / 1024,
+            Bvh8Node::WIDTH,
+            layout_sizes[2] / 1024,
+        );
+
+        // 8 bit trees of every mesh and the TLAS followed by 16 bit ones,
+        // the TLAS roots go last
+        let mut compressed_nodes: Vec<u32> = vec![];
+        let mut compressed_roots = vec![[0u32; 2]; app.get_mesh_pool().blases.len() + 1];
+        for (mesh, bvh) in app.get_mesh_pool().blases.iter().enumerate() {
+            let Some(compressed) = CompressedBvh::<CompressedNode8>::compress(bvh) else {
+                log::warn!(
+                    "Mesh {mesh} is too large for 8 bit compression, tracing its binary tree"
+                );
+                compressed_roots[mesh][0] = BINARY_ROOT;
+                layout_sizes[3] += bvh.nodes.len() * std::mem::size_of::<BvhNode>();
+                continue;
+            };
+            compressed_roots[mesh][0] = compressed_nodes.len() as u32;
+            compressed_nodes.extend_from_slice(bytemuck::cast_slice(&compressed.nodes));
+            layout_sizes[3] += compressed.size_bytes();
+        }
+        let compressed_tlas = CompressedBvh::<CompressedNode8>::compress_tlas(&tlas);
+        compressed_roots[app.get_mesh_pool().blases.len()][0] = compressed_nodes.len() as u32;
+        compressed_nodes.extend_from_slice(bytemuck::cast_slice(&compressed_tlas.nodes));
+        let mut tlas_sizes = [tlas.stats().size_bytes, compressed_tlas.size_bytes(), 0];
+        for (mesh, bvh) in app.get_mesh_pool().blases.iter().enumerate() {
+            let Some(compressed) = CompressedBvh::<CompressedNode16>::compress(bvh) else {
+                log::warn!(
+                    "Mesh {mesh} is too large for 16 bit compression, tracing its binary tree"
+                );
+                compressed_roots[mesh][1] = BINARY_ROOT;
+                layout_sizes[4] += bvh.nodes.len() * std::mem::size_of::<BvhNode>();
+                continue;
+            };
+            compressed_roots[mesh][1] = compressed_nodes.len() as u32;
+            compressed_nodes.extend_from_slice(bytemuck::cast_slice(&compressed.nodes));
+            layout_sizes[4] += compressed.size_bytes();
+        }
+        let compressed_tlas = CompressedBvh::<CompressedNode16>::compress_tlas(&tlas);
+        compressed_roots[app.get_mesh_pool().blases.len()][1] = compressed_nodes.len() as u32;
+        compressed_nodes.extend_from_slice(bytemuck::cast_slice(&compressed_tlas.nodes));
+        tlas_sizes[2] = compressed_tlas.size_bytes();
+
+        let saved = |size: usize| 100. - 100. * size as f32 / layout_sizes[0] as f32;
+        log::info!(
+            "Compressed BVH sizes: 8 bit {} KiB ({:.0}% saved), 16 bit {} KiB ({:.0}% saved)",
+            layout_sizes[3] / 1024,
+            saved(layout_sizes[3]),
+            layout_sizes[4] / 1024,
+            saved(layout_sizes[4]),
+        );
+        log::info!(
+            "TLAS sizes: binary {} B, 8 bit {} B, 16 bit {} B",
+            tlas_sizes[0],
+            tlas_sizes[1],
+            tlas_sizes[2],
+        );
+
+        // Copies of the bunnies only share positions, the trace shader needs
+        // nothing else
+        let lbvh_meshes: Vec<_> = bnuuy_mesh
+            .iter()
+            .map(|&(mesh, _)| {
+                let mut meshes = app.get_mesh_pool_mut();
+                let info = meshes.mesh_info_cpu[mesh.index() as usize];
+                let indices = meshes.indices_cpu[info.base_index as usize..]
+                    [..info.index_count as usize]
+                    .to_vec();
+                let vertex_count = indices.iter().max().map_or(0, |&max| max as usize + 1);
+                let vertices =
+                    meshes.vertices_cpu[info.vertex_offset as usize..][..vertex_count].to_vec();
+                let gpu_mesh = meshes.add_with_gpu_bvh(MeshRef {
+                    vertices: &vertices,
+                    normals: &vec![Vec3::ZERO; vertex_count],
+                    tangents: &vec![Vec4::ZERO; vertex_count],
+                    tex_coords: &vec![Vec2::ZERO; vertex_count],
+                    indices,
+                });
+                (mesh, gpu_mesh)
+            })
+            .collect();
+        let lbvh_instances: Vec<_> = instances
+            .iter()
+            .map(|instance| {
+                let mut instance = *instance;
+                if let Some(&(_, gpu_mesh)) = lbvh_meshes
+                    .iter()
+                    .find(|(cpu_mesh, _)| *cpu_mesh == instance.mesh)
+                {
+                    instance.mesh = gpu_mesh;
+                }
+                instance
+            })
+            .collect();
+        let mut lbvh_tlas = Tlas::empty();
+        lbvh_tlas.build(&lbvh_instances, &app.get_mesh_pool().mesh_info_cpu);
+        layout_sizes[5] = layout_sizes[0];
+        for &(cpu_mesh, gpu_mesh) in &lbvh_meshes {
+            let meshes = app.get_mesh_pool();
+            let gpu_nodes =
+                meshes.mesh_info_cpu[gpu_mesh.index() as usize].index_count as usize / 3 * 2;
+            layout_sizes[5] += gpu_nodes * std::mem::size_of::<BvhNode>();
+            layout_sizes[5] -= meshes.blases[cpu_mesh.index() as usize].nodes.len()
+                * std::mem::size_of::<BvhNode>();
+        }
+        let lbvh_pass = pass::lbvh::Lbvh::new(&app.world, "shaders/lbvh.wgsl")?;
+
+        let wide_nodes = app
+            .device()
+            .create_resizable_buffer_init(&wide_nodes, wgpu::BufferUsages::STORAGE);
+        let wide_roots = app
+            .device()
+            .create_resizable_buffer_init(&wide_roots, wgpu::BufferUsages::STORAGE);
+
+        let compressed_nodes = app
+            .device()
+            .create_resizable_buffer_init(&compressed_nodes, wgpu::BufferUsages::STORAGE);
+        let compressed_roots = app
+            .device()
+            .create_resizable_buffer_init(&compressed_roots, wgpu::BufferUsages::STORAGE);
+
+        let geometry_bind_group =
+            |label, tlas_nodes: &ResizableBuffer<TlasNode>, instances: &wgpu::Buffer| {
+                app.device().create_bind_group(&wgpu::BindGroupDescriptor {
+                    label: Some(label),
+                    layout: &geometry_bgl,
+                    entries: &[
+                        wgpu::BindGroupEntry {
+                            binding: 0,
+                            resource: tlas_nodes.as_entire_binding(),
+                        },
+                        wgpu::BindGroupEntry {
+                            binding: 1,
+                            resource: instances.as_entire_binding(),
+                        },
+                        wgpu::BindGroupEntry {
+                            binding: 2,
+                            resource: app.get_mesh_pool().mesh_info.as_entire_binding(),
+                        },
+                        wgpu::BindGroupEntry {
+                            binding: 3,
+                            resource: app.get_mesh_pool().bvh_nodes.as_entire_binding(),
+                        },
+                        wgpu::BindGroupEntry {
+                            binding: 4,
+                            resource: app.get_mesh_pool().vertices.as_entire_binding(),
+                        },
+                        wgpu::BindGroupEntry {
+                            binding: 5,
+                            resource: app.get_mesh_pool().indices.as_entire_binding(),
+                        },
+                        wgpu::BindGroupEntry {
+                            binding: 6,
+                            resource: wide_nodes.as_entire_binding(),
+                        },
+                        wgpu::BindGroupEntry {
+                            binding: 7,
+                            resource: wide_roots.as_entire_binding(),
+                        },
+                        wgpu::BindGroupEntry {
+                            binding: 8,
+                            resource: compressed_nodes.as_entire_binding(),
+                        },
+                        wgpu::BindGroupEntry {
+                            binding: 9,
+                            resource: compressed_roots.as_entire_binding(),
+                        },
+                    ],
+                })
+            };
+        let lbvh_tlas_nodes = app
+            .device()
+            .create_resizable_buffer_init(&lbvh_tlas.nodes, wgpu::BufferUsages::STORAGE);
+        let lbvh_instances = app
+            .device()
+            .create_resizable_buffer_init(&lbvh_instances, wgpu::BufferUsages::STORAGE);
+        let lbvh_bind_group = geometry_bind_group(
+            "Lbvh Geometry Bind Group",
+            &lbvh_tlas_nodes,
+            &lbvh_instances,
+        );
+        let geometry_bind_group = geometry_bind_group(
+            "Geometry Bind Group",
+            &tlas_nodes,
+            &app.get_instance_pool().instances,
+        );
 
         Ok(Self {
-            pipeline,
+            pipelines,
+            layout: BvhLayout::Binary,
+            layout_sizes,
             tlas,
             tlas_nodes,
+            wide_nodes,
+            wide_roots,
+            compressed_nodes,
+            compressed_roots,
             geometry_bind_group,
+
+            lbvh_pass,
+            lbvh_meshes,
+            lbvh_state: LbvhState::Pending,
+            lbvh_tlas_nodes,
+            lbvh_instances,
+            lbvh_bind_group,
         })
     }
 
-    fn update(&mut self, _ctx: UpdateContext) {}
+    fn update(&mut self, mut ctx: UpdateContext) {
+        match self.lbvh_state {
+            LbvhState::Pending => {
+                for &(_, gpu_mesh) in &self.lbvh_meshes {
+                    self.lbvh_pass.record(ctx.world, &mut ctx.encoder, gpu_mesh);
+                }
+                self.lbvh_state = LbvhState::Built;
+            }
+            // The build was submitted after the last update
+            LbvhState::Built => {
+                let check = check_lbvh(
+                    &ctx.world.gpu,
+                    &ctx.world.unwrap::<MeshPool>(),
+                    &self.lbvh_meshes,
+                );
+                if check.mismatches > 0 {
+                    log::error!(
+                        "LBVH hits differ from the CPU BVH for {} of {} rays",
+                        check.mismatches,
+                        check.rays
+                    );
+                } else {
+                    log::info!("LBVH hits match the CPU BVH for {} rays", check.rays);
+                }
+                if check.depth + 1 > WGSL_STACK_LEN {
+                    log::warn!(
+                        "LBVH is {} levels deep, shaders skip subtrees past {WGSL_STACK_LEN} stack entries",
+                        check.depth
+                    );
+                }
+                self.lbvh_state = LbvhState::Checked(check);
+            }
+            LbvhState::Checked(_) => {}
+        }
+    }
 
     fn resize(&mut self, _gpu: &Gpu, _width: u32, _height: u32) {}
 
@@ -198,9 +635,13 @@ impl Example for Demo {
             depth_stencil_attachment: None,
         });
 
-        pass.set_pipeline(arena.get_pipeline(self.pipeline));
+        let (layout, geometry_bind_group) = match self.layout {
+            BvhLayout::Lbvh => (BvhLayout::Binary, &self.lbvh_bind_group),
+            layout => (layout, &self.geometry_bind_group),
+        };
+        pass.set_pipeline(arena.get_pipeline(self.pipelines[layout as usize]));
         pass.set_bind_group(0, &camera.binding, &[]);
-        pass.set_bind_group(1, &self.geometry_bind_group, &[]);
+        pass.set_bind_group(1, geometry_bind_group, &[]);
         pass.draw(0..3, 0..1);
         drop(pass);
 
@@ -210,6 +651,19 @@ impl Example for Demo {
                     "Fps: {:.04?}",
                     Duration::from_secs_f64(ctx.app_state.dt)
                 ));
+                for layout in BvhLayout::ALL {
+                    let size = self.layout_sizes[layout as usize] / 1024;
+                    ui.radio_value(&mut self.layout, layout, format!("{layout:?}: {size} KiB"));
+                }
+                if let LbvhState::Checked(check) = &self.lbvh_state {
+                    ui.label(format!(
+                        "LBVH: {}/{} rays differ from CPU BVH, depth {}",
+                        check.mismatches, check.rays, check.depth
+                    ));
+                }
+                if ui.button("Rebuild LBVH").clicked() {
+                    self.lbvh_state = LbvhState::Pending;
+                }
             });
         });
     }
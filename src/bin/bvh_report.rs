@@ -0,0 +1,128 @@
+//! Prints quality statistics of the BVHs built for a glTF or OBJ model.
+//!
+//! Usage: `bvh_report <model> [--spatial-splits <budget>] [--check-stack]`
+//!
+//! With `--check-stack` the report fails if any tree can overflow the WGSL
+//! traversal stack.
+
+use std::path::PathBuf;
+
+use app::Watcher;
+use bvh::{BvhStats, CompressedBvh, CompressedNode16, CompressedNode8, Tlas, WGSL_STACK_LEN};
+use color_eyre::{eyre::eyre, Result};
+use voidin::*;
+use winit::event_loop::EventLoopBuilder;
+
+struct Args {
+    model: PathBuf,
+    spatial_splits: Option<f32>,
+    check_stack: bool,
+}
+
+fn parse_args() -> Result<Args> {
+    let mut model = None;
+    let mut spatial_splits = None;
+    let mut check_stack = false;
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--check-stack" => check_stack = true,
+            "--spatial-splits" => {
+                let budget = args
+                    .next()
+                    .ok_or_else(|| eyre!("--spatial-splits expects a budget"))?;
+                spatial_splits = Some(budget.parse()?);
+            }
+            _ if model.is_none() => model = Some(PathBuf::from(arg)),
+            _ => return Err(eyre!("Unexpected argument: {arg}")),
+        }
+    }
+    let model = model.ok_or_else(|| {
+        eyre!("Usage: bvh_report <model> [--spatial-splits <budget>] [--check-stack]")
+    })?;
+    Ok(Args {
+        model,
+        spatial_splits,
+        check_stack,
+    })
+}
+
+/// Prints `stats` along with the sizes of the 8 and 16 bit compressed trees
+/// and returns whether the tree fits the traversal stacks. `compressed` is
+/// `None` for meshes too large for compressed leaves.
+fn report(name: &str, stats: &BvhStats, compressed: Option<[usize; 2]>) -> bool {
+    println!("{name}");
+    println!("{stats}");
+    let saved = |size: usize| 100. - 100. * size as f32 / stats.size_bytes.max(1) as f32;
+    match compressed {
+        Some(compressed) => println!(
+            "Compressed:    8 bit {:.1} KiB ({:.0}% saved), 16 bit {:.1} KiB ({:.0}% saved)",
+            compressed[0] as f32 / 1024.,
+            saved(compressed[0]),
+            compressed[1] as f32 / 1024.,
+            saved(compressed[1]),
+        ),
+        None => println!("Compressed:    none, triangles past the 24 bit leaf start"),
+    }
+    let fits = !stats.exceeds_stack(WGSL_STACK_LEN);
+    if !fits {
+        println!(
+            "Stack:         needs {} entries, the WGSL stack holds {WGSL_STACK_LEN}",
+            stats.required_stack_len()
+        );
+    }
+    println!();
+    fits
+}
+
+fn main() -> Result<()> {
+    color_eyre::install()?;
+    let args = parse_args()?;
+
+    // Importers upload to the GPU, so they need an app with a surface
+    let event_loop = EventLoopBuilder::<PathBuf>::with_user_event().build();
+    let window = WindowBuilder::new()
+        .with_title("Bvh Report")
+        .with_visible(false)
+        .build(&event_loop)?;
+    let mut app = App::new(&window, Watcher::new(event_loop.create_proxy())?)?;
+    app.get_mesh_pool_mut().spatial_split_budget = args.spatial_splits;
+
+    let first_mesh = app.get_mesh_pool().count();
+    let instances = match args.model.extension().and_then(|ext| ext.to_str()) {
+        Some("obj") => models::ObjModel::import(&mut app, &args.model)?
+            .into_iter()
+            .map(|(mesh, material)| Instance::new(Mat4::IDENTITY, mesh, material))
+            .collect(),
+        Some("gltf" | "glb") => {
+            GltfDocument::import(&mut app, &args.model)?.get_scene_instances(Mat4::IDENTITY)
+        }
+        _ => return Err(eyre!("Unsupported model: {}", args.model.display())),
+    };
+
+    let meshes = app.get_mesh_pool();
+    let mut all_fit = true;
+    for mesh in first_mesh..meshes.count() {
+        let blas = &meshes.blases[mesh as usize];
+        let triangles = meshes.mesh_info_cpu[mesh as usize].index_count / 3;
+        let name = format!("Mesh {mesh}: {triangles} triangles");
+        let compressed = CompressedBvh::<CompressedNode8>::compress(blas)
+            .zip(CompressedBvh::<CompressedNode16>::compress(blas))
+            .map(|(bits8, bits16)| [bits8.size_bytes(), bits16.size_bytes()]);
+        all_fit &= report(&name, &blas.stats(), compressed);
+    }
+
+    let mut tlas = Tlas::empty();
+    tlas.build(&instances, &meshes.mesh_info_cpu);
+    let name = format!("TLAS: {} instances", instances.len());
+    let compressed = Some([
+        CompressedBvh::<CompressedNode8>::compress_tlas(&tlas).size_bytes(),
+        CompressedBvh::<CompressedNode16>::compress_tlas(&tlas).size_bytes(),
+    ]);
+    all_fit &= report(&name, &tlas.stats(), compressed);
+
+    if args.check_stack && !all_fit {
+        return Err(eyre!("Some trees are too deep for the traversal stacks"));
+    }
+    Ok(())
+}
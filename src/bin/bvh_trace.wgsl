@@ -1,94 +1,18 @@
 #import "shared.wgsl"
-#import "utils/math.wgsl"
+#import "utils/stack.wgsl"
+#import "utils/bvh_nodes.wgsl"
+#import "utils/bvh_wide.wgsl"
+#import "utils/bvh_compressed.wgsl"
 
 var<private> BDEPTH: f32 = 0.;
 var<private> TDEPTH: f32 = -1.;
 
-const STACK_LEN: u32 = 24u;
-struct Stack {
-    arr: array<u32, STACK_LEN>,
-	head: u32,
-}
-
-fn stack_new() -> Stack {
-    var arr: array<u32, STACK_LEN>;
-    return Stack(arr, 0u);
-}
-
-fn stack_push(stack: ptr<function, Stack>, val: u32) {
-    (*stack).arr[(*stack).head] = val;
-    (*stack).head += 1u;
-}
-
-fn stack_pop(stack: ptr<function, Stack>) -> u32 {
-    (*stack).head -= 1u;
-    return (*stack).arr[(*stack).head];
-}
-
-struct Ray {
-    eye: vec3<f32>,
-	dir: vec3<f32>,
-	inv_dir: vec3<f32>,
-}
-
-fn ray_new(eye: vec3<f32>, dir: vec3<f32>) -> Ray {
-    return Ray(eye, dir, 1. / dir);
-}
-
-fn intersect_aabb(ray: Ray, bmin: vec3<f32>, bmax: vec3<f32>, t: f32) -> f32 {
-    let tx1 = (bmin - ray.eye) * ray.inv_dir;
-    let tx2 = (bmax - ray.eye) * ray.inv_dir;
-    let tmax = min_element(max(tx1, tx2));
-    let tmin = max_element(min(tx1, tx2));
-    if tmax >= tmin && tmin < t && tmax > 0. {
-        return tmin;
-    } else {
-        return MAX_DIST;
-    }
-}
-
-fn intersect_trig(ray: Ray, v0: vec3<f32>, v1: vec3<f32>, v2: vec3<f32>, hit: ptr<function,f32>) -> bool {
-    let edge1 = v1 - v0;
-    let edge2 = v2 - v0;
-    let uvec = cross(ray.dir, edge2);
-    let det = dot(edge1, uvec);
-    if det < 1e-10 { return false; } // cull backface
-    let inv_det = 1. / det;
-    let orig = ray.eye - v0;
-    let u = inv_det * dot(orig, uvec);
-    if u < 0. || 1. < u { return false; }
-    let vvec = cross(orig, edge1);
-    let v = inv_det * dot(ray.dir, vvec);
-    if v < 0. || u + v > 1. { return false; }
-    let t = inv_det * dot(edge2, vvec);
-    if t > 0.0 && t < *hit {
-        *hit = t;
-        return true;
-    } else {
-        return false;
-    }
-}
-
 fn triangle_normal(v0: vec3<f32>, v1: vec3<f32>, v2: vec3<f32>) -> vec3<f32> {
     let  p1 = v1 - v0;
     let p2 = v1 - v2;
     return normalize(cross(normalize(p1), normalize(p2)));
 }
 
-struct TlasNode {
-	min: vec3<f32>,
-	left_right: u32,
-	max: vec3<f32>,
-	instance_idx: u32,
-}
-
-struct BvhNode {
-	min: vec3<f32>,
-	left_first: u32,
-	max: vec3<f32>,
-	count: u32,
-}
-
 struct TraceResult {
 	v0: vec3<f32>,
 	v1: vec3<f32>,
@@ -150,7 +74,10 @@ fn traverse_bvh(ray: Ray, mesh: MeshInfo, res: ptr<function, TraceResult>) {
     }
 }
 
-fn instance_intersect(ray: Ray, instance: Instance, res: ptr<function, TraceResult>) {
+// Width 2 walks the binary `BvhNode` tree, or its compressed form for
+// `bits` below 32, 4 and 8 the collapsed ones. Meshes without such a tree
+// fall back to the binary one.
+fn instance_intersect(ray: Ray, instance: Instance, width: u32, bits: u32, res: ptr<function, TraceResult>) {
     var new_ray = ray;
 
     let mesh = meshes[instance.mesh_id];
@@ -158,21 +85,46 @@ fn instance_intersect(ray: Ray, instance: Instance, res: ptr<function, TraceResu
     new_ray.dir = (instance.inv_transform * vec4(ray.dir, 0.)).xyz;
     new_ray.inv_dir = 1. / new_ray.dir;
 
-    traverse_bvh(new_ray, mesh, res);
+    let mesh_compressed_roots = compressed_roots[instance.mesh_id];
+    let compressed_root = select(mesh_compressed_roots.x, mesh_compressed_roots.y, bits == 16u);
+    let mesh_wide_roots = wide_roots[instance.mesh_id];
+    let wide_root = select(mesh_wide_roots.x, mesh_wide_roots.y, width == 8u);
+    let root = select(wide_root, compressed_root, width == 2u);
+    if (width == 2u && bits == 32u) || root == BINARY_ROOT {
+        traverse_bvh(new_ray, mesh, res);
+        return;
+    }
+    var hit: CompressedHit;
+    if width == 2u {
+        hit = traverse_compressed_bvh(new_ray, mesh, root, bits, (*res).dist);
+    } else {
+        let wide = traverse_wide_bvh(new_ray, mesh, root, width, (*res).dist);
+        hit = CompressedHit(wide.hit, wide.dist, wide.triangle, wide.barycentrics);
+    }
+    if hit.hit {
+        let v0 = fetch_vertex(3u * hit.triangle + 0u, mesh);
+        let v1 = fetch_vertex(3u * hit.triangle + 1u, mesh);
+        let v2 = fetch_vertex(3u * hit.triangle + 2u, mesh);
+        *res = TraceResult(v0, v1, v2, true, hit.dist);
+    }
 }
 
-fn traverse_tlas(ray: Ray) -> TraceResult {
+fn traverse_tlas(ray: Ray, width: u32, bits: u32) -> TraceResult {
+    if bits < 32u {
+        return traverse_compressed_tlas(ray, bits);
+    }
+
     var stack = stack_new();
     stack_push(&stack, 0u);
 
     var res = trace_result_new();
     while stack.head > 0u {
         let node = tlas_nodes[stack_pop(&stack)];
-        if node.left_right == 0u { // is leaf
-            instance_intersect(ray, instances[node.instance_idx], &res);
+        if node.left == 0u { // is leaf
+            instance_intersect(ray, instances[node.instance_idx], width, bits, &res);
 		} else {
-            var min_index = node.left_right & 0xffffu;
-            var max_index = node.left_right >> 16u;
+            var min_index = node.left;
+            var max_index = node.left + 1u;
 
             let min_child = tlas_nodes[min_index];
             let max_child = tlas_nodes[max_index];
@@ -198,6 +150,47 @@ fn traverse_tlas(ray: Ray) -> TraceResult {
     return res;
 }
 
+// `traverse_tlas` over the compressed TLAS, whose roots follow the mesh ones
+fn traverse_compressed_tlas(ray: Ray, bits: u32) -> TraceResult {
+    let roots = compressed_roots[arrayLength(&compressed_roots) - 1u];
+    let root = select(roots.x, roots.y, bits == 16u);
+    let stride = compressed_node_stride(bits);
+
+    var stack = stack_new();
+    stack_push(&stack, 0u);
+
+    var res = trace_result_new();
+    while stack.head > 0u {
+        let node = root + stack_pop(&stack) * stride;
+        var children = vec2(compressed_nodes[node + 4u], compressed_nodes[node + 5u]);
+        var dists = vec2(MAX_DIST);
+        for (var slot = 0u; slot < 2u; slot += 1u) {
+            if children[slot] != COMPRESSED_EMPTY_CHILD {
+                dists[slot] = compressed_child_dist(ray, node, bits, slot, res.dist);
+            }
+        }
+        if dists.y < dists.x {
+            children = children.yx;
+            dists = dists.yx;
+        }
+
+        for (var i = 0u; i < 2u; i += 1u) {
+            if dists[i] < res.dist && (children[i] & COMPRESSED_LEAF_FLAG) != 0u {
+                let instance = instances[children[i] & ~COMPRESSED_LEAF_FLAG];
+                instance_intersect(ray, instance, 2u, bits, &res);
+            }
+        }
+        for (var i = 0u; i < 2u; i += 1u) {
+            let slot = 1u - i;
+            if dists[slot] < res.dist && (children[slot] & COMPRESSED_LEAF_FLAG) == 0u {
+                stack_push(&stack, children[slot]);
+            }
+        }
+        TDEPTH += 1.;
+    }
+    return res;
+}
+
 @group(0) @binding(0) var<uniform> cam: Camera;
 
 @group(1) @binding(0) var<storage, read> tlas_nodes: array<TlasNode>;
@@ -206,6 +199,16 @@ fn traverse_tlas(ray: Ray) -> TraceResult {
 @group(1) @binding(3) var<storage, read> bvh_nodes: array<BvhNode>;
 @group(1) @binding(4) var<storage, read> vertices: array<f32>;
 @group(1) @binding(5) var<storage, read> indices: array<u32>;
+// Root of meshes too large for the wide and compressed layouts
+const BINARY_ROOT: u32 = 0xffffffffu;
+
+// BVH4 nodes of every mesh followed by BVH8 ones, roots are word offsets
+@group(1) @binding(6) var<storage, read> wide_nodes: array<u32>;
+@group(1) @binding(7) var<storage, read> wide_roots: array<vec2<u32>>;
+// 8 bit trees of every mesh and the TLAS followed by 16 bit ones, roots are
+// word offsets with the TLAS roots last
+@group(1) @binding(8) var<storage, read> compressed_nodes: array<u32>;
+@group(1) @binding(9) var<storage, read> compressed_roots: array<vec2<u32>>;
 
 struct VertexOutput {
 	@builtin(position) pos: vec4<f32>,
@@ -220,8 +223,7 @@ fn vs_main(@builtin(vertex_index) vertex_idx: u32) -> VertexOutput {
     return out;
 }
 
-@fragment
-fn fs_main(in: VertexOutput) -> @location(0) vec4<f32> {
+fn render(in: VertexOutput, width: u32, bits: u32) -> vec4<f32> {
     let uv = in.uv * 2. - 1.0;
 
     let view_pos = cam.clip_to_world * vec4(uv, 1., 1.);
@@ -233,7 +235,7 @@ fn fs_main(in: VertexOutput) -> @location(0) vec4<f32> {
     let ray = ray_new(eye, dir);
 
     var color = vec3(0.13);
-    let res = traverse_tlas(ray);
+    let res = traverse_tlas(ray, width, bits);
     if res.hit {
         let nor = triangle_normal(res.v0, res.v1, res.v2);
         color = vec3(length(sin(-nor * 2.5) * 0.5 + 0.5) / sqrt(3.));
@@ -246,3 +248,28 @@ fn fs_main(in: VertexOutput) -> @location(0) vec4<f32> {
     color = pow(color, vec3(1.4545));
     return vec4(color, 1.0);
 }
+
+@fragment
+fn fs_main(in: VertexOutput) -> @location(0) vec4<f32> {
+    return render(in, 2u, 32u);
+}
+
+@fragment
+fn fs_bvh4(in: VertexOutput) -> @location(0) vec4<f32> {
+    return render(in, 4u, 32u);
+}
+
+@fragment
+fn fs_bvh8(in: VertexOutput) -> @location(0) vec4<f32> {
+    return render(in, 8u, 32u);
+}
+
+@fragment
+fn fs_compressed8(in: VertexOutput) -> @location(0) vec4<f32> {
+    return render(in, 2u, 8u);
+}
+
+@fragment
+fn fs_compressed16(in: VertexOutput) -> @location(0) vec4<f32> {
+    return render(in, 2u, 16u);
+}
@@ -14,9 +14,12 @@ struct Model {
 
     update_pass: pass::compute_update::ComputeUpdate,
 
+    tlas_refit_pass: pass::tlas_refit::TlasRefit,
+
     taa_pass: pass::taa::Taa,
 
-    moving_instances: ResizableBuffer<InstanceId>,
+    /// Slots of the instances animated by `compute_update.wgsl`.
+    moving_instances: ResizableBuffer<u32>,
     moving_instances_bind_group: wgpu::BindGroup,
 }
 
@@ -37,6 +40,9 @@ impl Example for Model {
         let update_pass =
             pass::compute_update::ComputeUpdate::new(&app.world, "shaders/compute_update.wgsl")?;
 
+        let tlas_refit_pass =
+            pass::tlas_refit::TlasRefit::new(&app.world, "shaders/tlas_refit.wgsl")?;
+
         let taa_pass = pass::taa::Taa::new(
             &app.world,
             &app.gbuffer,
@@ -54,6 +60,7 @@ impl Example for Model {
             shading_pass,
             postprocess_pass,
             update_pass,
+            tlas_refit_pass,
             taa_pass,
 
             moving_instances,
@@ -132,7 +139,12 @@ impl Example for Model {
             moving_instances.push(Instance::new(
                 Mat4::from_translation(vec3(x, y, -17.)),
                 sphere_mesh_id,
-                MaterialId::new(rng.gen_range(0..app.get_material_pool().num_materials() as u32)),
+                // Materials of the demo are never removed, their slots are
+                // still in the first generation
+                MaterialId::new(
+                    rng.gen_range(0..app.get_material_pool().num_materials() as u32),
+                    0,
+                ),
             ));
 
             moving_instances.extend(gltf_ferris.get_scene_instances(
@@ -143,7 +155,8 @@ impl Example for Model {
         }
 
         let moving_instances_id = app.world.get_mut::<InstancePool>()?.add(&moving_instances);
-        self.moving_instances.push(&app.gpu, &moving_instances_id);
+        let moving_slots: Vec<_> = moving_instances_id.iter().map(InstanceId::index).collect();
+        self.moving_instances.push(&app.gpu, &moving_slots);
         self.moving_instances_bind_group = self
             .moving_instances
             .create_storage_read_bind_group(&mut app.world);
@@ -162,6 +175,7 @@ impl Example for Model {
         };
         self.update_pass
             .record(ctx.world, &mut ctx.encoder, resources);
+        self.tlas_refit_pass.record(ctx.world, &mut ctx.encoder, ());
     }
 
     fn resize(&mut self, gpu: &Gpu, width: u32, height: u32) {
@@ -3,6 +3,7 @@
 #import "utils/ltc.wgsl"
 #import "utils/uv.wgsl"
 #import "utils/bvh.wgsl"
+#import "utils/alpha_test.wgsl"
 
 @group(0) @binding(0) var<uniform> global: Globals;
 @group(0) @binding(1) var<uniform> camera: Camera;
@@ -15,6 +16,7 @@
 @group(2) @binding(0) var texture_array: binding_array<texture_2d<f32>>;
 @group(2) @binding(1) var tex_sampler: sampler;
 @group(2) @binding(2) var tex_ltc_sampler: sampler;
+@group(2) @binding(3) var samplers: binding_array<sampler>;
 
 @group(3) @binding(0) var<storage, read> materials: array<Material>;
 
@@ -27,6 +29,8 @@
 @group(6) @binding(3) var<storage, read> bvh_nodes: array<BvhNode>;
 @group(6) @binding(4) var<storage, read> vertices: array<f32>;
 @group(6) @binding(5) var<storage, read> indices: array<u32>;
+@group(6) @binding(6) var<storage, read> tex_coords: array<vec2<f32>>;
+@group(6) @binding(7) var<storage, read> primitives: array<ProceduralPrimitive>;
 
 struct VertexOutput {
   @builtin(position) pos: vec4<f32>,
@@ -65,14 +69,16 @@ fn fs_main(in: VertexOutput) -> @location(0) vec4<f32> {
 
     let material = materials[material_id];
     let uv = unpack2x16float(norm_uv_tex.y);
-    let albedo = textureSample(texture_array[material.albedo], t_sampler, uv);
-    let emissive = textureSample(texture_array[material.emissive], t_sampler, uv).rgb;
-    let metallic_roughness = textureSample(texture_array[material.metallic_roughness], t_sampler, uv);
+    let surface = sample_material(material, uv);
+    let albedo = surface.base_color;
+    let emissive = surface.emissive;
 
 
     let pos = world_position_from_depth(in.uv, depth, camera.clip_to_world);
     let nor = decode_octahedral_32(norm_uv_tex.x);
     let rd = normalize(camera.position.xyz - pos);
+    let ltc = ltc_matrix(nor, rd, surface.roughness);
+    let brdf = surface_brdf(surface, ltc.t2.xy);
 
     var color = vec3(0.);
 
@@ -80,13 +86,13 @@ fn fs_main(in: VertexOutput) -> @location(0) vec4<f32> {
     if material_id == 0u {
         return vec4(1., 0., 1., 1.);
     }
-    if material_id == LIGHT_MATERIAL {
+    if surface.material_type == MATERIAL_UNLIT {
         color = albedo.rgb + emissive;
     }
 
     let light_count = arrayLength(&point_lights);
     for (var i = 0u; i < light_count; i += 1u) {
-        if material_id == LIGHT_MATERIAL { break; }
+        if surface.material_type == MATERIAL_UNLIT { break; }
 
         let light = point_lights[i];
 
@@ -95,23 +101,16 @@ fn fs_main(in: VertexOutput) -> @location(0) vec4<f32> {
         if dist - light.radius > 0. { continue; }
 
         var occlusion = 1.0;
+        // Unnormalized direction puts the light at distance 1
         let ray = ray_new(pos + nor * 0.0001, light_vec);
-        let trace_result = traverse_tlas(ray);
-        if trace_result.hit {
+        if occluded_tlas(ray, 1.) {
             occlusion = 0.5;
         }
 
         let atten = attenuation(1., 1., dist, light.radius);
 
         let light_dir = normalize(light_vec);
-        let shade = max(0., dot(nor, light_dir));
-        let diff = light.color * albedo.rgb * shade;
-
-        let refl = reflect(-light_dir, rd);
-        let covr = max(0., dot(-rd, nor));
-        let spec = light.color * metallic_roughness.z * pow(covr, 16.);
-
-        color += (diff + spec) * occlusion * atten;
+        color += light.color * surface_evaluate(surface, brdf, nor, rd, light_dir) * occlusion * atten;
     }
 
     color = max(color, vec3(0.));
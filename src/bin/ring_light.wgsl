@@ -3,6 +3,7 @@
 #import "utils/ltc.wgsl"
 #import "utils/uv.wgsl"
 #import "utils/bvh.wgsl"
+#import "utils/alpha_test.wgsl"
 
 @group(0) @binding(0) var<uniform> global: Globals;
 @group(0) @binding(1) var<uniform> camera: Camera;
@@ -15,6 +16,7 @@
 @group(2) @binding(0) var texture_array: binding_array<texture_2d<f32>>;
 @group(2) @binding(1) var tex_sampler: sampler;
 @group(2) @binding(2) var tex_ltc_sampler: sampler;
+@group(2) @binding(3) var samplers: binding_array<sampler>;
 
 @group(3) @binding(0) var<storage, read> materials: array<Material>;
 
@@ -27,6 +29,8 @@
 @group(6) @binding(3) var<storage, read> bvh_nodes: array<BvhNode>;
 @group(6) @binding(4) var<storage, read> vertices: array<f32>;
 @group(6) @binding(5) var<storage, read> indices: array<u32>;
+@group(6) @binding(6) var<storage, read> tex_coords: array<vec2<f32>>;
+@group(6) @binding(7) var<storage, read> primitives: array<ProceduralPrimitive>;
 
 struct Ray2 {
 	origin: vec3<f32>,
@@ -353,13 +357,13 @@ fn fs_main(in: VertexOutput) -> @location(0) vec4<f32> {
 
     var depth = textureLoad(t_depth, load_uv, 0);
     let norm_uv_tex = textureLoad(t_normal_uv, load_uv, 0);
-    var material_id = textureLoad(t_material, load_uv, 0).r;
+    let material_id = textureLoad(t_material, load_uv, 0).r;
 
     let material = materials[material_id];
     let uv = unpack2x16float(norm_uv_tex.y);
-    let albedo = textureSample(texture_array[material.albedo], t_sampler, uv);
-    let emissive = textureSample(texture_array[material.emissive], t_sampler, uv).rgb;
-    let metallic_roughness = textureSample(texture_array[material.metallic_roughness], t_sampler, uv);
+    let surface = sample_material(material, uv);
+    let albedo = surface.base_color;
+    let emissive = surface.emissive;
 
     let pos = world_position_from_depth(in.uv, depth, camera.clip_to_world);
     let nor = decode_octahedral_32(norm_uv_tex.x);
@@ -382,7 +386,6 @@ fn fs_main(in: VertexOutput) -> @location(0) vec4<f32> {
     let ray = Ray2(pos, rd);
     let disk_hit = ray_disc_intersect(ray, disk);
     if disk_hit < MAX_DIST {
-        material_id = LIGHT_MATERIAL;
         color = albedo.rgb + emissive;
         return vec4(color, 1.0);
     }
@@ -391,7 +394,7 @@ fn fs_main(in: VertexOutput) -> @location(0) vec4<f32> {
     if material_id == 0u {
         return vec4(.13, 0.13, .13, 1.);
     }
-    if material_id == LIGHT_MATERIAL {
+    if surface.material_type == MATERIAL_UNLIT {
         color = albedo.rgb + emissive;
         return vec4(color, 1.0);
     }